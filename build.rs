@@ -0,0 +1,197 @@
+//! Packs the files under `initrd/` into a USTAR archive, which the kernel embeds
+//! as its initial ramdisk (see `src/fs/initrd.rs`), and creates the disk images the
+//! tests attach (see `test-args` in Cargo.toml).
+
+use std::{env,fs,io,path::{Path,PathBuf},process::Command};
+
+const BLOCK:usize = 512;
+
+fn main() {
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("initrd.tar");
+    let mut archive = Vec::new();
+    let root = Path::new("initrd");
+    if root.is_dir() {
+        add_dir(&mut archive,root,"").expect("packing the initrd failed");
+    }
+    archive.extend_from_slice(&[0;2*BLOCK]);//end of archive
+    fs::write(out,archive).expect("writing the initrd failed");
+    println!("cargo:rerun-if-changed=initrd");
+    test_disk().expect("creating the test disk failed");
+    fat_image().expect("creating the FAT32 test image failed");
+    ext2_image().expect("creating the ext2 test image failed");
+}
+
+/// Where the test images go: `target/`.
+fn image_path(name:&str) -> PathBuf {
+    Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("target").join(name)
+}
+
+/// Runs `program`; false if it isn't installed or fails.
+fn run(program:&str, args:&[&str]) -> bool {
+    match Command::new(program).args(args).env("MTOOLS_SKIP_CHECK","1").status() {
+        Ok(status) => status.success(),
+        Err(_) => false,
+    }
+}
+
+/// Stands in for an image the tools to make it are missing for, so that QEMU still
+/// starts; the tests using it then fail to mount it.
+fn blank_image(path:&Path, size:u64, tools:&str) -> io::Result<()> {
+    println!("cargo:warning=could not create {} with {}; attaching a blank disk instead, remove it once they are installed",path.display(),tools);
+    fs::File::create(path)?.set_len(size)
+}
+
+/// KiB of the FAT32 test image: with one 512-byte sector per cluster, just over the
+/// 65525 clusters FAT32 needs at least.
+const FAT_IMAGE_KIB:u64 = 40*1024;
+
+/// Creates `target/fat32-test.img` unless it exists: a volume made by mkfs.fat and
+/// filled by mcopy with long names, subdirectories, a directory of several
+/// clusters and a file of many. `tests/fat.rs` checks for exactly these files.
+fn fat_image() -> io::Result<()> {
+    let path = image_path("fat32-test.img");
+    if path.exists() {
+        return Ok(());
+    }
+    let files = Path::new(&env::var("OUT_DIR").unwrap()).join("fat32");
+    if files.exists() {
+        fs::remove_dir_all(&files)?;
+    }
+    fs::create_dir_all(files.join("docs/sub"))?;
+    fs::create_dir_all(files.join("many"))?;
+    fs::write(files.join("A long file name.txt"),"long names work\n")?;
+    fs::write(files.join("docs/Read Me First.md"),"nested\n")?;
+    fs::write(files.join("docs/sub/deep.txt"),"deeper\n")?;
+    for i in 0..40u8 {
+        fs::write(files.join(format!("many/file number {}",i)),[i])?;
+    }
+    fs::write(files.join("big.bin"),(0..100_000u32).map(|i|(i*7) as u8).collect::<Vec<u8>>())?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let image = path.to_str().unwrap();
+    let mut made = run("mkfs.fat",&["-C","-F","32","-s","1","-S","512","-n","TESTFAT",image,&FAT_IMAGE_KIB.to_string()]);
+    let mut entries:Vec<PathBuf> = fs::read_dir(&files)?.map(|entry|entry.map(|entry|entry.path())).collect::<io::Result<_>>()?;
+    entries.sort();
+    for entry in &entries {
+        made = made && run("mcopy",&["-i",image,"-s",entry.to_str().unwrap(),"::/"]);
+    }
+    if !made {
+        let _ = fs::remove_file(&path);
+        return blank_image(&path,FAT_IMAGE_KIB*1024,"mkfs.fat and mcopy (dosfstools, mtools)");
+    }
+    Ok(())
+}
+
+/// Sectors of the virtio test disk.
+const TEST_DISK_SECTORS:usize = 8192;
+
+/// Creates `target/virtio-test.img` (see `test-args` in Cargo.toml) unless it
+/// exists. Every sector starts with "sector <n>" so reads can be checked.
+fn test_disk() -> io::Result<()> {
+    let path = image_path("virtio-test.img");
+    if path.exists() {
+        return Ok(());
+    }
+    let mut image = vec![0u8;TEST_DISK_SECTORS*BLOCK];
+    for (number,sector) in image.chunks_mut(BLOCK).enumerate() {
+        let label = format!("sector {}",number);
+        sector[..label.len()].copy_from_slice(label.as_bytes());
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(path,image)
+}
+
+/// KiB of the ext2 test image, in four groups of 4096 4 KiB blocks and 512 inodes.
+const EXT2_IMAGE_KIB:u64 = 64*1024;
+
+/// Blocks of the large file on the ext2 image, enough to need double indirect blocks.
+const EXT2_BIG_BLOCKS:usize = 5120;
+
+/// Creates `target/ext2-test.img` unless it exists: a volume made by mke2fs with
+/// its default ext2 features, filled from a directory with a file larger than a
+/// group and a directory of enough files to spread over the groups.
+/// `tests/ext2.rs` checks for exactly these files.
+fn ext2_image() -> io::Result<()> {
+    let path = image_path("ext2-test.img");
+    if path.exists() {
+        return Ok(());
+    }
+    let files = Path::new(&env::var("OUT_DIR").unwrap()).join("ext2");
+    if files.exists() {
+        fs::remove_dir_all(&files)?;
+    }
+    fs::create_dir_all(files.join("docs"))?;
+    fs::create_dir_all(files.join("many"))?;
+    fs::write(files.join("hello.txt"),"hello from mke2fs\n")?;
+    fs::write(files.join("docs/notes.md"),"nested\n")?;
+    for i in 0..1500 {
+        fs::write(files.join(format!("many/f{:04}",i)),i.to_string())?;
+    }
+    //every block starts with its number, so that reads can be checked anywhere
+    let mut big = vec![0u8;EXT2_BIG_BLOCKS*4096];
+    for (number,block) in big.chunks_mut(4096).enumerate() {
+        let label = format!("block {}",number);
+        block[..label.len()].copy_from_slice(label.as_bytes());
+    }
+    fs::write(files.join("big"),big)?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let made = run("mke2fs",&["-q","-F","-t","ext2","-b","4096","-g","4096","-N","2048","-d",files.to_str().unwrap(),
+        path.to_str().unwrap(),&format!("{}k",EXT2_IMAGE_KIB)]);
+    if !made {
+        let _ = fs::remove_file(&path);
+        return blank_image(&path,EXT2_IMAGE_KIB*1024,"mke2fs (e2fsprogs 1.43 or later)");
+    }
+    Ok(())
+}
+
+/// Appends the contents of `dir`, whose path inside the archive is `prefix`, sorted by name.
+fn add_dir(archive:&mut Vec<u8>, dir:&Path, prefix:&str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry|entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}",prefix,entry.file_name().to_string_lossy());
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            archive.extend_from_slice(&header(&format!("{}/",name),0,b'5',0o755));
+            add_dir(archive,&entry.path(),&format!("{}/",name))?;
+        } else if kind.is_file() {
+            let data = fs::read(entry.path())?;
+            archive.extend_from_slice(&header(&name,data.len() as u64,b'0',0o644));
+            archive.extend_from_slice(&data);
+            archive.resize((archive.len() + BLOCK - 1)/BLOCK*BLOCK,0);
+        }
+    }
+    Ok(())
+}
+
+fn header(path:&str, size:u64, kind:u8, mode:u32) -> [u8;BLOCK] {
+    let mut header = [0u8;BLOCK];
+    let (prefix,name) = if path.len() <= 100 {
+        ("",path)
+    } else {
+        //split at a slash so that the name fits in 100 bytes and the rest in the prefix
+        let split = path[..=155.min(path.len()-1)].rfind('/').expect("initrd path too long");
+        (&path[..split],&path[split+1..])
+    };
+    assert!(name.len() <= 100 && prefix.len() <= 155,"initrd path too long:{}",path);
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108],mode as u64);
+    octal(&mut header[108..116],0);//uid
+    octal(&mut header[116..124],0);//gid
+    octal(&mut header[124..136],size);
+    octal(&mut header[136..148],0);//mtime
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345+prefix.len()].copy_from_slice(prefix.as_bytes());
+    header[148..156].copy_from_slice(b"        ");//the checksum counts itself as spaces
+    let checksum:u32 = header.iter().map(|&byte|byte as u32).sum();
+    octal(&mut header[148..155],checksum as u64);
+    header
+}
+
+/// Writes `value` as zero-padded octal digits followed by a NUL.
+fn octal(field:&mut [u8], value:u64) {
+    let digits = format!("{:0width$o}",value,width = field.len()-1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
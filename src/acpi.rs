@@ -0,0 +1,142 @@
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+/// Size of the header every System Description Table starts with.
+const SDT_HEADER_SIZE:u64 = 36;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    BadChecksum([u8;4]),
+    TableNotFound([u8;4]),
+}
+
+/// Reads a `T` from physical memory through the bootloader's physical memory mapping.
+pub(crate) fn read_phys<T:Copy>(addr:u64) -> T {
+    unsafe { ptr::read_unaligned(phys_to_virt(PhysAddr::new(addr)).as_ptr::<T>()) }
+}
+
+fn checksum_ok(addr:u64, len:u64) -> bool {
+    (0..len).fold(0u8,|sum,i|sum.wrapping_add(read_phys::<u8>(addr+i))) == 0
+}
+
+/// Searches the EBDA and the BIOS read-only area for the Root System Description Pointer.
+fn find_rsdp() -> Option<u64> {
+    let ebda = (read_phys::<u16>(0x40e) as u64) << 4;
+    let candidates = (ebda..ebda+1024).step_by(16).chain((0xe0000..0x100000).step_by(16));
+    for addr in candidates {
+        if read_phys::<[u8;8]>(addr) == *b"RSD PTR " && checksum_ok(addr,20) {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+/// The system description tables found through the RSDT or XSDT.
+pub struct Acpi {
+    tables:Vec<u64>,//physical addresses of the tables
+}impl Acpi {
+    pub fn new() -> Result<Self,AcpiError> {
+        let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+        let revision = read_phys::<u8>(rsdp+15);
+        let (root,entry_size):(u64,usize) = if revision >= 2 && read_phys::<u64>(rsdp+24) != 0 {
+            (read_phys::<u64>(rsdp+24),8)//XSDT
+        } else {
+            (read_phys::<u32>(rsdp+16) as u64,4)//RSDT
+        };
+        let length = read_phys::<u32>(root+4) as u64;
+        if !checksum_ok(root,length) {
+            return Err(AcpiError::BadChecksum(read_phys(root)));
+        }
+        let tables = (root+SDT_HEADER_SIZE..root+length).step_by(entry_size).map(|entry|{
+            if entry_size == 8 {read_phys::<u64>(entry)} else {read_phys::<u32>(entry) as u64}
+        }).collect();
+        Ok(Acpi {tables})
+    }
+
+    /// Returns the physical address and length of the first table with the given signature.
+    pub fn find_table(&self, signature:&[u8;4]) -> Result<(u64,u64),AcpiError> {
+        for &table in &self.tables {
+            if read_phys::<[u8;4]>(table) == *signature {
+                let length = read_phys::<u32>(table+4) as u64;
+                if !checksum_ok(table,length) {
+                    return Err(AcpiError::BadChecksum(*signature));
+                }
+                return Ok((table,length));
+            }
+        }
+        Err(AcpiError::TableNotFound(*signature))
+    }
+
+    /// Parses the Multiple APIC Description Table.
+    pub fn madt(&self) -> Result<Madt,AcpiError> {
+        let (table,length) = self.find_table(b"APIC")?;
+        let mut madt = Madt {
+            local_apic_address:read_phys::<u32>(table+SDT_HEADER_SIZE) as u64,
+            processors:Vec::new(),
+            io_apics:Vec::new(),
+            overrides:Vec::new(),
+        };
+        let mut entry = table + SDT_HEADER_SIZE + 8;
+        while entry + 2 <= table + length {
+            let entry_type = read_phys::<u8>(entry);
+            let entry_len = read_phys::<u8>(entry+1) as u64;
+            if entry_len < 2 {
+                break;//malformed, avoid looping forever
+            }
+            match entry_type {
+                0 => madt.processors.push(Processor {
+                    processor_id:read_phys(entry+2),
+                    apic_id:read_phys(entry+3),
+                    enabled:read_phys::<u32>(entry+4) & 0b11 != 0,//enabled or online capable
+                }),
+                1 => madt.io_apics.push(IoApic {
+                    id:read_phys(entry+2),
+                    address:read_phys::<u32>(entry+4) as u64,
+                    gsi_base:read_phys(entry+8),
+                }),
+                2 => madt.overrides.push(InterruptOverride {
+                    source:read_phys(entry+3),
+                    gsi:read_phys(entry+4),
+                    flags:read_phys(entry+8),
+                }),
+                5 => madt.local_apic_address = read_phys(entry+4),
+                _ => {}
+            }
+            entry += entry_len;
+        }
+        Ok(madt)
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct Processor {
+    pub processor_id:u8,
+    pub apic_id:u8,
+    pub enabled:bool,
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct IoApic {
+    pub id:u8,
+    pub address:u64,
+    pub gsi_base:u32,
+}
+
+/// Maps a legacy ISA IRQ to a different global system interrupt.
+#[derive(Debug,Clone,Copy)]
+pub struct InterruptOverride {
+    pub source:u8,
+    pub gsi:u32,
+    pub flags:u16,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address:u64,
+    pub processors:Vec<Processor>,
+    pub io_apics:Vec<IoApic>,
+    pub overrides:Vec<InterruptOverride>,
+}
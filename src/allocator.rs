@@ -6,13 +6,14 @@ use x86_64::{
     },
     VirtAddr,
 };
-use linked_list_allocator::LockedHeap;
-use bump::BumpAllocator;
+use linked_list_allocator::Heap;
+use core::ptr::NonNull;
+use x86_64::instructions::interrupts;
 
 pub mod bump;
 
 pub const HEAP_START:usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE:usize = 100*1024;//100*1Kib = 100Kib
+pub const HEAP_SIZE:usize = 1024*1024;//1Mib, enough for kernel thread stacks
 
 /// A wrapper around spin::Mutex to permit trait implementations.
 pub struct Locked<A> {
@@ -67,20 +68,44 @@ pub fn init_heap(
         //use ?mark to forward error to caller. On success, returns a MapperFlush instance which update Translation Lookaside Buffer by using flush().
         unsafe{
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-            //initialize heap after mapping the heap pages, since init() already tries to write to heap memory.
-            ALLOCATOR.lock().init(HEAP_START,HEAP_SIZE as usize);
         }
     }
+    //initialize heap after mapping the heap pages, since init() already tries to write to heap memory.
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START,HEAP_SIZE as usize);
+    }
     Ok(())
 }
 
-/// LockedHeap uses spinning_top::Spinlock for synchronization.
-/// This is required because multiple threads could access the ALLOCATOR static at the same time.
-/// when using a spinlock or mutex,be care of deadlock.
-/// This means that shouldn't perform any allocations in interrupt handlers
+/// The heap is protected by a spinlock, which is taken with interrupts disabled:
+/// the timer interrupt preempts kernel threads, and a thread preempted while holding
+/// the lock would make every other thread spin on it forever.
+/// Still, don't perform allocations in interrupt handlers
 /// since they can run anytime and might interrupt an in-progress allocation.
-//we declared BumpAllocator::new and Locked::new as const functions.
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self,layout:Layout) -> *mut u8 {
+        interrupts::without_interrupts(||{
+            self.lock().allocate_first_fit(layout).ok().map_or(null_mut(),|ptr|ptr.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr:*mut u8, layout:Layout) {
+        interrupts::without_interrupts(||{
+            self.lock().deallocate(NonNull::new_unchecked(ptr),layout)
+        })
+    }
+}
+
+/// Bytes of the kernel heap in use and free.
+pub fn heap_stats() -> (usize,usize) {
+    interrupts::without_interrupts(||{
+        let heap = ALLOCATOR.lock();
+        (heap.used(),heap.free())
+    })
+}
+
+//we declared Heap::empty and Locked::new as const functions.
 //If they were normal functions, a compilation error would occur
 //due to initialization expression of a static must evaluable at compile time.
 #[global_allocator]
-static ALLOCATOR:Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
\ No newline at end of file
+static ALLOCATOR:Locked<Heap> = Locked::new(Heap::empty());
\ No newline at end of file
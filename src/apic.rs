@@ -0,0 +1,118 @@
+use core::sync::atomic::{AtomicU64,Ordering};
+use x86_64::{PhysAddr,structures::paging::{FrameAllocator,Mapper,Size4KiB,mapper::MapToError}};
+use crate::memory;
+
+/// Vector of the per-CPU local APIC timer, right after the remapped PIC range.
+pub const TIMER_VECTOR:u8 = 0x30;
+pub const SPURIOUS_VECTOR:u8 = 0xff;
+/// Vectors of the inter-processor interrupts, see `smp::ipi` and `smp::tlb`.
+pub const TLB_SHOOTDOWN_VECTOR:u8 = 0x31;
+pub const CALL_FUNCTION_VECTOR:u8 = 0x32;
+
+/// Initial count of the periodic timer (bus clock divided by 16); roughly 10ms on QEMU.
+const TIMER_INITIAL_COUNT:u32 = 0x10_0000;
+
+const REG_ID:u32 = 0x20;
+const REG_TPR:u32 = 0x80;
+const REG_EOI:u32 = 0xb0;
+const REG_SVR:u32 = 0xf0;
+const REG_ICR_LOW:u32 = 0x300;
+const REG_ICR_HIGH:u32 = 0x310;
+const REG_LVT_TIMER:u32 = 0x320;
+const REG_TIMER_INITIAL:u32 = 0x380;
+const REG_TIMER_DIVIDE:u32 = 0x3e0;
+
+const ICR_PENDING:u32 = 1<<12;
+const ICR_SHORTHAND_SELF:u32 = 0b01<<18;
+const ICR_SHORTHAND_ALL:u32 = 0b10<<18;
+const ICR_SHORTHAND_ALL_BUT_SELF:u32 = 0b11<<18;
+const ICR_ASSERT:u32 = 1<<14;
+const ICR_INIT:u32 = 0b101<<8;
+const ICR_STARTUP:u32 = 0b110<<8;
+
+/// Virtual address of the local APIC registers, shared by all CPUs since every
+/// CPU sees its own APIC at the same physical address.
+static BASE:AtomicU64 = AtomicU64::new(0);
+
+fn read(reg:u32) -> u32 {
+    unsafe { core::ptr::read_volatile((BASE.load(Ordering::Relaxed) + reg as u64) as *const u32) }
+}
+
+fn write(reg:u32, value:u32) {
+    unsafe { core::ptr::write_volatile((BASE.load(Ordering::Relaxed) + reg as u64) as *mut u32,value) }
+}
+
+/// Maps the local APIC registers and enables the APIC of the calling CPU (the BSP).
+pub fn init(
+    phys:PhysAddr,
+    mapper:&mut impl Mapper<Size4KiB>,
+    frame_allocator:&mut impl FrameAllocator<Size4KiB>,
+) -> Result<(),MapToError<Size4KiB>> {
+    let virt = memory::map_mmio(phys,4096,mapper,frame_allocator)?;
+    BASE.store(virt.as_u64(),Ordering::Relaxed);
+    enable();
+    Ok(())
+}
+
+/// Enables the local APIC of the calling CPU. `init` must have run on the BSP before.
+pub fn enable() {
+    write(REG_TPR,0);//accept all interrupt priorities
+    write(REG_SVR,0x100 | SPURIOUS_VECTOR as u32);
+}
+
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the APIC id of the calling CPU.
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+pub fn end_of_interrupt() {
+    write(REG_EOI,0);
+}
+
+/// Starts the periodic local timer of the calling CPU on `TIMER_VECTOR`.
+pub fn start_timer() {
+    write(REG_TIMER_DIVIDE,0b0011);//divide by 16
+    write(REG_LVT_TIMER,(1<<17) | TIMER_VECTOR as u32);//periodic mode
+    write(REG_TIMER_INITIAL,TIMER_INITIAL_COUNT);
+}
+
+fn send_ipi(apic_id:u32, low:u32) {
+    write(REG_ICR_HIGH,apic_id << 24);
+    write(REG_ICR_LOW,low);//writing the low half sends the IPI
+    while read(REG_ICR_LOW) & ICR_PENDING != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Receivers of an inter-processor interrupt.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum IpiDestination {
+    Apic(u32),
+    Myself,
+    All,
+    AllButSelf,
+}
+
+/// Sends a fixed interrupt with `vector` to `destination`.
+pub fn send_fixed_ipi(destination:IpiDestination, vector:u8) {
+    match destination {
+        IpiDestination::Apic(apic_id) => send_ipi(apic_id,ICR_ASSERT | vector as u32),
+        IpiDestination::Myself => send_ipi(0,ICR_SHORTHAND_SELF | ICR_ASSERT | vector as u32),
+        IpiDestination::All => send_ipi(0,ICR_SHORTHAND_ALL | ICR_ASSERT | vector as u32),
+        IpiDestination::AllButSelf => send_ipi(0,ICR_SHORTHAND_ALL_BUT_SELF | ICR_ASSERT | vector as u32),
+    }
+}
+
+/// Sends an INIT IPI, which resets the target CPU into its wait-for-SIPI state.
+pub fn send_init(apic_id:u32) {
+    send_ipi(apic_id,ICR_INIT | ICR_ASSERT);
+}
+
+/// Sends a STARTUP IPI; the target CPU starts executing in real mode at `page*4096`.
+pub fn send_startup(apic_id:u32, page:u8) {
+    send_ipi(apic_id,ICR_STARTUP | ICR_ASSERT | page as u32);
+}
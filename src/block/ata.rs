@@ -0,0 +1,346 @@
+//! ATA disks on the legacy IDE controller, driven by PIO.
+//!
+//! Each of the two channels has a master and a slave drive. `init` probes all four
+//! with IDENTIFY and registers the disks found as "hda" to "hdd", along with their
+//! partitions. Transfers use LBA48 when the disk supports it and LBA28 otherwise;
+//! the data moves through the data port, and the channel's IRQ (14 or 15) says
+//! when the disk is ready for the next sector. Under QEMU the boot image is "hda";
+//! `-hdb disk.img` adds a disk to try things on.
+
+use alloc::{string::String,sync::Arc,vec::Vec};
+use core::sync::atomic::{AtomicBool,AtomicU8,Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+use crate::{interrupts,println,time};
+use crate::sync::{Mutex,WaitQueue};
+use crate::syscall::Errno;
+use super::{check_range,BlockDevice};
+
+const SECTOR_SIZE:usize = 512;
+
+/// Registers, as offsets from the channel's I/O base.
+const REG_DATA:u16 = 0;
+const REG_SECTOR_COUNT:u16 = 2;
+const REG_LBA_LOW:u16 = 3;
+const REG_LBA_MID:u16 = 4;
+const REG_LBA_HIGH:u16 = 5;
+const REG_DRIVE:u16 = 6;
+const REG_STATUS:u16 = 7;
+const REG_COMMAND:u16 = 7;
+
+const STATUS_ERR:u8 = 0x01;
+const STATUS_DRQ:u8 = 0x08;
+const STATUS_DF:u8 = 0x20;
+const STATUS_BSY:u8 = 0x80;
+
+/// Device control register: interrupts off.
+const CONTROL_NIEN:u8 = 0x02;
+
+const CMD_READ_SECTORS:u8 = 0x20;
+const CMD_READ_SECTORS_EXT:u8 = 0x24;
+const CMD_WRITE_SECTORS:u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT:u8 = 0x34;
+const CMD_CACHE_FLUSH:u8 = 0xe7;
+const CMD_CACHE_FLUSH_EXT:u8 = 0xea;
+const CMD_IDENTIFY:u8 = 0xec;
+
+/// Most sectors per command; 256 is the LBA28 limit (written as 0).
+const MAX_SECTORS:usize = 256;
+/// LBA28 can address this many sectors.
+const LBA28_LIMIT:u64 = 1 << 28;
+/// How long to wait for a disk, in timer ticks, before giving up on the interrupt
+/// and on the disk.
+const IRQ_TIMEOUT:u64 = time::TICKS_PER_SECOND;
+const BUSY_TIMEOUT:u64 = 5*time::TICKS_PER_SECOND;
+
+struct Channel {
+    base:u16,
+    control:u16,
+    irq:u8,
+    /// One command at a time per channel, for either drive.
+    lock:Mutex<()>,
+    interrupted:AtomicBool,
+    /// The status the interrupt handler read, which also acknowledged the interrupt.
+    status:AtomicU8,
+    waiters:WaitQueue,
+}impl Channel {
+    fn new(base:u16, control:u16, irq:u8) -> Self {
+        Channel {
+            base,
+            control,
+            irq,
+            lock:Mutex::new(()),
+            interrupted:AtomicBool::new(false),
+            status:AtomicU8::new(0),
+            waiters:WaitQueue::new(),
+        }
+    }
+
+    fn read(&self, register:u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register:u16, value:u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// The status without acknowledging an interrupt.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    fn set_interrupts(&self, enabled:bool) {
+        unsafe { Port::<u8>::new(self.control).write(if enabled {0} else {CONTROL_NIEN}) }
+    }
+
+    /// The 400ns a drive needs to put its status on the bus, e.g. after selecting it.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn select(&self, slave:bool, lba_bits:u8) {
+        self.write(REG_DRIVE,0xe0 | (slave as u8) << 4 | lba_bits);//LBA mode
+        self.delay();
+    }
+
+    /// Spins until the drive is no longer busy and returns its status.
+    fn wait_not_busy(&self) -> Result<u8,Errno> {
+        let deadline = time::ticks() + BUSY_TIMEOUT;
+        loop {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if time::ticks() > deadline {
+                return Err(Errno::EIO);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits until the drive has data for us or wants data, or failed.
+    fn wait_data(&self) -> Result<(),Errno> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 || status & STATUS_DRQ == 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    /// Sleeps until the channel's interrupt arrives and checks the status it read.
+    /// A lost interrupt only costs time: after `IRQ_TIMEOUT` the status register is polled.
+    fn wait_interrupt(&self) -> Result<u8,Errno> {
+        let status = if self.waiters.wait_until_timeout(||self.interrupted.swap(false,Ordering::AcqRel),IRQ_TIMEOUT) {
+            match self.status.load(Ordering::Acquire) {
+                status if status & STATUS_BSY != 0 => self.wait_not_busy()?,
+                status => status,
+            }
+        } else {
+            let status = self.wait_not_busy()?;
+            self.read(REG_STATUS);//acknowledge it in case it comes late
+            status
+        };
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(status)
+    }
+
+    /// Writes the address and count registers and issues `command`.
+    fn command(&self, slave:bool, lba48:bool, lba:u64, count:usize, command:u8) {
+        let count = if count == MAX_SECTORS && !lba48 {0} else {count};
+        self.interrupted.store(false,Ordering::Release);
+        if lba48 {
+            self.select(slave,0);
+            //high bytes first, the registers keep the previous value
+            self.write(REG_SECTOR_COUNT,(count >> 8) as u8);
+            self.write(REG_LBA_LOW,(lba >> 24) as u8);
+            self.write(REG_LBA_MID,(lba >> 32) as u8);
+            self.write(REG_LBA_HIGH,(lba >> 40) as u8);
+        } else {
+            self.select(slave,((lba >> 24) & 0x0f) as u8);
+        }
+        self.write(REG_SECTOR_COUNT,count as u8);
+        self.write(REG_LBA_LOW,lba as u8);
+        self.write(REG_LBA_MID,(lba >> 8) as u8);
+        self.write(REG_LBA_HIGH,(lba >> 16) as u8);
+        self.write(REG_COMMAND,command);
+    }
+
+    fn read_sector(&self, buf:&mut [u8]) {
+        let mut data:Port<u16> = Port::new(self.base + REG_DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, buf:&[u8]) {
+        let mut data:Port<u16> = Port::new(self.base + REG_DATA);
+        for word in buf.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0],word[1]])) };
+        }
+    }
+
+    /// Runs IDENTIFY on a drive with interrupts off. None if there is no ATA disk.
+    fn identify(&self, slave:bool) -> Option<[u16;256]> {
+        if self.alt_status() == 0xff {
+            return None;//nothing attached to the channel: the bus floats
+        }
+        self.set_interrupts(false);
+        self.select(slave,0);
+        for register in REG_SECTOR_COUNT..=REG_LBA_HIGH {
+            self.write(register,0);
+        }
+        self.write(REG_COMMAND,CMD_IDENTIFY);
+        if self.alt_status() == 0 {
+            return None;//no drive
+        }
+        self.wait_not_busy().ok()?;
+        if self.read(REG_LBA_MID) != 0 || self.read(REG_LBA_HIGH) != 0 {
+            return None;//ATAPI or SATA signature, not an ATA disk
+        }
+        self.wait_data().ok()?;
+        let mut bytes = [0u8;SECTOR_SIZE];
+        self.read_sector(&mut bytes);
+        self.read(REG_STATUS);
+        let mut words = [0u16;256];
+        for (word,pair) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+            *word = u16::from_le_bytes([pair[0],pair[1]]);
+        }
+        Some(words)
+    }
+}
+
+lazy_static! {
+    static ref CHANNELS:[Channel;2] = [Channel::new(0x1f0,0x3f6,14),Channel::new(0x170,0x376,15)];
+}
+
+/// Called by the IRQ 14 and 15 handlers with the channel number.
+pub fn handle_interrupt(channel:usize) {
+    let channel = &CHANNELS[channel];
+    channel.status.store(channel.read(REG_STATUS),Ordering::Release);//acknowledges the interrupt
+    channel.interrupted.store(true,Ordering::Release);
+    channel.waiters.notify_all();
+}
+
+/// An ATA disk.
+pub struct AtaDisk {
+    channel:&'static Channel,
+    slave:bool,
+    lba48:bool,
+    sectors:u64,
+    model:String,
+}impl AtaDisk {
+    fn new(channel:&'static Channel, slave:bool, identify:&[u16;256]) -> Option<Self> {
+        if identify[49] & (1 << 9) == 0 {
+            return None;//CHS only
+        }
+        let lba48 = identify[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            identify[100..104].iter().rev().fold(0u64,|sectors,&word|sectors << 16 | word as u64)
+        } else {
+            (identify[61] as u64) << 16 | identify[60] as u64
+        };
+        //the model is stored as big-endian pairs of characters
+        let model:Vec<u8> = identify[27..47].iter().flat_map(|word|word.to_be_bytes().to_vec()).collect();
+        let model = String::from_utf8_lossy(&model).trim().into();
+        Some(AtaDisk {channel, slave, lba48, sectors, model})
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.lba48
+    }
+
+    /// Checks the range and picks the command for a transfer of up to `MAX_SECTORS` sectors.
+    fn command_for(&self, start:u64, count:usize, commands:(u8,u8)) -> Result<u8,Errno> {
+        if start + count as u64 > LBA28_LIMIT && !self.lba48 {
+            return Err(Errno::EINVAL);
+        }
+        Ok(if self.lba48 {commands.1} else {commands.0})
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+    fn read_blocks(&self, start:u64, buf:&mut [u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        let _channel = self.channel.lock.lock();
+        for (i,chunk) in buf.chunks_mut(MAX_SECTORS*SECTOR_SIZE).enumerate() {
+            let lba = start + (i*MAX_SECTORS) as u64;
+            let count = chunk.len()/SECTOR_SIZE;
+            let command = self.command_for(lba,count,(CMD_READ_SECTORS,CMD_READ_SECTORS_EXT))?;
+            self.channel.set_interrupts(true);
+            self.channel.command(self.slave,self.lba48,lba,count,command);
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                //an interrupt for each sector once it is in the drive's buffer
+                self.channel.wait_interrupt()?;
+                self.channel.wait_data()?;
+                self.channel.read_sector(sector);
+            }
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, start:u64, buf:&[u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        let _channel = self.channel.lock.lock();
+        for (i,chunk) in buf.chunks(MAX_SECTORS*SECTOR_SIZE).enumerate() {
+            let lba = start + (i*MAX_SECTORS) as u64;
+            let count = chunk.len()/SECTOR_SIZE;
+            let command = self.command_for(lba,count,(CMD_WRITE_SECTORS,CMD_WRITE_SECTORS_EXT))?;
+            self.channel.set_interrupts(true);
+            self.channel.command(self.slave,self.lba48,lba,count,command);
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                //the first sector is asked for without an interrupt, the others with one
+                self.channel.wait_data()?;
+                self.channel.interrupted.store(false,Ordering::Release);
+                self.channel.write_sector(sector);
+                self.channel.wait_interrupt()?;
+            }
+        }
+        Ok(())
+    }
+    fn flush(&self) -> Result<(),Errno> {
+        let _channel = self.channel.lock.lock();
+        self.channel.set_interrupts(true);
+        self.channel.interrupted.store(false,Ordering::Release);
+        self.channel.select(self.slave,0);
+        self.channel.write(REG_COMMAND,if self.lba48 {CMD_CACHE_FLUSH_EXT} else {CMD_CACHE_FLUSH});
+        self.channel.wait_interrupt().map(|_|())
+    }
+}
+
+/// Probes both channels and registers the disks found with the block layer.
+/// Returns the names of the disks.
+pub fn init() -> Vec<String> {
+    const NAMES:[&str;4] = ["hda","hdb","hdc","hdd"];
+    let mut found = Vec::new();
+    for (index,channel) in CHANNELS.iter().enumerate() {
+        let disks:Vec<AtaDisk> = [false,true].iter()
+            .filter_map(|&slave|channel.identify(slave).and_then(|identify|AtaDisk::new(channel,slave,&identify)))
+            .collect();
+        if disks.is_empty() {
+            continue;
+        }
+        interrupts::unmask_irq(channel.irq);
+        for disk in disks {
+            let name = NAMES[index*2 + disk.slave as usize];
+            println!("{}: {} ({} MiB{})",name,disk.model,disk.sectors/2048,if disk.lba48 {", LBA48"} else {""});
+            match super::register_disk(name,Arc::new(disk)) {
+                Ok(()) => found.push(String::from(name)),
+                Err(err) => println!("{}: registering failed:{:?}",name,err),
+            }
+        }
+    }
+    found
+}
@@ -0,0 +1,167 @@
+//! A write-back buffer cache in front of a block device.
+
+use alloc::{boxed::Box,collections::BTreeMap,sync::Arc,vec};
+use crate::sync::Mutex;
+use crate::syscall::Errno;
+use super::{check_range,BlockDevice};
+
+struct Buffer {
+    data:Box<[u8]>,
+    dirty:bool,
+    stamp:u64,//when it was last used
+}
+
+struct State {
+    buffers:BTreeMap<u64,Buffer>,
+    /// Block numbers by the stamp of their last use, least recently used first.
+    lru:BTreeMap<u64,u64>,
+    clock:u64,
+    stats:CacheStats,
+}
+
+/// Counters for tests and diagnostics.
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct CacheStats {
+    pub hits:u64,
+    pub misses:u64,
+    /// Dirty blocks written to the device, on eviction or `sync`.
+    pub writebacks:u64,
+}
+
+/// Keeps up to `capacity` recently used blocks of a device in memory. Writes only
+/// go to the cache; dirty blocks reach the device when they are evicted or on
+/// `sync` (which `flush` does too), so a cache must be synced before the device
+/// underneath is used directly or goes away.
+pub struct BlockCache {
+    device:Arc<dyn BlockDevice>,
+    capacity:usize,
+    state:Mutex<State>,
+}impl BlockCache {
+    /// Buffers live on the kernel heap, so `capacity` should stay small.
+    pub fn new(device:Arc<dyn BlockDevice>, capacity:usize) -> Self {
+        BlockCache {
+            device,
+            capacity:capacity.max(1),
+            state:Mutex::new(State {buffers:BTreeMap::new(), lru:BTreeMap::new(), clock:0, stats:CacheStats::default()}),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    /// Writes all dirty blocks to the device and flushes it.
+    pub fn sync(&self) -> Result<(),Errno> {
+        let mut state = self.state.lock();
+        let State {buffers,stats,..} = &mut *state;
+        for (&block,buffer) in buffers.iter_mut().filter(|(_,buffer)|buffer.dirty) {
+            self.device.write_blocks(block,&buffer.data)?;
+            buffer.dirty = false;
+            stats.writebacks += 1;
+        }
+        drop(state);
+        self.device.flush()
+    }
+
+    /// Drops all cached blocks after writing back the dirty ones.
+    pub fn invalidate(&self) -> Result<(),Errno> {
+        self.sync()?;
+        let mut state = self.state.lock();
+        state.buffers.clear();
+        state.lru.clear();
+        Ok(())
+    }
+
+    /// Marks `block` as just used.
+    fn touch(state:&mut State, block:u64) {
+        state.clock += 1;
+        let stamp = state.clock;
+        if let Some(buffer) = state.buffers.get_mut(&block) {
+            state.lru.remove(&buffer.stamp);
+            buffer.stamp = stamp;
+            state.lru.insert(stamp,block);
+        }
+    }
+
+    /// Makes room for one more buffer, writing the least recently used one back if needed.
+    fn evict(&self, state:&mut State) -> Result<(),Errno> {
+        while state.buffers.len() >= self.capacity {
+            let (&stamp,&block) = state.lru.iter().next().expect("the LRU list is out of step");
+            if state.buffers[&block].dirty {
+                self.device.write_blocks(block,&state.buffers[&block].data)?;
+                state.stats.writebacks += 1;
+            }
+            state.lru.remove(&stamp);
+            state.buffers.remove(&block);
+        }
+        Ok(())
+    }
+
+    /// Adds `data` as the contents of `block`, which isn't cached yet.
+    fn insert(&self, state:&mut State, block:u64, data:Box<[u8]>, dirty:bool) -> Result<(),Errno> {
+        self.evict(state)?;
+        state.clock += 1;
+        let stamp = state.clock;
+        state.buffers.insert(block,Buffer {data, dirty, stamp});
+        state.lru.insert(stamp,block);
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+    fn read_blocks(&self, start:u64, buf:&mut [u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        let block_size = self.block_size();
+        let mut state = self.state.lock();
+        for (i,chunk) in buf.chunks_mut(block_size).enumerate() {
+            let block = start + i as u64;
+            if let Some(buffer) = state.buffers.get(&block) {
+                chunk.copy_from_slice(&buffer.data);
+                state.stats.hits += 1;
+                Self::touch(&mut state,block);
+                continue;
+            }
+            self.device.read_blocks(block,chunk)?;
+            state.stats.misses += 1;
+            self.insert(&mut state,block,chunk.to_vec().into_boxed_slice(),false)?;
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, start:u64, buf:&[u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        let block_size = self.block_size();
+        let mut state = self.state.lock();
+        for (i,chunk) in buf.chunks(block_size).enumerate() {
+            let block = start + i as u64;
+            if let Some(buffer) = state.buffers.get_mut(&block) {
+                buffer.data.copy_from_slice(chunk);
+                buffer.dirty = true;
+                Self::touch(&mut state,block);
+                continue;
+            }
+            let mut data = vec![0u8;block_size].into_boxed_slice();
+            data.copy_from_slice(chunk);
+            self.insert(&mut state,block,data,true)?;
+        }
+        Ok(())
+    }
+    fn flush(&self) -> Result<(),Errno> {
+        self.sync()
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        let _ = self.sync();//nothing to report an error to
+    }
+}
@@ -0,0 +1,141 @@
+//! Block devices: storage that is read and written in fixed-size blocks.
+//!
+//! Devices stack: a `Partition` is a range of another device and a `BlockCache`
+//! keeps recently used blocks of the device below it, so file systems work the
+//! same on a whole disk, a partition or a cached view of either.
+
+use alloc::{collections::BTreeMap,format,string::String,sync::Arc,vec,vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::syscall::Errno;
+
+pub mod ramdisk;
+pub mod cache;
+pub mod partition;
+pub mod ata;
+pub mod virtio;
+
+pub use ramdisk::RamDisk;
+pub use cache::{BlockCache,CacheStats};
+pub use partition::{partitions,Partition,PartitionKind};
+
+/// A disk, a partition of one, or anything else addressed in blocks.
+///
+/// Transfers cover whole blocks: `buf.len()` must be a multiple of `block_size`
+/// and the blocks must lie within the device, or the call fails with `EINVAL`.
+/// Device errors are reported as `EIO`.
+pub trait BlockDevice:Send+Sync {
+    /// Size of a block in bytes, a power of two of at least 512.
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    /// Reads the blocks starting at `start` into `buf`.
+    fn read_blocks(&self, start:u64, buf:&mut [u8]) -> Result<(),Errno>;
+    /// Writes `buf` to the blocks starting at `start`.
+    fn write_blocks(&self, start:u64, buf:&[u8]) -> Result<(),Errno>;
+    /// Waits until completed writes are on stable storage.
+    fn flush(&self) -> Result<(),Errno> {
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// The devices drivers found, by name ("hda", "hda1"...).
+    static ref DEVICES:Mutex<BTreeMap<String,Arc<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Makes `device` known as `name`. Fails with `EEXIST` if the name is taken.
+pub fn register(name:&str, device:Arc<dyn BlockDevice>) -> Result<(),Errno> {
+    interrupts::without_interrupts(||{
+        let mut devices = DEVICES.lock();
+        if devices.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        devices.insert(String::from(name),device);
+        Ok(())
+    })
+}
+
+/// Registers a disk as `name` and each of its partitions as `name` followed by the
+/// partition number. A damaged partition table only costs the partitions.
+pub fn register_disk(name:&str, disk:Arc<dyn BlockDevice>) -> Result<(),Errno> {
+    register(name,disk.clone())?;
+    for partition in partitions(&disk).unwrap_or_default() {
+        let number = partition.number;
+        register(&format!("{}{}",name,number),Arc::new(partition))?;
+    }
+    Ok(())
+}
+
+pub fn unregister(name:&str) -> Option<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(||DEVICES.lock().remove(name))
+}
+
+pub fn device(name:&str) -> Option<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(||DEVICES.lock().get(name).cloned())
+}
+
+/// The names of the registered devices, sorted.
+pub fn devices() -> Vec<String> {
+    interrupts::without_interrupts(||DEVICES.lock().keys().cloned().collect())
+}
+
+/// Checks that a transfer of `len` bytes starting at block `start` fits `device`
+/// and returns the number of blocks.
+pub fn check_range(device:&dyn BlockDevice, start:u64, len:usize) -> Result<u64,Errno> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let blocks = (len/block_size) as u64;
+    match start.checked_add(blocks) {
+        Some(end) if end <= device.block_count() => Ok(blocks),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Reads `buf.len()` bytes starting at byte `pos` of `device`, which need not be
+/// aligned to blocks.
+pub fn read_bytes(device:&dyn BlockDevice, pos:u64, buf:&mut [u8]) -> Result<(),Errno> {
+    let block_size = device.block_size();
+    let mut block = vec![0u8;block_size];
+    let mut done = 0;
+    while done < buf.len() {
+        let at = pos + done as u64;
+        let (index,start) = (at/block_size as u64,(at%block_size as u64) as usize);
+        let whole = (buf.len() - done)/block_size;
+        if start == 0 && whole > 0 {
+            device.read_blocks(index,&mut buf[done..done+whole*block_size])?;
+            done += whole*block_size;
+            continue;
+        }
+        let chunk = (block_size - start).min(buf.len() - done);
+        device.read_blocks(index,&mut block)?;
+        buf[done..done+chunk].copy_from_slice(&block[start..start+chunk]);
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Writes `data` starting at byte `pos` of `device`, reading partially covered blocks first.
+pub fn write_bytes(device:&dyn BlockDevice, pos:u64, data:&[u8]) -> Result<(),Errno> {
+    let block_size = device.block_size();
+    let mut block = vec![0u8;block_size];
+    let mut done = 0;
+    while done < data.len() {
+        let at = pos + done as u64;
+        let (index,start) = (at/block_size as u64,(at%block_size as u64) as usize);
+        let whole = (data.len() - done)/block_size;
+        if start == 0 && whole > 0 {
+            device.write_blocks(index,&data[done..done+whole*block_size])?;
+            done += whole*block_size;
+            continue;
+        }
+        let chunk = (block_size - start).min(data.len() - done);
+        device.read_blocks(index,&mut block)?;
+        block[start..start+chunk].copy_from_slice(&data[done..done+chunk]);
+        device.write_blocks(index,&block)?;
+        done += chunk;
+    }
+    Ok(())
+}
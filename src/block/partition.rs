@@ -0,0 +1,201 @@
+//! Partition tables: MBR (with logical partitions in an extended one) and GPT.
+//! Each partition found becomes a block device of its own.
+
+use alloc::{string::String,sync::Arc,vec,vec::Vec};
+use core::convert::TryInto;
+use crate::syscall::Errno;
+use super::{check_range,BlockDevice};
+
+/// MBR partition types.
+const MBR_EMPTY:u8 = 0x00;
+const MBR_EXTENDED:[u8;3] = [0x05,0x0f,0x85];
+const MBR_GPT_PROTECTIVE:u8 = 0xee;
+/// Longest chain of logical partitions followed, against loops.
+const MAX_LOGICAL:usize = 128;
+
+const GPT_SIGNATURE:&[u8;8] = b"EFI PART";
+const GPT_MAX_ENTRIES:u32 = 1024;
+
+fn u32_at(data:&[u8], offset:usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset+4].try_into().unwrap())
+}
+fn u64_at(data:&[u8], offset:usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset+8].try_into().unwrap())
+}
+
+/// The CRC-32 (IEEE 802.3, as in zlib) of `data`, which GPT uses.
+pub fn crc32(data:&[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb8_8320} else {crc >> 1};
+        }
+    }
+    !crc
+}
+
+/// What the partition table says a partition holds.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum PartitionKind {
+    /// The type byte of an MBR entry.
+    Mbr(u8),
+    /// The type GUID and name of a GPT entry.
+    Gpt {type_guid:[u8;16], name:String},
+}
+
+/// A range of blocks of another device, used as a device of its own.
+pub struct Partition {
+    device:Arc<dyn BlockDevice>,
+    /// 1-based, as in "sda1"; MBR logical partitions start at 5.
+    pub number:u32,
+    pub start:u64,
+    pub kind:PartitionKind,
+    blocks:u64,
+}impl Partition {
+    /// Blocks `start..start+blocks` of `device`. Fails with `EINVAL` if that isn't inside it.
+    pub fn new(device:Arc<dyn BlockDevice>, number:u32, start:u64, blocks:u64, kind:PartitionKind) -> Result<Self,Errno> {
+        match start.checked_add(blocks) {
+            Some(end) if blocks > 0 && end <= device.block_count() => Ok(Partition {device, number, start, kind, blocks}),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+    fn read_blocks(&self, start:u64, buf:&mut [u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        self.device.read_blocks(self.start + start,buf)
+    }
+    fn write_blocks(&self, start:u64, buf:&[u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        self.device.write_blocks(self.start + start,buf)
+    }
+    fn flush(&self) -> Result<(),Errno> {
+        self.device.flush()
+    }
+}
+
+fn read_block(device:&dyn BlockDevice, block:u64) -> Result<Vec<u8>,Errno> {
+    let mut data = vec![0u8;device.block_size()];
+    device.read_blocks(block,&mut data)?;
+    Ok(data)
+}
+
+/// The partitions of `device`, from its GPT if it has one, else from its MBR. A
+/// device without a partition table has no partitions; entries that don't fit the
+/// device are skipped. Fails with `EIO` for a damaged GPT.
+pub fn partitions(device:&Arc<dyn BlockDevice>) -> Result<Vec<Partition>,Errno> {
+    let mbr = read_block(&**device,0)?;
+    if mbr[510] != 0x55 || mbr[511] != 0xaa {
+        return Ok(Vec::new());
+    }
+    let entries:Vec<&[u8]> = mbr[446..510].chunks_exact(16).collect();
+    if entries.iter().any(|entry|entry[4] == MBR_GPT_PROTECTIVE) {
+        return gpt(device);
+    }
+    let mut partitions = Vec::new();
+    for (index,entry) in entries.iter().enumerate() {
+        let (kind,start,blocks) = (entry[4],u32_at(entry,8) as u64,u32_at(entry,12) as u64);
+        if kind == MBR_EMPTY {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&kind) {
+            logical(device,start,blocks,&mut partitions)?;
+            continue;
+        }
+        if let Ok(partition) = Partition::new(device.clone(),index as u32 + 1,start,blocks,PartitionKind::Mbr(kind)) {
+            partitions.push(partition);
+        }
+    }
+    Ok(partitions)
+}
+
+/// Follows the chain of extended boot records in the extended partition at
+/// `extended`. Each holds a logical partition, relative to itself, and a link to
+/// the next record, relative to the extended partition.
+fn logical(device:&Arc<dyn BlockDevice>, extended:u64, size:u64, partitions:&mut Vec<Partition>) -> Result<(),Errno> {
+    let mut record = extended;
+    for number in 5..5 + MAX_LOGICAL as u32 {
+        if record >= device.block_count() || record >= extended + size {
+            break;
+        }
+        let ebr = read_block(&**device,record)?;
+        if ebr[510] != 0x55 || ebr[511] != 0xaa {
+            break;
+        }
+        let (entry,link) = (&ebr[446..462],&ebr[462..478]);
+        if entry[4] != MBR_EMPTY {
+            let start = record + u32_at(entry,8) as u64;
+            if let Ok(partition) = Partition::new(device.clone(),number,start,u32_at(entry,12) as u64,PartitionKind::Mbr(entry[4])) {
+                partitions.push(partition);
+            }
+        }
+        if link[4] == MBR_EMPTY || u32_at(link,8) == 0 {
+            break;
+        }
+        record = extended + u32_at(link,8) as u64;
+    }
+    Ok(())
+}
+
+/// Reads the GPT header at `block` and its entries, checking both checksums.
+fn gpt_header(device:&dyn BlockDevice, block:u64) -> Result<(Vec<u8>,Vec<u8>),Errno> {
+    let mut header = read_block(device,block)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(Errno::EIO);
+    }
+    let header_size = u32_at(&header,12) as usize;
+    if header_size < 92 || header_size > header.len() || u64_at(&header,24) != block {
+        return Err(Errno::EIO);
+    }
+    let checksum = u32_at(&header,16);
+    header[16..20].copy_from_slice(&[0;4]);
+    if crc32(&header[..header_size]) != checksum {
+        return Err(Errno::EIO);
+    }
+    let (count,entry_size) = (u32_at(&header,80),u32_at(&header,84) as usize);
+    if count > GPT_MAX_ENTRIES || entry_size < 128 || entry_size%8 != 0 {
+        return Err(Errno::EIO);
+    }
+    let block_size = device.block_size();
+    let len = count as usize*entry_size;
+    let mut entries = vec![0u8;(len + block_size - 1)/block_size*block_size];
+    device.read_blocks(u64_at(&header,72),&mut entries)?;
+    entries.truncate(len);
+    if crc32(&entries) != u32_at(&header,88) {
+        return Err(Errno::EIO);
+    }
+    Ok((header,entries))
+}
+
+fn gpt(device:&Arc<dyn BlockDevice>) -> Result<Vec<Partition>,Errno> {
+    //the backup header in the last block stands in for a damaged primary one
+    let (header,entries) = gpt_header(&**device,1).or_else(|_|gpt_header(&**device,device.block_count() - 1))?;
+    let entry_size = u32_at(&header,84) as usize;
+    let (first_usable,last_usable) = (u64_at(&header,40),u64_at(&header,48));
+    let mut partitions = Vec::new();
+    for (index,entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid:[u8;16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0;16] {
+            continue;//unused
+        }
+        let (first,last) = (u64_at(entry,32),u64_at(entry,40));
+        if first < first_usable || last > last_usable || last < first {
+            continue;
+        }
+        let units:Vec<u16> = entry[56..128].chunks_exact(2).map(|unit|u16::from_le_bytes([unit[0],unit[1]])).take_while(|&unit|unit != 0).collect();
+        let name = core::char::decode_utf16(units).map(|c|c.unwrap_or(core::char::REPLACEMENT_CHARACTER)).collect();
+        let kind = PartitionKind::Gpt {type_guid, name};
+        if let Ok(partition) = Partition::new(device.clone(),index as u32 + 1,first,last - first + 1,kind) {
+            partitions.push(partition);
+        }
+    }
+    Ok(partitions)
+}
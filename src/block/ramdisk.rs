@@ -0,0 +1,86 @@
+use alloc::vec::Vec;
+use x86_64::structures::paging::{FrameAllocator,PhysFrame};
+use crate::memory::{self,GlobalFrameAllocator};
+use crate::sync::RwLock;
+use crate::syscall::Errno;
+use super::{check_range,BlockDevice};
+
+const BLOCK_SIZE:usize = 512;
+const BLOCKS_PER_FRAME:u64 = 4096/BLOCK_SIZE as u64;
+
+/// A disk in RAM, kept in physical frames rather than on the (small) kernel heap.
+pub struct RamDisk {
+    blocks:u64,
+    frames:RwLock<Vec<PhysFrame>>,
+}impl RamDisk {
+    /// A zeroed disk of `blocks` 512-byte blocks.
+    pub fn new(blocks:u64) -> Result<Self,Errno> {
+        let count = (blocks + BLOCKS_PER_FRAME - 1)/BLOCKS_PER_FRAME;
+        let mut frames = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            match GlobalFrameAllocator.allocate_frame() {
+                Some(frame) => {
+                    unsafe { core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),0,4096) };
+                    frames.push(frame);
+                }
+                None => {
+                    for frame in frames {
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                    }
+                    return Err(Errno::ENOMEM);
+                }
+            }
+        }
+        Ok(RamDisk {blocks, frames:RwLock::new(frames)})
+    }
+
+    /// A disk holding a copy of `image`, padded with zeros to whole blocks.
+    pub fn from_image(image:&[u8]) -> Result<Self,Errno> {
+        let blocks = (image.len() + BLOCK_SIZE - 1)/BLOCK_SIZE;
+        let disk = RamDisk::new(blocks as u64)?;
+        let mut padded = Vec::from(image);
+        padded.resize(blocks*BLOCK_SIZE,0);
+        disk.write_blocks(0,&padded)?;
+        Ok(disk)
+    }
+}
+
+/// The bytes of block `block` in `frames`.
+fn block_ptr(frames:&[PhysFrame], block:u64) -> *mut u8 {
+    let frame = frames[(block/BLOCKS_PER_FRAME) as usize];
+    let offset = (block%BLOCKS_PER_FRAME) as usize*BLOCK_SIZE;
+    unsafe { memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().add(offset) }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+    fn read_blocks(&self, start:u64, buf:&mut [u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        let frames = self.frames.read();
+        for (i,chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+            unsafe { core::ptr::copy_nonoverlapping(block_ptr(&frames,start + i as u64),chunk.as_mut_ptr(),BLOCK_SIZE) };
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, start:u64, buf:&[u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        let frames = self.frames.write();
+        for (i,chunk) in buf.chunks(BLOCK_SIZE).enumerate() {
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(),block_ptr(&frames,start + i as u64),BLOCK_SIZE) };
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        for frame in self.frames.write().drain(..) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Virtio block devices ("vda", "vdb"...), e.g. QEMU's `-drive if=virtio`.
+//!
+//! Each request is a chain of three parts: a header saying what to do and where,
+//! the data, and a status byte the device writes when it is done. Data moves
+//! through bounce frames, since the callers' buffers need not be physically
+//! contiguous, up to `MAX_PAGES` of them per request.
+
+use alloc::{format,string::String,sync::Arc,vec::Vec};
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::pci::{self,Driver,Match,PciDevice};
+use crate::virtio::{self,Buffer,Dma,VirtioPci,Virtqueue};
+use crate::{println,time};
+use crate::sync::WaitQueue;
+use crate::syscall::Errno;
+use super::{check_range,BlockDevice};
+
+const SECTOR_SIZE:usize = 512;
+const PAGE_SIZE:usize = 4096;
+/// Largest transfer per request, in bounce frames.
+const MAX_PAGES:usize = 32;
+
+/// Feature bits.
+const F_RO:u64 = 1 << 5;
+const F_FLUSH:u64 = 1 << 9;
+
+/// Device configuration: the capacity in sectors.
+const CONFIG_CAPACITY:u64 = 0;
+
+/// Request types.
+const T_IN:u32 = 0;
+const T_OUT:u32 = 1;
+const T_FLUSH:u32 = 4;
+
+const S_OK:u8 = 0;
+
+/// How long to sleep before looking at the queue without an interrupt, and how long
+/// to wait for a request at all, in timer ticks.
+const IRQ_TIMEOUT:u64 = 2;
+const REQUEST_TIMEOUT:u64 = 5*time::TICKS_PER_SECOND;
+
+/// The device type in the PCI device id.
+const DEVICE_TYPE:u16 = 2;
+
+static DRIVER:Driver = Driver {
+    name:"virtio-blk",
+    matches:&[
+        Match::Id {vendor:virtio::VENDOR_ID, device:0x1001},//transitional
+        Match::Id {vendor:virtio::VENDOR_ID, device:virtio::MODERN_DEVICE_ID_BASE + DEVICE_TYPE},
+    ],
+    probe,
+};
+
+lazy_static! {
+    /// For the interrupt handler, which all disks share.
+    static ref DISKS:Mutex<Vec<Arc<VirtioBlk>>> = Mutex::new(Vec::new());
+}
+/// Disks named so far, for the next name.
+static NAMED:AtomicUsize = AtomicUsize::new(0);
+
+/// A virtio block device.
+pub struct VirtioBlk {
+    transport:VirtioPci,
+    queue:Virtqueue,
+    sectors:u64,
+    read_only:bool,
+    flush:bool,
+    /// Set when a request timed out; the device was reset then and is no longer used.
+    broken:AtomicBool,
+    /// Threads waiting for a request to finish or for free descriptors.
+    waiters:WaitQueue,
+}impl VirtioBlk {
+    fn new(device:&Arc<PciDevice>) -> Result<Self,Errno> {
+        let mut transport = VirtioPci::new(device)?;
+        let features = transport.negotiate(F_RO | F_FLUSH)?;
+        if let Err(err) = transport.set_interrupt_handler(handle_interrupt) {
+            println!("virtio-blk {}: no interrupt ({:?}), polling",device.address,err);
+        }
+        let queue = match transport.queue(0) {
+            Ok(queue) => queue,
+            Err(err) => {
+                transport.fail();
+                return Err(err);
+            }
+        };
+        let sectors = transport.config_u64(CONFIG_CAPACITY);
+        Ok(VirtioBlk {
+            transport,
+            queue,
+            sectors,
+            read_only:features & F_RO != 0,
+            flush:features & F_FLUSH != 0,
+            broken:AtomicBool::new(false),
+            waiters:WaitQueue::new(),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Runs one request on `data` (the first `len` bytes) and waits for it.
+    fn request(&self, kind:u32, sector:u64, data:&Dma, len:usize) -> Result<(),Errno> {
+        if self.broken.load(Ordering::Acquire) {
+            return Err(Errno::EIO);
+        }
+        let header = Dma::new(1)?;//the status byte goes right after the header
+        let mut fields = [0u8;16];
+        fields[0..4].copy_from_slice(&kind.to_le_bytes());
+        fields[8..16].copy_from_slice(&sector.to_le_bytes());
+        header.write(0,&fields);
+        header.write(16,&[0xff]);
+        let mut chain = Vec::with_capacity(data.pages() + 2);
+        chain.push(Buffer {addr:header.phys(0), len:16, writable:false});
+        for page in 0..(len + PAGE_SIZE - 1)/PAGE_SIZE {
+            let part = (len - page*PAGE_SIZE).min(PAGE_SIZE) as u32;
+            chain.push(Buffer {addr:data.phys(page), len:part, writable:kind == T_IN});
+        }
+        chain.push(Buffer {addr:header.phys(0) + 16, len:1, writable:true});
+
+        let deadline = time::ticks() + REQUEST_TIMEOUT;
+        let mut head = None;
+        while !self.waiters.wait_until_timeout(||{
+            head = head.or_else(||self.queue.submit(&chain));
+            head.map_or(false,|head|self.queue.take(head).is_some())
+        },IRQ_TIMEOUT) {
+            if time::ticks() > deadline {
+                //the reset keeps the device from writing to the frames once they are freed
+                self.broken.store(true,Ordering::Release);
+                self.transport.reset();
+                println!("virtio-blk {}: request timed out, device disabled",self.transport.device.address);
+                return Err(Errno::EIO);
+            }
+        }
+        self.waiters.notify_all();//descriptors were freed for whoever waits for them
+        let mut status = [0u8];
+        header.read(16,&mut status);
+        match status[0] {
+            S_OK => Ok(()),
+            _ => Err(Errno::EIO),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+    fn read_blocks(&self, start:u64, buf:&mut [u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        let data = Dma::new(MAX_PAGES.min((buf.len() + PAGE_SIZE - 1)/PAGE_SIZE))?;
+        for (i,chunk) in buf.chunks_mut(MAX_PAGES*PAGE_SIZE).enumerate() {
+            let sector = start + (i*MAX_PAGES*PAGE_SIZE/SECTOR_SIZE) as u64;
+            self.request(T_IN,sector,&data,chunk.len())?;
+            data.read(0,chunk);
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, start:u64, buf:&[u8]) -> Result<(),Errno> {
+        check_range(self,start,buf.len())?;
+        if self.read_only {
+            return Err(Errno::EROFS);
+        }
+        let data = Dma::new(MAX_PAGES.min((buf.len() + PAGE_SIZE - 1)/PAGE_SIZE))?;
+        for (i,chunk) in buf.chunks(MAX_PAGES*PAGE_SIZE).enumerate() {
+            let sector = start + (i*MAX_PAGES*PAGE_SIZE/SECTOR_SIZE) as u64;
+            data.write(0,chunk);
+            self.request(T_OUT,sector,&data,chunk.len())?;
+        }
+        Ok(())
+    }
+    fn flush(&self) -> Result<(),Errno> {
+        if !self.flush {
+            return Ok(());//without the feature the device writes through
+        }
+        self.request(T_FLUSH,0,&Dma::new(0)?,0)
+    }
+}
+
+impl Drop for VirtioBlk {
+    fn drop(&mut self) {
+        self.transport.reset();//before the queue's frames go back
+    }
+}
+
+/// Wakes the waiters of every disk that signalled; MSI-X vectors and pins are
+/// shared by all of them.
+fn handle_interrupt() {
+    for disk in DISKS.lock().iter() {
+        if disk.transport.interrupted() {
+            disk.waiters.notify_all();
+        }
+    }
+}
+
+fn probe(device:&Arc<PciDevice>) -> Result<(),Errno> {
+    let disk = Arc::new(VirtioBlk::new(device)?);
+    interrupts::without_interrupts(||DISKS.lock().push(disk.clone()));
+    disk.transport.driver_ok();
+    let name = disk_name(NAMED.fetch_add(1,Ordering::Relaxed));
+    println!("{}: virtio-blk at {} ({} MiB{})",name,device.address,disk.sectors/2048,if disk.read_only {", read-only"} else {""});
+    super::register_disk(&name,disk)
+}
+
+/// "vda" to "vdz", then "vdaa" and so on.
+fn disk_name(index:usize) -> String {
+    let letter = |i:usize|(b'a' + i as u8) as char;
+    match index {
+        0..=25 => format!("vd{}",letter(index)),
+        _ => format!("vd{}{}",letter(index/26 - 1),letter(index%26)),
+    }
+}
+
+/// Registers the driver with the PCI bus; disks show up when `pci::init` scans it.
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
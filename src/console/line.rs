@@ -0,0 +1,131 @@
+//! Reading a line with editing: moving the cursor, deleting before and at it, and
+//! going through the lines entered before with Up and Down.
+
+use alloc::{collections::VecDeque,string::String,vec::Vec};
+use super::{Key,Terminal};
+
+/// Lines remembered for Up and Down.
+const HISTORY_SIZE:usize = 32;
+
+pub struct LineEditor {
+    history:VecDeque<String>,
+}impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {history:VecDeque::new()}
+    }
+
+    /// The lines entered so far, oldest first, without empty lines and repeats.
+    pub fn history(&self) -> impl Iterator<Item=&str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    fn remember(&mut self, line:&str) {
+        if line.trim().is_empty() || self.history.back().map_or(false,|last|last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+
+    /// Shows `prompt` and reads a line. Ctrl-C abandons the line, which reads as an
+    /// empty one; Ctrl-D on an empty line gives None.
+    pub fn read_line(&mut self, terminal:&dyn Terminal, prompt:&str) -> Option<String> {
+        terminal.write_str(prompt);
+        let mut line:Vec<char> = Vec::new();
+        let mut cursor = 0;
+        //which history entry is shown; `history.len()` is the line being typed, kept in `draft`
+        let mut shown = self.history.len();
+        let mut draft = Vec::new();
+        loop {
+            match terminal.read_key() {
+                Key::Char(character) => {
+                    line.insert(cursor,character);
+                    write_chars(terminal,&line[cursor..]);
+                    cursor += 1;
+                    terminal.cursor_left(line.len() - cursor);
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                    terminal.cursor_left(1);
+                    redraw_tail(terminal,&line[cursor..]);
+                }
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                    redraw_tail(terminal,&line[cursor..]);
+                }
+                Key::Left if cursor > 0 => {
+                    cursor -= 1;
+                    terminal.cursor_left(1);
+                }
+                Key::Right if cursor < line.len() => {
+                    write_chars(terminal,&line[cursor..cursor + 1]);
+                    cursor += 1;
+                }
+                Key::Home => {
+                    terminal.cursor_left(cursor);
+                    cursor = 0;
+                }
+                Key::End => {
+                    write_chars(terminal,&line[cursor..]);
+                    cursor = line.len();
+                }
+                Key::Up if shown > 0 => {
+                    if shown == self.history.len() {
+                        draft = line.clone();
+                    }
+                    shown -= 1;
+                    let entry = self.history[shown].chars().collect();
+                    replace(terminal,&mut line,&mut cursor,entry);
+                }
+                Key::Down if shown < self.history.len() => {
+                    shown += 1;
+                    let entry = match self.history.get(shown) {
+                        Some(entry) => entry.chars().collect(),
+                        None => draft.clone(),
+                    };
+                    replace(terminal,&mut line,&mut cursor,entry);
+                }
+                Key::Enter => {
+                    terminal.write_str("\n");
+                    let line:String = line.into_iter().collect();
+                    self.remember(&line);
+                    return Some(line);
+                }
+                Key::Interrupt => {
+                    terminal.write_str("^C\n");
+                    return Some(String::new());
+                }
+                Key::EndOfFile if line.is_empty() => {
+                    terminal.write_str("\n");
+                    return None;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn write_chars(terminal:&dyn Terminal, chars:&[char]) {
+    let text:String = chars.iter().collect();
+    terminal.write_str(&text);
+}
+
+/// Rewrites the line from the cursor on after something in it was deleted, then
+/// puts the cursor back.
+fn redraw_tail(terminal:&dyn Terminal, tail:&[char]) {
+    write_chars(terminal,tail);
+    terminal.clear_to_end();
+    terminal.cursor_left(tail.len());
+}
+
+/// Shows `new` instead of `line`, with the cursor at its end.
+fn replace(terminal:&dyn Terminal, line:&mut Vec<char>, cursor:&mut usize, new:Vec<char>) {
+    terminal.cursor_left(*cursor);
+    write_chars(terminal,&new);
+    terminal.clear_to_end();
+    *cursor = new.len();
+    *line = new;
+}
@@ -0,0 +1,55 @@
+//! Interactive consoles: a terminal to type on and read from, either a serial port
+//! or the screen with the keyboard, and the line editor the shell reads commands
+//! with. Both kinds of terminal hand out the same `Key`s, so the editor and the
+//! shell work the same on either.
+
+use core::fmt;
+
+pub mod line;
+pub mod serial;
+pub mod vga;
+
+pub use line::LineEditor;
+pub use serial::SerialTerminal;
+pub use vga::VgaTerminal;
+
+/// A key press, decoded from escape sequences or scancodes.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    /// Ctrl-C.
+    Interrupt,
+    /// Ctrl-D.
+    EndOfFile,
+}
+
+/// Where an interactive session runs.
+pub trait Terminal:Send+Sync {
+    /// Waits for the next key.
+    fn read_key(&self) -> Key;
+    /// Writes `text` at the cursor; "\n" starts a new line.
+    fn write_str(&self, text:&str);
+    /// Moves the cursor `n` characters to the left, within the current line.
+    fn cursor_left(&self, n:usize);
+    /// Blanks the current line from the cursor on.
+    fn clear_to_end(&self);
+}
+
+/// Lets `write!` print to a terminal.
+pub struct Output<'a>(pub &'a dyn Terminal);
+
+impl fmt::Write for Output<'_> {
+    fn write_str(&mut self, s:&str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! A terminal on a serial port, e.g. QEMU's `-serial stdio`, speaking VT100.
+
+use core::sync::atomic::{AtomicBool,Ordering};
+use crate::serial;
+use super::{Key,Terminal};
+
+const ESC:u8 = 0x1b;
+/// How long the rest of an escape sequence may take, in ticks; a lone ESC is dropped.
+const SEQUENCE_TIMEOUT:u64 = 5;
+
+pub struct SerialTerminal {
+    port:usize,
+    /// The last key was a "\r", so a "\n" right after it is part of the same Enter.
+    after_cr:AtomicBool,
+}impl SerialTerminal {
+    pub fn new(port:usize) -> Self {
+        SerialTerminal {port, after_cr:AtomicBool::new(false)}
+    }
+
+    fn read_byte(&self, timeout:Option<u64>) -> Option<u8> {
+        let mut byte = [0u8];
+        match serial::read_timeout(self.port,&mut byte,timeout) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    /// Decodes what follows an ESC: "[A" and so on, "[3~" for Delete, "OH" and "OF".
+    fn read_sequence(&self) -> Option<Key> {
+        let introducer = self.read_byte(Some(SEQUENCE_TIMEOUT))?;
+        if introducer != b'[' && introducer != b'O' {
+            return None;
+        }
+        let mut parameter = 0u32;
+        loop {
+            let byte = self.read_byte(Some(SEQUENCE_TIMEOUT))?;
+            match byte {
+                b'0'..=b'9' => parameter = parameter.saturating_mul(10).saturating_add((byte - b'0') as u32),//no key has a long one
+                b'A' => return Some(Key::Up),
+                b'B' => return Some(Key::Down),
+                b'C' => return Some(Key::Right),
+                b'D' => return Some(Key::Left),
+                b'H' => return Some(Key::Home),
+                b'F' => return Some(Key::End),
+                b'~' => return match parameter {
+                    1 | 7 => Some(Key::Home),
+                    3 => Some(Key::Delete),
+                    4 | 8 => Some(Key::End),
+                    _ => None,
+                },
+                _ => return None,
+            }
+        }
+    }
+
+    fn send(&self, text:&str) {
+        let _ = serial::write(self.port,text.as_bytes());
+    }
+}
+
+impl Terminal for SerialTerminal {
+    fn read_key(&self) -> Key {
+        loop {
+            let byte = match self.read_byte(None) {
+                Some(byte) => byte,
+                None => continue,
+            };
+            let after_cr = self.after_cr.swap(byte == b'\r',Ordering::Relaxed);
+            let key = match byte {
+                b'\n' if after_cr => None,
+                b'\r' | b'\n' => Some(Key::Enter),
+                0x08 | 0x7f => Some(Key::Backspace),
+                0x03 => Some(Key::Interrupt),
+                0x04 => Some(Key::EndOfFile),
+                ESC => self.read_sequence(),
+                0x20..=0x7e => Some(Key::Char(byte as char)),
+                _ => None,
+            };
+            if let Some(key) = key {
+                return key;
+            }
+        }
+    }
+
+    fn write_str(&self, text:&str) {
+        //the other end is in raw mode, so lines need a carriage return as well
+        if text.contains('\n') {
+            self.send(&text.replace('\n',"\r\n"));
+        } else {
+            self.send(text);
+        }
+    }
+
+    fn cursor_left(&self, n:usize) {
+        if n > 0 {
+            self.send(&alloc::format!("\x1b[{}D",n));
+        }
+    }
+
+    fn clear_to_end(&self) {
+        self.send("\x1b[K");
+    }
+}
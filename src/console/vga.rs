@@ -0,0 +1,102 @@
+//! The screen with the PS/2 keyboard as a terminal.
+//!
+//! The keyboard interrupt handler decodes keys and queues them here, in a ring
+//! that does not allocate since it is filled in the interrupt handler.
+
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey,KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::print;
+use crate::sync::WaitQueue;
+use crate::vga_buffer::WRITER;
+use super::{Key,Terminal};
+
+/// Keys kept until they are read; more are dropped.
+const QUEUE_SIZE:usize = 64;
+
+struct KeyQueue {
+    keys:[Key;QUEUE_SIZE],
+    start:usize,
+    len:usize,
+}
+
+static KEYS:Mutex<KeyQueue> = Mutex::new(KeyQueue {keys:[Key::Enter;QUEUE_SIZE], start:0, len:0});
+lazy_static! {
+    static ref READERS:WaitQueue = WaitQueue::new();
+}
+
+/// What a decoded key means to a terminal, if anything.
+fn translate(key:DecodedKey) -> Option<Key> {
+    match key {
+        DecodedKey::Unicode('\n') => Some(Key::Enter),
+        DecodedKey::Unicode('\u{8}') => Some(Key::Backspace),
+        DecodedKey::Unicode('\u{7f}') => Some(Key::Delete),
+        DecodedKey::Unicode('\u{3}') => Some(Key::Interrupt),
+        DecodedKey::Unicode('\u{4}') => Some(Key::EndOfFile),
+        DecodedKey::Unicode(character) if !character.is_control() => Some(Key::Char(character)),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Key::Left),
+        DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Key::Right),
+        DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Key::Up),
+        DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Key::Down),
+        DecodedKey::RawKey(KeyCode::Home) => Some(Key::Home),
+        DecodedKey::RawKey(KeyCode::End) => Some(Key::End),
+        _ => None,
+    }
+}
+
+/// Called by the keyboard interrupt handler for every decoded key.
+pub fn key_pressed(key:DecodedKey) {
+    let key = match translate(key) {
+        Some(key) => key,
+        None => return,
+    };
+    {
+        let mut queue = KEYS.lock();//interrupts are off in the handler
+        if queue.len == QUEUE_SIZE {
+            return;
+        }
+        let end = (queue.start + queue.len)%QUEUE_SIZE;
+        queue.keys[end] = key;
+        queue.len += 1;
+    }
+    READERS.notify_all();
+}
+
+fn pop_key() -> Option<Key> {
+    interrupts::without_interrupts(||{
+        let mut queue = KEYS.lock();
+        if queue.len == 0 {
+            return None;
+        }
+        let key = queue.keys[queue.start];
+        queue.start = (queue.start + 1)%QUEUE_SIZE;
+        queue.len -= 1;
+        Some(key)
+    })
+}
+
+pub struct VgaTerminal;
+
+impl Terminal for VgaTerminal {
+    fn read_key(&self) -> Key {
+        let mut key = None;
+        READERS.wait_until(||{
+            key = pop_key();
+            key.is_some()
+        });
+        key.unwrap()
+    }
+
+    fn write_str(&self, text:&str) {
+        print!("{}",text);
+    }
+
+    fn cursor_left(&self, n:usize) {
+        interrupts::without_interrupts(||WRITER.lock().cursor_left(n));
+    }
+
+    fn clear_to_end(&self) {
+        interrupts::without_interrupts(||WRITER.lock().clear_to_end());
+    }
+}
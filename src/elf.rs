@@ -0,0 +1,246 @@
+use alloc::{sync::Arc,vec::Vec};
+use core::convert::TryInto;
+use x86_64::{VirtAddr,structures::paging::PageTableFlags};
+use crate::memory::address_space::AddressSpace;
+use crate::task::{self,ThreadId};
+use crate::usermode::{self,USER_START,USER_END,USER_STACK_PAGES};
+
+const ELF_MAGIC:[u8;4] = [0x7f,b'E',b'L',b'F'];
+const ELFCLASS64:u8 = 2;
+const ELFDATA2LSB:u8 = 1;
+const EV_CURRENT:u8 = 1;
+const ET_EXEC:u16 = 2;
+const ET_DYN:u16 = 3;
+const EM_X86_64:u16 = 0x3e;
+const EHDR_SIZE:usize = 64;
+const PHDR_SIZE:usize = 56;
+
+const PT_LOAD:u32 = 1;
+const PT_INTERP:u32 = 3;
+const PF_X:u32 = 1;
+const PF_W:u32 = 2;
+
+//auxiliary vector entry types
+const AT_NULL:u64 = 0;
+const AT_PHDR:u64 = 3;
+const AT_PHENT:u64 = 4;
+const AT_PHNUM:u64 = 5;
+const AT_PAGESZ:u64 = 6;
+const AT_ENTRY:u64 = 9;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    Not64Bit,
+    NotLittleEndian,
+    BadVersion,
+    UnsupportedType(u16),
+    UnsupportedMachine(u16),
+    BadProgramHeader,
+    /// A segment lies (partly) outside the user part of the address space.
+    SegmentOutOfRange,
+    /// The program asks for an interpreter (`PT_INTERP`).
+    DynamicLinkingUnsupported,
+    OutOfMemory,
+}
+
+fn u16_at(data:&[u8], offset:usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset+2].try_into().unwrap())
+}
+fn u32_at(data:&[u8], offset:usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset+4].try_into().unwrap())
+}
+fn u64_at(data:&[u8], offset:usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset+8].try_into().unwrap())
+}
+
+#[derive(Debug,Clone,Copy)]
+struct ProgramHeader {
+    kind:u32,
+    flags:u32,
+    offset:u64,
+    vaddr:u64,
+    file_size:u64,
+    mem_size:u64,
+}
+
+/// The parts of an ELF64 file the loader needs, validated against the image.
+struct Elf<'a> {
+    image:&'a [u8],
+    kind:u16,
+    entry:u64,
+    ph_offset:u64,
+    headers:Vec<ProgramHeader>,
+}impl<'a> Elf<'a> {
+    fn parse(image:&'a [u8]) -> Result<Self,ElfError> {
+        if image.len() < EHDR_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if image[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if image[4] != ELFCLASS64 {
+            return Err(ElfError::Not64Bit);
+        }
+        if image[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if image[6] != EV_CURRENT || u32_at(image,20) != EV_CURRENT as u32 {
+            return Err(ElfError::BadVersion);
+        }
+        let kind = u16_at(image,16);
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(ElfError::UnsupportedType(kind));
+        }
+        let machine = u16_at(image,18);
+        if machine != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+        let entry = u64_at(image,24);
+        let ph_offset = u64_at(image,32);
+        let ph_size = u16_at(image,54) as usize;
+        let ph_count = u16_at(image,56) as usize;
+        if ph_count > 0 && ph_size < PHDR_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let table_end = (ph_offset as usize).checked_add(ph_size*ph_count).ok_or(ElfError::BadProgramHeader)?;
+        if ph_offset > image.len() as u64 || table_end > image.len() {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let mut headers = Vec::with_capacity(ph_count);
+        for i in 0..ph_count {
+            let at = ph_offset as usize + i*ph_size;
+            let header = ProgramHeader {
+                kind:u32_at(image,at),
+                flags:u32_at(image,at+4),
+                offset:u64_at(image,at+8),
+                vaddr:u64_at(image,at+16),
+                file_size:u64_at(image,at+32),
+                mem_size:u64_at(image,at+40),
+            };
+            if header.kind == PT_INTERP {
+                return Err(ElfError::DynamicLinkingUnsupported);
+            }
+            if header.kind == PT_LOAD {
+                let file_end = header.offset.checked_add(header.file_size).ok_or(ElfError::BadProgramHeader)?;
+                if file_end > image.len() as u64 || header.file_size > header.mem_size {
+                    return Err(ElfError::BadProgramHeader);
+                }
+            }
+            headers.push(header);
+        }
+        Ok(Elf {image,kind,entry,ph_offset,headers})
+    }
+}
+
+/// A program mapped into its own address space, ready to enter user mode.
+pub struct LoadedProgram {
+    pub address_space:Arc<AddressSpace>,
+    pub entry:VirtAddr,
+    pub stack_pointer:VirtAddr,
+}
+
+/// Loads the ELF64 executable `image` into a new address space and builds its
+/// initial stack with `argv`, `envp` and an auxiliary vector, as the System V ABI
+/// expects at the entry point.
+///
+/// Programs have to be statically linked. `ET_EXEC` files must be linked inside
+/// the user range (`USER_START..USER_END`); position independent (`ET_DYN`)
+/// files are loaded at `USER_START`.
+pub fn load(image:&[u8], argv:&[&str], envp:&[&str]) -> Result<LoadedProgram,ElfError> {
+    let elf = Elf::parse(image)?;
+    let base = if elf.kind == ET_DYN {USER_START} else {0};
+    let address_space = AddressSpace::new().map_err(|_|ElfError::OutOfMemory)?;
+    let mut phdr = None;
+    for header in elf.headers.iter().filter(|header|header.kind == PT_LOAD && header.mem_size > 0) {
+        let start = base.checked_add(header.vaddr).ok_or(ElfError::SegmentOutOfRange)?;
+        let end = start.checked_add(header.mem_size).ok_or(ElfError::SegmentOutOfRange)?;
+        //the top of the range is left to the stack
+        if start < USER_START || end > USER_END - (USER_STACK_PAGES+1)*4096 {
+            return Err(ElfError::SegmentOutOfRange);
+        }
+        let mut flags = PageTableFlags::PRESENT;
+        if header.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if header.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        let first_page = start & !0xfff;
+        let pages = (end - first_page + 4095)/4096;
+        address_space.map_zeroed(VirtAddr::new(first_page),pages,flags).map_err(|_|ElfError::OutOfMemory)?;
+        let data = &elf.image[header.offset as usize..(header.offset+header.file_size) as usize];
+        address_space.write_bytes(VirtAddr::new(start),data).map_err(|_|ElfError::OutOfMemory)?;
+        //the program headers are usually part of the first segment
+        if elf.ph_offset >= header.offset && elf.ph_offset < header.offset + header.file_size {
+            phdr = Some(start + (elf.ph_offset - header.offset));
+        }
+    }
+    let entry = base.checked_add(elf.entry).ok_or(ElfError::SegmentOutOfRange)?;
+    if entry < USER_START || entry >= USER_END {
+        return Err(ElfError::SegmentOutOfRange);
+    }
+    let auxv = [
+        (AT_PHDR,phdr.unwrap_or(0)),
+        (AT_PHENT,PHDR_SIZE as u64),
+        (AT_PHNUM,elf.headers.len() as u64),
+        (AT_PAGESZ,4096),
+        (AT_ENTRY,entry),
+        (AT_NULL,0),
+    ];
+    let stack_pointer = build_stack(&address_space,argv,envp,&auxv)?;
+    Ok(LoadedProgram {
+        address_space:Arc::new(address_space),
+        entry:VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// Maps the user stack right below `USER_END` and lays out, from the stack pointer
+/// upwards: argc, argv pointers, NULL, envp pointers, NULL, auxv pairs, then the strings.
+fn build_stack(address_space:&AddressSpace, argv:&[&str], envp:&[&str], auxv:&[(u64,u64)]) -> Result<VirtAddr,ElfError> {
+    let size = USER_STACK_PAGES*4096;
+    let bottom = USER_END - size;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    address_space.map_zeroed(VirtAddr::new(bottom),USER_STACK_PAGES,flags).map_err(|_|ElfError::OutOfMemory)?;
+
+    let mut top = USER_END;
+    let mut push_string = |s:&str| -> Result<u64,ElfError> {
+        let len = s.len() as u64 + 1;
+        if top - bottom < len + 4096 {//keep room for the vectors
+            return Err(ElfError::OutOfMemory);
+        }
+        top -= len;
+        address_space.write_bytes(VirtAddr::new(top),s.as_bytes()).map_err(|_|ElfError::OutOfMemory)?;
+        Ok(top)//the terminating zero is already there
+    };
+    let arg_ptrs = argv.iter().map(|s|push_string(s)).collect::<Result<Vec<u64>,_>>()?;
+    let env_ptrs = envp.iter().map(|s|push_string(s)).collect::<Result<Vec<u64>,_>>()?;
+
+    let mut words:Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(&arg_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    for &(kind,value) in auxv {
+        words.push(kind);
+        words.push(value);
+    }
+    let bytes = words.len() as u64 * 8;
+    let sp = (top - bytes) & !0xf;//rsp must be 16-byte aligned at the entry point
+    if sp < bottom {
+        return Err(ElfError::OutOfMemory);
+    }
+    let data:Vec<u8> = words.iter().flat_map(|word|word.to_le_bytes().to_vec()).collect();
+    address_space.write_bytes(VirtAddr::new(sp),&data).map_err(|_|ElfError::OutOfMemory)?;
+    Ok(VirtAddr::new(sp))
+}
+
+/// Loads `image` and starts it in a new thread running in user mode.
+pub fn spawn(image:&[u8], argv:&[&str], envp:&[&str]) -> Result<ThreadId,ElfError> {
+    let program = load(image,argv,envp)?;
+    let (entry,stack) = (program.entry,program.stack_pointer);
+    Ok(task::spawn_in(program.address_space,move ||unsafe { usermode::enter(entry,stack) }))
+}
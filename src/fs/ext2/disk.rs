@@ -0,0 +1,215 @@
+//! On-disk structures: the superblock, block group descriptors, inodes and
+//! directory entries.
+
+use alloc::{string::String,vec::Vec};
+use core::convert::TryInto;
+use crate::syscall::Errno;
+use crate::vfs::FileType;
+
+const MAGIC:u16 = 0xef53;
+/// Byte offset of the superblock, whatever the block size.
+pub(super) const SUPERBLOCK_OFFSET:u64 = 1024;
+pub(super) const SUPERBLOCK_SIZE:usize = 1024;
+pub(super) const GROUP_DESCRIPTOR_SIZE:usize = 32;
+pub(super) const ROOT_INO:u32 = 2;
+
+/// Directory entries carry a file type byte.
+const INCOMPAT_FILETYPE:u32 = 0x0002;
+/// Bitmaps and inode tables may live outside their group; the descriptors still say where.
+const INCOMPAT_FLEX_BG:u32 = 0x0200;
+/// Everything else in the incompatible set changes the layout (extents, 64-bit block
+/// numbers, meta block groups, inline data...) or means the journal needs replaying.
+const INCOMPAT_SUPPORTED:u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+/// Files may be 4 GiB or larger, with the upper size bits in `i_size_high`.
+const RO_COMPAT_LARGE_FILE:u32 = 0x0002;
+
+/// Inode flag of files mapped by extents rather than block pointers.
+const EXTENTS_FL:u32 = 0x0008_0000;
+
+/// Number of block pointers in an inode: 12 direct, then single, double and triple indirect.
+pub(super) const DIRECT_BLOCKS:usize = 12;
+const POINTERS:usize = 15;
+
+fn u16_at(data:&[u8], offset:usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset+2].try_into().unwrap())
+}
+pub(super) fn u32_at(data:&[u8], offset:usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset+4].try_into().unwrap())
+}
+
+#[derive(Debug,Clone)]
+pub(super) struct Superblock {
+    pub inodes_count:u32,
+    pub blocks_count:u32,
+    pub first_data_block:u32,
+    pub block_size:usize,
+    pub blocks_per_group:u32,
+    pub inodes_per_group:u32,
+    pub inode_size:usize,
+    pub incompat:u32,
+    pub ro_compat:u32,
+}impl Superblock {
+    /// Decodes and checks a superblock. Fails with `EINVAL` if it isn't ext2 or uses
+    /// features that this driver would misread.
+    pub fn parse(data:&[u8]) -> Result<Self,Errno> {
+        if data.len() < SUPERBLOCK_SIZE || u16_at(data,56) != MAGIC {
+            return Err(Errno::EINVAL);
+        }
+        let log_block_size = u32_at(data,24);
+        if log_block_size > 6 {
+            return Err(Errno::EINVAL);//more than 64 KiB
+        }
+        let revision = u32_at(data,76);
+        let (inode_size,incompat,ro_compat) = match revision {
+            0 => (128,0,0),
+            1 => (u16_at(data,88) as usize,u32_at(data,96),u32_at(data,100)),
+            _ => return Err(Errno::EINVAL),
+        };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Errno::EINVAL);
+        }
+        //a read-only driver may ignore read-only compatible features, by their definition
+        let superblock = Superblock {
+            inodes_count:u32_at(data,0),
+            blocks_count:u32_at(data,4),
+            first_data_block:u32_at(data,20),
+            block_size:1024 << log_block_size,
+            blocks_per_group:u32_at(data,32),
+            inodes_per_group:u32_at(data,40),
+            inode_size,
+            incompat,
+            ro_compat,
+        };
+        if !inode_size.is_power_of_two() || inode_size < 128 || inode_size > superblock.block_size
+            || superblock.blocks_per_group == 0 || superblock.inodes_per_group == 0
+            || superblock.first_data_block >= superblock.blocks_count {
+            return Err(Errno::EINVAL);
+        }
+        Ok(superblock)
+    }
+
+    pub fn groups(&self) -> u32 {
+        let blocks = self.blocks_count - self.first_data_block;
+        (blocks + self.blocks_per_group - 1)/self.blocks_per_group
+    }
+
+    pub fn has_file_types(&self) -> bool {
+        self.incompat & INCOMPAT_FILETYPE != 0
+    }
+
+    pub fn large_files(&self) -> bool {
+        self.ro_compat & RO_COMPAT_LARGE_FILE != 0
+    }
+}
+
+/// The part of a block group descriptor a read-only driver needs.
+#[derive(Debug,Clone,Copy)]
+pub(super) struct Group {
+    pub inode_table:u32,
+}impl Group {
+    pub fn parse(data:&[u8]) -> Self {
+        Group {inode_table:u32_at(data,8)}
+    }
+}
+
+/// An inode as stored in the inode table.
+#[derive(Debug,Clone)]
+pub(super) struct DiskInode {
+    pub mode:u16,
+    pub size:u64,
+    pub links:u16,
+    /// Space used, in 512-byte units.
+    pub sectors:u32,
+    pub flags:u32,
+    pub file_acl:u32,
+    /// The block pointers, or the target of a short symbolic link.
+    pub block:[u8;4*POINTERS],
+}impl DiskInode {
+    pub fn parse(data:&[u8], superblock:&Superblock) -> Self {
+        let mode = u16_at(data,0);
+        let mut size = u32_at(data,4) as u64;
+        if mode & 0xf000 == 0x8000 && superblock.large_files() {
+            size |= (u32_at(data,108) as u64) << 32;//`i_dir_acl` for directories
+        }
+        DiskInode {
+            mode,
+            size,
+            links:u16_at(data,26),
+            sectors:u32_at(data,28),
+            flags:u32_at(data,32),
+            file_acl:u32_at(data,104),
+            block:data[40..40+4*POINTERS].try_into().unwrap(),
+        }
+    }
+
+    /// The file type, or None for FIFOs and sockets, which the VFS has no type for.
+    pub fn kind(&self) -> Option<FileType> {
+        match self.mode & 0xf000 {
+            0x8000 => Some(FileType::Regular),
+            0x4000 => Some(FileType::Directory),
+            0xa000 => Some(FileType::Symlink),
+            0x2000 => Some(FileType::CharDevice),
+            0x6000 => Some(FileType::BlockDevice),
+            _ => None,
+        }
+    }
+
+    pub fn pointer(&self, index:usize) -> u32 {
+        u32_at(&self.block,index*4)
+    }
+
+    pub fn uses_extents(&self) -> bool {
+        self.flags & EXTENTS_FL != 0
+    }
+
+    /// Whether this symbolic link keeps its target in the block pointers: it then
+    /// has no data blocks, except maybe one for extended attributes.
+    pub fn is_fast_symlink(&self, block_size:usize) -> bool {
+        let attribute_sectors = if self.file_acl != 0 {(block_size/512) as u32} else {0};
+        self.sectors == attribute_sectors && self.size < self.block.len() as u64
+    }
+}
+
+/// A directory entry.
+#[derive(Debug,Clone)]
+pub(super) struct DirRecord {
+    pub ino:u32,
+    pub name:String,
+    /// From the entry itself if the file system records types, else None.
+    pub kind:Option<FileType>,
+}
+
+/// Decodes the entries of a directory made of `block_size` blocks, skipping unused
+/// ones, "." and "..". Names that aren't UTF-8 are converted lossily. Fails with `EIO`
+/// if a record is malformed.
+pub(super) fn parse_dir(data:&[u8], block_size:usize, file_types:bool) -> Result<Vec<DirRecord>,Errno> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let ino = u32_at(data,offset);
+        let rec_len = u16_at(data,offset+4) as usize;
+        let (name_len,file_type) = if file_types {
+            (data[offset+6] as usize,data[offset+7])
+        } else {
+            (u16_at(data,offset+6) as usize,0)
+        };
+        let block_end = (offset/block_size + 1)*block_size;
+        if rec_len < 8 || rec_len%4 != 0 || offset + rec_len > block_end.min(data.len()) || 8 + name_len > rec_len {
+            return Err(Errno::EIO);
+        }
+        let name = &data[offset+8..offset+8+name_len];
+        if ino != 0 && name != b"." && name != b".." {
+            let kind = match file_type {
+                1 => Some(FileType::Regular),
+                2 => Some(FileType::Directory),
+                3 => Some(FileType::CharDevice),
+                4 => Some(FileType::BlockDevice),
+                7 => Some(FileType::Symlink),
+                _ => None,//unknown, FIFO or socket
+            };
+            records.push(DirRecord {ino, name:String::from_utf8_lossy(name).into_owned(), kind});
+        }
+        offset += rec_len;
+    }
+    Ok(records)
+}
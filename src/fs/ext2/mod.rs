@@ -0,0 +1,277 @@
+//! Read-only ext2 file system driver.
+//!
+//! Files are read through the block pointers of their inodes: 12 direct ones, then
+//! single, double and triple indirect blocks, where a zero pointer is a hole.
+//! Volumes using incompatible features that change the layout, such as ext4
+//! extents, are refused at mount time rather than misread; read-only compatible
+//! features don't matter here. Anything that would write fails with `EROFS`.
+
+use alloc::{string::String,sync::Arc,vec,vec::Vec};
+use crate::block::{self,BlockDevice};
+use crate::syscall::Errno;
+use crate::vfs::{self,DirEntry,FileSystem,FileType,Inode,Metadata};
+
+mod disk;
+
+use disk::{DirRecord,DiskInode,Group,Superblock};
+
+struct Volume {
+    device:Arc<dyn BlockDevice>,
+    superblock:Superblock,
+    groups:Vec<Group>,
+}impl Volume {
+    fn block_size(&self) -> usize {
+        self.superblock.block_size
+    }
+
+    /// Reads from block `block` at byte `offset` into `buf`.
+    fn read_block(&self, block:u32, offset:usize, buf:&mut [u8]) -> Result<(),Errno> {
+        if block >= self.superblock.blocks_count {
+            return Err(Errno::EIO);
+        }
+        block::read_bytes(&*self.device,block as u64*self.block_size() as u64 + offset as u64,buf)
+    }
+
+    fn read_inode(&self, ino:u32) -> Result<DiskInode,Errno> {
+        if ino == 0 || ino > self.superblock.inodes_count {
+            return Err(Errno::EIO);
+        }
+        let index = ino - 1;
+        let group = self.groups.get((index/self.superblock.inodes_per_group) as usize).ok_or(Errno::EIO)?;
+        let position = (index%self.superblock.inodes_per_group) as usize*self.superblock.inode_size;
+        let mut data = vec![0u8;self.superblock.inode_size];
+        let block_size = self.block_size();
+        self.read_block(group.inode_table + (position/block_size) as u32,position%block_size,&mut data)?;
+        Ok(DiskInode::parse(&data,&self.superblock))
+    }
+
+    /// Entry `index` of the pointer block `block`; 0 inside a hole.
+    fn pointer(&self, block:u32, index:u64) -> Result<u32,Errno> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut entry = [0u8;4];
+        self.read_block(block,index as usize*4,&mut entry)?;
+        Ok(u32::from_le_bytes(entry))
+    }
+
+    /// The block holding block `index` of the file, 0 for a hole.
+    fn map(&self, inode:&DiskInode, index:u64) -> Result<u32,Errno> {
+        let per_block = (self.block_size()/4) as u64;
+        if index < disk::DIRECT_BLOCKS as u64 {
+            return Ok(inode.pointer(index as usize));
+        }
+        //walk down from the indirect block covering `index`, one level at a time
+        let mut index = index - disk::DIRECT_BLOCKS as u64;
+        let mut span = 1;//blocks covered by one pointer at the current level
+        for level in 0..3 {
+            span *= per_block;
+            if index < span {
+                let mut block = inode.pointer(disk::DIRECT_BLOCKS + level);
+                while span > 1 {
+                    span /= per_block;
+                    block = self.pointer(block,index/span)?;
+                    index %= span;
+                }
+                return Ok(block);
+            }
+            index -= span;
+        }
+        Err(Errno::EIO)//beyond what the pointers can reach
+    }
+
+    /// Reads the data of `inode` at `offset`, up to its size.
+    fn read_data(&self, inode:&DiskInode, offset:u64, buf:&mut [u8]) -> Result<usize,Errno> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((inode.size - offset) as usize);
+        let block_size = self.block_size() as u64;
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u64;
+            let within = (at%block_size) as usize;
+            let chunk = (block_size as usize - within).min(len - done);
+            match self.map(inode,at/block_size)? {
+                0 => buf[done..done+chunk].iter_mut().for_each(|byte|*byte = 0),
+                block => self.read_block(block,within,&mut buf[done..done+chunk])?,
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+}
+
+/// A mounted ext2 volume.
+pub struct Ext2Fs {
+    root:Arc<Ext2Inode>,
+}impl Ext2Fs {
+    /// Reads the file system on `device`. Fails with `EINVAL` if it isn't ext2 or
+    /// needs features this driver lacks.
+    pub fn new(device:Arc<dyn BlockDevice>) -> Result<Arc<Self>,Errno> {
+        let mut data = vec![0u8;disk::SUPERBLOCK_SIZE];
+        block::read_bytes(&*device,disk::SUPERBLOCK_OFFSET,&mut data)?;
+        let superblock = Superblock::parse(&data)?;
+        let size = superblock.blocks_count as u64*superblock.block_size as u64;
+        if size > device.block_count()*device.block_size() as u64 {
+            return Err(Errno::EINVAL);//larger than the device
+        }
+        //the descriptor table follows the block holding the superblock
+        let table = (superblock.first_data_block as u64 + 1)*superblock.block_size as u64;
+        let mut descriptors = vec![0u8;superblock.groups() as usize*disk::GROUP_DESCRIPTOR_SIZE];
+        block::read_bytes(&*device,table,&mut descriptors)?;
+        let groups = descriptors.chunks_exact(disk::GROUP_DESCRIPTOR_SIZE).map(Group::parse).collect();
+        let volume = Arc::new(Volume {device, superblock, groups});
+        let root = Ext2Inode::read(&volume,disk::ROOT_INO)?;
+        if root.kind != FileType::Directory {
+            return Err(Errno::EINVAL);
+        }
+        Ok(Arc::new(Ext2Fs {root}))
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct Ext2Inode {
+    volume:Arc<Volume>,
+    ino:u32,
+    kind:FileType,
+    disk:DiskInode,
+}impl Ext2Inode {
+    fn read(volume:&Arc<Volume>, ino:u32) -> Result<Arc<Self>,Errno> {
+        let disk = volume.read_inode(ino)?;
+        let kind = disk.kind().ok_or(Errno::ENOENT)?;//FIFOs and sockets are left out of listings too
+        if disk.uses_extents() {
+            return Err(Errno::EIO);//can't happen without the extents feature, which was refused
+        }
+        Ok(Arc::new(Ext2Inode {volume:volume.clone(), ino, kind, disk}))
+    }
+
+    /// Goes through the records of this directory one block at a time, so that
+    /// large directories need no more memory than a block, until `visit` returns true.
+    fn visit_records<F:FnMut(DirRecord)->Result<bool,Errno>>(&self, mut visit:F) -> Result<(),Errno> {
+        if self.kind != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        let volume_size = self.volume.superblock.blocks_count as u64*self.volume.block_size() as u64;
+        if self.disk.size > volume_size {
+            return Err(Errno::EIO);//a corrupt size
+        }
+        let block_size = self.volume.block_size();
+        let mut data = vec![0u8;block_size];
+        let mut offset = 0;
+        while offset < self.disk.size {
+            let len = self.volume.read_data(&self.disk,offset,&mut data)?;
+            for record in disk::parse_dir(&data[..len],block_size,self.volume.superblock.has_file_types())? {
+                if visit(record)? {
+                    return Ok(());
+                }
+            }
+            offset += block_size as u64;
+        }
+        Ok(())
+    }
+
+    /// The error for attempts to change this inode.
+    fn read_only(&self, directory_operation:bool) -> Errno {
+        match (directory_operation,self.kind == FileType::Directory) {
+            (true,false) => Errno::ENOTDIR,
+            (false,true) => Errno::EISDIR,
+            _ => Errno::EROFS,
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino:self.ino as u64,
+            kind:self.kind,
+            size:self.disk.size,
+            links:self.disk.links as u32,
+        }
+    }
+
+    fn read_at(&self, offset:u64, buf:&mut [u8]) -> Result<usize,Errno> {
+        match self.kind {
+            FileType::Regular => self.volume.read_data(&self.disk,offset,buf),
+            FileType::Directory => Err(Errno::EISDIR),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+    fn write_at(&self, _offset:u64, _buf:&[u8]) -> Result<usize,Errno> {
+        Err(self.read_only(false))
+    }
+    fn truncate(&self, _size:u64) -> Result<(),Errno> {
+        Err(self.read_only(false))
+    }
+
+    fn lookup(&self, name:&str) -> Result<Arc<dyn Inode>,Errno> {
+        let mut found = None;
+        self.visit_records(|record|{
+            if record.name == name {
+                found = Some(record.ino);
+                return Ok(true);
+            }
+            Ok(false)
+        })?;
+        Ok(Ext2Inode::read(&self.volume,found.ok_or(Errno::ENOENT)?)?)
+    }
+    fn create(&self, _name:&str, _kind:FileType) -> Result<Arc<dyn Inode>,Errno> {
+        Err(self.read_only(true))
+    }
+    fn link(&self, _name:&str, _target:&Arc<dyn Inode>) -> Result<(),Errno> {
+        Err(self.read_only(true))
+    }
+    fn unlink(&self, _name:&str) -> Result<(),Errno> {
+        Err(self.read_only(true))
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>,Errno> {
+        let mut entries = Vec::new();
+        self.visit_records(|record|{
+            let kind = match record.kind {
+                Some(kind) => Some(kind),
+                None => self.volume.read_inode(record.ino)?.kind(),
+            };
+            if let Some(kind) = kind {
+                entries.push(DirEntry {name:record.name, ino:record.ino as u64, kind});
+            }
+            Ok(false)
+        })?;
+        Ok(entries)
+    }
+
+    fn readlink(&self) -> Result<String,Errno> {
+        if self.kind != FileType::Symlink {
+            return Err(Errno::EINVAL);
+        }
+        let size = self.disk.size as usize;
+        let target = if self.disk.is_fast_symlink(self.volume.block_size()) {
+            self.disk.block[..size].to_vec()
+        } else {
+            if size > vfs::MAX_PATH {
+                return Err(Errno::EIO);
+            }
+            let mut data = vec![0u8;size];
+            let len = self.volume.read_data(&self.disk,0,&mut data)?;
+            data.truncate(len);
+            data
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+}
+
+/// Mounts the ext2 file system on `device` at `path`.
+pub fn mount(path:&str, device:Arc<dyn BlockDevice>) -> Result<Arc<Ext2Fs>,Errno> {
+    let fs = Ext2Fs::new(device)?;
+    vfs::mount(path,fs.clone())?;
+    Ok(fs)
+}
@@ -0,0 +1,209 @@
+//! The boot sector with the BIOS parameter block, the FSInfo sector, and `format`.
+
+use alloc::{vec,vec::Vec};
+use core::convert::TryInto;
+use crate::block::BlockDevice;
+use crate::syscall::Errno;
+use super::dir;
+
+const FSINFO_LEAD:u32 = 0x4161_5252;
+const FSINFO_STRUCT:u32 = 0x6141_7272;
+const FSINFO_TRAIL:u32 = 0xaa55_0000;
+/// FSInfo value for "unknown".
+pub(super) const UNKNOWN:u32 = 0xffff_ffff;
+
+fn u16_at(data:&[u8], offset:usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset+2].try_into().unwrap())
+}
+fn u32_at(data:&[u8], offset:usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset+4].try_into().unwrap())
+}
+
+/// Where things are on a FAT32 volume.
+#[derive(Debug,Clone,Copy)]
+pub(super) struct Geometry {
+    pub bytes_per_sector:usize,
+    pub sectors_per_cluster:u64,
+    pub reserved_sectors:u64,
+    pub fats:u64,
+    pub fat_sectors:u64,
+    pub root_cluster:u32,
+    pub fsinfo_sector:Option<u64>,
+    /// Number of data clusters; valid cluster numbers are 2 to `clusters + 1`.
+    pub clusters:u32,
+}impl Geometry {
+    /// Reads the BIOS parameter block. Only FAT32 is supported, which is recognized,
+    /// as Linux does, by the FAT12/16 fields being zero rather than by the number of
+    /// clusters, so that small volumes work too.
+    pub fn parse(sector:&[u8]) -> Result<Self,Errno> {
+        if sector.len() < 512 || sector[510] != 0x55 || sector[511] != 0xaa {
+            return Err(Errno::EINVAL);
+        }
+        let bytes_per_sector = u16_at(sector,11) as usize;
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved_sectors = u16_at(sector,14) as u64;
+        let fats = sector[16] as u64;
+        let root_entries = u16_at(sector,17);
+        let fat16_sectors = u16_at(sector,22);
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 512 || bytes_per_sector > 4096
+            || !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0 || fats == 0 {
+            return Err(Errno::EINVAL);
+        }
+        if root_entries != 0 || fat16_sectors != 0 {
+            return Err(Errno::EINVAL);//FAT12 or FAT16
+        }
+        let total_sectors = match u16_at(sector,19) {
+            0 => u32_at(sector,32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = u32_at(sector,36) as u64;
+        let root_cluster = u32_at(sector,44);
+        let fsinfo_sector = match u16_at(sector,48) {
+            0|0xffff => None,
+            fsinfo => Some(fsinfo as u64),
+        };
+        let data_start = reserved_sectors + fats*fat_sectors;
+        if fat_sectors == 0 || data_start >= total_sectors {
+            return Err(Errno::EINVAL);
+        }
+        let clusters = ((total_sectors - data_start)/sectors_per_cluster)
+            .min(fat_sectors*bytes_per_sector as u64/4 - 2)//the FAT must have an entry for each
+            .min(0x0fff_fff5) as u32;
+        if root_cluster < 2 || root_cluster >= clusters + 2 {
+            return Err(Errno::EINVAL);
+        }
+        Ok(Geometry {bytes_per_sector, sectors_per_cluster, reserved_sectors, fats, fat_sectors, root_cluster, fsinfo_sector, clusters})
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector*self.sectors_per_cluster as usize
+    }
+
+    /// Byte offset of the entry for `cluster` in FAT number `fat`.
+    pub fn fat_entry(&self, fat:u64, cluster:u32) -> u64 {
+        (self.reserved_sectors + fat*self.fat_sectors)*self.bytes_per_sector as u64 + cluster as u64*4
+    }
+
+    /// Byte offset of the first byte of `cluster`.
+    pub fn cluster_start(&self, cluster:u32) -> u64 {
+        let data_start = self.reserved_sectors + self.fats*self.fat_sectors;
+        (data_start + (cluster as u64 - 2)*self.sectors_per_cluster)*self.bytes_per_sector as u64
+    }
+}
+
+/// The free cluster count and allocation hint from an FSInfo sector, if it is valid.
+pub(super) fn parse_fsinfo(sector:&[u8]) -> Option<(u32,u32)> {
+    if u32_at(sector,0) != FSINFO_LEAD || u32_at(sector,484) != FSINFO_STRUCT || u32_at(sector,508) != FSINFO_TRAIL {
+        return None;
+    }
+    Some((u32_at(sector,488),u32_at(sector,492)))
+}
+
+pub(super) fn fsinfo(free:u32, next_free:u32, bytes_per_sector:usize) -> Vec<u8> {
+    let mut sector = vec![0u8;bytes_per_sector];
+    sector[0..4].copy_from_slice(&FSINFO_LEAD.to_le_bytes());
+    sector[484..488].copy_from_slice(&FSINFO_STRUCT.to_le_bytes());
+    sector[488..492].copy_from_slice(&free.to_le_bytes());
+    sector[492..496].copy_from_slice(&next_free.to_le_bytes());
+    sector[508..512].copy_from_slice(&FSINFO_TRAIL.to_le_bytes());
+    sector
+}
+
+/// Sectors per cluster for a volume of `bytes`, as mkfs.fat chooses them.
+fn sectors_per_cluster(bytes:u64) -> u64 {
+    const MIB:u64 = 1024*1024;
+    match bytes {
+        bytes if bytes < 260*MIB => 1,
+        bytes if bytes < 8*1024*MIB => 8,
+        bytes if bytes < 16*1024*MIB => 16,
+        bytes if bytes < 32*1024*MIB => 32,
+        _ => 64,
+    }
+}
+
+/// Writes an empty FAT32 file system with volume label `label` to `device`.
+pub fn format(device:&dyn BlockDevice, label:&str) -> Result<(),Errno> {
+    let block_size = device.block_size();
+    let bytes_per_sector = block_size.max(512);
+    if bytes_per_sector > 4096 {
+        return Err(Errno::EINVAL);
+    }
+    let total_sectors = device.block_count()*block_size as u64/bytes_per_sector as u64;
+    let sectors_per_cluster = sectors_per_cluster(total_sectors*bytes_per_sector as u64);
+    let reserved_sectors:u64 = 32;
+    let fats:u64 = 2;
+    //the FAT size depends on the number of clusters and the other way round
+    let mut fat_sectors = 1;
+    let clusters = loop {
+        let data = total_sectors.checked_sub(reserved_sectors + fats*fat_sectors).ok_or(Errno::ENOSPC)?;
+        let clusters = data/sectors_per_cluster;
+        let needed = ((clusters + 2)*4 + bytes_per_sector as u64 - 1)/bytes_per_sector as u64;
+        if needed <= fat_sectors {
+            break clusters;
+        }
+        fat_sectors = needed;
+    };
+    if clusters < 16 || total_sectors > u32::MAX as u64 {
+        return Err(Errno::ENOSPC);
+    }
+
+    let mut boot = vec![0u8;bytes_per_sector];
+    boot[0..3].copy_from_slice(&[0xeb,0x58,0x90]);//jump over the BPB
+    boot[3..11].copy_from_slice(b"BENTOS  ");
+    boot[11..13].copy_from_slice(&(bytes_per_sector as u16).to_le_bytes());
+    boot[13] = sectors_per_cluster as u8;
+    boot[14..16].copy_from_slice(&(reserved_sectors as u16).to_le_bytes());
+    boot[16] = fats as u8;
+    boot[21] = 0xf8;//media: fixed disk
+    boot[24..26].copy_from_slice(&32u16.to_le_bytes());//sectors per track
+    boot[26..28].copy_from_slice(&64u16.to_le_bytes());//heads
+    boot[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());//root directory cluster
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes());//FSInfo sector
+    boot[50..52].copy_from_slice(&6u16.to_le_bytes());//backup boot sector
+    boot[64] = 0x80;//drive number
+    boot[66] = 0x29;//extended boot signature
+    boot[67..71].copy_from_slice(&(total_sectors as u32 ^ 0xb3e7_05a1).to_le_bytes());//volume id
+    let mut volume_label = [b' ';11];
+    for (slot,byte) in volume_label.iter_mut().zip(label.bytes()) {
+        *slot = byte.to_ascii_uppercase();
+    }
+    boot[71..82].copy_from_slice(&volume_label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510] = 0x55;
+    boot[511] = 0xaa;
+    let info = fsinfo(clusters as u32 - 1,3,bytes_per_sector);
+
+    let write = |sector:u64, data:&[u8]|{
+        let ratio = (bytes_per_sector/block_size) as u64;
+        device.write_blocks(sector*ratio,data)
+    };
+    let zero = vec![0u8;bytes_per_sector];
+    for sector in 0..reserved_sectors {
+        write(sector,&zero)?;
+    }
+    for &copy in &[0u64,6] {//the backup at 6 is what tools fall back to
+        write(copy,&boot)?;
+        write(copy + 1,&info)?;
+    }
+    let mut first_fat_sector = zero.clone();
+    first_fat_sector[0..4].copy_from_slice(&0x0fff_fff8u32.to_le_bytes());//media
+    first_fat_sector[4..8].copy_from_slice(&0x0fff_ffffu32.to_le_bytes());
+    first_fat_sector[8..12].copy_from_slice(&0x0fff_ffffu32.to_le_bytes());//end of the root directory
+    for fat in 0..fats {
+        let start = reserved_sectors + fat*fat_sectors;
+        write(start,&first_fat_sector)?;
+        for sector in 1..fat_sectors {
+            write(start + sector,&zero)?;
+        }
+    }
+    let root = reserved_sectors + fats*fat_sectors;
+    let mut label_entry = zero.clone();
+    label_entry[..dir::ENTRY_SIZE].copy_from_slice(&dir::raw_entry(&volume_label,dir::ATTR_VOLUME_ID,0,0));
+    write(root,&label_entry)?;
+    for sector in 1..sectors_per_cluster {
+        write(root + sector,&zero)?;
+    }
+    device.flush()
+}
@@ -0,0 +1,287 @@
+//! Directory entries: 8.3 short names, long file name (LFN) slots and the
+//! conversion between the two.
+
+use alloc::{string::String,vec::Vec};
+use core::convert::TryInto;
+
+pub(super) const ENTRY_SIZE:usize = 32;
+
+pub(super) const ATTR_READ_ONLY:u8 = 0x01;
+pub(super) const ATTR_HIDDEN:u8 = 0x02;
+pub(super) const ATTR_SYSTEM:u8 = 0x04;
+pub(super) const ATTR_VOLUME_ID:u8 = 0x08;
+pub(super) const ATTR_DIRECTORY:u8 = 0x10;
+pub(super) const ATTR_ARCHIVE:u8 = 0x20;
+/// The attribute combination marking a long name slot.
+const ATTR_LONG_NAME:u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First name byte of a deleted entry.
+pub(super) const DELETED:u8 = 0xe5;
+/// Marks the last (first stored) slot of a long name.
+const LAST_SLOT:u8 = 0x40;
+/// Characters of a long name per slot.
+const SLOT_CHARS:usize = 13;
+/// Byte offsets of the 13 UTF-16 characters within a slot.
+const SLOT_OFFSETS:[usize;SLOT_CHARS] = [1,3,5,7,9,14,16,18,20,22,24,28,30];
+
+/// Case flags (used by Windows NT and Linux) for short names stored in upper case.
+const LOWER_BASE:u8 = 0x08;
+const LOWER_EXT:u8 = 0x10;
+
+/// Longest long name, in UTF-16 units.
+pub(super) const MAX_LONG_NAME:usize = 255;
+
+/// 2020-01-01, since there is no wall clock yet.
+const DATE:u16 = (40 << 9) | (1 << 5) | 1;
+
+/// A directory entry with its long name resolved.
+#[derive(Debug,Clone)]
+pub(super) struct Entry {
+    pub name:String,
+    pub short:[u8;11],
+    pub attr:u8,
+    pub cluster:u32,
+    pub size:u32,
+    /// Byte offset of the short entry within the directory.
+    pub offset:usize,
+    /// Byte offset of the first slot, the first long name slot if there are any.
+    pub first_slot:usize,
+}impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+/// The contents of a directory, decoded.
+pub(super) struct Listing {
+    pub entries:Vec<Entry>,
+    /// Offset of the end marker, or the length of the directory if there is none.
+    pub end:usize,
+}
+
+/// Decodes the raw bytes of a directory. Deleted entries, volume labels and long
+/// names that don't belong to the short entry after them are skipped.
+pub(super) fn parse(data:&[u8]) -> Listing {
+    let mut entries = Vec::new();
+    let mut long:Vec<u16> = Vec::new();
+    let mut long_start = 0;
+    let mut expected = 0;//sequence number of the next long name slot, 0 if none
+    let mut checksum = 0;
+    for (index,raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        let offset = index*ENTRY_SIZE;
+        match raw[0] {
+            0 => return Listing {entries, end:offset},
+            DELETED => {
+                long.clear();
+                expected = 0;
+                continue;
+            }
+            _ => {}
+        }
+        let attr = raw[11];
+        if attr & 0x3f == ATTR_LONG_NAME {
+            let sequence = raw[0] & 0x3f;
+            if raw[0] & LAST_SLOT != 0 {
+                //slots come last part first; make room for all of them
+                long = alloc::vec![0xffff;sequence as usize*SLOT_CHARS];
+                long_start = offset;
+                checksum = raw[13];
+                expected = sequence;
+            } else if sequence != expected || raw[13] != checksum {
+                long.clear();
+                expected = 0;
+                continue;
+            }
+            if expected == 0 || sequence == 0 {
+                continue;
+            }
+            let at = (sequence as usize - 1)*SLOT_CHARS;
+            for (i,&slot_offset) in SLOT_OFFSETS.iter().enumerate() {
+                long[at + i] = u16::from_le_bytes([raw[slot_offset],raw[slot_offset+1]]);
+            }
+            expected -= 1;
+            continue;
+        }
+        let short:[u8;11] = raw[0..11].try_into().unwrap();
+        let has_long = expected == 0 && !long.is_empty() && checksum == short_checksum(&short);
+        let first_slot = if has_long {long_start} else {offset};
+        let name = if has_long {decode_long(&long)} else {decode_short(&short,raw[12])};
+        long.clear();
+        expected = 0;
+        if attr & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        entries.push(Entry {
+            name,
+            short,
+            attr,
+            cluster:(u16::from_le_bytes([raw[20],raw[21]]) as u32) << 16 | u16::from_le_bytes([raw[26],raw[27]]) as u32,
+            size:u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            offset,
+            first_slot,
+        });
+    }
+    Listing {entries, end:data.len()}
+}
+
+fn decode_long(units:&[u16]) -> String {
+    let len = units.iter().position(|&unit|unit == 0 || unit == 0xffff).unwrap_or(units.len());
+    core::char::decode_utf16(units[..len].iter().copied())
+        .map(|c|c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+fn decode_short(short:&[u8;11], case:u8) -> String {
+    let part = |bytes:&[u8],lower:bool|{
+        let mut text = String::new();
+        for &byte in bytes.iter().take_while(|&&byte|byte != b' ') {
+            let byte = if byte == 0x05 {DELETED} else {byte};//0x05 stands for a leading 0xe5
+            let c = byte as char;
+            text.push(if lower {c.to_ascii_lowercase()} else {c});
+        }
+        text
+    };
+    let mut name = part(&short[..8],case & LOWER_BASE != 0);
+    let ext = part(&short[8..],case & LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+/// The checksum of a short name stored in each of its long name slots.
+fn short_checksum(short:&[u8;11]) -> u8 {
+    short.iter().fold(0u8,|sum,&byte|(sum >> 1 | sum << 7).wrapping_add(byte))
+}
+
+/// True for the characters FAT doesn't allow in long names.
+fn forbidden(c:char) -> bool {
+    (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)
+}
+
+/// Characters allowed in short names besides letters and digits.
+fn short_char(c:char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+pub(super) fn valid_name(name:&str) -> bool {
+    !name.chars().any(forbidden)
+        && name.encode_utf16().count() <= MAX_LONG_NAME
+        && !name.ends_with(' ') && !name.ends_with('.')//Windows drops them, so the name would change
+}
+
+/// `name` as a short name with case flags, if it can be stored without a long name.
+fn exact_short_name(name:&str) -> Option<([u8;11],u8)> {
+    let (base,ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot],&name[dot+1..]),
+        None => (name,""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !base.chars().chain(ext.chars()).all(short_char) {
+        return None;
+    }
+    //one case per part, which the flags can express
+    let case_of = |part:&str|{
+        let lower = part.chars().any(|c|c.is_ascii_lowercase());
+        let upper = part.chars().any(|c|c.is_ascii_uppercase());
+        if lower && upper {None} else {Some(lower)}
+    };
+    let (base_lower,ext_lower) = (case_of(base)?,case_of(ext)?);
+    let mut short = [b' ';11];
+    short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short[8..8+ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    let case = (if base_lower {LOWER_BASE} else {0}) | (if ext_lower {LOWER_EXT} else {0});
+    Some((short,case))
+}
+
+/// A short name "BASE~N.EXT" for a long name, unique among `taken`.
+fn generated_short_name(name:&str, taken:&[[u8;11]]) -> [u8;11] {
+    let clean = |part:&str,max:usize|{
+        let mut out:Vec<u8> = Vec::new();
+        for c in part.chars().filter(|&c|c != ' ' && c != '.') {
+            if out.len() == max {
+                break;
+            }
+            let c = c.to_ascii_uppercase();
+            out.push(if short_char(c) {c as u8} else {b'_'});
+        }
+        out
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base,ext) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot],&trimmed[dot+1..]),
+        None => (trimmed,""),
+    };
+    let base = clean(base,8);
+    let ext = clean(ext,3);
+    let mut short = [b' ';11];
+    short[8..8+ext.len()].copy_from_slice(&ext);
+    for n in 1u32.. {
+        let tail = alloc::format!("~{}",n);
+        let keep = base.len().min(8 - tail.len());
+        short[..8].copy_from_slice(b"        ");
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep+tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short) {
+            break;
+        }
+    }
+    short
+}
+
+/// The raw slots for an entry called `name`: long name slots if needed, then the
+/// short entry. `taken` are the short names already in the directory.
+pub(super) fn encode(name:&str, attr:u8, cluster:u32, size:u32, taken:&[[u8;11]]) -> Vec<[u8;ENTRY_SIZE]> {
+    let (short,case,needs_long) = match exact_short_name(name) {
+        Some((short,case)) if !taken.contains(&short) => (short,case,false),
+        _ => (generated_short_name(name,taken),0,true),
+    };
+    let mut slots = Vec::new();
+    if needs_long {
+        let mut units:Vec<u16> = name.encode_utf16().collect();
+        let count = (units.len() + SLOT_CHARS - 1)/SLOT_CHARS;
+        if units.len() < count*SLOT_CHARS {
+            units.push(0);
+            units.resize(count*SLOT_CHARS,0xffff);
+        }
+        let checksum = short_checksum(&short);
+        for sequence in (1..=count).rev() {
+            let mut slot = [0u8;ENTRY_SIZE];
+            slot[0] = sequence as u8 | if sequence == count {LAST_SLOT} else {0};
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            for (i,&slot_offset) in SLOT_OFFSETS.iter().enumerate() {
+                let unit = units[(sequence - 1)*SLOT_CHARS + i];
+                slot[slot_offset..slot_offset+2].copy_from_slice(&unit.to_le_bytes());
+            }
+            slots.push(slot);
+        }
+    }
+    let mut entry = raw_entry(&short,attr,cluster,size);
+    entry[12] = case;
+    slots.push(entry);
+    slots
+}
+
+/// A short entry without long name, e.g. "." and "..".
+pub(super) fn raw_entry(short:&[u8;11], attr:u8, cluster:u32, size:u32) -> [u8;ENTRY_SIZE] {
+    let mut entry = [0u8;ENTRY_SIZE];
+    entry[0..11].copy_from_slice(short);
+    entry[11] = attr;
+    entry[16..18].copy_from_slice(&DATE.to_le_bytes());//created
+    entry[18..20].copy_from_slice(&DATE.to_le_bytes());//accessed
+    entry[24..26].copy_from_slice(&DATE.to_le_bytes());//modified
+    set_location(&mut entry,cluster,size);
+    entry
+}
+
+/// Updates the first cluster and the size of a raw short entry.
+pub(super) fn set_location(entry:&mut [u8], cluster:u32, size:u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+/// The short names of "." and "..".
+pub(super) const DOT:[u8;11] = *b".          ";
+pub(super) const DOT_DOT:[u8;11] = *b"..         ";
@@ -0,0 +1,666 @@
+//! FAT32 file system driver.
+//!
+//! Files are chains of clusters linked through the file allocation table (FAT);
+//! directories are files made of 32-byte entries, with long names stored in extra
+//! entries in front of the 8.3 short entry. All changes go straight to the block
+//! device, so a volume is consistent on disk whenever no call is in progress,
+//! except for the free cluster count in the FSInfo sector, which `sync` updates.
+//!
+//! FAT has no inodes; an inode here is identified by where its directory entry is.
+//! Hard links can't be expressed, and deleted files keep their clusters until the
+//! last reference to them is gone.
+
+use alloc::{collections::BTreeMap,string::String,sync::{Arc,Weak},vec,vec::Vec};
+use core::convert::TryInto;
+use spin::Mutex as SpinMutex;
+use x86_64::instructions::interrupts;
+use crate::block::{self,BlockDevice};
+use crate::sync::Mutex;
+use crate::syscall::Errno;
+use crate::vfs::{self,DirEntry,FileSystem,FileType,Inode,Metadata};
+
+mod bpb;
+mod dir;
+
+pub use bpb::format;
+use bpb::Geometry;
+use dir::ENTRY_SIZE;
+
+/// FAT entry values.
+const FREE:u32 = 0;
+const END_OF_CHAIN:u32 = 0x0fff_ffff;
+/// Entries at or above this end a chain.
+const END_MIN:u32 = 0x0fff_fff8;
+const ENTRY_MASK:u32 = 0x0fff_ffff;
+
+/// A directory may have at most 65536 entries.
+const MAX_DIR_SIZE:usize = 65536*ENTRY_SIZE;
+/// Inode number of the root directory, which has no directory entry.
+const ROOT_INO:u64 = 1;
+
+/// Cluster number `index` of a chain, where a walk along it can go on from.
+#[derive(Debug,Clone,Copy)]
+struct ChainPos {
+    index:u64,
+    cluster:u32,
+}
+
+/// Allocation state, guarded by the volume lock together with everything on disk.
+struct Allocation {
+    free:u32,
+    next_free:u32,
+}
+
+struct Volume {
+    device:Arc<dyn BlockDevice>,
+    geometry:Geometry,
+    /// Serializes all access to the volume. Taken before the lock of any inode.
+    lock:Mutex<Allocation>,
+    /// The live inodes by number, so that a file has one inode however it is reached.
+    inodes:SpinMutex<BTreeMap<u64,Weak<FatInode>>>,
+}impl Volume {
+    fn read_bytes(&self, pos:u64, buf:&mut [u8]) -> Result<(),Errno> {
+        block::read_bytes(&*self.device,pos,buf)
+    }
+
+    fn write_bytes(&self, pos:u64, data:&[u8]) -> Result<(),Errno> {
+        block::write_bytes(&*self.device,pos,data)
+    }
+
+    fn valid_cluster(&self, cluster:u32) -> bool {
+        cluster >= 2 && cluster < self.geometry.clusters + 2
+    }
+
+    fn fat_get(&self, cluster:u32) -> Result<u32,Errno> {
+        let mut entry = [0u8;4];
+        self.read_bytes(self.geometry.fat_entry(0,cluster),&mut entry)?;
+        Ok(u32::from_le_bytes(entry) & ENTRY_MASK)
+    }
+
+    /// Sets the entry for `cluster` in every copy of the FAT.
+    fn fat_set(&self, cluster:u32, value:u32) -> Result<(),Errno> {
+        for fat in 0..self.geometry.fats {
+            let pos = self.geometry.fat_entry(fat,cluster);
+            let mut entry = [0u8;4];
+            self.read_bytes(pos,&mut entry)?;
+            let old = u32::from_le_bytes(entry);
+            self.write_bytes(pos,&((old & !ENTRY_MASK) | value).to_le_bytes())?;//the top 4 bits are reserved
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain.
+    fn next_cluster(&self, cluster:u32) -> Result<Option<u32>,Errno> {
+        match self.fat_get(cluster)? {
+            next if next >= END_MIN => Ok(None),
+            next if self.valid_cluster(next) => Ok(Some(next)),
+            _ => Err(Errno::EIO),//free or bad cluster in a chain: the volume is damaged
+        }
+    }
+
+    /// Walks the chain starting at `first` towards its cluster number `index`, going
+    /// on from `hint` if that is no further along. Stops early at the end of the
+    /// chain; `None` for an empty chain.
+    fn walk(&self, first:u32, index:u64, hint:Option<ChainPos>) -> Result<Option<ChainPos>,Errno> {
+        if first == 0 {
+            return Ok(None);
+        }
+        let mut pos = match hint {
+            Some(hint) if hint.index <= index => hint,
+            _ => ChainPos {index:0, cluster:first},
+        };
+        if !self.valid_cluster(pos.cluster) {
+            return Err(Errno::EIO);
+        }
+        while pos.index < index {
+            if pos.index >= self.geometry.clusters as u64 {
+                return Err(Errno::EIO);//longer than the volume: a loop
+            }
+            match self.next_cluster(pos.cluster)? {
+                Some(next) => pos = ChainPos {index:pos.index + 1, cluster:next},
+                None => break,
+            }
+        }
+        Ok(Some(pos))
+    }
+
+    /// Counts the free clusters by reading the whole FAT.
+    fn count_free(&self) -> Result<u32,Errno> {
+        let sector_size = self.geometry.bytes_per_sector;
+        let mut sector = vec![0u8;sector_size];
+        let mut free = 0;
+        let entries_per_sector = (sector_size/4) as u32;
+        let end = self.geometry.clusters + 2;
+        let mut cluster = 0;
+        while cluster < end {
+            self.read_bytes(self.geometry.fat_entry(0,cluster),&mut sector)?;
+            for entry in sector.chunks_exact(4).take((end - cluster).min(entries_per_sector) as usize) {
+                if cluster >= 2 && u32::from_le_bytes([entry[0],entry[1],entry[2],entry[3]]) & ENTRY_MASK == FREE {
+                    free += 1;
+                }
+                cluster += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /// Allocates a zeroed cluster and appends it to the chain ending in `last`, if any.
+    fn allocate(&self, allocation:&mut Allocation, last:Option<u32>) -> Result<u32,Errno> {
+        if allocation.free == 0 {
+            return Err(Errno::ENOSPC);
+        }
+        let clusters = self.geometry.clusters;
+        let start = if self.valid_cluster(allocation.next_free) {allocation.next_free} else {2};
+        let mut found = None;
+        for i in 0..clusters {
+            let cluster = 2 + (start - 2 + i)%clusters;
+            if self.fat_get(cluster)? == FREE {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = match found {
+            Some(cluster) => cluster,
+            None => {
+                allocation.free = 0;//the count was off
+                return Err(Errno::ENOSPC);
+            }
+        };
+        self.write_bytes(self.geometry.cluster_start(cluster),&vec![0u8;self.geometry.cluster_size()])?;
+        self.fat_set(cluster,END_OF_CHAIN)?;
+        if let Some(last) = last {
+            self.fat_set(last,cluster)?;
+        }
+        allocation.free -= 1;
+        allocation.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// Frees the clusters of the chain starting at `first`.
+    fn free_chain(&self, allocation:&mut Allocation, first:u32) -> Result<(),Errno> {
+        if first == 0 {
+            return Ok(());
+        }
+        if !self.valid_cluster(first) {
+            return Err(Errno::EIO);
+        }
+        let mut next = Some(first);
+        while let Some(cluster) = next {
+            next = self.next_cluster(cluster)?;//a loop ends at the freed cluster
+            self.fat_set(cluster,FREE)?;
+            allocation.free += 1;
+        }
+        Ok(())
+    }
+
+    /// Cuts the chain starting at `first` down to `keep` clusters and returns the new first cluster.
+    fn shrink_chain(&self, allocation:&mut Allocation, first:u32, keep:u64) -> Result<u32,Errno> {
+        if keep == 0 {
+            self.free_chain(allocation,first)?;
+            return Ok(0);
+        }
+        if let Some(pos) = self.walk(first,keep - 1,None)? {
+            if pos.index == keep - 1 {
+                if let Some(rest) = self.next_cluster(pos.cluster)? {
+                    self.fat_set(pos.cluster,END_OF_CHAIN)?;
+                    self.free_chain(allocation,rest)?;
+                }
+            }
+        }
+        Ok(first)
+    }
+
+    /// The device byte position of byte `offset` of the chain starting at `first`.
+    fn chain_pos(&self, first:u32, offset:u64) -> Result<u64,Errno> {
+        let cluster_size = self.geometry.cluster_size() as u64;
+        let index = offset/cluster_size;
+        match self.walk(first,index,None)? {
+            Some(pos) if pos.index == index => Ok(self.geometry.cluster_start(pos.cluster) + offset%cluster_size),
+            _ => Err(Errno::EIO),
+        }
+    }
+
+    /// Reads from the chain starting at `first` at byte `offset`; stops at the end of the chain.
+    /// The walk goes on from `*hint`, which is left at the last cluster read.
+    fn read_chain(&self, first:u32, offset:u64, buf:&mut [u8], hint:&mut Option<ChainPos>) -> Result<usize,Errno> {
+        let cluster_size = self.geometry.cluster_size() as u64;
+        let index = offset/cluster_size;
+        let mut pos = match self.walk(first,index,*hint)? {
+            Some(pos) if pos.index == index => pos,
+            _ => return Ok(0),
+        };
+        let mut done = 0;
+        loop {
+            let within = (offset + done as u64)%cluster_size;
+            let chunk = ((cluster_size - within) as usize).min(buf.len() - done);
+            self.read_bytes(self.geometry.cluster_start(pos.cluster) + within,&mut buf[done..done+chunk])?;
+            done += chunk;
+            *hint = Some(pos);
+            if done == buf.len() {
+                return Ok(done);
+            }
+            match self.next_cluster(pos.cluster)? {
+                Some(next) => pos = ChainPos {index:pos.index + 1, cluster:next},
+                None => return Ok(done),
+            }
+        }
+    }
+
+    /// Writes `data` at byte `offset` of the chain starting at `*first`, growing the
+    /// chain as needed; `*first` is set when the chain was empty. The walk goes on
+    /// from `*hint`, which is left at the last cluster written.
+    fn write_chain(&self, allocation:&mut Allocation, first:&mut u32, offset:u64, data:&[u8], hint:&mut Option<ChainPos>) -> Result<(),Errno> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let cluster_size = self.geometry.cluster_size() as u64;
+        let index = offset/cluster_size;
+        let mut pos = match self.walk(*first,index,*hint)? {
+            Some(pos) => pos,
+            None => {
+                let cluster = self.allocate(allocation,None)?;
+                *first = cluster;
+                ChainPos {index:0, cluster}
+            }
+        };
+        while pos.index < index {
+            pos = ChainPos {index:pos.index + 1, cluster:self.allocate(allocation,Some(pos.cluster))?};
+        }
+        let mut done = 0;
+        loop {
+            let within = (offset + done as u64)%cluster_size;
+            let chunk = ((cluster_size - within) as usize).min(data.len() - done);
+            self.write_bytes(self.geometry.cluster_start(pos.cluster) + within,&data[done..done+chunk])?;
+            done += chunk;
+            *hint = Some(pos);
+            if done == data.len() {
+                return Ok(());
+            }
+            let next = match self.next_cluster(pos.cluster)? {
+                Some(next) => next,
+                None => self.allocate(allocation,Some(pos.cluster))?,
+            };
+            pos = ChainPos {index:pos.index + 1, cluster:next};
+        }
+    }
+
+    /// Writes the free cluster count and allocation hint to the FSInfo sector.
+    fn write_fsinfo(&self, allocation:&Allocation) -> Result<(),Errno> {
+        if let Some(sector) = self.geometry.fsinfo_sector {
+            let info = bpb::fsinfo(allocation.free,allocation.next_free,self.geometry.bytes_per_sector);
+            self.write_bytes(sector*self.geometry.bytes_per_sector as u64,&info)?;
+        }
+        Ok(())
+    }
+}
+
+/// A mounted FAT32 volume.
+pub struct FatFs {
+    volume:Arc<Volume>,
+    root:Arc<FatInode>,
+}impl FatFs {
+    /// Reads the file system on `device`. Fails with `EINVAL` if it isn't FAT32.
+    pub fn new(device:Arc<dyn BlockDevice>) -> Result<Arc<Self>,Errno> {
+        let block_size = device.block_size();
+        let mut first = vec![0u8;(512 + block_size - 1)/block_size*block_size];
+        device.read_blocks(0,&mut first)?;
+        let geometry = Geometry::parse(&first)?;
+        let volume = Arc::new(Volume {
+            device,
+            geometry,
+            lock:Mutex::new(Allocation {free:0, next_free:bpb::UNKNOWN}),
+            inodes:SpinMutex::new(BTreeMap::new()),
+        });
+        let mut fsinfo = None;
+        if let Some(sector) = geometry.fsinfo_sector {
+            let mut data = vec![0u8;geometry.bytes_per_sector];
+            volume.read_bytes(sector*geometry.bytes_per_sector as u64,&mut data)?;
+            fsinfo = bpb::parse_fsinfo(&data);
+        }
+        let allocation = match fsinfo {
+            Some((free,next_free)) if free <= geometry.clusters => Allocation {free, next_free},
+            _ => Allocation {free:volume.count_free()?, next_free:2},
+        };
+        *volume.lock.lock() = allocation;
+        let root = Arc::new(FatInode {
+            volume:volume.clone(),
+            ino:ROOT_INO,
+            kind:FileType::Directory,
+            node:Mutex::new(Node {cluster:geometry.root_cluster, size:0, entry:None, removed:false}),
+        });
+        Ok(Arc::new(FatFs {volume, root}))
+    }
+
+    /// Bytes available for new data.
+    pub fn free_space(&self) -> u64 {
+        self.volume.lock.lock().free as u64*self.volume.geometry.cluster_size() as u64
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+    fn sync(&self) -> Result<(),Errno> {
+        let allocation = self.volume.lock.lock();
+        self.volume.write_fsinfo(&allocation)?;
+        self.volume.device.flush()
+    }
+}
+
+/// Where the directory entry of a file is.
+#[derive(Debug,Clone,Copy)]
+struct Location {
+    dir_cluster:u32,//first cluster of the directory
+    offset:usize,//of the short entry
+}
+
+struct Node {
+    cluster:u32,//first cluster, 0 for an empty file
+    size:u32,//0 for directories
+    entry:Option<Location>,//None for the root and deleted files
+    removed:bool,//deleted while still referenced; the clusters are freed on drop
+    pos:Option<ChainPos>,//where the last read or write ended, so the next one needn't walk from the start
+}
+
+struct FatInode {
+    volume:Arc<Volume>,
+    ino:u64,
+    kind:FileType,
+    node:Mutex<Node>,
+}impl FatInode {
+    fn ino_for(location:Location) -> u64 {
+        (location.dir_cluster as u64) << 32 | (location.offset/ENTRY_SIZE) as u64
+    }
+
+    /// The inode for `entry` of the directory starting at `dir_cluster`.
+    fn get(volume:&Arc<Volume>, dir_cluster:u32, entry:&dir::Entry) -> Arc<FatInode> {
+        let location = Location {dir_cluster, offset:entry.offset};
+        let ino = Self::ino_for(location);
+        interrupts::without_interrupts(||{
+            let mut inodes = volume.inodes.lock();
+            if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+                return inode;
+            }
+            let inode = Arc::new(FatInode {
+                volume:volume.clone(),
+                ino,
+                kind:if entry.is_dir() {FileType::Directory} else {FileType::Regular},
+                node:Mutex::new(Node {
+                    cluster:entry.cluster,
+                    size:if entry.is_dir() {0} else {entry.size},
+                    entry:Some(location),
+                    removed:false,
+                    pos:None,
+                }),
+            });
+            inodes.insert(ino,Arc::downgrade(&inode));
+            inode
+        })
+    }
+
+    /// The cluster where this directory starts.
+    fn dir_cluster(&self) -> Result<u32,Errno> {
+        if self.kind != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        Ok(self.node.lock().cluster)
+    }
+
+    /// Reads and decodes this directory. Needs the volume lock.
+    fn listing(&self) -> Result<(u32,Vec<u8>,dir::Listing),Errno> {
+        let cluster = self.dir_cluster()?;
+        let cluster_size = self.volume.geometry.cluster_size();
+        let beyond = (MAX_DIR_SIZE/cluster_size) as u64;//the first cluster a directory can't have
+        let clusters = match self.volume.walk(cluster,beyond,None)? {
+            Some(pos) if pos.index == beyond => return Err(Errno::EIO),
+            Some(pos) => pos.index + 1,
+            None => 0,
+        };
+        let mut data = vec![0u8;clusters as usize*cluster_size];
+        self.volume.read_chain(cluster,0,&mut data,&mut None)?;
+        let listing = dir::parse(&data);
+        Ok((cluster,data,listing))
+    }
+
+    fn find<'a>(listing:&'a dir::Listing, name:&str) -> Option<&'a dir::Entry> {
+        //FAT compares names without regard to case
+        listing.entries.iter().find(|entry|entry.name.to_lowercase() == name.to_lowercase())
+    }
+
+    /// Writes the first cluster and size of this file to its directory entry.
+    fn store(&self, node:&Node) -> Result<(),Errno> {
+        if let Some(location) = node.entry {
+            let pos = self.volume.chain_pos(location.dir_cluster,location.offset as u64)?;
+            let mut entry = [0u8;ENTRY_SIZE];
+            self.volume.read_bytes(pos,&mut entry)?;
+            let size = if self.kind == FileType::Directory {0} else {node.size};
+            dir::set_location(&mut entry,node.cluster,size);
+            self.volume.write_bytes(pos,&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Fills `start..end` of the file with zeros. Needs the volume lock.
+    fn zero_fill(&self, allocation:&mut Allocation, node:&mut Node, start:u64, end:u64) -> Result<(),Errno> {
+        let zeros = vec![0u8;self.volume.geometry.cluster_size()];
+        let mut at = start;
+        while at < end {
+            let chunk = (end - at).min(zeros.len() as u64) as usize;
+            self.volume.write_chain(allocation,&mut node.cluster,at,&zeros[..chunk],&mut node.pos)?;
+            at += chunk as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(||{
+            let mut inodes = self.volume.inodes.lock();
+            if inodes.get(&self.ino).map_or(false,|inode|inode.strong_count() == 0) {
+                inodes.remove(&self.ino);
+            }
+        });
+        let (removed,cluster) = {
+            let node = self.node.lock();
+            (node.removed,node.cluster)
+        };
+        if removed && cluster != 0 {
+            let mut allocation = self.volume.lock.lock();
+            let _ = self.volume.free_chain(&mut allocation,cluster);//nothing to report an error to
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino:self.ino,
+            kind:self.kind,
+            size:self.node.lock().size as u64,
+            links:1,
+        }
+    }
+
+    fn read_at(&self, offset:u64, buf:&mut [u8]) -> Result<usize,Errno> {
+        if self.kind == FileType::Directory {
+            return Err(Errno::EISDIR);
+        }
+        let _volume = self.volume.lock.lock();
+        let mut node = self.node.lock();
+        if offset >= node.size as u64 {
+            return Ok(0);
+        }
+        let len = buf.len().min((node.size as u64 - offset) as usize);
+        let node = &mut *node;
+        self.volume.read_chain(node.cluster,offset,&mut buf[..len],&mut node.pos)
+    }
+
+    fn write_at(&self, offset:u64, buf:&[u8]) -> Result<usize,Errno> {
+        if self.kind == FileType::Directory {
+            return Err(Errno::EISDIR);
+        }
+        let end = offset.checked_add(buf.len() as u64).filter(|&end|end <= u32::MAX as u64).ok_or(Errno::EFBIG)?;
+        let mut allocation = self.volume.lock.lock();
+        let mut node = self.node.lock();
+        let size = node.size as u64;
+        if offset > size {
+            //FAT can't have holes
+            self.zero_fill(&mut allocation,&mut node,size,offset)?;
+        }
+        let node = &mut *node;
+        let result = self.volume.write_chain(&mut allocation,&mut node.cluster,offset,buf,&mut node.pos);
+        if result.is_ok() {
+            node.size = node.size.max(end as u32);
+        }
+        self.store(node)?;//also after a failure, which may have allocated the first cluster
+        result.map(|_|buf.len())
+    }
+
+    fn truncate(&self, new_size:u64) -> Result<(),Errno> {
+        if self.kind == FileType::Directory {
+            return Err(Errno::EISDIR);
+        }
+        if new_size > u32::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        let mut allocation = self.volume.lock.lock();
+        let mut node = self.node.lock();
+        let size = node.size as u64;
+        if new_size > size {
+            self.zero_fill(&mut allocation,&mut node,size,new_size)?;
+        } else {
+            let cluster_size = self.volume.geometry.cluster_size() as u64;
+            let keep = (new_size + cluster_size - 1)/cluster_size;
+            node.cluster = self.volume.shrink_chain(&mut allocation,node.cluster,keep)?;
+            node.pos = None;//may be among the freed clusters
+        }
+        node.size = new_size as u32;
+        self.store(&node)
+    }
+
+    fn lookup(&self, name:&str) -> Result<Arc<dyn Inode>,Errno> {
+        let _volume = self.volume.lock.lock();
+        let (cluster,_,listing) = self.listing()?;
+        let entry = Self::find(&listing,name).ok_or(Errno::ENOENT)?;
+        Ok(FatInode::get(&self.volume,cluster,entry))
+    }
+
+    fn create(&self, name:&str, kind:FileType) -> Result<Arc<dyn Inode>,Errno> {
+        if !dir::valid_name(name) {
+            return Err(Errno::EINVAL);
+        }
+        let attr = match kind {
+            FileType::Regular => dir::ATTR_ARCHIVE,
+            FileType::Directory => dir::ATTR_DIRECTORY,
+            _ => return Err(Errno::EPERM),//no device files on FAT
+        };
+        let mut allocation = self.volume.lock.lock();
+        let (mut cluster,data,listing) = self.listing()?;
+        if Self::find(&listing,name).is_some() {
+            return Err(Errno::EEXIST);
+        }
+        let mut first = 0;
+        if kind == FileType::Directory {
+            first = self.volume.allocate(&mut allocation,None)?;
+            //".." of a directory in the root points to cluster 0
+            let parent = if cluster == self.volume.geometry.root_cluster {0} else {cluster};
+            let mut dots = [0u8;2*ENTRY_SIZE];
+            dots[..ENTRY_SIZE].copy_from_slice(&dir::raw_entry(&dir::DOT,dir::ATTR_DIRECTORY,first,0));
+            dots[ENTRY_SIZE..].copy_from_slice(&dir::raw_entry(&dir::DOT_DOT,dir::ATTR_DIRECTORY,parent,0));
+            self.volume.write_chain(&mut allocation,&mut first,0,&dots,&mut None)?;
+        }
+        let taken:Vec<[u8;11]> = listing.entries.iter().map(|entry|entry.short).collect();
+        let slots = dir::encode(name,attr,first,0,&taken);
+        //the first run of free entries that is long enough, else the end
+        let needed = slots.len();
+        let mut offset = listing.end;
+        let mut run = 0;
+        for (index,raw) in data[..listing.end].chunks_exact(ENTRY_SIZE).enumerate() {
+            run = if raw[0] == dir::DELETED {run + 1} else {0};
+            if run == needed {
+                offset = (index + 1 - needed)*ENTRY_SIZE;
+                break;
+            }
+        }
+        if offset + needed*ENTRY_SIZE > MAX_DIR_SIZE {
+            if first != 0 {
+                let _ = self.volume.free_chain(&mut allocation,first);
+            }
+            return Err(Errno::ENOSPC);
+        }
+        let bytes:Vec<u8> = slots.iter().flat_map(|slot|slot.iter().copied()).collect();
+        if let Err(err) = self.volume.write_chain(&mut allocation,&mut cluster,offset as u64,&bytes,&mut None) {
+            if first != 0 {
+                let _ = self.volume.free_chain(&mut allocation,first);
+            }
+            return Err(err);
+        }
+        let entry = dir::Entry {
+            name:String::from(name),
+            short:slots[needed-1][0..11].try_into().unwrap(),
+            attr,
+            cluster:first,
+            size:0,
+            offset:offset + (needed - 1)*ENTRY_SIZE,
+            first_slot:offset,
+        };
+        Ok(FatInode::get(&self.volume,cluster,&entry))
+    }
+
+    fn link(&self, _name:&str, _target:&Arc<dyn Inode>) -> Result<(),Errno> {
+        Err(Errno::EPERM)
+    }
+
+    fn unlink(&self, name:&str) -> Result<(),Errno> {
+        let doomed;//dropped after the volume lock, since dropping it may free clusters
+        {
+            let _volume = self.volume.lock.lock();
+            let (cluster,_,listing) = self.listing()?;
+            let entry = Self::find(&listing,name).ok_or(Errno::ENOENT)?.clone();
+            let inode = FatInode::get(&self.volume,cluster,&entry);
+            if entry.is_dir() {
+                let (_,_,children) = inode.listing()?;
+                if children.entries.iter().any(|child|child.short != dir::DOT && child.short != dir::DOT_DOT) {
+                    return Err(Errno::ENOTEMPTY);
+                }
+            }
+            for offset in (entry.first_slot..=entry.offset).step_by(ENTRY_SIZE) {
+                self.volume.write_bytes(self.volume.chain_pos(cluster,offset as u64)?,&[dir::DELETED])?;
+            }
+            //a new file may get the same entry, and with it the same number
+            interrupts::without_interrupts(||self.volume.inodes.lock().remove(&inode.ino));
+            let mut node = inode.node.lock();
+            node.entry = None;
+            node.removed = true;
+            drop(node);
+            doomed = inode;
+        }
+        drop(doomed);
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>,Errno> {
+        let _volume = self.volume.lock.lock();
+        let (cluster,_,listing) = self.listing()?;
+        Ok(listing.entries.iter()
+            .filter(|entry|entry.short != dir::DOT && entry.short != dir::DOT_DOT)
+            .map(|entry|DirEntry {
+                name:entry.name.clone(),
+                ino:FatInode::ino_for(Location {dir_cluster:cluster, offset:entry.offset}),
+                kind:if entry.is_dir() {FileType::Directory} else {FileType::Regular},
+            })
+            .collect())
+    }
+}
+
+/// Mounts the FAT32 file system on `device` at `path`.
+pub fn mount(path:&str, device:Arc<dyn BlockDevice>) -> Result<Arc<FatFs>,Errno> {
+    let fs = FatFs::new(device)?;
+    vfs::mount(path,fs.clone())?;
+    Ok(fs)
+}
@@ -0,0 +1,186 @@
+//! The initial ramdisk: a USTAR archive built from `initrd/` by `build.rs` and
+//! embedded in the kernel image, unpacked into the VFS at boot.
+
+use alloc::{format,string::String,vec::Vec};
+use core::str;
+use crate::syscall::Errno;
+use crate::vfs::{self,FileType};
+
+/// The archive built into the kernel.
+pub static IMAGE:&[u8] = include_bytes!(concat!(env!("OUT_DIR"),"/initrd.tar"));
+
+const BLOCK:usize = 512;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum InitrdError {
+    /// The archive ends in the middle of a header or of file data.
+    Truncated,
+    BadChecksum,
+    /// A header field that should hold an octal number doesn't.
+    BadNumber,
+    BadName,
+    /// Creating a file in the VFS failed.
+    Vfs(Errno),
+}
+
+impl From<Errno> for InitrdError {
+    fn from(err:Errno) -> Self {
+        InitrdError::Vfs(err)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A hard link to the earlier entry at the given path.
+    HardLink,
+    /// Symbolic links, devices and the like, which the VFS can't represent.
+    Other(u8),
+}
+
+/// One member of the archive.
+#[derive(Debug,Clone,Copy)]
+pub struct Entry<'a> {
+    prefix:&'a str,
+    name:&'a str,
+    pub kind:EntryKind,
+    pub link_target:&'a str,
+    pub data:&'a [u8],
+}impl<'a> Entry<'a> {
+    /// The path inside the archive, without a leading "./" or trailing "/".
+    pub fn path(&self) -> String {
+        let mut path = String::from(self.prefix);
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(self.name);
+        let trimmed = path.trim_start_matches("./").trim_matches('/');
+        String::from(trimmed)
+    }
+}
+
+/// Iterates over the members of a USTAR (or plain old tar) archive.
+pub struct Archive<'a> {
+    data:&'a [u8],
+    offset:usize,
+}impl<'a> Archive<'a> {
+    pub fn new(data:&'a [u8]) -> Self {
+        Archive {data, offset:0}
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>,InitrdError> {
+        if self.offset == self.data.len() {
+            return Ok(None);//archives cut right after the last member are fine
+        }
+        let header = self.data.get(self.offset..self.offset+BLOCK).ok_or(InitrdError::Truncated)?;
+        if header.iter().all(|&byte|byte == 0) {
+            return Ok(None);//the end-of-archive blocks
+        }
+        let checksum = octal(&header[148..156])?;
+        let sum:u64 = header.iter().enumerate()
+            .map(|(i,&byte)|if (148..156).contains(&i) {b' ' as u64} else {byte as u64})
+            .sum();
+        if sum != checksum {
+            return Err(InitrdError::BadChecksum);
+        }
+        let size = octal(&header[124..136])? as usize;
+        let kind = match header[156] {
+            b'0'|0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            b'1' => EntryKind::HardLink,
+            other => EntryKind::Other(other),
+        };
+        let ustar = &header[257..262] == b"ustar";
+        let start = self.offset + BLOCK;
+        let data = self.data.get(start..start+size).ok_or(InitrdError::Truncated)?;
+        self.offset = (start + size + BLOCK - 1)/BLOCK*BLOCK;
+        self.offset = self.offset.min(self.data.len());
+        Ok(Some(Entry {
+            prefix:if ustar {text(&header[345..500])?} else {""},
+            name:text(&header[0..100])?,
+            kind,
+            link_target:text(&header[157..257])?,
+            data:if kind == EntryKind::File {data} else {&[]},
+        }))
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>,InitrdError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_entry();
+        if next.is_err() {
+            self.offset = self.data.len();//don't go on after garbage
+        }
+        next.transpose()
+    }
+}
+
+/// A NUL terminated (or field filling) string.
+fn text(field:&[u8]) -> Result<&str,InitrdError> {
+    let len = field.iter().position(|&byte|byte == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_|InitrdError::BadName)
+}
+
+/// An octal number padded with spaces or NULs.
+fn octal(field:&[u8]) -> Result<u64,InitrdError> {
+    let digits = field.iter().skip_while(|&&byte|byte == b' ').take_while(|&&byte|byte != 0 && byte != b' ');
+    let mut value:u64 = 0;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return Err(InitrdError::BadNumber);
+        }
+        value = value.checked_mul(8).ok_or(InitrdError::BadNumber)? + (digit - b'0') as u64;
+    }
+    Ok(value)
+}
+
+/// Creates the directories on the way to `path`, like `mkdir -p`.
+fn create_parents(path:&str) -> Result<(),Errno> {
+    let mut prefix = String::new();
+    let mut names:Vec<&str> = path.split('/').filter(|name|!name.is_empty()).collect();
+    names.pop();
+    for name in names {
+        prefix.push('/');
+        prefix.push_str(name);
+        match vfs::mkdir(&prefix) {
+            Err(Errno::EEXIST) if vfs::stat(&prefix)?.kind == FileType::Directory => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks `archive` into the directory `target` of the VFS and returns the number
+/// of files and directories created. Members the VFS can't represent are skipped.
+pub fn unpack(archive:&[u8], target:&str) -> Result<usize,InitrdError> {
+    let target = target.trim_end_matches('/');
+    let mut created = 0;
+    for entry in Archive::new(archive) {
+        let entry = entry?;
+        let relative = entry.path();
+        if relative.is_empty() {
+            continue;//"./" itself
+        }
+        if relative.split('/').any(|name|name == "..") {
+            return Err(InitrdError::BadName);
+        }
+        let path = format!("{}/{}",target,relative);
+        create_parents(&path)?;
+        match entry.kind {
+            EntryKind::File => vfs::write_file(&path,entry.data)?,
+            EntryKind::Directory => match vfs::mkdir(&path) {
+                Err(Errno::EEXIST) => continue,//already created for one of its members
+                result => result?,
+            },
+            EntryKind::HardLink => {
+                let link_target = entry.link_target.trim_start_matches("./");
+                vfs::link(&format!("{}/{}",target,link_target),&path)?;
+            }
+            EntryKind::Other(_) => continue,
+        }
+        created += 1;
+    }
+    Ok(created)
+}
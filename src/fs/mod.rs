@@ -0,0 +1,21 @@
+//! File systems that can be mounted into the VFS.
+
+use crate::{println,vfs};
+
+pub mod ramfs;
+pub mod initrd;
+pub mod fat;
+pub mod ext2;
+
+pub use ramfs::RamFs;
+pub use fat::FatFs;
+pub use ext2::Ext2Fs;
+
+/// Mounts a ramfs as the root file system, so that the kernel has a scratch
+/// directory tree from early boot on, and unpacks the initrd into it. Needs the heap.
+pub fn init() {
+    vfs::mount("/",RamFs::new()).expect("mounting the root file system failed");
+    if let Err(err) = initrd::unpack(initrd::IMAGE,"/") {
+        println!("unpacking the initrd failed:{:?}",err);
+    }
+}
@@ -0,0 +1,290 @@
+use alloc::{boxed::Box,collections::BTreeMap,string::String,sync::{Arc,Weak},vec::Vec};
+use core::sync::atomic::{AtomicU64,AtomicUsize,Ordering};
+use crate::sync::Mutex;
+use crate::syscall::Errno;
+use crate::vfs::{DirEntry,FileSystem,FileType,Inode,Metadata};
+
+const PAGE_SIZE:usize = 4096;
+
+type Page = Box<[u8;PAGE_SIZE]>;
+
+/// State shared by all inodes of one ramfs.
+struct Shared {
+    limit:usize,//most pages the file data may take
+    used:AtomicUsize,//pages allocated right now
+    next_ino:AtomicU64,
+    inodes:Mutex<BTreeMap<u64,Weak<RamInode>>>,//to find the `RamInode` behind an `Arc<dyn Inode>`
+}impl Shared {
+    /// Accounts for `pages` new pages, failing with `ENOSPC` above the limit.
+    fn reserve(&self, pages:usize) -> Result<(),Errno> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let new = used.checked_add(pages).filter(|&new|new <= self.limit).ok_or(Errno::ENOSPC)?;
+            match self.used.compare_exchange_weak(used,new,Ordering::Relaxed,Ordering::Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(current) => used = current,
+            }
+        }
+    }
+    fn release(&self, pages:usize) {
+        self.used.fetch_sub(pages,Ordering::Relaxed);
+    }
+}
+
+/// A file system that keeps everything on the kernel heap and is gone on reboot.
+///
+/// File data lives in separately allocated pages; pages that were never written
+/// (holes) take no memory and read as zeros. Only the pages count against the limit.
+pub struct RamFs {
+    shared:Arc<Shared>,
+    root:Arc<RamInode>,
+}impl RamFs {
+    /// A ramfs without a size limit, other than the heap.
+    pub fn new() -> Arc<Self> {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// A ramfs whose file data may use at most `max_bytes`, rounded up to whole pages.
+    /// Writes beyond that fail with `ENOSPC`.
+    pub fn with_limit(max_bytes:usize) -> Arc<Self> {
+        let shared = Arc::new(Shared {
+            limit:max_bytes/PAGE_SIZE + (max_bytes%PAGE_SIZE != 0) as usize,
+            used:AtomicUsize::new(0),
+            next_ino:AtomicU64::new(1),
+            inodes:Mutex::new(BTreeMap::new()),
+        });
+        let root = RamInode::new(&shared,FileType::Directory);
+        Arc::new(RamFs {shared, root})
+    }
+
+    /// Bytes taken by file data.
+    pub fn used(&self) -> usize {
+        self.shared.used.load(Ordering::Relaxed)*PAGE_SIZE
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Content {
+    File{pages:BTreeMap<usize,Page>, size:u64},//pages by index, holes are missing
+    Directory(BTreeMap<String,Arc<RamInode>>),
+}
+
+struct State {
+    content:Content,
+    links:u32,
+}
+
+struct RamInode {
+    ino:u64,
+    kind:FileType,
+    shared:Arc<Shared>,
+    state:Mutex<State>,
+}impl RamInode {
+    fn new(shared:&Arc<Shared>, kind:FileType) -> Arc<Self> {
+        let ino = shared.next_ino.fetch_add(1,Ordering::Relaxed);
+        let (content,links) = match kind {
+            FileType::Directory => (Content::Directory(BTreeMap::new()),2),//its entry in the parent and "."
+            _ => (Content::File{pages:BTreeMap::new(), size:0},1),
+        };
+        let inode = Arc::new(RamInode {
+            ino,
+            kind,
+            shared:shared.clone(),
+            state:Mutex::new(State {content, links}),
+        });
+        shared.inodes.lock().insert(ino,Arc::downgrade(&inode));
+        inode
+    }
+
+    /// The inode of this file system `inode` is, if it is one.
+    fn find(&self, inode:&Arc<dyn Inode>) -> Option<Arc<RamInode>> {
+        let ino = inode.metadata().ino;//not under the table lock, `create` takes them the other way round
+        let candidate = self.shared.inodes.lock().get(&ino)?.upgrade()?;
+        //another ramfs may use the same number
+        if Arc::as_ptr(&candidate) as *const u8 == Arc::as_ptr(inode) as *const u8 {Some(candidate)} else {None}
+    }
+}
+
+impl Drop for RamInode {
+    fn drop(&mut self) {
+        if let Content::File{pages,..} = &self.state.lock().content {
+            self.shared.release(pages.len());
+        }
+        self.shared.inodes.lock().remove(&self.ino);
+    }
+}
+
+/// Drops the pages past `size` and zeroes the rest of the last one, so that growing
+/// the file again reads zeros.
+fn shrink(shared:&Shared, pages:&mut BTreeMap<usize,Page>, size:u64) {
+    let keep = (size as usize + PAGE_SIZE - 1)/PAGE_SIZE;
+    shared.release(pages.split_off(&keep).len());
+    let tail = size as usize % PAGE_SIZE;
+    if tail != 0 {
+        if let Some(page) = pages.get_mut(&(keep - 1)) {
+            page[tail..].iter_mut().for_each(|byte|*byte = 0);
+        }
+    }
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        let size = match &state.content {
+            Content::File{size,..} => *size,
+            Content::Directory(_) => 0,
+        };
+        Metadata {ino:self.ino, kind:self.kind, size, links:state.links}
+    }
+
+    fn read_at(&self, offset:u64, buf:&mut [u8]) -> Result<usize,Errno> {
+        let state = self.state.lock();
+        let (pages,size) = match &state.content {
+            Content::File{pages,size} => (pages,*size),
+            Content::Directory(_) => return Err(Errno::EISDIR),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let pos = offset as usize + done;
+            let (index,start) = (pos/PAGE_SIZE,pos%PAGE_SIZE);
+            let chunk = (PAGE_SIZE - start).min(len - done);
+            match pages.get(&index) {
+                Some(page) => buf[done..done+chunk].copy_from_slice(&page[start..start+chunk]),
+                None => buf[done..done+chunk].iter_mut().for_each(|byte|*byte = 0),//a hole
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset:u64, buf:&[u8]) -> Result<usize,Errno> {
+        let mut state = self.state.lock();
+        let (pages,size) = match &mut state.content {
+            Content::File{pages,size} => (pages,size),
+            Content::Directory(_) => return Err(Errno::EISDIR),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buf.len() as u64).filter(|&end|end <= isize::MAX as u64).ok_or(Errno::EINVAL)?;
+        let (first,last) = (offset as usize/PAGE_SIZE,(end as usize - 1)/PAGE_SIZE);
+        let missing = (first..=last).filter(|index|!pages.contains_key(index)).count();
+        self.shared.reserve(missing)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset as usize + done;
+            let (index,start) = (pos/PAGE_SIZE,pos%PAGE_SIZE);
+            let chunk = (PAGE_SIZE - start).min(buf.len() - done);
+            let page = pages.entry(index).or_insert_with(||Box::new([0;PAGE_SIZE]));
+            page[start..start+chunk].copy_from_slice(&buf[done..done+chunk]);
+            done += chunk;
+        }
+        *size = (*size).max(end);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, new_size:u64) -> Result<(),Errno> {
+        let mut state = self.state.lock();
+        match &mut state.content {
+            Content::File{pages,size} => {
+                if new_size > isize::MAX as u64 {
+                    return Err(Errno::EINVAL);
+                }
+                if new_size < *size {
+                    shrink(&self.shared,pages,new_size);
+                }
+                *size = new_size;//growing only leaves a hole
+                Ok(())
+            }
+            Content::Directory(_) => Err(Errno::EISDIR),
+        }
+    }
+
+    fn lookup(&self, name:&str) -> Result<Arc<dyn Inode>,Errno> {
+        match &self.state.lock().content {
+            Content::Directory(entries) => entries.get(name).map(|inode|inode.clone() as Arc<dyn Inode>).ok_or(Errno::ENOENT),
+            Content::File{..} => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn create(&self, name:&str, kind:FileType) -> Result<Arc<dyn Inode>,Errno> {
+        let mut state = self.state.lock();
+        let entries = match &mut state.content {
+            Content::Directory(entries) => entries,
+            Content::File{..} => return Err(Errno::ENOTDIR),
+        };
+        if entries.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        let inode = RamInode::new(&self.shared,kind);
+        entries.insert(String::from(name),inode.clone());
+        if kind == FileType::Directory {
+            state.links += 1;//the ".." of the new directory
+        }
+        Ok(inode)
+    }
+
+    fn link(&self, name:&str, target:&Arc<dyn Inode>) -> Result<(),Errno> {
+        let target = self.find(target).ok_or(Errno::EXDEV)?;
+        if target.kind == FileType::Directory {
+            return Err(Errno::EPERM);
+        }
+        let mut state = self.state.lock();
+        let entries = match &mut state.content {
+            Content::Directory(entries) => entries,
+            Content::File{..} => return Err(Errno::ENOTDIR),
+        };
+        if entries.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        target.state.lock().links += 1;
+        entries.insert(String::from(name),target);
+        Ok(())
+    }
+
+    fn unlink(&self, name:&str) -> Result<(),Errno> {
+        let mut state = self.state.lock();
+        let entries = match &mut state.content {
+            Content::Directory(entries) => entries,
+            Content::File{..} => return Err(Errno::ENOTDIR),
+        };
+        let inode = entries.get(name).ok_or(Errno::ENOENT)?.clone();
+        {
+            let mut child = inode.state.lock();
+            if let Content::Directory(children) = &child.content {
+                if !children.is_empty() {
+                    return Err(Errno::ENOTEMPTY);
+                }
+            }
+            child.links -= 1;
+        }
+        entries.remove(name);
+        if inode.kind == FileType::Directory {
+            state.links -= 1;
+        }
+        Ok(())//the data goes away with the last reference, which open files may still hold
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>,Errno> {
+        match &self.state.lock().content {
+            Content::Directory(entries) => Ok(entries.iter().map(|(name,inode)|DirEntry {
+                name:name.clone(),
+                ino:inode.ino,
+                kind:inode.kind,
+            }).collect()),
+            Content::File{..} => Err(Errno::ENOTDIR),
+        }
+    }
+}
@@ -1,45 +1,111 @@
 use x86_64::VirtAddr;
+use x86_64::PrivilegeLevel;
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
+use alloc::{boxed::Box,vec};
+use core::cell::UnsafeCell;
 
 // create a static GDT that includes a segment for TSS static:
 use x86_64::structures::gdt::{GlobalDescriptorTable,Descriptor,SegmentSelector};
 struct Selectors {
     code_selector:SegmentSelector,
+    data_selector:SegmentSelector,
+    user_data_selector:SegmentSelector,
+    user_code_selector:SegmentSelector,
     tss_selector:SegmentSelector,
 }
-lazy_static! {
-    static ref GDT:(GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt,Selectors {code_selector,tss_selector})
-    };
+
+/// A TSS that can still be modified after it was loaded, e.g. to switch `privilege_stack_table[0]`.
+struct TssCell(UnsafeCell<TaskStateSegment>);
+unsafe impl Sync for TssCell {}
+
+lazy_static! {//the BSP's tables. They are needed before the heap exists, so they can't be allocated
+    static ref GDT:(GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+/// Flat 64-bit data segment for the kernel: present | user segment(code/data) | writable.
+const KERNEL_DATA_SEGMENT:u64 = (1<<47) | (1<<44) | (1<<41);
+
+/// Builds a GDT with kernel and user segments and a segment for `tss`.
+/// Every CPU needs its own, since loading a TSS marks its descriptor busy.
+///
+/// The order kernel code, kernel data, user data, user code is required by `sysret`,
+/// which derives the user selectors from a single base in the STAR register.
+fn new_gdt(tss:&'static TssCell) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT));
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe{&*tss.0.get()}));
+    (gdt,Selectors {
+        code_selector,
+        data_selector,
+        user_data_selector:SegmentSelector::new(user_data_selector.index(),PrivilegeLevel::Ring3),
+        user_code_selector:SegmentSelector::new(user_code_selector.index(),PrivilegeLevel::Ring3),
+        tss_selector,
+    })
+}
+
+fn load(gdt:&'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{set_cs,load_ss,load_ds,load_es};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        set_cs(GDT.1.code_selector);//reload code segment
-        load_tss(GDT.1.tss_selector);//load TSS
+        set_cs(gdt.1.code_selector);//reload code segment
+        load_ss(gdt.1.data_selector);
+        load_ds(gdt.1.data_selector);
+        load_es(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);//load TSS
     }
 }
 
+/// Loads the GDT and TSS of the bootstrap processor.
+pub fn init() {
+    load(&GDT);
+}
+
+/// The TSS of the bootstrap processor.
+pub fn bsp_tss() -> *mut TaskStateSegment {
+    TSS.0.get()
+}
+
+/// Builds and loads a fresh GDT and TSS for an application processor and returns the TSS.
+/// Requires the heap, the tables are leaked since the CPU uses them forever.
+pub fn init_ap() -> *mut TaskStateSegment {
+    let stack = Box::leak(vec![0u8;DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let stack_end = VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let tss:&'static TssCell = Box::leak(Box::new(TssCell(UnsafeCell::new(new_tss(stack_end)))));
+    load(Box::leak(Box::new(new_gdt(tss))));
+    tss.0.get()
+}
+
+/// Selectors (with RPL 3) of the user code and data segments; identical on every CPU.
+pub fn user_selectors() -> (SegmentSelector,SegmentSelector) {
+    (GDT.1.user_code_selector,GDT.1.user_data_selector)
+}
+
+/// Selectors of the kernel code and data segments; identical on every CPU.
+pub fn kernel_selectors() -> (SegmentSelector,SegmentSelector) {
+    (GDT.1.code_selector,GDT.1.data_selector)
+}
+
 pub const DOUBLE_FAULT_IST_INDEX:u16 = 0; //define 0th IST(Interrupt Stack Table) entry as double fault stack
+const DOUBLE_FAULT_STACK_SIZE:usize = 4096;
+
+fn new_tss(double_fault_stack_end:VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss
+}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {//got a tss
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE:usize = 4096;
-            static mut STACK:[u8;STACK_SIZE] = [0;STACK_SIZE];//size of STACK is 4096byte
-
-            let stack_start = VirtAddr::from_ptr(unsafe{&STACK});//static mut risks data race
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end//write to highest address coz stacks on x86 grow downwards
-        };
-        tss
+    static ref TSS: TssCell = {//got a tss
+        static mut STACK:[u8;DOUBLE_FAULT_STACK_SIZE] = [0;DOUBLE_FAULT_STACK_SIZE];//size of STACK is 4096byte
+
+        let stack_start = VirtAddr::from_ptr(unsafe{&STACK});//static mut risks data race
+        let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
+        TssCell(UnsafeCell::new(new_tss(stack_end)))//write to highest address coz stacks on x86 grow downwards
     };
-}
\ No newline at end of file
+}
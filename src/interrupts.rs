@@ -1,9 +1,13 @@
-use x86_64::structures::idt::{InterruptDescriptorTable,InterruptStackFrame,PageFaultErrorCode};
+use x86_64::structures::idt::{HandlerFunc,HandlerFuncWithErrCode,InterruptDescriptorTable,InterruptStackFrame,PageFaultErrorCode,PageFaultHandlerFunc};
+use alloc::vec::Vec;
 use crate::{print,println,gdt};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use spin;
 use crate::hlt_loop;
+use crate::smp::percpu::KernelGsGuard;
+use crate::usermode;
+use crate::process::{self,signal};
 
 pub const PIC_1_OFFSET:u8 = 32;
 pub const PIC_2_OFFSET:u8 = PIC_1_OFFSET + 8;
@@ -13,6 +17,17 @@ pub const PIC_2_OFFSET:u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET, // Timer interrupt arrives at the CPU as interrupt 32
     Keyboard, //by default its 33
+    Com2 = PIC_1_OFFSET + 3,//IRQ 3, shared with COM4
+    Com1,//IRQ 4, shared with COM3
+    PciIrq9 = PIC_2_OFFSET + 1,//the lines PCI interrupt pins are routed to, see pci::irq
+    PciIrq10,
+    PciIrq11,
+    PrimaryAta = PIC_2_OFFSET + 6,//IRQ 14
+    SecondaryAta,//IRQ 15
+    LapicTimer = crate::apic::TIMER_VECTOR, //per-CPU timer of the application processors
+    TlbShootdown = crate::apic::TLB_SHOOTDOWN_VECTOR,
+    CallFunction = crate::apic::CALL_FUNCTION_VECTOR,
+    LapicSpurious = crate::apic::SPURIOUS_VECTOR,
 } impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
@@ -25,6 +40,140 @@ pub enum InterruptIndex {
 pub static PICS:spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
 
+/// All registers of interrupted code, saved by the entry stubs below for the
+/// interrupts that may deliver signals on their way back to user mode. Handlers
+/// may modify the frame; it is restored by `iretq`.
+#[repr(C)]
+#[derive(Debug,Clone,Copy,Default)]
+pub struct TrapFrame {
+    pub r15:u64,
+    pub r14:u64,
+    pub r13:u64,
+    pub r12:u64,
+    pub r11:u64,
+    pub r10:u64,
+    pub r9:u64,
+    pub r8:u64,
+    pub rbp:u64,
+    pub rdi:u64,
+    pub rsi:u64,
+    pub rdx:u64,
+    pub rcx:u64,
+    pub rbx:u64,
+    pub rax:u64,
+    pub error_code:u64,//0 for exceptions without one
+    //pushed by the CPU
+    pub rip:u64,
+    pub cs:u64,
+    pub rflags:u64,
+    pub rsp:u64,
+    pub ss:u64,
+}impl TrapFrame {
+    /// Returns true if the interrupted code was running in ring 3.
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+// Each stub makes the stack look the same, with an error code and the handler in
+// rax, and goes on to `bentos_trap_common`, which saves the other registers and
+// calls the handler with the frame. The handlers take care of GS themselves
+// (see `KernelGsGuard`), so `bentos_trap_return` expects the GS base of the
+// code it returns to.
+global_asm!(r#"
+.intel_syntax noprefix
+.global bentos_trap_timer
+bentos_trap_timer:
+    push 0
+    push rax
+    lea rax, [rip + bentos_timer_trap]
+    jmp bentos_trap_common
+.global bentos_trap_lapic_timer
+bentos_trap_lapic_timer:
+    push 0
+    push rax
+    lea rax, [rip + bentos_lapic_timer_trap]
+    jmp bentos_trap_common
+.global bentos_trap_divide_error
+bentos_trap_divide_error:
+    push 0
+    push rax
+    lea rax, [rip + bentos_divide_error_trap]
+    jmp bentos_trap_common
+.global bentos_trap_invalid_opcode
+bentos_trap_invalid_opcode:
+    push 0
+    push rax
+    lea rax, [rip + bentos_invalid_opcode_trap]
+    jmp bentos_trap_common
+.global bentos_trap_general_protection
+bentos_trap_general_protection:
+    push rax
+    lea rax, [rip + bentos_general_protection_trap]
+    jmp bentos_trap_common
+.global bentos_trap_page_fault
+bentos_trap_page_fault:
+    push rax
+    lea rax, [rip + bentos_page_fault_trap]
+    jmp bentos_trap_common
+bentos_trap_common:
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    mov rbx, rsp
+    and rsp, -16
+    cld
+    call rax
+    mov rsp, rbx
+.global bentos_trap_return
+bentos_trap_return:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 8
+    iretq
+.att_syntax
+"#);
+
+extern "C" {
+    fn bentos_trap_timer();
+    fn bentos_trap_lapic_timer();
+    fn bentos_trap_divide_error();
+    fn bentos_trap_invalid_opcode();
+    fn bentos_trap_general_protection();
+    fn bentos_trap_page_fault();
+}
+
+/// The IDT takes handlers by their x86-interrupt type; the stubs are entered by the
+/// CPU all the same.
+unsafe fn stub<F>(entry:unsafe extern "C" fn()) -> F {
+    core::mem::transmute_copy(&entry)
+}
+
 
 lazy_static! {
     static ref IDT:InterruptDescriptorTable = {
@@ -34,13 +183,48 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)// ... for double fault
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);//set DOUBLE_FAULT_IST_INDEX(0) as stack for double fault
         }
-        idt[InterruptIndex::Timer.as_usize()]
-        .set_handler_fn(timer_interrupt_handler);
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()]
+            .set_handler_fn(stub::<HandlerFunc>(bentos_trap_timer));
+        }
         
         idt[InterruptIndex::Keyboard.as_usize()]
         .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Com2.as_usize()]
+        .set_handler_fn(com2_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()]
+        .set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::PciIrq9.as_usize()]
+        .set_handler_fn(pci_irq9_interrupt_handler);
+        idt[InterruptIndex::PciIrq10.as_usize()]
+        .set_handler_fn(pci_irq10_interrupt_handler);
+        idt[InterruptIndex::PciIrq11.as_usize()]
+        .set_handler_fn(pci_irq11_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()]
+        .set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+        .set_handler_fn(secondary_ata_interrupt_handler);
 
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt[InterruptIndex::LapicTimer.as_usize()]
+            .set_handler_fn(stub::<HandlerFunc>(bentos_trap_lapic_timer));
+        }
+        idt[InterruptIndex::TlbShootdown.as_usize()]
+        .set_handler_fn(tlb_shootdown_interrupt_handler);
+        idt[InterruptIndex::CallFunction.as_usize()]
+        .set_handler_fn(call_function_interrupt_handler);
+        idt[InterruptIndex::LapicSpurious.as_usize()]
+        .set_handler_fn(spurious_interrupt_handler);
+        for (i,&handler) in MSI_HANDLERS.iter().enumerate() {
+            idt[crate::pci::msi::VECTOR_BASE as usize + i].set_handler_fn(handler);
+        }
+
+        unsafe {
+            idt.page_fault.set_handler_fn(stub::<PageFaultHandlerFunc>(bentos_trap_page_fault));
+            idt.divide_error.set_handler_fn(stub::<HandlerFunc>(bentos_trap_divide_error));
+            idt.invalid_opcode.set_handler_fn(stub::<HandlerFunc>(bentos_trap_invalid_opcode));
+            idt.general_protection_fault.set_handler_fn(stub::<HandlerFuncWithErrCode>(bentos_trap_general_protection));
+        }
 
         idt
     };
@@ -49,7 +233,64 @@ pub fn init_idt() {
     IDT.load();//make CPU load IDT we created
 }
 
+/// Lets the legacy PIC deliver IRQ `irq` (0 to 15), which the firmware may have masked.
+pub fn unmask_irq(irq:u8) {
+    use x86_64::instructions::port::Port;
+    let (port,bit) = if irq < 8 {(0x21,irq)} else {(0xa1,irq - 8)};
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let _pics = PICS.lock();//no changes to the masks while the PICs are programmed
+        let mut mask:Port<u8> = Port::new(port);
+        unsafe {
+            let value = mask.read();
+            mask.write(value & !(1 << bit));
+            if irq >= 8 {
+                let mut master:Port<u8> = Port::new(0x21);
+                let value = master.read();
+                master.write(value & !(1 << 2));//the cascade from the second PIC
+            }
+        }
+    });
+}
+
+/// How many times each vector came in.
+static COUNTS:spin::Mutex<[u64;256]> = spin::Mutex::new([0;256]);
+
+/// Called by the handlers of device and inter-processor interrupts, with
+/// interrupts off.
+fn count(vector:u8) {
+    COUNTS.lock()[vector as usize] += 1;
+}
+
+fn vector_name(vector:u8) -> &'static str {
+    const NAMES:[(InterruptIndex,&str);12] = [
+        (InterruptIndex::Timer,"timer"),
+        (InterruptIndex::Keyboard,"keyboard"),
+        (InterruptIndex::Com2,"com2/4"),
+        (InterruptIndex::Com1,"com1/3"),
+        (InterruptIndex::PciIrq9,"pci irq 9"),
+        (InterruptIndex::PciIrq10,"pci irq 10"),
+        (InterruptIndex::PciIrq11,"pci irq 11"),
+        (InterruptIndex::PrimaryAta,"ata 0"),
+        (InterruptIndex::SecondaryAta,"ata 1"),
+        (InterruptIndex::LapicTimer,"lapic timer"),
+        (InterruptIndex::TlbShootdown,"tlb shootdown"),
+        (InterruptIndex::CallFunction,"call function"),
+    ];
+    match NAMES.iter().find(|(index,_)|index.as_u8() == vector) {
+        Some((_,name)) => name,
+        None => "msi",
+    }
+}
+
+/// The vectors that came in so far with their names and counts, e.g. for the
+/// shell's `irq` command.
+pub fn counts() -> Vec<(u8,&'static str,u64)> {
+    let counts = x86_64::instructions::interrupts::without_interrupts(||*COUNTS.lock());
+    counts.iter().enumerate().filter(|(_,&count)|count > 0).map(|(vector,&count)|(vector as u8,vector_name(vector as u8),count)).collect()
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -57,21 +298,55 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame:&mut InterruptStackFr
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame:&mut InterruptStackFrame) {
-    print!(".");
+#[no_mangle]
+extern "C" fn bentos_timer_trap(frame:&mut TrapFrame) {
+    let _gs = KernelGsGuard::enter(frame.cs);
+    count(InterruptIndex::Timer.as_u8());
+    crate::time::tick();
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    crate::task::tick();//may switch to another thread, so the EOI has to be sent first
+    signal::deliver_on_interrupt(frame);
+}
+
+#[no_mangle]
+extern "C" fn bentos_lapic_timer_trap(frame:&mut TrapFrame) {
+    let _gs = KernelGsGuard::enter(frame.cs);
+    count(InterruptIndex::LapicTimer.as_u8());
+    crate::apic::end_of_interrupt();
+    crate::task::tick();
+    signal::deliver_on_interrupt(frame);
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::TlbShootdown.as_u8());
+    crate::smp::tlb::handle_shootdown();
+    crate::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn call_function_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::CallFunction.as_u8());
+    crate::smp::ipi::handle_call_function();
+    crate::apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame:&mut InterruptStackFrame) {
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame:&mut InterruptStackFrame) {
+    //spurious interrupts must not be acknowledged
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::Keyboard.as_u8());
     use x86_64::instructions::port::Port;
     use pc_keyboard::{layouts,DecodedKey,HandleControl,Keyboard,ScancodeSet1};
     use spin::Mutex;
 
     lazy_static! {//creare a static Keyboard object.
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key,ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
+            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode));
     }
 
     let mut keyboard = KEYBOARD.lock();
@@ -80,9 +355,12 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame:&mut Interrupt
 
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {//add_byte method return Option<KeyEvent> which contains which key was pressed or released
         if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}",character),
-                DecodedKey::RawKey(key) => print!("{:?}",key),
+            match (key,process::foreground()) {
+                (DecodedKey::Unicode('\u{3}'),Some(pid)) => {//Ctrl-C
+                    print!("^C");
+                    let _ = signal::kill(pid,signal::SIGINT);
+                }
+                (key,_) => crate::console::vga::key_pressed(key),//the screen's terminal echoes what it reads
             }
         }
     }
@@ -91,15 +369,137 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame:&mut Interrupt
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame:&mut InterruptStackFrame, error_code:PageFaultErrorCode) {
+extern "x86-interrupt" fn com1_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::Com1.as_u8());
+    crate::serial::handle_interrupt(4);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn com2_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::Com2.as_u8());
+    crate::serial::handle_interrupt(3);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Com2.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn pci_irq9_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::PciIrq9.as_u8());
+    crate::pci::irq::dispatch(9);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::PciIrq9.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn pci_irq10_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::PciIrq10.as_u8());
+    crate::pci::irq::dispatch(10);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::PciIrq10.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn pci_irq11_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::PciIrq11.as_u8());
+    crate::pci::irq::dispatch(11);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::PciIrq11.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::PrimaryAta.as_u8());
+    crate::block::ata::handle_interrupt(0);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(stack_frame:&mut InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+    count(InterruptIndex::SecondaryAta.as_u8());
+    crate::block::ata::handle_interrupt(1);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
+
+/// One handler per MSI vector, each passing its index on to `pci::msi::dispatch`.
+macro_rules! msi_handlers {
+    ($($name:ident = $index:expr),*) => {
+        $(extern "x86-interrupt" fn $name(stack_frame:&mut InterruptStackFrame) {
+            let _gs = KernelGsGuard::enter(stack_frame.code_segment);
+            count(crate::pci::msi::VECTOR_BASE + $index);
+            crate::pci::msi::dispatch($index);
+            crate::apic::end_of_interrupt();
+        })*
+        const MSI_HANDLERS:[HandlerFunc;crate::pci::msi::VECTOR_COUNT] = [$($name),*];
+    };
+}
+msi_handlers!(msi_handler_0 = 0, msi_handler_1 = 1, msi_handler_2 = 2, msi_handler_3 = 3,
+    msi_handler_4 = 4, msi_handler_5 = 5, msi_handler_6 = 6, msi_handler_7 = 7);
+
+#[no_mangle]
+extern "C" fn bentos_page_fault_trap(frame:&mut TrapFrame) {
     use x86_64::registers::control::Cr2;//CR2 register automatically set by the CPU on a page fault and contains the accessed virtual address that caused the page fault. 
+    let _gs = KernelGsGuard::enter(frame.cs);
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    if frame.from_user_mode() {//a user task can't take the kernel down
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE) {
+            let addr = Cr2::read();
+            if crate::task::current_address_space().map_or(false,|space|space.resolve_copy_on_write(addr)) {
+                return;
+            }
+        }
+        println!("user page fault at {:?}, {:?}",Cr2::read(),error_code);
+        usermode::fault("page fault",signal::SIGSEGV,frame);
+        return;
+    }
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address:{:?}", Cr2::read());
     println!("Error Code:{:?}",error_code);//giving type of operation which caused page fault(read or write?)
-    println!("{:#?}",stack_frame);
+    println!("{:#x?}",frame);
     hlt_loop();
 }
 
+#[no_mangle]
+extern "C" fn bentos_divide_error_trap(frame:&mut TrapFrame) {
+    let _gs = KernelGsGuard::enter(frame.cs);
+    if frame.from_user_mode() {
+        usermode::fault("divide error",signal::SIGFPE,frame);
+        return;
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#x?}", frame);
+}
+
+#[no_mangle]
+extern "C" fn bentos_invalid_opcode_trap(frame:&mut TrapFrame) {
+    let _gs = KernelGsGuard::enter(frame.cs);
+    if frame.from_user_mode() {
+        usermode::fault("invalid opcode",signal::SIGILL,frame);
+        return;
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#x?}", frame);
+}
+
+#[no_mangle]
+extern "C" fn bentos_general_protection_trap(frame:&mut TrapFrame) {
+    let _gs = KernelGsGuard::enter(frame.cs);
+    if frame.from_user_mode() {
+        usermode::fault("general protection fault",signal::SIGSEGV,frame);
+        return;
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#x?}", frame.error_code, frame);
+}
+
 #[cfg(test)]
 use crate::{serial_print,serial_println};
 
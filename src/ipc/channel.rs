@@ -0,0 +1,280 @@
+use alloc::{collections::VecDeque,sync::Arc,vec::Vec};
+use core::sync::atomic::{AtomicUsize,Ordering};
+use spin::Mutex;
+use x86_64::{VirtAddr,instructions::interrupts,structures::paging::{Page,PageTableFlags,PhysFrame}};
+use crate::memory::{self,GlobalFrameAllocator,address_space::AddressSpace};
+use crate::sync::WaitQueue;
+use crate::syscall::Errno;
+
+/// Most messages a channel can buffer.
+pub const MAX_CAPACITY:usize = 64;
+/// Largest inline message; bigger data is sent as pages.
+pub const MAX_MESSAGE:usize = 4096;
+
+/// What the holder of an endpoint may do with the channel.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Rights(u32);
+impl Rights {
+    pub const SEND:Rights = Rights(1);
+    pub const RECV:Rights = Rights(2);
+    pub const ALL:Rights = Rights(3);
+
+    pub fn from_bits(bits:u32) -> Option<Rights> {
+        if bits & !Self::ALL.0 == 0 {Some(Rights(bits))} else {None}
+    }
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+    pub fn contains(self, other:Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Pages moved from one address space to another with a message.
+///
+/// The frames belong to the transfer until `map_into` hands them to the receiver,
+/// and are freed if the message is dropped before that.
+pub struct PageTransfer {
+    frames:Vec<PhysFrame>,
+}impl PageTransfer {
+    /// Unmaps `pages` pages starting at `start` from `address_space`.
+    pub fn take(address_space:&AddressSpace, start:VirtAddr, pages:u64) -> Result<Self,Errno> {
+        if start.as_u64() % 4096 != 0 || pages == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let frames = address_space.take_pages(start,pages).ok_or(Errno::EFAULT)?;
+        Ok(PageTransfer {frames})
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.frames.len() as u64
+    }
+
+    /// Maps the pages, writable, at a free place of `address_space` and returns their start.
+    /// On failure nothing stays mapped and the frames are freed.
+    pub fn map_into(mut self, address_space:&AddressSpace) -> Result<VirtAddr,Errno> {
+        let pages = self.pages();
+        let start = address_space.reserve(pages).ok_or(Errno::ENOMEM)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for i in 0..self.frames.len() {
+            let page = Page::containing_address(start + i as u64*4096);
+            if memory::map_user_page(address_space.p4(),page,self.frames[i],flags).is_err() {
+                //the frames mapped so far are freed by `unmap`, the others by `drop`
+                self.frames.drain(..i);
+                address_space.unmap(start,i as u64);
+                address_space.unreserve(start,pages);
+                return Err(Errno::ENOMEM);
+            }
+        }
+        self.frames.clear();//they belong to the address space now
+        Ok(start)
+    }
+}
+
+impl Drop for PageTransfer {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// A message: inline data, optionally pages, and for `call`s the way to answer.
+pub struct Message {
+    pub data:Vec<u8>,
+    pub pages:Option<PageTransfer>,
+    reply:Option<ReplyHandle>,
+}impl Message {
+    pub fn new(data:Vec<u8>) -> Self {
+        Message {data, pages:None, reply:None}
+    }
+
+    pub fn with_pages(data:Vec<u8>, pages:PageTransfer) -> Self {
+        Message {data, pages:Some(pages), reply:None}
+    }
+
+    /// The handle to answer a message sent with `call`, which only the first caller gets.
+    pub fn take_reply(&mut self) -> Option<ReplyHandle> {
+        self.reply.take()
+    }
+}
+
+/// Where the answer to a `call` goes.
+struct ReplySlot {
+    answer:Mutex<Option<Result<Message,Errno>>>,
+    done:WaitQueue,
+}impl ReplySlot {
+    fn complete(&self, answer:Result<Message,Errno>) {
+        interrupts::without_interrupts(||*self.answer.lock() = Some(answer));
+        self.done.notify_all();
+    }
+}
+
+/// The right to answer one `call`. Dropping it unanswered fails the call with `EPIPE`.
+pub struct ReplyHandle {
+    slot:Option<Arc<ReplySlot>>,
+}impl ReplyHandle {
+    pub fn reply(mut self, message:Message) {
+        if let Some(slot) = self.slot.take() {
+            slot.complete(Ok(message));
+        }
+    }
+}
+
+impl Drop for ReplyHandle {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            slot.complete(Err(Errno::EPIPE));
+        }
+    }
+}
+
+/// A bounded message queue, the kernel object behind channel endpoints.
+pub struct Channel {
+    queue:Mutex<VecDeque<Message>>,
+    capacity:usize,
+    senders:AtomicUsize,//endpoints with Rights::SEND
+    receivers:AtomicUsize,//endpoints with Rights::RECV
+    not_empty:WaitQueue,
+    not_full:WaitQueue,
+}
+
+/// A reference to a channel with some rights, as stored in a handle table.
+///
+/// Once no endpoint with `RECV` is left, sending fails with `EPIPE`; once no
+/// endpoint with `SEND` is left, receiving from an empty channel does.
+pub struct Endpoint {
+    channel:Arc<Channel>,
+    rights:Rights,
+}
+
+/// Creates a channel buffering up to `capacity` messages and returns an endpoint with all rights.
+pub fn channel(capacity:usize) -> Result<Endpoint,Errno> {
+    if capacity == 0 || capacity > MAX_CAPACITY {
+        return Err(Errno::EINVAL);
+    }
+    let channel = Arc::new(Channel {
+        queue:Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        senders:AtomicUsize::new(0),
+        receivers:AtomicUsize::new(0),
+        not_empty:WaitQueue::new(),
+        not_full:WaitQueue::new(),
+    });
+    Ok(Endpoint::new(channel,Rights::ALL))
+}
+
+impl Endpoint {
+    fn new(channel:Arc<Channel>, rights:Rights) -> Self {
+        if rights.contains(Rights::SEND) {
+            channel.senders.fetch_add(1,Ordering::AcqRel);
+        }
+        if rights.contains(Rights::RECV) {
+            channel.receivers.fetch_add(1,Ordering::AcqRel);
+        }
+        Endpoint {channel, rights}
+    }
+
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+
+    /// Another endpoint for the same channel with (at most) `rights`.
+    pub fn duplicate(&self, rights:Rights) -> Result<Endpoint,Errno> {
+        if !self.rights.contains(rights) {
+            return Err(Errno::EPERM);
+        }
+        Ok(Endpoint::new(self.channel.clone(),rights))
+    }
+
+    /// Queues `message`, sleeping while the channel is full.
+    pub fn send(&self, message:Message) -> Result<(),Errno> {
+        if !self.rights.contains(Rights::SEND) {
+            return Err(Errno::EPERM);
+        }
+        if message.data.len() > MAX_MESSAGE {
+            return Err(Errno::EINVAL);
+        }
+        let channel = &self.channel;
+        let mut message = Some(message);
+        let mut result = Ok(());
+        channel.not_full.wait_until(||{
+            let mut queue = channel.queue.lock();//held while checking, so the last receiver can't leave in between
+            if channel.receivers.load(Ordering::Acquire) == 0 {
+                result = Err(Errno::EPIPE);
+                return true;
+            }
+            if queue.len() < channel.capacity {
+                queue.push_back(message.take().unwrap());
+                true
+            } else {
+                false
+            }
+        });
+        if result.is_ok() {
+            channel.not_empty.notify_one();
+        }
+        result
+    }
+
+    /// Takes the oldest message, sleeping while the channel is empty.
+    pub fn recv(&self) -> Result<Message,Errno> {
+        if !self.rights.contains(Rights::RECV) {
+            return Err(Errno::EPERM);
+        }
+        let channel = &self.channel;
+        let mut result = Err(Errno::EPIPE);
+        channel.not_empty.wait_until(||{
+            if let Some(message) = channel.queue.lock().pop_front() {
+                result = Ok(message);
+                return true;
+            }
+            channel.senders.load(Ordering::Acquire) == 0//nobody left who could send
+        });
+        if result.is_ok() {
+            channel.not_full.notify_one();
+        }
+        result
+    }
+
+    /// Sends `message` and sleeps until the receiver answers it through its `ReplyHandle`.
+    pub fn call(&self, mut message:Message) -> Result<Message,Errno> {
+        let slot = Arc::new(ReplySlot {
+            answer:Mutex::new(None),
+            done:WaitQueue::new(),
+        });
+        message.reply = Some(ReplyHandle {slot:Some(slot.clone())});
+        self.send(message)?;
+        let mut answer = None;
+        slot.done.wait_until(||{
+            answer = slot.answer.lock().take();
+            answer.is_some()
+        });
+        answer.unwrap()
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let channel = &self.channel;
+        if self.rights.contains(Rights::SEND) && channel.senders.fetch_sub(1,Ordering::AcqRel) == 1 {
+            channel.not_empty.notify_all();
+        }
+        if self.rights.contains(Rights::RECV) {
+            //under the queue lock, so no sender queues a message after the drain
+            let messages:Option<Vec<Message>> = interrupts::without_interrupts(||{
+                let mut queue = channel.queue.lock();
+                if channel.receivers.fetch_sub(1,Ordering::AcqRel) == 1 {
+                    Some(queue.drain(..).collect())
+                } else {
+                    None
+                }
+            });
+            if let Some(messages) = messages {
+                //nobody will receive the queued messages; failing their calls now avoids a deadlock
+                drop(messages);
+                channel.not_full.notify_all();
+            }
+        }
+    }
+}
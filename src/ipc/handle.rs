@@ -0,0 +1,80 @@
+use alloc::{sync::Arc,vec::Vec};
+use crate::syscall::Errno;
+use super::channel::{Endpoint,ReplyHandle};
+
+/// Most handles a process may hold at the same time.
+pub const MAX_HANDLES:usize = 256;
+
+/// A kernel object a process holds a capability for.
+pub enum Object {
+    Channel(Arc<Endpoint>),//shared, so that system calls can use it without holding the table
+    Reply(ReplyHandle),
+}
+
+/// Per-process table of capabilities, indexed by handle number.
+///
+/// A process can only use kernel objects it holds a handle for, and only with
+/// the rights stored with the handle.
+pub struct HandleTable {
+    objects:Vec<Option<Object>>,
+}impl HandleTable {
+    pub fn new() -> Self {
+        HandleTable {objects:Vec::new()}
+    }
+
+    /// Stores `object` under the lowest free handle and returns it.
+    pub fn insert(&mut self, object:Object) -> Result<usize,Errno> {
+        if let Some(handle) = self.objects.iter().position(Option::is_none) {
+            self.objects[handle] = Some(object);
+            return Ok(handle);
+        }
+        if self.objects.len() >= MAX_HANDLES {
+            return Err(Errno::EMFILE);
+        }
+        self.objects.push(Some(object));
+        Ok(self.objects.len() - 1)
+    }
+
+    pub fn get(&self, handle:usize) -> Result<&Object,Errno> {
+        self.objects.get(handle).and_then(Option::as_ref).ok_or(Errno::EBADF)
+    }
+
+    pub fn remove(&mut self, handle:usize) -> Result<Object,Errno> {
+        self.objects.get_mut(handle).and_then(Option::take).ok_or(Errno::EBADF)
+    }
+
+    /// The channel endpoint behind `handle`.
+    pub fn endpoint(&self, handle:usize) -> Result<Arc<Endpoint>,Errno> {
+        match self.get(handle)? {
+            Object::Channel(endpoint) => Ok(endpoint.clone()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    /// Removes the reply handle `handle` in order to answer its call.
+    pub fn take_reply(&mut self, handle:usize) -> Result<ReplyHandle,Errno> {
+        match self.objects.get_mut(handle) {
+            Some(slot @ Some(Object::Reply(_))) => match slot.take() {
+                Some(Object::Reply(reply)) => Ok(reply),
+                _ => unreachable!(),
+            },
+            Some(Some(_)) => Err(Errno::EINVAL),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    /// The handle table of a forked child: the same channels, but no pending replies,
+    /// which only the process that received the call may answer.
+    pub fn fork(&self) -> Self {
+        let objects = self.objects.iter().map(|object|match object {
+            Some(Object::Channel(endpoint)) => Some(Object::Channel(endpoint.clone())),
+            _ => None,
+        }).collect();
+        HandleTable {objects}
+    }
+
+    /// Drops every capability.
+    pub fn clear(&mut self) {
+        self.objects.clear();
+    }
+}
@@ -0,0 +1,19 @@
+//! Message-passing IPC.
+//!
+//! A channel is a bounded queue of messages. Processes refer to channels
+//! through handles in their `HandleTable`, each carrying the rights to send,
+//! receive or both; handles are inherited on `fork` and can be duplicated with
+//! fewer rights. `call` sends a message and waits for the receiver to answer it
+//! through the reply handle that comes with the message. Data up to
+//! `MAX_MESSAGE` bytes is copied, whole pages can be moved to the receiver's
+//! address space instead.
+//!
+//! Pipes are byte streams used through file descriptors.
+
+pub mod channel;
+pub mod handle;
+pub mod pipe;
+
+pub use channel::{channel,Endpoint,Message,PageTransfer,ReplyHandle,Rights,MAX_CAPACITY,MAX_MESSAGE};
+pub use handle::{HandleTable,Object};
+pub use pipe::{pipe,PipeReader,PipeWriter};
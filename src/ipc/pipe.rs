@@ -0,0 +1,125 @@
+use alloc::{collections::VecDeque,sync::Arc};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::process::{fd::File,signal};
+use crate::sync::WaitQueue;
+use crate::syscall::Errno;
+
+/// Bytes a pipe buffers before writers have to wait.
+pub const PIPE_CAPACITY:usize = 4096;
+
+struct PipeState {
+    buffer:VecDeque<u8>,
+    readers:usize,
+    writers:usize,
+}
+
+struct Pipe {
+    state:Mutex<PipeState>,
+    readable:WaitQueue,//data arrived or the last writer left
+    writable:WaitQueue,//space freed or the last reader left
+}impl Pipe {
+    fn with_state<R, F:FnOnce(&mut PipeState)->R>(&self, f:F) -> R {
+        interrupts::without_interrupts(||f(&mut self.state.lock()))
+    }
+}
+
+/// The read end of a pipe.
+pub struct PipeReader(Arc<Pipe>);
+/// The write end of a pipe.
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Creates an anonymous pipe and returns its two ends.
+pub fn pipe() -> (PipeReader,PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state:Mutex::new(PipeState {
+            buffer:VecDeque::with_capacity(PIPE_CAPACITY),
+            readers:1,
+            writers:1,
+        }),
+        readable:WaitQueue::new(),
+        writable:WaitQueue::new(),
+    });
+    (PipeReader(pipe.clone()),PipeWriter(pipe))
+}
+
+impl File for PipeReader {
+    /// Sleeps until there is data and returns what is there, up to `buf.len()`
+    /// bytes. Returns 0 (end of file) once the pipe is empty and has no writer left.
+    fn read(&self, buf:&mut [u8]) -> Result<usize,Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut result = Ok(0);
+        pipe.readable.wait_until(||{
+            if signal::interrupted() {
+                result = Err(Errno::EINTR);
+                return true;
+            }
+            pipe.with_state(|state|{
+                let len = state.buffer.len().min(buf.len());
+                for (dst,src) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+                    *dst = src;
+                }
+                result = Ok(len);
+                len > 0 || state.writers == 0
+            })
+        });
+        if let Ok(len) = result {
+            if len > 0 {
+                pipe.writable.notify_all();
+            }
+        }
+        result
+    }
+}
+
+impl File for PipeWriter {
+    /// Writes all of `buf`, sleeping whenever the pipe is full. Fails with `EPIPE`
+    /// if there is no reader (left); `written` bytes may have gone through before.
+    fn write(&self, buf:&[u8]) -> Result<usize,Errno> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < buf.len() {
+            let mut result = Ok(());
+            pipe.writable.wait_until(||{
+                if signal::interrupted() {
+                    result = Err(Errno::EINTR);
+                    return true;
+                }
+                pipe.with_state(|state|{
+                    if state.readers == 0 {
+                        result = Err(Errno::EPIPE);
+                        return true;
+                    }
+                    let len = (PIPE_CAPACITY - state.buffer.len()).min(buf.len() - written);
+                    state.buffer.extend(&buf[written..written+len]);
+                    written += len;
+                    len > 0
+                })
+            });
+            pipe.readable.notify_all();
+            match result {
+                Err(Errno::EINTR) if written > 0 => return Ok(written),
+                Err(err) => return Err(err),
+                Ok(()) => {}
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.with_state(|state|state.readers -= 1);
+        self.0.writable.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.with_state(|state|state.writers -= 1);
+        self.0.readable.notify_all();
+    }
+}
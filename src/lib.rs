@@ -5,6 +5,8 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]//feature gate for handler function when allocation error occur
+#![feature(global_asm)]
+#![feature(asm)]
 
 use core::panic::PanicInfo;
 extern crate alloc;
@@ -16,6 +18,26 @@ pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod task;
+pub mod acpi;
+pub mod apic;
+pub mod smp;
+pub mod usermode;
+pub mod elf;
+pub mod process;
+pub mod ipc;
+pub mod syscall;
+pub mod time;
+pub mod sync;
+pub mod block;
+pub mod vfs;
+pub mod fs;
+pub mod pci;
+pub mod virtio;
+pub mod net;
+pub mod console;
+pub mod shell;
+pub mod power;
 
 pub fn hlt_loop()->! {
     loop {
@@ -26,7 +48,11 @@ pub fn hlt_loop()->! {
 pub fn init() {
     interrupts::init_idt();
     gdt::init();
+    smp::percpu::init_bsp();
+    syscall::init();
+    time::init();
     unsafe{ interrupts::PICS.lock().initialize()};
+    serial::init();//receive interrupts for the serial ports
     x86_64::instructions::interrupts::enable();
 }
 
@@ -44,6 +70,7 @@ pub enum QemuExitCode {
 
 pub fn exit_qemu(exit_code:QemuExitCode){
     use x86_64::instructions::port::Port;
+    serial::flush();//the last messages may still wait for the UART
     unsafe {
         let mut port = Port::new(0xf4);
         port.write(exit_code as u32);
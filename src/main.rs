@@ -7,7 +7,7 @@
 
 extern crate alloc;
 use core::panic::PanicInfo;
-use alloc::{boxed::Box,vec,vec::Vec,rc::Rc};
+use alloc::{boxed::Box,vec,vec::Vec,rc::Rc,sync::Arc};
 use bentos::{print,println};
 use bootloader::{BootInfo,entry_point};
 
@@ -31,6 +31,24 @@ fn kernel_main(boot_info: &'static BootInfo) ->! {
     unsafe{page_ptr.offset(300).write_volatile(0x_f021_f077_f065_f04e)};
     */
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    match bentos::smp::init(&mut mapper, &mut frame_allocator) {
+        Ok(cpus) => println!("{} CPU(s) online",cpus),
+        Err(err) => println!("SMP initialization failed:{:?}",err),
+    }
+    memory::install_frame_allocator(frame_allocator);//from here on, frames come from memory::GlobalFrameAllocator
+    bentos::fs::init();//a ramfs at "/" with the initrd in it
+    bentos::block::ata::init();//disks on the IDE controller, starting with the boot disk
+    bentos::block::virtio::init();
+    bentos::net::init();//the loopback interface and the thread running the stack
+    bentos::net::virtio::init();
+    bentos::pci::init();//lists the devices and hands them to the drivers registered so far
+    bentos::task::spawn(||{
+        if let Err(err) = bentos::net::dhcp::configure("eth0") {
+            println!("eth0: DHCP failed:{:?}",err);
+        }
+    });
+    bentos::shell::spawn(Arc::new(bentos::console::SerialTerminal::new(bentos::serial::COM1)));//for -display none
+    bentos::shell::spawn(Arc::new(bentos::console::VgaTerminal));
 
     let x = Box::new(41);
     println!("heap value address:{:p}",x);
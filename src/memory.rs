@@ -1,15 +1,31 @@
 use x86_64::{
     VirtAddr,
-    structures::paging::{PhysFrame,Mapper,Size4KiB,FrameAllocator,UnusedPhysFrame,Page,PageTable,OffsetPageTable},
+    structures::paging::{PhysFrame,Mapper,Size4KiB,FrameAllocator,UnusedPhysFrame,Page,PageTable,OffsetPageTable,mapper::{MapToError,UnmapError,FlagUpdateError},PageTableFlags},
     PhysAddr,
 };
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
+use core::sync::atomic::{AtomicU64,Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+
+pub mod address_space;
+pub mod shared;
+
+/// Virtual address at which the bootloader mapped the complete physical memory.
+static PHYSICAL_MEMORY_OFFSET:AtomicU64 = AtomicU64::new(0);
+
+/// Start of the virtual region used for memory-mapped device registers.
+pub const MMIO_START:u64 = 0x_4444_6000_0000;
+static NEXT_MMIO:AtomicU64 = AtomicU64::new(MMIO_START);
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map:&'static MemoryMap,
     next:usize,
+    free:Vec<PhysFrame>,//frames handed back by deallocate_frame, reused first
 }impl BootInfoFrameAllocator {
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self)->impl Iterator<Item = PhysFrame> {
@@ -34,14 +50,29 @@ pub struct BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free: Vec::new(),//doesn't allocate, so this works before the heap exists
         }
     }
+    /// Gives a frame back for reuse. Requires the heap.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frame
+    /// is no longer mapped or otherwise in use.
+    pub unsafe fn deallocate_frame(&mut self, frame:PhysFrame) {
+        self.free.push(frame);
+    }
+    /// Usable frames in the memory map and how many of them are handed out.
+    pub fn stats(&self) -> (usize,usize) {
+        (self.usable_frames().count(),self.next - self.free.len())
+    }
 }
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);//get usable frames
-        self.next = self.next + 1;
-        frame
+        if let Some(frame) = self.free.pop() {
+            return Some(frame);
+        }
+        let frame = self.usable_frames().nth(self.next)?;//get usable frames
+        self.next = self.next + 1;//only past frames handed out, so `stats` stays within the total
+        Some(frame)
     }
 }
 
@@ -75,6 +106,7 @@ pub fn create_example_mapping(
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset:VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(),Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table,physical_memory_offset)
 }
@@ -93,4 +125,170 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();// convert lv4 table's virtual address to a *mut PageTable raw pointer
 
     &mut *page_table_ptr //unsafe. get &mut lv4 table page(virtual start address)
-}
\ No newline at end of file
+}
+/// Returns the virtual address through which the physical address `addr` can be accessed.
+pub fn phys_to_virt(addr:PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Maps `size` bytes of device registers starting at physical address `phys` as uncached
+/// memory and returns the virtual address of `phys`.
+pub fn map_mmio(
+    phys:PhysAddr,
+    size:u64,
+    mapper:&mut impl Mapper<Size4KiB>,
+    frame_allocator:&mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr,MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + size - 1u64);
+    let frame_count = (last_frame.start_address() - first_frame.start_address())/4096 + 1;
+    let virt_start = NEXT_MMIO.fetch_add(frame_count*4096,Ordering::Relaxed);
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH | Flags::NO_EXECUTE;
+    for (i,frame) in PhysFrame::range_inclusive(first_frame,last_frame).enumerate() {
+        let page = Page::containing_address(VirtAddr::new(virt_start + i as u64*4096));
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+    Ok(VirtAddr::new(virt_start + (phys - first_frame.start_address())))
+}
+
+/// Removes the mapping of `page` and flushes it from the TLBs of all CPUs.
+/// Returns the frame that was mapped; freeing it is up to the caller.
+pub fn unmap_page(
+    page:Page,
+    mapper:&mut impl Mapper<Size4KiB>,
+) -> Result<PhysFrame,UnmapError> {
+    let (frame,flush) = mapper.unmap(page)?;
+    flush.ignore();//shootdown flushes the local TLB too
+    crate::smp::tlb::shootdown(page.start_address(),1);
+    Ok(frame)
+}
+
+/// Changes the flags of the mapping of `page` and flushes it from the TLBs of all CPUs.
+pub fn update_flags(
+    page:Page,
+    flags:PageTableFlags,
+    mapper:&mut impl Mapper<Size4KiB>,
+) -> Result<(),FlagUpdateError> {
+    unsafe {
+        mapper.update_flags(page,flags)?.ignore();
+    }
+    crate::smp::tlb::shootdown(page.start_address(),1);
+    Ok(())
+}
+
+/// The frame allocator shared by the whole kernel once boot is done, see `install_frame_allocator`.
+static FRAME_ALLOCATOR:Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+/// Serializes all page table modifications made through `with_page_table`.
+static PAGE_TABLES:Mutex<()> = Mutex::new(());
+/// Level 4 table the kernel booted with; its kernel entries are shared by every address space.
+static KERNEL_P4:AtomicU64 = AtomicU64::new(0);
+
+/// Hands the boot frame allocator over to the kernel so that code running later
+/// (threads, interrupt handlers) can allocate and free frames through `GlobalFrameAllocator`.
+pub fn install_frame_allocator(frame_allocator:BootInfoFrameAllocator) {
+    KERNEL_P4.store(Cr3::read().0.start_address().as_u64(),Ordering::Relaxed);
+    interrupts::without_interrupts(||{
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+/// Handle to the installed kernel frame allocator.
+pub struct GlobalFrameAllocator;
+impl GlobalFrameAllocator {
+    /// Gives a frame back for reuse.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frame
+    /// is no longer mapped or otherwise in use.
+    pub unsafe fn deallocate_frame(&mut self, frame:PhysFrame) {
+        interrupts::without_interrupts(||{
+            if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+                allocator.deallocate_frame(frame);
+            }
+        });
+    }
+}
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        interrupts::without_interrupts(||{
+            FRAME_ALLOCATOR.lock().as_mut().and_then(|allocator|allocator.allocate_frame())
+        })
+    }
+}
+
+/// Usable frames and frames in use, once the frame allocator is installed.
+pub fn frame_stats() -> Option<(usize,usize)> {
+    interrupts::without_interrupts(||FRAME_ALLOCATOR.lock().as_ref().map(BootInfoFrameAllocator::stats))
+}
+
+/// The level 4 table the kernel booted with.
+pub fn kernel_p4() -> PhysFrame {
+    match KERNEL_P4.load(Ordering::Relaxed) {
+        0 => Cr3::read().0,//not installed yet, so we are still on the boot page table
+        addr => PhysFrame::containing_address(PhysAddr::new(addr)),
+    }
+}
+
+/// Runs `f` with a mapper for the page table whose level 4 table is `p4`.
+///
+/// Page table changes from different threads and CPUs are serialized, and
+/// interrupts stay disabled while `f` runs, so `f` must be short.
+pub fn with_page_table<R,F:FnOnce(&mut OffsetPageTable<'static>,&mut GlobalFrameAllocator)->R>(p4:PhysFrame, f:F) -> R {
+    interrupts::without_interrupts(||{
+        let _guard = PAGE_TABLES.lock();
+        let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        let table = unsafe { &mut *phys_to_virt(p4.start_address()).as_mut_ptr::<PageTable>() };
+        let mut mapper = unsafe { OffsetPageTable::new(table,offset) };
+        f(&mut mapper,&mut GlobalFrameAllocator)
+    })
+}
+
+/// Maps `page` to `frame` in the page table rooted at `p4` with `flags | USER_ACCESSIBLE`.
+///
+/// The mapper only sets PRESENT | WRITABLE on the intermediate tables it creates, but the
+/// CPU checks USER_ACCESSIBLE on every level, so it is added to the parent entries here.
+pub fn map_user_page(
+    p4:PhysFrame,
+    page:Page,
+    frame:PhysFrame,
+    flags:PageTableFlags,
+) -> Result<(),MapToError<Size4KiB>> {
+    with_page_table(p4,|mapper,frame_allocator|{
+        unsafe {
+            mapper.map_to(page, frame, flags | PageTableFlags::USER_ACCESSIBLE, frame_allocator)?.flush();
+        }
+        let mut table = unsafe { &mut *phys_to_virt(p4.start_address()).as_mut_ptr::<PageTable>() };
+        for index in [page.p4_index(),page.p3_index(),page.p2_index()].iter() {
+            let entry = &mut table[*index];
+            entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+            table = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+        }
+        Ok(())
+    })
+}
+
+/// Returns the effective flags of the mapping of `addr` in the page table rooted at `p4`.
+///
+/// WRITABLE and USER_ACCESSIBLE are only reported if every level of the walk grants them,
+/// since that is how the CPU checks them.
+pub fn page_flags(p4:PhysFrame, addr:VirtAddr) -> Option<PageTableFlags> {
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut granted = inherited;
+    let mut table = unsafe { &*phys_to_virt(p4.start_address()).as_ptr::<PageTable>() };
+    let indices = [addr.p4_index(),addr.p3_index(),addr.p2_index(),addr.p1_index()];
+    for (level,index) in indices.iter().enumerate() {
+        let entry = &table[*index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        granted &= flags;
+        if level == 3 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Some((flags - inherited) | granted);
+        }
+        table = unsafe { &*phys_to_virt(entry.addr()).as_ptr::<PageTable>() };
+    }
+    None
+}
@@ -0,0 +1,366 @@
+use alloc::{collections::BTreeMap,vec::Vec};
+use core::sync::atomic::{AtomicU64,Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    PhysAddr,VirtAddr,
+    instructions::{interrupts,tlb},
+    registers::control::{Cr3,Cr3Flags},
+    structures::paging::{mapper::MapToError,FrameAllocator,Mapper,Page,PageTable,PageTableEntry,PageTableFlags,PhysFrame,Size4KiB},
+};
+use crate::usermode::{USER_START,USER_END,MMAP_START};
+use super::{GlobalFrameAllocator,kernel_p4,map_user_page,phys_to_virt,with_page_table};
+
+/// Level 4 entries covering the user part; everything else is shared with the kernel.
+const USER_P4_ENTRIES:core::ops::Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Marks a page whose frame is shared after a fork. It is mapped read-only and
+/// gets a private copy on the first write.
+pub const COPY_ON_WRITE:PageTableFlags = PageTableFlags::BIT_9;
+/// Marks a page of a shared memory object, which stays shared across `fork`.
+pub const SHARED:PageTableFlags = PageTableFlags::BIT_10;
+
+lazy_static! {
+    /// Number of additional address spaces mapping a frame, for frames shared copy-on-write.
+    static ref SHARED_FRAMES:Mutex<BTreeMap<u64,usize>> = Mutex::new(BTreeMap::new());
+}
+
+pub(super) fn share_frame(frame:PhysFrame) {
+    interrupts::without_interrupts(||*SHARED_FRAMES.lock().entry(frame.start_address().as_u64()).or_insert(0) += 1);
+}
+
+fn is_shared(frame:PhysFrame) -> bool {
+    interrupts::without_interrupts(||SHARED_FRAMES.lock().contains_key(&frame.start_address().as_u64()))
+}
+
+/// Drops one reference to a user frame and frees it once nobody maps it anymore.
+pub(super) fn release_frame(frame:PhysFrame) {
+    let last = interrupts::without_interrupts(||{
+        let mut shared = SHARED_FRAMES.lock();
+        let addr = frame.start_address().as_u64();
+        match shared.get_mut(&addr) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                shared.remove(&addr);
+                false
+            }
+            None => true,
+        }
+    });
+    if last {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+fn table_at(addr:PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(addr).as_mut_ptr::<PageTable>() }
+}
+
+/// Calls `f` for every mapped 4KiB page below the level `level` table `table`, whose first page is at `base`.
+fn for_each_page(table:&mut PageTable, level:usize, base:u64, f:&mut dyn FnMut(VirtAddr,&mut PageTableEntry)) {
+    for (i,entry) in table.iter_mut().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let addr = base | (i as u64) << (12 + 9*level);
+        if level == 0 {
+            f(VirtAddr::new(addr),entry);
+        } else {
+            for_each_page(table_at(entry.addr()),level-1,addr,f);
+        }
+    }
+}
+
+/// A page table of its own for a user process.
+///
+/// The kernel half is shared: all level 4 entries outside the user range are
+/// copied from the kernel's table, so kernel code, heap and stacks stay mapped.
+/// Frames and page tables of the user range are freed when the address space is dropped.
+pub struct AddressSpace {
+    p4:PhysFrame,
+    next_mmap:AtomicU64,
+}impl AddressSpace {
+    pub fn new() -> Result<Self,MapToError<Size4KiB>> {
+        let p4 = allocate_zeroed_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let table = unsafe { &mut *phys_to_virt(p4.start_address()).as_mut_ptr::<PageTable>() };
+        let kernel = unsafe { &*phys_to_virt(kernel_p4().start_address()).as_ptr::<PageTable>() };
+        for i in 0..512 {
+            if !USER_P4_ENTRIES.contains(&i) {
+                table[i] = kernel[i].clone();
+            }
+        }
+        Ok(AddressSpace {
+            p4,
+            next_mmap:AtomicU64::new(MMAP_START),
+        })
+    }
+
+    /// Frame of the level 4 table, i.e. the value to load into CR3.
+    pub fn p4(&self) -> PhysFrame {
+        self.p4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4
+    }
+
+    /// Loads this address space on the calling CPU.
+    ///
+    /// This function is unsafe because the address space must stay alive as long as it is loaded.
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            Cr3::write(self.p4,Cr3Flags::empty());
+        }
+    }
+
+    /// Maps `pages` zeroed frames starting at `start` with `flags | USER_ACCESSIBLE`.
+    /// Pages that are already mapped keep their frame and get `flags` added.
+    pub fn map_zeroed(&self, start:VirtAddr, pages:u64, flags:PageTableFlags) -> Result<(),MapToError<Size4KiB>> {
+        let first = Page::<Size4KiB>::containing_address(start);
+        for i in 0..pages {
+            let page = first + i;
+            match self.translate(page.start_address()) {
+                Some(_) => {
+                    let current = super::page_flags(self.p4,page.start_address()).unwrap_or(PageTableFlags::empty());
+                    let mut merged = current | flags;
+                    if !(current.contains(PageTableFlags::NO_EXECUTE) && flags.contains(PageTableFlags::NO_EXECUTE)) {
+                        merged.remove(PageTableFlags::NO_EXECUTE);
+                    }
+                    with_page_table(self.p4,|mapper,_|unsafe { mapper.update_flags(page,merged).map(|flush|flush.ignore()) })
+                        .map_err(|_|MapToError::ParentEntryHugePage)?;
+                    crate::smp::tlb::shootdown(page.start_address(),1);//outside with_page_table, whose lock other CPUs may spin on
+                }
+                None => {
+                    let frame = allocate_zeroed_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                    map_user_page(self.p4,page,frame,flags)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves `pages` pages of the `mmap` area and returns their start.
+    pub fn reserve(&self, pages:u64) -> Option<VirtAddr> {
+        let start = self.next_mmap.fetch_add(pages*4096,Ordering::Relaxed);
+        if start + pages*4096 > USER_END - (1 << 32) {//keep the top 4GiB free for stacks
+            return None;
+        }
+        Some(VirtAddr::new(start))
+    }
+
+    /// Gives back a range `reserve` returned, if nothing was reserved after it;
+    /// otherwise it just stays unused.
+    pub fn unreserve(&self, start:VirtAddr, pages:u64) {
+        let _ = self.next_mmap.compare_exchange(start.as_u64() + pages*4096,start.as_u64(),Ordering::Relaxed,Ordering::Relaxed);
+    }
+
+    /// Reserves and maps `pages` pages of zeroed memory for `mmap`.
+    pub fn map_anonymous(&self, pages:u64, flags:PageTableFlags) -> Result<VirtAddr,MapToError<Size4KiB>> {
+        let start = self.reserve(pages).ok_or(MapToError::FrameAllocationFailed)?;
+        self.map_zeroed(start,pages,flags)?;
+        Ok(start)
+    }
+
+    /// Creates a copy of the user part of this address space for `fork`.
+    ///
+    /// No memory is copied: both address spaces map the same frames, writable
+    /// pages become read-only `COPY_ON_WRITE` pages in both, and whoever writes
+    /// first gets a private copy (see `resolve_copy_on_write`). `SHARED` pages
+    /// stay writable and shared.
+    pub fn fork(&self) -> Result<AddressSpace,MapToError<Size4KiB>> {
+        let child = AddressSpace::new()?;
+        child.next_mmap.store(self.next_mmap.load(Ordering::Relaxed),Ordering::Relaxed);
+        let mut pages = Vec::new();
+        with_page_table(self.p4,|_,_|{
+            let table = table_at(self.p4.start_address());
+            for i in USER_P4_ENTRIES {
+                if !table[i].flags().contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                for_each_page(table_at(table[i].addr()),2,(i as u64) << 39,&mut |addr,entry|{
+                    let mut flags = entry.flags();
+                    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                        flags.remove(PageTableFlags::WRITABLE);
+                        flags.insert(COPY_ON_WRITE);
+                        entry.set_flags(flags);
+                    }
+                    pages.push((Page::<Size4KiB>::containing_address(addr),entry.addr(),flags));
+                });
+            }
+        });
+        //other CPUs may still cache the writable mappings of the parent
+        crate::smp::tlb::shootdown(VirtAddr::new(USER_START),0);
+        for (page,frame,flags) in pages {
+            let frame = PhysFrame::containing_address(frame);
+            share_frame(frame);
+            if let Err(err) = map_user_page(child.p4,page,frame,flags) {
+                release_frame(frame);
+                return Err(err);
+            }
+        }
+        Ok(child)
+    }
+
+    /// Gives this address space a private, writable copy of the copy-on-write page
+    /// containing `addr`. Returns false if the page isn't a copy-on-write page.
+    pub fn resolve_copy_on_write(&self, addr:VirtAddr) -> bool {
+        let resolved = with_page_table(self.p4,|_,frame_allocator|{
+            let mut table = table_at(self.p4.start_address());
+            let indices = [addr.p4_index(),addr.p3_index(),addr.p2_index()];
+            for index in indices.iter() {
+                let entry = &table[*index];
+                if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    return false;
+                }
+                table = table_at(entry.addr());
+            }
+            let entry = &mut table[addr.p1_index()];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT | COPY_ON_WRITE) {
+                return false;
+            }
+            let old = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+            let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+            if is_shared(old) {
+                let new = match frame_allocator.allocate_frame() {
+                    Some(frame) => frame,
+                    None => return false,
+                };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        phys_to_virt(old.start_address()).as_ptr::<u8>(),
+                        phys_to_virt(new.start_address()).as_mut_ptr::<u8>(),
+                        4096,
+                    );
+                }
+                entry.set_addr(new.start_address(),flags);
+                release_frame(old);
+            } else {
+                entry.set_flags(flags);//everybody else already made their copy
+            }
+            true
+        });
+        if resolved && self.is_active() {
+            tlb::flush(addr);
+        }
+        resolved
+    }
+
+    /// Removes the `pages` pages starting at `start` from this address space and
+    /// returns their frames, so that they can be mapped somewhere else.
+    ///
+    /// Frames shared copy-on-write are copied first, so the returned frames are
+    /// private. Returns `None`, changing nothing, if a page isn't mapped, and with
+    /// the pages gone if there was no memory for a copy.
+    pub fn take_pages(&self, start:VirtAddr, pages:u64) -> Option<Vec<PhysFrame>> {
+        let first = Page::<Size4KiB>::containing_address(start);
+        if (0..pages).any(|i|self.translate((first + i).start_address()).is_none()) {
+            return None;
+        }
+        let mut frames = Vec::with_capacity(pages as usize);
+        for i in 0..pages {
+            let page = first + i;
+            let (frame,flush) = with_page_table(self.p4,|mapper,_|mapper.unmap(page)).expect("page was checked to be mapped");
+            flush.ignore();//flushed below, on all CPUs
+            if is_shared(frame) {
+                let copy = match allocate_zeroed_frame() {
+                    Some(copy) => copy,
+                    None => {//out of memory: the pages taken so far are lost to the caller
+                        frames.into_iter().chain(core::iter::once(frame)).for_each(release_frame);
+                        crate::smp::tlb::shootdown(first.start_address(),pages);
+                        return None;
+                    }
+                };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                        phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                        4096,
+                    );
+                }
+                release_frame(frame);
+                frames.push(copy);
+            } else {
+                frames.push(frame);
+            }
+        }
+        crate::smp::tlb::shootdown(first.start_address(),pages);
+        Some(frames)
+    }
+
+    /// Unmaps the `pages` pages starting at `start`, skipping pages that aren't mapped.
+    pub fn unmap(&self, start:VirtAddr, pages:u64) {
+        let first = Page::<Size4KiB>::containing_address(start);
+        let mut frames = Vec::new();
+        with_page_table(self.p4,|mapper,_|{
+            for i in 0..pages {
+                if let Ok((frame,flush)) = mapper.unmap(first + i) {
+                    flush.ignore();
+                    frames.push(frame);
+                }
+            }
+        });
+        crate::smp::tlb::shootdown(first.start_address(),pages);
+        frames.into_iter().for_each(release_frame);
+    }
+
+    /// Returns the physical address `addr` is mapped to.
+    pub fn translate(&self, addr:VirtAddr) -> Option<PhysAddr> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let frame = with_page_table(self.p4,|mapper,_|mapper.translate_page(page)).ok()?;
+        Some(frame.start_address() + (addr - page.start_address()))
+    }
+
+    /// Copies `data` to `addr`, which must be mapped. Works whether or not the address space is active.
+    pub fn write_bytes(&self, addr:VirtAddr, data:&[u8]) -> Result<(),MapToError<Size4KiB>> {
+        let mut done = 0;
+        while done < data.len() {
+            let current = addr + done as u64;
+            let in_page = (4096 - current.as_u64() % 4096) as usize;
+            let len = in_page.min(data.len() - done);
+            let phys = self.translate(current).ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(),phys_to_virt(phys).as_mut_ptr::<u8>(),len);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(),"dropping the active address space");
+        let table = unsafe { &mut *phys_to_virt(self.p4.start_address()).as_mut_ptr::<PageTable>() };
+        for i in USER_P4_ENTRIES {
+            free_table(&mut table[i],3);
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.p4) };
+    }
+}
+
+/// Frees the frame behind `entry` and, for table levels above 1, everything below it.
+fn free_table(entry:&mut PageTableEntry, level:usize) {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,//unused, or a huge page we never create in user space
+    };
+    if level > 0 {
+        for child in table_at(frame.start_address()).iter_mut() {
+            free_table(child,level-1);
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    } else {
+        release_frame(frame);//may still be mapped copy-on-write elsewhere
+    }
+    entry.set_unused();
+}
+
+pub(super) fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = GlobalFrameAllocator.allocate_frame()?;
+    unsafe { core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),0,4096) };
+    Some(frame)
+}
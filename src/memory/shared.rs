@@ -0,0 +1,93 @@
+//! Named shared memory objects.
+//!
+//! A shared memory object is a set of zeroed frames that any number of address
+//! spaces can map at the same time. Mappings are marked `SHARED`, so they stay
+//! shared instead of becoming copy-on-write on `fork`. The frames are freed when
+//! the object was unlinked and the last mapping is gone.
+
+use alloc::{collections::BTreeMap,string::String,sync::Arc,vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{VirtAddr,instructions::interrupts,structures::paging::{Page,PageTableFlags,PhysFrame}};
+use crate::syscall::Errno;
+use super::address_space::{allocate_zeroed_frame,release_frame,share_frame,AddressSpace,SHARED};
+use super::map_user_page;
+
+/// Largest shared memory object, in pages.
+pub const MAX_PAGES:u64 = 4096;
+
+lazy_static! {
+    static ref OBJECTS:Mutex<BTreeMap<String,Arc<SharedMemory>>> = Mutex::new(BTreeMap::new());
+}
+
+pub struct SharedMemory {
+    frames:Vec<PhysFrame>,
+}impl SharedMemory {
+    fn new(pages:u64) -> Result<Self,Errno> {
+        let mut frames = Vec::with_capacity(pages as usize);
+        for _ in 0..pages {
+            match allocate_zeroed_frame() {
+                Some(frame) => frames.push(frame),
+                None => {
+                    frames.into_iter().for_each(release_frame);
+                    return Err(Errno::ENOMEM);
+                }
+            }
+        }
+        Ok(SharedMemory {frames})
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.frames.len() as u64
+    }
+
+    /// Maps the object at a free place of `address_space` and returns its start.
+    pub fn map_into(&self, address_space:&AddressSpace, writable:bool) -> Result<VirtAddr,Errno> {
+        let start = address_space.reserve(self.pages()).ok_or(Errno::ENOMEM)?;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE | SHARED;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        for (i,frame) in self.frames.iter().enumerate() {
+            let page = Page::containing_address(start + i as u64*4096);
+            share_frame(*frame);//the mapping is released with the address space or by `unmap`
+            if map_user_page(address_space.p4(),page,*frame,flags).is_err() {
+                release_frame(*frame);
+                address_space.unmap(start,i as u64);
+                address_space.unreserve(start,self.pages());
+                return Err(Errno::ENOMEM);
+            }
+        }
+        Ok(start)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        self.frames.drain(..).for_each(release_frame);
+    }
+}
+
+/// Opens the object called `name`. If it doesn't exist and `create_pages` is
+/// given, it is created with that many pages.
+pub fn open(name:&str, create_pages:Option<u64>) -> Result<Arc<SharedMemory>,Errno> {
+    if let Some(object) = interrupts::without_interrupts(||OBJECTS.lock().get(name).cloned()) {
+        return Ok(object);
+    }
+    let pages = create_pages.ok_or(Errno::ENOENT)?;
+    if pages == 0 || pages > MAX_PAGES {
+        return Err(Errno::EINVAL);
+    }
+    let object = Arc::new(SharedMemory::new(pages)?);
+    //somebody else may have created it in the meantime; theirs wins
+    Ok(interrupts::without_interrupts(||{
+        OBJECTS.lock().entry(String::from(name)).or_insert(object).clone()
+    }))
+}
+
+/// Removes the name. Address spaces that mapped the object keep their mappings.
+pub fn unlink(name:&str) -> Result<(),Errno> {
+    let object = interrupts::without_interrupts(||OBJECTS.lock().remove(name)).ok_or(Errno::ENOENT)?;
+    drop(object);//frees the frames unless they are still mapped somewhere
+    Ok(())
+}
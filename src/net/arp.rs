@@ -0,0 +1,111 @@
+//! ARP: which MAC address an IPv4 address on the link has.
+//!
+//! Packets for an address that isn't resolved yet wait here while requests go out,
+//! and are dropped if no reply comes after a few tries.
+
+use alloc::{collections::BTreeMap,vec::Vec};
+use core::convert::TryInto;
+use crate::time;
+use super::{Ipv4Addr,MacAddr};
+
+pub const OP_REQUEST:u16 = 1;
+pub const OP_REPLY:u16 = 2;
+const PACKET_SIZE:usize = 28;
+
+/// How long an entry stays valid, and how often and how many times a request is sent.
+const ENTRY_LIFETIME:u64 = 60*time::TICKS_PER_SECOND;
+const RETRY_INTERVAL:u64 = time::TICKS_PER_SECOND;
+const MAX_REQUESTS:u32 = 3;
+/// Packets kept waiting per address.
+const MAX_WAITING:usize = 16;
+
+pub struct Arp {
+    pub op:u16,
+    pub sender_mac:MacAddr,
+    pub sender_ip:Ipv4Addr,
+    pub target_ip:Ipv4Addr,
+}impl Arp {
+    /// Only Ethernet/IPv4 packets are accepted.
+    pub fn parse(data:&[u8]) -> Option<Self> {
+        if data.len() < PACKET_SIZE || data[0..6] != [0,1,8,0,6,4] {
+            return None;
+        }
+        Some(Arp {
+            op:u16::from_be_bytes([data[6],data[7]]),
+            sender_mac:MacAddr(data[8..14].try_into().unwrap()),
+            sender_ip:Ipv4Addr(data[14..18].try_into().unwrap()),
+            target_ip:Ipv4Addr(data[24..28].try_into().unwrap()),
+        })
+    }
+}
+
+pub fn build(op:u16, sender_mac:MacAddr, sender_ip:Ipv4Addr, target_mac:MacAddr, target_ip:Ipv4Addr) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PACKET_SIZE);
+    packet.extend_from_slice(&[0,1,8,0,6,4]);//Ethernet, IPv4, address sizes
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&sender_mac.0);
+    packet.extend_from_slice(&sender_ip.0);
+    packet.extend_from_slice(&target_mac.0);
+    packet.extend_from_slice(&target_ip.0);
+    packet
+}
+
+struct Waiting {
+    packets:Vec<Vec<u8>>,
+    requests:u32,
+    next_request:u64,
+}
+
+/// Resolved addresses and the packets waiting for resolution, per interface.
+#[derive(Default)]
+pub struct Cache {
+    entries:BTreeMap<(usize,Ipv4Addr),(MacAddr,u64)>,
+    waiting:BTreeMap<(usize,Ipv4Addr),Waiting>,
+}impl Cache {
+    pub fn lookup(&self, interface:usize, ip:Ipv4Addr) -> Option<MacAddr> {
+        match self.entries.get(&(interface,ip)) {
+            Some(&(mac,expires)) if expires > time::ticks() => Some(mac),
+            _ => None,
+        }
+    }
+
+    /// Records `mac` for `ip` and returns the packets that waited for it.
+    pub fn insert(&mut self, interface:usize, ip:Ipv4Addr, mac:MacAddr) -> Vec<Vec<u8>> {
+        self.entries.insert((interface,ip),(mac,time::ticks() + ENTRY_LIFETIME));
+        self.waiting.remove(&(interface,ip)).map_or_else(Vec::new,|waiting|waiting.packets)
+    }
+
+    /// Keeps `packet` until `ip` is resolved. Returns whether a request should go out
+    /// now, which is when nothing waited for `ip` yet.
+    pub fn wait(&mut self, interface:usize, ip:Ipv4Addr, packet:Vec<u8>) -> bool {
+        let now = time::ticks();
+        let waiting = self.waiting.entry((interface,ip)).or_insert_with(||Waiting {packets:Vec::new(), requests:0, next_request:now});
+        if waiting.packets.len() < MAX_WAITING {
+            waiting.packets.push(packet);
+        }
+        if waiting.requests == 0 {
+            waiting.requests = 1;
+            waiting.next_request = now + RETRY_INTERVAL;
+            return true;
+        }
+        false
+    }
+
+    /// The addresses to send another request for; gives up on the others.
+    pub fn retries(&mut self) -> Vec<(usize,Ipv4Addr)> {
+        let now = time::ticks();
+        let mut again = Vec::new();
+        let due:Vec<(usize,Ipv4Addr)> = self.waiting.iter().filter(|(_,waiting)|waiting.next_request <= now).map(|(&key,_)|key).collect();
+        for key in due {
+            let waiting = self.waiting.get_mut(&key).unwrap();
+            if waiting.requests >= MAX_REQUESTS {
+                self.waiting.remove(&key);//the packets are lost, as if the link had dropped them
+                continue;
+            }
+            waiting.requests += 1;
+            waiting.next_request = now + RETRY_INTERVAL;
+            again.push(key);
+        }
+        again
+    }
+}
@@ -0,0 +1,145 @@
+//! A DHCP client: asks the network for an address, gateway and DNS server.
+//!
+//! Only the initial exchange (discover, offer, request, acknowledgement) is done;
+//! leases are not renewed, which the lease times of QEMU and most home networks
+//! make up for during a session.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use crate::{println,time};
+use crate::syscall::Errno;
+use super::{Config,Ipv4Addr,MacAddr,UdpSocket};
+
+const CLIENT_PORT:u16 = 68;
+const SERVER_PORT:u16 = 67;
+
+const OP_REQUEST:u8 = 1;
+const OP_REPLY:u8 = 2;
+const FLAG_BROADCAST:u16 = 0x8000;
+const MAGIC:[u8;4] = [99,130,83,99];
+/// Where the options start, after the fixed fields and the magic cookie.
+const OPTIONS:usize = 240;
+
+const OPTION_PAD:u8 = 0;
+const OPTION_SUBNET_MASK:u8 = 1;
+const OPTION_ROUTER:u8 = 3;
+const OPTION_DNS:u8 = 6;
+const OPTION_REQUESTED_ADDRESS:u8 = 50;
+const OPTION_MESSAGE_TYPE:u8 = 53;
+const OPTION_SERVER_ID:u8 = 54;
+const OPTION_PARAMETERS:u8 = 55;
+const OPTION_END:u8 = 255;
+
+const DISCOVER:u8 = 1;
+const OFFER:u8 = 2;
+const REQUEST:u8 = 3;
+const ACK:u8 = 5;
+const NAK:u8 = 6;
+
+/// How long to wait for each answer, and how many times to ask.
+const TIMEOUT:u64 = 2*time::TICKS_PER_SECOND;
+const ATTEMPTS:u32 = 3;
+
+/// The parts of a server's reply that matter here.
+struct Reply {
+    kind:u8,
+    address:Ipv4Addr,
+    server:Option<Ipv4Addr>,
+    prefix:u8,
+    gateway:Option<Ipv4Addr>,
+    dns:Option<Ipv4Addr>,
+}
+
+fn build(xid:u32, mac:MacAddr, kind:u8, requested:Option<(Ipv4Addr,Ipv4Addr)>) -> Vec<u8> {
+    let mut message = alloc::vec![0u8;OPTIONS];
+    message[0..4].copy_from_slice(&[OP_REQUEST,1,6,0]);//Ethernet addresses
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());//no address to answer to yet
+    message[28..34].copy_from_slice(&mac.0);
+    message[236..240].copy_from_slice(&MAGIC);
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE,1,kind]);
+    if let Some((address,server)) = requested {
+        message.extend_from_slice(&[OPTION_REQUESTED_ADDRESS,4]);
+        message.extend_from_slice(&address.0);
+        message.extend_from_slice(&[OPTION_SERVER_ID,4]);
+        message.extend_from_slice(&server.0);
+    }
+    message.extend_from_slice(&[OPTION_PARAMETERS,3,OPTION_SUBNET_MASK,OPTION_ROUTER,OPTION_DNS]);
+    message.push(OPTION_END);
+    message
+}
+
+fn parse(data:&[u8], xid:u32) -> Option<Reply> {
+    if data.len() < OPTIONS || data[0] != OP_REPLY || data[4..8] != xid.to_be_bytes() || data[236..240] != MAGIC {
+        return None;
+    }
+    let address = |bytes:&[u8]|bytes.get(..4).map(|bytes|Ipv4Addr(bytes.try_into().unwrap()));
+    let mut reply = Reply {kind:0, address:address(&data[16..20])?, server:None, prefix:24, gateway:None, dns:None};
+    let mut i = OPTIONS;
+    while i < data.len() {
+        match data[i] {
+            OPTION_PAD => i += 1,
+            OPTION_END => break,
+            option => {
+                let len = *data.get(i + 1)? as usize;
+                let value = data.get(i + 2..i + 2 + len)?;
+                match option {
+                    OPTION_MESSAGE_TYPE => reply.kind = *value.first()?,
+                    OPTION_SUBNET_MASK => reply.prefix = address(value)?.to_u32().count_ones() as u8,
+                    OPTION_ROUTER => reply.gateway = address(value),
+                    OPTION_DNS => reply.dns = address(value),
+                    OPTION_SERVER_ID => reply.server = address(value),
+                    _ => {}
+                }
+                i += 2 + len;
+            }
+        }
+    }
+    Some(reply)
+}
+
+/// Sends `message` and waits for a reply of one of `kinds`, trying a few times.
+fn exchange(socket:&mut UdpSocket, message:&[u8], xid:u32, kinds:&[u8]) -> Result<Reply,Errno> {
+    let mut buf = alloc::vec![0u8;1500];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(message,Ipv4Addr::BROADCAST,SERVER_PORT)?;
+        let deadline = time::ticks() + TIMEOUT;
+        loop {
+            let now = time::ticks();
+            if now >= deadline {
+                break;
+            }
+            socket.set_timeout(Some(deadline - now));
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len,_,_)) => len,
+                Err(Errno::ETIMEDOUT) => break,
+                Err(err) => return Err(err),
+            };
+            if let Some(reply) = parse(&buf[..len],xid) {
+                if kinds.contains(&reply.kind) {
+                    return Ok(reply);
+                }
+            }
+        }
+    }
+    Err(Errno::ETIMEDOUT)
+}
+
+/// Configures interface `name` through DHCP and returns what it got.
+pub fn configure(name:&str) -> Result<Config,Errno> {
+    let mac = super::interfaces().into_iter().find(|(interface,_,_)|interface == name).ok_or(Errno::ENODEV)?.1;
+    let mut socket = UdpSocket::bind(CLIENT_PORT)?;
+    socket.bind_interface(name)?;
+    let xid = u32::from_be_bytes([mac.0[2],mac.0[3],mac.0[4],mac.0[5]]) ^ time::ticks() as u32;
+
+    let offer = exchange(&mut socket,&build(xid,mac,DISCOVER,None),xid,&[OFFER])?;
+    let server = offer.server.ok_or(Errno::EIO)?;
+    let ack = exchange(&mut socket,&build(xid,mac,REQUEST,Some((offer.address,server))),xid,&[ACK,NAK])?;
+    if ack.kind == NAK {
+        return Err(Errno::ECONNREFUSED);
+    }
+    let config = Config {address:ack.address, prefix:ack.prefix, gateway:ack.gateway, dns:ack.dns};
+    super::configure(name,config)?;
+    println!("{}: {}/{} via DHCP from {}",name,config.address,config.prefix,server);
+    Ok(config)
+}
@@ -0,0 +1,48 @@
+//! Ethernet II frames.
+
+use core::convert::TryInto;
+use core::fmt;
+
+pub const HEADER_SIZE:usize = 14;
+pub const ETHERTYPE_IPV4:u16 = 0x0800;
+pub const ETHERTYPE_ARP:u16 = 0x0806;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Default)]
+pub struct MacAddr(pub [u8;6]);
+impl MacAddr {
+    pub const BROADCAST:MacAddr = MacAddr([0xff;6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let b = self.0;
+        write!(f,"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",b[0],b[1],b[2],b[3],b[4],b[5])
+    }
+}
+
+/// The header fields of a frame and its payload.
+pub struct Frame<'a> {
+    pub destination:MacAddr,
+    pub source:MacAddr,
+    pub ethertype:u16,
+    pub payload:&'a [u8],
+}impl<'a> Frame<'a> {
+    pub fn parse(data:&'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        Some(Frame {
+            destination:MacAddr(data[0..6].try_into().unwrap()),
+            source:MacAddr(data[6..12].try_into().unwrap()),
+            ethertype:u16::from_be_bytes([data[12],data[13]]),
+            payload:&data[HEADER_SIZE..],
+        })
+    }
+}
+
+/// Writes the header of a frame into the first `HEADER_SIZE` bytes of `frame`.
+pub fn write_header(frame:&mut [u8], destination:MacAddr, source:MacAddr, ethertype:u16) {
+    frame[0..6].copy_from_slice(&destination.0);
+    frame[6..12].copy_from_slice(&source.0);
+    frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+}
@@ -0,0 +1,78 @@
+//! ICMP echo: answering pings and sending them.
+
+use alloc::{collections::BTreeMap,vec::Vec};
+use crate::time;
+use crate::syscall::Errno;
+use super::ipv4::{self,Ipv4Addr,Packet};
+use super::Stack;
+
+const HEADER_SIZE:usize = 8;
+const TYPE_ECHO_REPLY:u8 = 0;
+const TYPE_ECHO_REQUEST:u8 = 8;
+
+/// The pings in flight, by identifier and sequence number, with the tick their
+/// reply came in.
+#[derive(Default)]
+pub struct State {
+    replies:BTreeMap<(u16,u16),Option<u64>>,
+    next_id:u16,
+}
+
+fn build(kind:u8, id:u16, sequence:u16, data:&[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + data.len());
+    message.extend_from_slice(&[kind,0,0,0]);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(data);
+    let sum = ipv4::checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
+
+pub(super) fn receive(stack:&mut Stack, index:usize, packet:&Packet) {
+    let message = packet.payload;
+    if message.len() < HEADER_SIZE || ipv4::checksum(message) != 0 {
+        return;
+    }
+    let id = u16::from_be_bytes([message[4],message[5]]);
+    let sequence = u16::from_be_bytes([message[6],message[7]]);
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            //answers for broadcasts come from our own address
+            let source = match stack.interfaces[index].config.address {
+                _ if packet.destination.is_loopback() => packet.destination,
+                address if address.is_unspecified() => return,
+                address => address,
+            };
+            let reply = build(TYPE_ECHO_REPLY,id,sequence,&message[HEADER_SIZE..]);
+            let _ = stack.send_ip(Some(index),source,packet.source,ipv4::PROTOCOL_ICMP,&reply);
+        }
+        TYPE_ECHO_REPLY => {
+            if let Some(reply @ None) = stack.icmp.replies.get_mut(&(id,sequence)) {
+                *reply = Some(time::ticks());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends an echo request to `destination` and waits up to `timeout` ticks for the
+/// reply. Returns the round trip time in ticks.
+pub fn ping(destination:Ipv4Addr, timeout:u64) -> Result<u64,Errno> {
+    let (key,sent) = super::with_stack(|stack|{
+        let (index,source) = stack.source_for(destination,None)?;
+        stack.icmp.next_id = stack.icmp.next_id.wrapping_add(1);
+        let key = (stack.icmp.next_id,1);
+        let sent = time::ticks();
+        stack.icmp.replies.insert(key,None);
+        let request = build(TYPE_ECHO_REQUEST,key.0,key.1,&sent.to_be_bytes());
+        if let Err(err) = stack.send_ip(Some(index),source,destination,ipv4::PROTOCOL_ICMP,&request) {
+            stack.icmp.replies.remove(&key);
+            return Err(err);
+        }
+        Ok((key,sent))
+    })?;
+    let received = super::wait_until(|stack|stack.icmp.replies.get(&key).copied().flatten(),Some(timeout));
+    super::with_stack(|stack|stack.icmp.replies.remove(&key));
+    received.map(|received|received - sent).ok_or(Errno::ETIMEDOUT)
+}
@@ -0,0 +1,148 @@
+//! IPv4 addresses and headers. Fragments are not reassembled but dropped, and
+//! outgoing packets must fit the link.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+pub const HEADER_SIZE:usize = 20;
+pub const PROTOCOL_ICMP:u8 = 1;
+pub const PROTOCOL_TCP:u8 = 6;
+pub const PROTOCOL_UDP:u8 = 17;
+const DEFAULT_TTL:u8 = 64;
+const FLAG_MORE_FRAGMENTS:u16 = 0x2000;
+const FRAGMENT_OFFSET:u16 = 0x1fff;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Default)]
+pub struct Ipv4Addr(pub [u8;4]);
+impl Ipv4Addr {
+    pub const UNSPECIFIED:Ipv4Addr = Ipv4Addr([0;4]);
+    pub const BROADCAST:Ipv4Addr = Ipv4Addr([255;4]);
+    pub const LOCALHOST:Ipv4Addr = Ipv4Addr([127,0,0,1]);
+
+    pub const fn new(a:u8, b:u8, c:u8, d:u8) -> Self {
+        Ipv4Addr([a,b,c,d])
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+    pub fn from_u32(value:u32) -> Self {
+        Ipv4Addr(value.to_be_bytes())
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Self::UNSPECIFIED
+    }
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+
+    /// Whether `self` and `other` are on the same network of `prefix` bits.
+    pub fn same_network(self, other:Ipv4Addr, prefix:u8) -> bool {
+        let mask = netmask(prefix);
+        self.to_u32() & mask == other.to_u32() & mask
+    }
+
+    /// Parses "a.b.c.d".
+    pub fn parse(text:&str) -> Option<Self> {
+        let mut octets = [0u8;4];
+        let mut parts = text.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next()?.parse().ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Ipv4Addr(octets)),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}.{}.{}.{}",self.0[0],self.0[1],self.0[2],self.0[3])
+    }
+}
+
+/// The mask of a network prefix of `prefix` bits.
+pub fn netmask(prefix:u8) -> u32 {
+    match prefix {
+        0 => 0,
+        prefix => !0u32 << (32 - prefix.min(32) as u32),
+    }
+}
+
+/// Adds `data` to a running ones' complement sum of 16-bit words.
+pub fn sum(mut acc:u32, data:&[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        acc += u16::from_be_bytes([word[0],word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+/// Folds a sum from `sum` into the internet checksum.
+pub fn finish(mut acc:u32) -> u16 {
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+pub fn checksum(data:&[u8]) -> u16 {
+    finish(sum(0,data))
+}
+
+/// The sum over the pseudo header TCP and UDP checksums include.
+pub fn pseudo_header_sum(source:Ipv4Addr, destination:Ipv4Addr, protocol:u8, len:usize) -> u32 {
+    let acc = sum(sum(0,&source.0),&destination.0);
+    acc + protocol as u32 + len as u32
+}
+
+/// A received packet.
+pub struct Packet<'a> {
+    pub source:Ipv4Addr,
+    pub destination:Ipv4Addr,
+    pub protocol:u8,
+    pub payload:&'a [u8],
+}impl<'a> Packet<'a> {
+    /// None for malformed packets and fragments.
+    pub fn parse(data:&'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || data[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (data[0] & 0xf) as usize*4;
+        let total_len = u16::from_be_bytes([data[2],data[3]]) as usize;
+        if header_len < HEADER_SIZE || total_len < header_len || total_len > data.len() || checksum(&data[..header_len]) != 0 {
+            return None;
+        }
+        let fragment = u16::from_be_bytes([data[6],data[7]]);
+        if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+            return None;
+        }
+        Some(Packet {
+            source:Ipv4Addr(data[12..16].try_into().unwrap()),
+            destination:Ipv4Addr(data[16..20].try_into().unwrap()),
+            protocol:data[9],
+            payload:&data[header_len..total_len],//drops the link's padding
+        })
+    }
+}
+
+/// Builds a packet with a header of the default size.
+pub fn build(source:Ipv4Addr, destination:Ipv4Addr, protocol:u8, id:u16, payload:&[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+    packet.extend_from_slice(&[0x45,0]);
+    packet.extend_from_slice(&((HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0,0,DEFAULT_TTL,protocol,0,0]);
+    packet.extend_from_slice(&source.0);
+    packet.extend_from_slice(&destination.0);
+    let sum = checksum(&packet);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
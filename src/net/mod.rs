@@ -0,0 +1,358 @@
+//! The network stack: Ethernet, ARP, IPv4, ICMP, UDP and TCP, plus a DHCP client.
+//!
+//! Network devices hand received frames over through `NetDevice::receive` and call
+//! `wake` when there are some. The "net" thread started by `init` then runs them
+//! through the stack, and also drives its timers (ARP and TCP retransmissions).
+//! All protocol state lives in one `Stack` behind a spin lock, which is only held
+//! for the processing itself: sockets (`UdpSocket`, `TcpListener`, `TcpStream`)
+//! sleep on a wait queue outside of it until the state they wait for shows up.
+//!
+//! Interface "lo" is the loopback device with 127.0.0.1; NIC drivers add "eth0"
+//! and so on, which `dhcp::configure` or `configure` give an address.
+
+use alloc::{string::String,sync::Arc,vec::Vec};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool,Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::console::Output;
+use crate::shell;
+use crate::sync::WaitQueue;
+use crate::syscall::Errno;
+use crate::task;
+
+pub mod ethernet;
+pub mod arp;
+pub mod ipv4;
+pub mod icmp;
+pub mod udp;
+pub mod tcp;
+pub mod dhcp;
+pub mod loopback;
+pub mod virtio;
+
+pub use ethernet::MacAddr;
+pub use ipv4::Ipv4Addr;
+pub use icmp::ping;
+pub use udp::UdpSocket;
+pub use tcp::{TcpListener,TcpStream};
+
+/// Largest IP packet on Ethernet.
+pub const ETHERNET_MTU:usize = 1500;
+/// How often the net thread runs the timers when there is nothing to receive.
+const TIMER_INTERVAL:u64 = 5;
+/// Frames taken from one device in a row before the others get their turn.
+const RECEIVE_BUDGET:usize = 64;
+/// Ports handed out for sockets bound to port 0.
+const EPHEMERAL_PORTS:core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// A network card, or the loopback device.
+pub trait NetDevice:Send+Sync {
+    fn mac(&self) -> MacAddr;
+    /// Largest IP packet the link carries.
+    fn mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
+    /// Queues an Ethernet frame for sending. Frames may be dropped when the device
+    /// is busy, as on any link.
+    fn transmit(&self, frame:&[u8]) -> Result<(),Errno>;
+    /// The next received frame, if any.
+    fn receive(&self) -> Option<Vec<u8>>;
+    /// Loopback frames go back to the sender, so no ARP is needed.
+    fn is_loopback(&self) -> bool {
+        false
+    }
+}
+
+/// The IPv4 configuration of an interface. An unspecified address means not
+/// configured yet; such an interface accepts any packet, which DHCP relies on.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct Config {
+    pub address:Ipv4Addr,
+    pub prefix:u8,
+    pub gateway:Option<Ipv4Addr>,
+    pub dns:Option<Ipv4Addr>,
+}impl Config {
+    fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.address.to_u32() | !ipv4::netmask(self.prefix))
+    }
+}
+
+struct Interface {
+    name:String,
+    device:Arc<dyn NetDevice>,
+    config:Config,
+}
+
+pub(crate) struct Stack {
+    interfaces:Vec<Interface>,
+    arp:arp::Cache,
+    icmp:icmp::State,
+    udp:udp::Table,
+    tcp:tcp::Table,
+    next_id:u16,
+    next_port:u16,
+}impl Stack {
+    /// The interface packets to `destination` leave through and the next hop, which
+    /// is `Ipv4Addr::BROADCAST` for broadcasts. `interface` picks the interface.
+    fn route(&self, destination:Ipv4Addr, interface:Option<usize>) -> Result<(usize,Ipv4Addr),Errno> {
+        let index = if destination.is_loopback() {
+            self.interfaces.iter().position(|interface|interface.device.is_loopback())
+        } else if interface.is_some() {
+            interface
+        } else {
+            let candidates = ||self.interfaces.iter().enumerate().filter(|(_,interface)|!interface.device.is_loopback());
+            let direct = candidates().find(|(_,interface)|{
+                !interface.config.address.is_unspecified() && destination.same_network(interface.config.address,interface.config.prefix)
+            });
+            direct.or_else(||candidates().find(|(_,interface)|interface.config.gateway.is_some()))
+                .or_else(||if destination == Ipv4Addr::BROADCAST {candidates().next()} else {None})
+                .map(|(index,_)|index)
+        };
+        let index = index.ok_or(Errno::ENETUNREACH)?;
+        let config = &self.interfaces.get(index).ok_or(Errno::ENODEV)?.config;
+        let next_hop = if destination == Ipv4Addr::BROADCAST || (!config.address.is_unspecified() && destination == config.broadcast()) {
+            Ipv4Addr::BROADCAST
+        } else if config.address.is_unspecified() || destination.same_network(config.address,config.prefix) {
+            destination
+        } else {
+            config.gateway.ok_or(Errno::EHOSTUNREACH)?
+        };
+        Ok((index,next_hop))
+    }
+
+    /// The interface and source address for packets to `destination`.
+    pub(crate) fn source_for(&self, destination:Ipv4Addr, interface:Option<usize>) -> Result<(usize,Ipv4Addr),Errno> {
+        let (index,_) = self.route(destination,interface)?;
+        Ok((index,self.interfaces[index].config.address))
+    }
+
+    pub(crate) fn interface_index(&self, name:&str) -> Option<usize> {
+        self.interfaces.iter().position(|interface|interface.name == name)
+    }
+
+    /// Sends an IP packet, resolving the next hop's MAC address first if needed.
+    pub(crate) fn send_ip(&mut self, interface:Option<usize>, source:Ipv4Addr, destination:Ipv4Addr, protocol:u8, payload:&[u8]) -> Result<(),Errno> {
+        let (index,next_hop) = self.route(destination,interface)?;
+        let device = self.interfaces[index].device.clone();
+        if ipv4::HEADER_SIZE + payload.len() > device.mtu() {
+            return Err(Errno::EMSGSIZE);
+        }
+        self.next_id = self.next_id.wrapping_add(1);
+        let packet = ipv4::build(source,destination,protocol,self.next_id,payload);
+        let mac = if next_hop == Ipv4Addr::BROADCAST {
+            Some(MacAddr::BROADCAST)
+        } else if device.is_loopback() {
+            Some(device.mac())
+        } else {
+            self.arp.lookup(index,next_hop)
+        };
+        match mac {
+            Some(mac) => self.transmit(index,mac,ethernet::ETHERTYPE_IPV4,&packet),
+            None => {
+                if self.arp.wait(index,next_hop,packet) {
+                    self.arp_request(index,next_hop);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arp_request(&mut self, index:usize, ip:Ipv4Addr) {
+        let interface = &self.interfaces[index];
+        let request = arp::build(arp::OP_REQUEST,interface.device.mac(),interface.config.address,MacAddr::default(),ip);
+        self.transmit(index,MacAddr::BROADCAST,ethernet::ETHERTYPE_ARP,&request);
+    }
+
+    fn transmit(&self, index:usize, destination:MacAddr, ethertype:u16, payload:&[u8]) {
+        let device = &self.interfaces[index].device;
+        let mut frame = alloc::vec![0u8;ethernet::HEADER_SIZE + payload.len()];
+        ethernet::write_header(&mut frame,destination,device.mac(),ethertype);
+        frame[ethernet::HEADER_SIZE..].copy_from_slice(payload);
+        let _ = device.transmit(&frame);//lost like a frame lost on the wire
+    }
+
+    /// Runs a frame received on interface `index` through the stack.
+    fn receive(&mut self, index:usize, data:&[u8]) {
+        let frame = match ethernet::Frame::parse(data) {
+            Some(frame) => frame,
+            None => return,
+        };
+        let (mac,config) = (self.interfaces[index].device.mac(),self.interfaces[index].config);
+        if frame.destination != mac && frame.destination != MacAddr::BROADCAST {
+            return;
+        }
+        match frame.ethertype {
+            ethernet::ETHERTYPE_ARP => {
+                let packet = match arp::Arp::parse(frame.payload) {
+                    Some(packet) => packet,
+                    None => return,
+                };
+                if config.address.is_unspecified() || packet.target_ip != config.address {
+                    return;
+                }
+                for waiting in self.arp.insert(index,packet.sender_ip,packet.sender_mac) {
+                    self.transmit(index,packet.sender_mac,ethernet::ETHERTYPE_IPV4,&waiting);
+                }
+                if packet.op == arp::OP_REQUEST {
+                    let reply = arp::build(arp::OP_REPLY,mac,config.address,packet.sender_mac,packet.sender_ip);
+                    self.transmit(index,packet.sender_mac,ethernet::ETHERTYPE_ARP,&reply);
+                }
+            }
+            ethernet::ETHERTYPE_IPV4 => {
+                let packet = match ipv4::Packet::parse(frame.payload) {
+                    Some(packet) => packet,
+                    None => return,
+                };
+                let for_us = config.address.is_unspecified() || packet.destination == config.address
+                    || packet.destination == Ipv4Addr::BROADCAST || packet.destination == config.broadcast()
+                    || (self.interfaces[index].device.is_loopback() && packet.destination.is_loopback());
+                if !for_us {
+                    return;
+                }
+                match packet.protocol {
+                    ipv4::PROTOCOL_ICMP => icmp::receive(self,index,&packet),
+                    ipv4::PROTOCOL_UDP => udp::receive(self,index,&packet),
+                    ipv4::PROTOCOL_TCP => tcp::receive(self,&packet),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn timers(&mut self) {
+        for (index,ip) in self.arp.retries() {
+            self.arp_request(index,ip);
+        }
+        tcp::timers(self);
+    }
+
+    /// A free port from `EPHEMERAL_PORTS`, given which are taken.
+    pub(crate) fn ephemeral_port(&mut self, taken:impl Fn(&Stack,u16)->bool) -> Result<u16,Errno> {
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_port;
+            self.next_port = if port == *EPHEMERAL_PORTS.end() {*EPHEMERAL_PORTS.start()} else {port + 1};
+            if !taken(self,port) {
+                return Ok(port);
+            }
+        }
+        Err(Errno::EADDRINUSE)
+    }
+}
+
+lazy_static! {
+    static ref STACK:Mutex<Stack> = Mutex::new(Stack {
+        interfaces:Vec::new(),
+        arp:arp::Cache::default(),
+        icmp:icmp::State::default(),
+        udp:udp::Table::default(),
+        tcp:tcp::Table::default(),
+        next_id:0,
+        next_port:*EPHEMERAL_PORTS.start(),
+    });
+    /// The net thread, waiting for received frames.
+    static ref RECEIVER:WaitQueue = WaitQueue::new();
+    /// Threads in socket calls, waiting for the stack to change.
+    static ref SOCKETS:WaitQueue = WaitQueue::new();
+}
+/// Set by `wake` when devices have frames for the net thread.
+static PENDING:AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` on the stack, then wakes the sockets so they check what changed.
+pub(crate) fn with_stack<R>(f:impl FnOnce(&mut Stack)->R) -> R {
+    let result = interrupts::without_interrupts(||f(&mut STACK.lock()));
+    SOCKETS.notify_all();//not under the stack lock: waiters take it in their condition
+    result
+}
+
+/// Sleeps until `f` returns Some, evaluating it on the stack after every change.
+/// Gives up with None after `timeout` ticks, if there is one.
+pub(crate) fn wait_until<R>(mut f:impl FnMut(&mut Stack)->Option<R>, timeout:Option<u64>) -> Option<R> {
+    let mut result = None;
+    let mut condition = ||{
+        result = f(&mut STACK.lock());//the wait queue runs this with interrupts off
+        result.is_some()
+    };
+    match timeout {
+        Some(timeout) => {
+            SOCKETS.wait_until_timeout(&mut condition,timeout);
+        }
+        None => SOCKETS.wait_until(&mut condition),
+    }
+    result
+}
+
+/// Called by device drivers, also from interrupt handlers, when frames arrived.
+pub fn wake() {
+    PENDING.store(true,Ordering::Release);
+    RECEIVER.notify_all();
+}
+
+/// Adds an unconfigured interface and returns its index.
+pub fn add_interface(name:&str, device:Arc<dyn NetDevice>) -> usize {
+    with_stack(|stack|{
+        stack.interfaces.push(Interface {name:String::from(name), device, config:Config::default()});
+        stack.interfaces.len() - 1
+    })
+}
+
+/// Sets the address of interface `name`. Fails with `ENODEV` if there is none.
+pub fn configure(name:&str, config:Config) -> Result<(),Errno> {
+    with_stack(|stack|{
+        let index = stack.interface_index(name).ok_or(Errno::ENODEV)?;
+        stack.interfaces[index].config = config;
+        Ok(())
+    })
+}
+
+pub fn config(name:&str) -> Option<Config> {
+    with_stack(|stack|stack.interface_index(name).map(|index|stack.interfaces[index].config))
+}
+
+/// The interfaces with their MAC addresses and configurations.
+pub fn interfaces() -> Vec<(String,MacAddr,Config)> {
+    with_stack(|stack|stack.interfaces.iter().map(|interface|(interface.name.clone(),interface.device.mac(),interface.config)).collect())
+}
+
+/// Runs the frames the devices received through the stack, and the timers.
+fn poll() {
+    with_stack(|stack|{
+        'devices: for index in 0..stack.interfaces.len() {
+            let device = stack.interfaces[index].device.clone();
+            for _ in 0..RECEIVE_BUDGET {
+                match device.receive() {
+                    Some(frame) => stack.receive(index,&frame),
+                    None => continue 'devices,
+                }
+            }
+            PENDING.store(true,Ordering::Release);//more to do in the next round
+        }
+        stack.timers();
+    });
+}
+
+/// The shell's `ifconfig`.
+fn ifconfig(out:&mut Output, _args:&[&str]) -> Result<(),Errno> {
+    for (name,mac,config) in interfaces() {
+        let _ = write!(out,"{:<6}{}  {}/{}",name,mac,config.address,config.prefix);
+        if let Some(gateway) = config.gateway {
+            let _ = write!(out," via {}",gateway);
+        }
+        let _ = writeln!(out);
+    }
+    Ok(())
+}
+
+/// Sets up the loopback interface and starts the net thread. NIC drivers can add
+/// their interfaces before or after.
+pub fn init() {
+    add_interface("lo",Arc::new(loopback::Loopback::new()));
+    configure("lo",Config {address:Ipv4Addr::LOCALHOST, prefix:8, gateway:None, dns:None}).unwrap();
+    let _ = shell::register(shell::Command {name:"ifconfig", help:"lists the network interfaces", run:ifconfig});
+    task::spawn(||loop {
+        RECEIVER.wait_until_timeout(||PENDING.swap(false,Ordering::AcqRel),TIMER_INTERVAL);
+        poll();
+    });
+}
@@ -0,0 +1,755 @@
+//! TCP connections and the stream socket API.
+//!
+//! Retransmission is go-back-N: when the timer runs out everything not acknowledged
+//! yet goes out again, and the timeout doubles. Out-of-order segments are dropped
+//! rather than queued, which the sender's retransmissions make up for. There are
+//! no window scaling, SACK or congestion control.
+
+use alloc::{collections::{BTreeMap,VecDeque},vec::Vec};
+use crate::time;
+use crate::syscall::Errno;
+use super::ipv4::{self,Ipv4Addr,Packet};
+use super::Stack;
+
+const HEADER_SIZE:usize = 20;
+
+const FIN:u8 = 0x01;
+const SYN:u8 = 0x02;
+const RST:u8 = 0x04;
+const PSH:u8 = 0x08;
+const ACK:u8 = 0x10;
+
+const OPTION_END:u8 = 0;
+const OPTION_NOP:u8 = 1;
+const OPTION_MSS:u8 = 2;
+
+/// The segment size assumed when the peer does not tell.
+const DEFAULT_MSS:usize = 536;
+const SEND_BUFFER:usize = 16*1024;
+const RECV_BUFFER:usize = 16*1024;
+/// Connections waiting to be accepted per listener.
+const BACKLOG:usize = 8;
+
+const INITIAL_RTO:u64 = time::TICKS_PER_SECOND;
+const MAX_RTO:u64 = 60*time::TICKS_PER_SECOND;
+/// Retransmissions before a connection, or a connection attempt, is given up.
+const MAX_RETRIES:u32 = 6;
+const MAX_SYN_RETRIES:u32 = 3;
+const TIME_WAIT:u64 = 2*time::TICKS_PER_SECOND;
+/// How long a closed socket's connection waits for the peer's FIN.
+const ORPHAN_TIMEOUT:u64 = 10*time::TICKS_PER_SECOND;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// A segment to send, built while a connection is borrowed.
+struct Segment {
+    source:Ipv4Addr,
+    destination:Ipv4Addr,
+    data:Vec<u8>,
+}
+
+struct Connection {
+    local:(Ipv4Addr,u16),
+    remote:(Ipv4Addr,u16),
+    state:State,
+    /// The first unacknowledged sequence number; the SYN, the data in `send` and the
+    /// FIN follow it.
+    snd_una:u32,
+    snd_wnd:u32,
+    mss:usize,
+    rcv_nxt:u32,
+    /// Data not acknowledged yet, of which the first `sent` bytes went out.
+    send:VecDeque<u8>,
+    sent:usize,
+    syn_pending:bool,
+    fin_sent:bool,
+    /// Set by `shutdown`: a FIN follows the data.
+    closing:bool,
+    recv:VecDeque<u8>,
+    /// The peer's FIN arrived.
+    eof:bool,
+    error:Option<Errno>,
+    rto:u64,
+    retransmit_at:Option<u64>,
+    retries:u32,
+    /// When the connection is dropped, in TimeWait or when orphaned.
+    deadline:Option<u64>,
+    /// The listener's port while waiting to be accepted.
+    listener:Option<u16>,
+    /// The socket was dropped; the connection goes away once it is closed.
+    orphan:bool,
+    /// The window in the last segment sent.
+    advertised:u32,
+}impl Connection {
+    fn new(local:(Ipv4Addr,u16), remote:(Ipv4Addr,u16), state:State, iss:u32) -> Self {
+        Connection {
+            local,
+            remote,
+            state,
+            snd_una:iss,
+            snd_wnd:DEFAULT_MSS as u32,
+            mss:DEFAULT_MSS,
+            rcv_nxt:0,
+            send:VecDeque::new(),
+            sent:0,
+            syn_pending:true,
+            fin_sent:false,
+            closing:false,
+            recv:VecDeque::new(),
+            eof:false,
+            error:None,
+            rto:INITIAL_RTO,
+            retransmit_at:None,
+            retries:0,
+            deadline:None,
+            listener:None,
+            orphan:false,
+            advertised:0,
+        }
+    }
+
+    fn in_flight(&self) -> u32 {
+        self.syn_pending as u32 + self.sent as u32 + self.fin_sent as u32
+    }
+
+    fn window(&self) -> u32 {
+        (RECV_BUFFER - self.recv.len()).min(0xffff) as u32
+    }
+
+    fn segment(&mut self, seq:u32, flags:u8, payload:&[u8]) -> Segment {
+        let flags = if self.state == State::SynSent {flags} else {flags | ACK};
+        let options = if flags & SYN != 0 {
+            let mss = (super::ETHERNET_MTU - ipv4::HEADER_SIZE - HEADER_SIZE) as u16;
+            let [high,low] = mss.to_be_bytes();
+            alloc::vec![OPTION_MSS,4,high,low]
+        } else {
+            Vec::new()
+        };
+        self.advertised = self.window();
+        let data = build(self.local,self.remote,seq,self.rcv_nxt,flags,self.advertised as u16,&options,payload);
+        Segment {source:self.local.0, destination:self.remote.0, data}
+    }
+
+    fn ack(&mut self) -> Segment {
+        let seq = self.snd_una.wrapping_add(self.in_flight());
+        self.segment(seq,ACK,&[])
+    }
+
+    /// Sends what the window allows: new data, then the FIN once all data is out.
+    fn output(&mut self, out:&mut Vec<Segment>) {
+        if self.state != State::Established && self.state != State::CloseWait {
+            return;
+        }
+        let before = out.len();
+        while self.sent < self.send.len() {
+            let in_flight = self.in_flight();
+            //with nothing in flight a zero window is probed with a byte
+            let window = if in_flight == 0 {self.snd_wnd.max(1)} else {self.snd_wnd};
+            if in_flight >= window {
+                break;
+            }
+            let len = (self.send.len() - self.sent).min(self.mss).min((window - in_flight) as usize);
+            let chunk:Vec<u8> = self.send.iter().skip(self.sent).take(len).copied().collect();
+            let seq = self.snd_una.wrapping_add(in_flight);
+            out.push(self.segment(seq,PSH,&chunk));
+            self.sent += len;
+        }
+        if self.closing && !self.fin_sent && self.sent == self.send.len() {
+            let seq = self.snd_una.wrapping_add(self.in_flight());
+            out.push(self.segment(seq,FIN,&[]));
+            self.fin_sent = true;
+            self.state = if self.state == State::Established {State::FinWait1} else {State::LastAck};
+        }
+        if out.len() > before && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(time::ticks() + self.rto);
+        }
+    }
+
+    /// Sends everything unacknowledged again.
+    fn retransmit(&mut self, out:&mut Vec<Segment>) {
+        let mut seq = self.snd_una;
+        if self.syn_pending {
+            let flags = if self.state == State::SynSent {SYN} else {SYN | ACK};
+            out.push(self.segment(seq,flags,&[]));
+            seq = seq.wrapping_add(1);
+        }
+        let mut offset = 0;
+        while offset < self.sent {
+            let len = (self.sent - offset).min(self.mss);
+            let chunk:Vec<u8> = self.send.iter().skip(offset).take(len).copied().collect();
+            out.push(self.segment(seq,PSH,&chunk));
+            seq = seq.wrapping_add(len as u32);
+            offset += len;
+        }
+        if self.fin_sent {
+            out.push(self.segment(seq,FIN,&[]));
+        }
+    }
+
+    fn close(&mut self, error:Option<Errno>) {
+        self.state = State::Closed;
+        self.error = self.error.or(error);
+        self.retransmit_at = None;
+    }
+
+    /// Handles the acknowledgement in a segment. Returns false if it acknowledges
+    /// something never sent.
+    fn acknowledge(&mut self, ack:u32, window:u16) -> bool {
+        let mut acked = ack.wrapping_sub(self.snd_una);
+        if acked > self.in_flight() {
+            return false;
+        }
+        self.snd_wnd = window as u32;
+        if acked == 0 {
+            return true;
+        }
+        self.snd_una = ack;
+        if self.syn_pending {
+            self.syn_pending = false;
+            acked -= 1;
+        }
+        let data = (acked as usize).min(self.sent);
+        self.send.drain(..data);
+        self.sent -= data;
+        let fin_acked = acked as usize > data;
+        if fin_acked {
+            self.fin_sent = false;
+            self.closing = false;//so that the FIN is not sent again
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(),
+                State::LastAck => self.close(None),
+                _ => {}
+            }
+        }
+        self.retries = 0;
+        self.rto = INITIAL_RTO;
+        self.retransmit_at = if self.in_flight() > 0 {Some(time::ticks() + self.rto)} else {None};
+        true
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.retransmit_at = None;
+        self.deadline = Some(time::ticks() + TIME_WAIT);
+    }
+
+    /// Processes a segment for a connection past SynSent.
+    fn receive(&mut self, segment:&Header, payload:&[u8], out:&mut Vec<Segment>) -> Verdict {
+        match self.state {
+            State::SynSent => return self.receive_syn_sent(segment,out),
+            State::Closed => return Verdict::Keep,
+            _ => {}
+        }
+        if segment.seq != self.rcv_nxt {
+            //a retransmission of something we have, or something after a gap
+            if segment.flags & RST == 0 {
+                out.push(self.ack());
+            }
+            return Verdict::Keep;
+        }
+        if segment.flags & RST != 0 {
+            if self.state == State::SynReceived && self.listener.is_some() {
+                return Verdict::Remove;
+            }
+            self.close(Some(Errno::ECONNRESET));
+            return Verdict::Keep;
+        }
+        if segment.flags & SYN != 0 {
+            out.push(self.segment(self.snd_una.wrapping_add(self.in_flight()),RST,&[]));
+            self.close(Some(Errno::ECONNRESET));
+            return Verdict::Keep;
+        }
+        if segment.flags & ACK == 0 {
+            return Verdict::Keep;
+        }
+        let handshake = self.state == State::SynReceived;
+        if handshake {
+            if segment.ack != self.snd_una.wrapping_add(1) {
+                out.push(reset_for(segment));
+                return Verdict::Keep;
+            }
+            self.state = State::Established;
+        }
+        if !self.acknowledge(segment.ack,segment.window) {
+            out.push(self.ack());
+            return Verdict::Keep;
+        }
+        let mut need_ack = false;
+        let mut accepted = payload.len();
+        if !payload.is_empty() {
+            match self.state {
+                State::Established | State::FinWait1 | State::FinWait2 => {
+                    accepted = payload.len().min(RECV_BUFFER - self.recv.len());
+                    self.recv.extend(payload[..accepted].iter().copied());
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+                }
+                _ => {}
+            }
+            need_ack = true;
+        }
+        if segment.flags & FIN != 0 && accepted == payload.len() && self.state != State::TimeWait {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.eof = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(),
+                _ => {}
+            }
+            need_ack = true;
+        }
+        if need_ack {
+            out.push(self.ack());
+        }
+        self.output(out);
+        if handshake {Verdict::Accepted} else {Verdict::Keep}
+    }
+
+    fn receive_syn_sent(&mut self, segment:&Header, out:&mut Vec<Segment>) -> Verdict {
+        let ack_ok = segment.ack == self.snd_una.wrapping_add(1);
+        if segment.flags & ACK != 0 && !ack_ok {
+            if segment.flags & RST == 0 {
+                out.push(reset_for(segment));
+            }
+            return Verdict::Keep;
+        }
+        if segment.flags & RST != 0 {
+            if ack_ok {
+                self.close(Some(Errno::ECONNREFUSED));
+            }
+            return Verdict::Keep;
+        }
+        if segment.flags & SYN == 0 || segment.flags & ACK == 0 {
+            return Verdict::Keep;//simultaneous opens are not supported
+        }
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.mss = segment.mss.unwrap_or(DEFAULT_MSS);
+        self.state = State::Established;
+        self.acknowledge(segment.ack,segment.window);
+        out.push(self.ack());
+        self.output(out);
+        Verdict::Keep
+    }
+}
+
+/// What `Connection::receive` wants done with the connection.
+enum Verdict {
+    Keep,
+    Remove,
+    /// The handshake of a listener's connection completed.
+    Accepted,
+}
+
+struct Listener {
+    /// Established connections waiting for `accept`.
+    ready:VecDeque<u64>,
+}
+
+/// The connections, by handle, and the listening ports.
+#[derive(Default)]
+pub struct Table {
+    connections:BTreeMap<u64,Connection>,
+    listeners:BTreeMap<u16,Listener>,
+    next_handle:u64,
+}impl Table {
+    fn port_in_use(&self, port:u16) -> bool {
+        self.listeners.contains_key(&port) || self.connections.values().any(|connection|connection.local.1 == port)
+    }
+
+    fn find(&self, local:(Ipv4Addr,u16), remote:(Ipv4Addr,u16)) -> Option<u64> {
+        self.connections.iter().find(|(_,connection)|connection.local == local && connection.remote == remote).map(|(&handle,_)|handle)
+    }
+
+    fn add(&mut self, connection:Connection) -> u64 {
+        self.next_handle += 1;
+        self.connections.insert(self.next_handle,connection);
+        self.next_handle
+    }
+
+    /// An initial sequence number that differs between connections and over time.
+    fn iss(&self) -> u32 {
+        (time::ticks() as u32).wrapping_mul(250_000).wrapping_add((self.next_handle as u32).wrapping_mul(64_000))
+    }
+}
+
+/// The fields of a received segment's header.
+struct Header {
+    source_port:u16,
+    destination_port:u16,
+    seq:u32,
+    ack:u32,
+    flags:u8,
+    window:u16,
+    mss:Option<usize>,
+    source:Ipv4Addr,
+    destination:Ipv4Addr,
+    /// Sequence space the segment takes, for resets.
+    len:u32,
+}
+
+fn parse(packet:&Packet) -> Option<(Header,&[u8])> {
+    let data = packet.payload;
+    if data.len() < HEADER_SIZE || ipv4::finish(ipv4::sum(ipv4::pseudo_header_sum(packet.source,packet.destination,ipv4::PROTOCOL_TCP,data.len()),data)) != 0 {
+        return None;
+    }
+    let offset = (data[12] >> 4) as usize*4;
+    if offset < HEADER_SIZE || offset > data.len() {
+        return None;
+    }
+    let mut mss = None;
+    let options = &data[HEADER_SIZE..offset];
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            kind => {
+                let len = *options.get(i + 1)? as usize;
+                if len < 2 || i + len > options.len() {
+                    return None;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    mss = Some(u16::from_be_bytes([options[i + 2],options[i + 3]]) as usize);
+                }
+                i += len;
+            }
+        }
+    }
+    let flags = data[13];
+    let payload = &data[offset..];
+    let header = Header {
+        source_port:u16::from_be_bytes([data[0],data[1]]),
+        destination_port:u16::from_be_bytes([data[2],data[3]]),
+        seq:u32::from_be_bytes([data[4],data[5],data[6],data[7]]),
+        ack:u32::from_be_bytes([data[8],data[9],data[10],data[11]]),
+        flags,
+        window:u16::from_be_bytes([data[14],data[15]]),
+        mss,
+        source:packet.source,
+        destination:packet.destination,
+        len:payload.len() as u32 + (flags & SYN != 0) as u32 + (flags & FIN != 0) as u32,
+    };
+    Some((header,payload))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build(local:(Ipv4Addr,u16), remote:(Ipv4Addr,u16), seq:u32, ack:u32, flags:u8, window:u16, options:&[u8], payload:&[u8]) -> Vec<u8> {
+    let header_len = HEADER_SIZE + options.len();
+    let mut segment = Vec::with_capacity(header_len + payload.len());
+    segment.extend_from_slice(&local.1.to_be_bytes());
+    segment.extend_from_slice(&remote.1.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&(if flags & ACK != 0 {ack} else {0}).to_be_bytes());
+    segment.extend_from_slice(&[(header_len/4) as u8 * 16,flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0,0,0,0]);//checksum and urgent pointer
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(payload);
+    let sum = ipv4::finish(ipv4::sum(ipv4::pseudo_header_sum(local.0,remote.0,ipv4::PROTOCOL_TCP,segment.len()),&segment));
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+/// The reset answering `segment`, for segments no connection wants.
+fn reset_for(segment:&Header) -> Segment {
+    let local = (segment.destination,segment.destination_port);
+    let remote = (segment.source,segment.source_port);
+    let data = if segment.flags & ACK != 0 {
+        build(local,remote,segment.ack,0,RST,0,&[],&[])
+    } else {
+        build(local,remote,0,segment.seq.wrapping_add(segment.len),RST | ACK,0,&[],&[])
+    };
+    Segment {source:local.0, destination:remote.0, data}
+}
+
+fn send(stack:&mut Stack, out:Vec<Segment>) {
+    for segment in out {
+        let _ = stack.send_ip(None,segment.source,segment.destination,ipv4::PROTOCOL_TCP,&segment.data);
+    }
+}
+
+pub(super) fn receive(stack:&mut Stack, packet:&Packet) {
+    let (segment,payload) = match parse(packet) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let local = (segment.destination,segment.destination_port);
+    let remote = (segment.source,segment.source_port);
+    let mut out = Vec::new();
+    let table = &mut stack.tcp;
+    match table.find(local,remote) {
+        Some(handle) => {
+            let connection = table.connections.get_mut(&handle).unwrap();
+            match connection.receive(&segment,payload,&mut out) {
+                Verdict::Keep => {}
+                Verdict::Remove => {
+                    table.connections.remove(&handle);
+                }
+                Verdict::Accepted => {
+                    let port = connection.listener;
+                    if let Some(listener) = port.and_then(|port|table.listeners.get_mut(&port)) {
+                        listener.ready.push_back(handle);
+                    }
+                }
+            }
+        }
+        None if segment.flags & (SYN | ACK | RST) == SYN && table.listeners.contains_key(&local.1) => {
+            let pending = table.connections.values().filter(|connection|connection.listener == Some(local.1)).count();
+            if pending >= BACKLOG {
+                return;//the peer tries again
+            }
+            let mut connection = Connection::new(local,remote,State::SynReceived,table.iss());
+            connection.rcv_nxt = segment.seq.wrapping_add(1);
+            connection.mss = segment.mss.unwrap_or(DEFAULT_MSS);
+            connection.snd_wnd = segment.window as u32;
+            connection.listener = Some(local.1);
+            connection.retransmit(&mut out);
+            connection.retransmit_at = Some(time::ticks() + connection.rto);
+            table.add(connection);
+        }
+        None if segment.flags & RST == 0 => out.push(reset_for(&segment)),
+        None => {}
+    }
+    send(stack,out);
+}
+
+/// Retransmissions and the end of TimeWait; drops connections nobody uses anymore.
+pub(super) fn timers(stack:&mut Stack) {
+    let now = time::ticks();
+    let mut out = Vec::new();
+    let mut dead = Vec::new();
+    for (&handle,connection) in stack.tcp.connections.iter_mut() {
+        if connection.retransmit_at.map_or(false,|at|at <= now) {
+            connection.retries += 1;
+            let limit = if connection.state == State::SynSent {MAX_SYN_RETRIES} else {MAX_RETRIES};
+            if connection.retries > limit {
+                out.push(connection.segment(connection.snd_una.wrapping_add(connection.in_flight()),RST,&[]));
+                connection.close(Some(Errno::ETIMEDOUT));
+            } else {
+                connection.rto = (connection.rto*2).min(MAX_RTO);
+                connection.retransmit_at = Some(now + connection.rto);
+                connection.retransmit(&mut out);
+            }
+        }
+        if connection.orphan && connection.state == State::FinWait2 && connection.deadline.is_none() {
+            connection.deadline = Some(now + ORPHAN_TIMEOUT);
+        }
+        if connection.deadline.map_or(false,|deadline|deadline <= now) {
+            connection.close(None);
+        }
+        if connection.state == State::Closed && (connection.orphan || connection.listener.is_some()) {
+            dead.push(handle);
+        }
+    }
+    for handle in dead {
+        stack.tcp.connections.remove(&handle);
+    }
+    send(stack,out);
+}
+
+/// A listening port. Connections that are not accepted yet are reset when it is
+/// dropped.
+pub struct TcpListener {
+    port:u16,
+}impl TcpListener {
+    pub fn bind(port:u16) -> Result<Self,Errno> {
+        super::with_stack(|stack|{
+            if stack.tcp.port_in_use(port) {
+                return Err(Errno::EADDRINUSE);
+            }
+            stack.tcp.listeners.insert(port,Listener {ready:VecDeque::new()});
+            Ok(TcpListener {port})
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for a connection.
+    pub fn accept(&self) -> Result<TcpStream,Errno> {
+        let port = self.port;
+        let handle = super::wait_until(|stack|{
+            let table = &mut stack.tcp;
+            while let Some(handle) = table.listeners.get_mut(&port)?.ready.pop_front() {
+                if let Some(connection) = table.connections.get_mut(&handle) {
+                    connection.listener = None;
+                    return Some(handle);
+                }
+            }
+            None
+        },None).ok_or(Errno::EINVAL)?;
+        Ok(TcpStream {handle, timeout:None})
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let port = self.port;
+        super::with_stack(|stack|{
+            stack.tcp.listeners.remove(&port);
+            let mut out = Vec::new();
+            for connection in stack.tcp.connections.values_mut().filter(|connection|connection.listener == Some(port)) {
+                out.push(connection.segment(connection.snd_una.wrapping_add(connection.in_flight()),RST,&[]));
+                connection.close(Some(Errno::ECONNRESET));
+            }
+            send(stack,out);
+        });
+    }
+}
+
+/// A TCP connection. Dropping it closes the connection, which finishes in the
+/// background.
+pub struct TcpStream {
+    handle:u64,
+    timeout:Option<u64>,
+}impl TcpStream {
+    /// Connects to `port` at `address`, waiting for the handshake.
+    pub fn connect(address:Ipv4Addr, port:u16) -> Result<Self,Errno> {
+        let handle = super::with_stack(|stack|{
+            let (_,source) = stack.source_for(address,None)?;
+            if source.is_unspecified() {
+                return Err(Errno::ENETUNREACH);
+            }
+            let local_port = stack.ephemeral_port(|stack,port|stack.tcp.port_in_use(port))?;
+            let iss = stack.tcp.iss();
+            let mut connection = Connection::new((source,local_port),(address,port),State::SynSent,iss);
+            let mut out = Vec::new();
+            connection.retransmit(&mut out);
+            connection.retransmit_at = Some(time::ticks() + connection.rto);
+            let segment = out.pop().unwrap();
+            stack.send_ip(None,segment.source,segment.destination,ipv4::PROTOCOL_TCP,&segment.data)?;
+            Ok(stack.tcp.add(connection))
+        })?;
+        let result = super::wait_until(|stack|{
+            let connection = &stack.tcp.connections[&handle];
+            match connection.state {
+                State::SynSent => None,
+                State::Closed => Some(Err(connection.error.unwrap_or(Errno::ECONNREFUSED))),
+                _ => Some(Ok(())),
+            }
+        },None).unwrap();
+        match result {
+            Ok(()) => Ok(TcpStream {handle, timeout:None}),
+            Err(err) => {
+                super::with_stack(|stack|stack.tcp.connections.remove(&handle));
+                Err(err)
+            }
+        }
+    }
+
+    /// How many ticks `read` and `write` wait; None waits forever.
+    pub fn set_timeout(&mut self, timeout:Option<u64>) {
+        self.timeout = timeout;
+    }
+
+    /// The remote address and port.
+    pub fn peer(&self) -> (Ipv4Addr,u16) {
+        super::with_stack(|stack|stack.tcp.connections[&self.handle].remote)
+    }
+
+    pub fn local(&self) -> (Ipv4Addr,u16) {
+        super::with_stack(|stack|stack.tcp.connections[&self.handle].local)
+    }
+
+    /// Reads what has arrived, waiting for something first. Returns 0 once the
+    /// peer closed its side.
+    pub fn read(&self, buf:&mut [u8]) -> Result<usize,Errno> {
+        let handle = self.handle;
+        super::wait_until(|stack|{
+            let connection = stack.tcp.connections.get_mut(&handle).unwrap();
+            if connection.recv.is_empty() || buf.is_empty() {
+                return match (connection.error,connection.eof || connection.state == State::Closed || buf.is_empty()) {
+                    (Some(err),_) => Some(Err(err)),
+                    (None,true) => Some(Ok(0)),
+                    (None,false) => None,
+                };
+            }
+            let len = buf.len().min(connection.recv.len());
+            for (byte,received) in buf.iter_mut().zip(connection.recv.drain(..len)) {
+                *byte = received;
+            }
+            //tells the peer about the space once it is worth it
+            let window = connection.window();
+            if (connection.advertised < connection.mss as u32 && window >= connection.mss as u32) || window >= connection.advertised + RECV_BUFFER as u32/2 {
+                let ack = connection.ack();
+                send(stack,alloc::vec![ack]);
+            }
+            Some(Ok(len))
+        },self.timeout).unwrap_or(Err(Errno::ETIMEDOUT))
+    }
+
+    /// Queues some of `data` for sending, waiting for buffer space first. Returns how
+    /// much was queued.
+    pub fn write(&self, data:&[u8]) -> Result<usize,Errno> {
+        let handle = self.handle;
+        super::wait_until(|stack|{
+            let connection = stack.tcp.connections.get_mut(&handle).unwrap();
+            if let Some(err) = connection.error {
+                return Some(Err(err));
+            }
+            if connection.closing || connection.fin_sent || (connection.state != State::Established && connection.state != State::CloseWait) {
+                return Some(Err(Errno::EPIPE));
+            }
+            let len = data.len().min(SEND_BUFFER - connection.send.len());
+            if len == 0 && !data.is_empty() {
+                return None;
+            }
+            connection.send.extend(data[..len].iter().copied());
+            let mut out = Vec::new();
+            connection.output(&mut out);
+            send(stack,out);
+            Some(Ok(len))
+        },self.timeout).unwrap_or(Err(Errno::ETIMEDOUT))
+    }
+
+    pub fn write_all(&self, mut data:&[u8]) -> Result<(),Errno> {
+        while !data.is_empty() {
+            let len = self.write(data)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Closes the sending side: a FIN follows the data written so far.
+    pub fn shutdown(&self) {
+        let handle = self.handle;
+        super::with_stack(|stack|{
+            let connection = stack.tcp.connections.get_mut(&handle).unwrap();
+            if connection.state != State::Established && connection.state != State::CloseWait {
+                return;//the FIN is out already, or there is no connection to close
+            }
+            connection.closing = true;
+            let mut out = Vec::new();
+            connection.output(&mut out);
+            send(stack,out);
+        });
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.shutdown();
+        let handle = self.handle;
+        super::with_stack(|stack|{
+            let connection = stack.tcp.connections.get_mut(&handle).unwrap();
+            connection.orphan = true;
+            if connection.state == State::Closed {
+                stack.tcp.connections.remove(&handle);
+            }
+        });
+    }
+}
@@ -0,0 +1,128 @@
+//! UDP sockets.
+
+use alloc::{collections::{BTreeMap,VecDeque},vec::Vec};
+use crate::syscall::Errno;
+use super::ipv4::{self,Ipv4Addr,Packet};
+use super::Stack;
+
+const HEADER_SIZE:usize = 8;
+/// Datagrams kept per socket until it reads them; more are dropped.
+const MAX_QUEUED:usize = 32;
+
+struct Binding {
+    /// Received datagrams with their source address and port.
+    queue:VecDeque<(Vec<u8>,Ipv4Addr,u16)>,
+    /// Only datagrams from this interface are accepted, and sent ones leave through it.
+    interface:Option<usize>,
+}
+
+/// The bound ports.
+#[derive(Default)]
+pub struct Table {
+    bindings:BTreeMap<u16,Binding>,
+}
+
+fn build(source:Ipv4Addr, source_port:u16, destination:Ipv4Addr, destination_port:u16, data:&[u8]) -> Vec<u8> {
+    let len = HEADER_SIZE + data.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&destination_port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0,0]);
+    datagram.extend_from_slice(data);
+    let sum = match ipv4::finish(ipv4::sum(ipv4::pseudo_header_sum(source,destination,ipv4::PROTOCOL_UDP,len),&datagram)) {
+        0 => 0xffff,//0 means no checksum
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    datagram
+}
+
+pub(super) fn receive(stack:&mut Stack, index:usize, packet:&Packet) {
+    let datagram = packet.payload;
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = u16::from_be_bytes([datagram[4],datagram[5]]) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    let checked = datagram[6..8] != [0,0];
+    if checked && ipv4::finish(ipv4::sum(ipv4::pseudo_header_sum(packet.source,packet.destination,ipv4::PROTOCOL_UDP,len),datagram)) != 0 {
+        return;
+    }
+    let source_port = u16::from_be_bytes([datagram[0],datagram[1]]);
+    let destination_port = u16::from_be_bytes([datagram[2],datagram[3]]);
+    if let Some(binding) = stack.udp.bindings.get_mut(&destination_port) {
+        if binding.interface.map_or(true,|interface|interface == index) && binding.queue.len() < MAX_QUEUED {
+            binding.queue.push_back((datagram[HEADER_SIZE..].to_vec(),packet.source,source_port));
+        }
+    }
+}
+
+/// A bound UDP port. Unbound when dropped.
+pub struct UdpSocket {
+    port:u16,
+    timeout:Option<u64>,
+}impl UdpSocket {
+    /// Binds `port`, or a free ephemeral port for 0.
+    pub fn bind(port:u16) -> Result<Self,Errno> {
+        let port = super::with_stack(|stack|{
+            let port = match port {
+                0 => stack.ephemeral_port(|stack,port|stack.udp.bindings.contains_key(&port))?,
+                port if stack.udp.bindings.contains_key(&port) => return Err(Errno::EADDRINUSE),
+                port => port,
+            };
+            stack.udp.bindings.insert(port,Binding {queue:VecDeque::new(), interface:None});
+            Ok(port)
+        })?;
+        Ok(UdpSocket {port, timeout:None})
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Restricts the socket to interface `name`, which also lets it send from an
+    /// unconfigured interface, as DHCP does.
+    pub fn bind_interface(&self, name:&str) -> Result<(),Errno> {
+        super::with_stack(|stack|{
+            let index = stack.interface_index(name).ok_or(Errno::ENODEV)?;
+            stack.udp.bindings.get_mut(&self.port).unwrap().interface = Some(index);
+            Ok(())
+        })
+    }
+
+    /// How many ticks `recv_from` waits; None waits forever.
+    pub fn set_timeout(&mut self, timeout:Option<u64>) {
+        self.timeout = timeout;
+    }
+
+    pub fn send_to(&self, data:&[u8], destination:Ipv4Addr, port:u16) -> Result<usize,Errno> {
+        super::with_stack(|stack|{
+            let interface = stack.udp.bindings[&self.port].interface;
+            let (index,source) = stack.source_for(destination,interface)?;
+            let datagram = build(source,self.port,destination,port,data);
+            stack.send_ip(Some(index),source,destination,ipv4::PROTOCOL_UDP,&datagram)?;
+            Ok(data.len())
+        })
+    }
+
+    /// Receives a datagram into `buf`, cutting off what does not fit. Returns its
+    /// length and where it came from.
+    pub fn recv_from(&self, buf:&mut [u8]) -> Result<(usize,Ipv4Addr,u16),Errno> {
+        let port = self.port;
+        let (data,source,source_port) = super::wait_until(|stack|stack.udp.bindings.get_mut(&port)?.queue.pop_front(),self.timeout)
+            .ok_or(Errno::ETIMEDOUT)?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len,source,source_port))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        super::with_stack(|stack|stack.udp.bindings.remove(&self.port));
+    }
+}
@@ -0,0 +1,185 @@
+//! Virtio network cards ("eth0", "eth1"...), e.g. QEMU's `-device virtio-net-pci`.
+//!
+//! Queue 0 receives and queue 1 transmits. Every frame is preceded by a header for
+//! offloads, none of which are negotiated, so it is all zeros going out and ignored
+//! coming in. The receive queue is kept full of one-page buffers, each of which
+//! holds a whole frame.
+
+use alloc::{collections::BTreeMap,format,sync::Arc,vec::Vec};
+use core::sync::atomic::{AtomicUsize,Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::pci::{self,Driver,Match,PciDevice};
+use crate::virtio::{self,Buffer,Dma,VirtioPci,Virtqueue};
+use crate::println;
+use crate::syscall::Errno;
+use super::{ethernet,MacAddr,NetDevice};
+
+const PAGE_SIZE:usize = 4096;
+/// The header before each frame: flags, GSO fields, checksum fields and the buffer
+/// count, the last of which version 1 devices always have.
+const NET_HEADER_SIZE:usize = 12;
+/// Receive buffers kept in the queue at most.
+const RX_BUFFERS:u16 = 64;
+
+/// Feature bits.
+const F_MAC:u64 = 1 << 5;
+
+/// Device configuration: the MAC address.
+const CONFIG_MAC:u64 = 0;
+
+/// The device type in the PCI device id.
+const DEVICE_TYPE:u16 = 1;
+
+const RECEIVE_QUEUE:u16 = 0;
+const TRANSMIT_QUEUE:u16 = 1;
+
+static DRIVER:Driver = Driver {
+    name:"virtio-net",
+    matches:&[
+        Match::Id {vendor:virtio::VENDOR_ID, device:0x1000},//transitional
+        Match::Id {vendor:virtio::VENDOR_ID, device:virtio::MODERN_DEVICE_ID_BASE + DEVICE_TYPE},
+    ],
+    probe,
+};
+
+lazy_static! {
+    /// For the interrupt handler, which all cards share.
+    static ref CARDS:Mutex<Vec<Arc<VirtioNet>>> = Mutex::new(Vec::new());
+}
+/// Cards named so far, for the next name.
+static NAMED:AtomicUsize = AtomicUsize::new(0);
+
+/// A virtio network card.
+pub struct VirtioNet {
+    transport:VirtioPci,
+    rx:Virtqueue,
+    tx:Virtqueue,
+    mac:MacAddr,
+    /// The buffers in the queues by the head of their chain.
+    rx_buffers:Mutex<BTreeMap<u16,Dma>>,
+    tx_buffers:Mutex<BTreeMap<u16,Dma>>,
+}impl VirtioNet {
+    fn new(device:&Arc<PciDevice>) -> Result<Self,Errno> {
+        let mut transport = VirtioPci::new(device)?;
+        let features = transport.negotiate(F_MAC)?;
+        if let Err(err) = transport.set_interrupt_handler(handle_interrupt) {
+            println!("virtio-net {}: no interrupt ({:?}), polling",device.address,err);
+        }
+        let (rx,tx) = match (transport.queue(RECEIVE_QUEUE),transport.queue(TRANSMIT_QUEUE)) {
+            (Ok(rx),Ok(tx)) => (rx,tx),
+            (Err(err),_) | (_,Err(err)) => {
+                transport.fail();
+                return Err(err);
+            }
+        };
+        let mac = match features & F_MAC {
+            0 => MacAddr([0x52,0x54,0x00,0x12,0x34,0x56 + NAMED.load(Ordering::Relaxed) as u8]),
+            _ => {
+                let mut mac = [0u8;6];
+                for (i,byte) in mac.iter_mut().enumerate() {
+                    *byte = transport.config_u8(CONFIG_MAC + i as u64);
+                }
+                MacAddr(mac)
+            }
+        };
+        let card = VirtioNet {
+            transport,
+            rx,
+            tx,
+            mac,
+            rx_buffers:Mutex::new(BTreeMap::new()),
+            tx_buffers:Mutex::new(BTreeMap::new()),
+        };
+        for _ in 0..RX_BUFFERS.min(card.rx.size()) {
+            card.give_rx_buffer(Dma::new(1)?);
+        }
+        Ok(card)
+    }
+
+    /// Puts `buffer` into the receive queue.
+    fn give_rx_buffer(&self, buffer:Dma) {
+        let chain = [Buffer {addr:buffer.phys(0), len:PAGE_SIZE as u32, writable:true}];
+        interrupts::without_interrupts(||{
+            let mut buffers = self.rx_buffers.lock();
+            if let Some(head) = self.rx.submit(&chain) {
+                buffers.insert(head,buffer);
+            }
+        });
+    }
+
+    /// Frees the buffers of frames the device has sent.
+    fn reclaim_tx(&self) {
+        interrupts::without_interrupts(||{
+            let mut buffers = self.tx_buffers.lock();
+            while let Some((head,_)) = self.tx.pop() {
+                buffers.remove(&head);
+            }
+        });
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn transmit(&self, frame:&[u8]) -> Result<(),Errno> {
+        if NET_HEADER_SIZE + frame.len() > PAGE_SIZE || frame.len() > ethernet::HEADER_SIZE + self.mtu() {
+            return Err(Errno::EMSGSIZE);
+        }
+        self.reclaim_tx();
+        let buffer = Dma::new(1)?;//zeroed, which is the header
+        buffer.write(NET_HEADER_SIZE,frame);
+        let chain = [Buffer {addr:buffer.phys(0), len:(NET_HEADER_SIZE + frame.len()) as u32, writable:false}];
+        interrupts::without_interrupts(||{
+            let mut buffers = self.tx_buffers.lock();
+            let head = self.tx.submit(&chain).ok_or(Errno::EAGAIN)?;//the queue is full
+            buffers.insert(head,buffer);
+            Ok(())
+        })
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let (head,len) = self.rx.pop()?;
+        let buffer = interrupts::without_interrupts(||self.rx_buffers.lock().remove(&head))?;
+        let len = (len as usize).min(PAGE_SIZE);
+        let mut frame = alloc::vec![0u8;len.saturating_sub(NET_HEADER_SIZE)];
+        buffer.read(NET_HEADER_SIZE,&mut frame);
+        self.give_rx_buffer(buffer);
+        Some(frame)
+    }
+}
+
+impl Drop for VirtioNet {
+    fn drop(&mut self) {
+        self.transport.reset();//before the buffers go back
+    }
+}
+
+/// Hands the frames of every card that signalled to the net thread.
+fn handle_interrupt() {
+    let mut any = false;
+    for card in CARDS.lock().iter() {
+        any |= card.transport.interrupted();
+    }
+    if any {
+        super::wake();
+    }
+}
+
+fn probe(device:&Arc<PciDevice>) -> Result<(),Errno> {
+    let card = Arc::new(VirtioNet::new(device)?);
+    interrupts::without_interrupts(||CARDS.lock().push(card.clone()));
+    card.transport.driver_ok();
+    let name = format!("eth{}",NAMED.fetch_add(1,Ordering::Relaxed));
+    println!("{}: virtio-net at {}, MAC {}",name,device.address,card.mac);
+    super::add_interface(&name,card);
+    Ok(())
+}
+
+/// Registers the driver with the PCI bus; cards show up when `pci::init` scans it.
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
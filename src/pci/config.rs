@@ -0,0 +1,121 @@
+//! Configuration space access, through the legacy ports or memory mapped (ECAM).
+
+use alloc::collections::BTreeMap;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr,instructions::{interrupts,port::Port}};
+use crate::acpi::Acpi;
+use crate::memory;
+
+const CONFIG_ADDRESS:u16 = 0xcf8;
+const CONFIG_DATA:u16 = 0xcfc;
+/// Size of one function's configuration space through ECAM; the ports reach the first 256 bytes.
+const ECAM_FUNCTION_SIZE:u64 = 4096;
+
+/// Where a function sits on the bus.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Address {
+    pub bus:u8,
+    pub device:u8,
+    pub function:u8,
+}impl Address {
+    pub fn new(bus:u8, device:u8, function:u8) -> Self {
+        Address {bus, device, function}
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{:02x}:{:02x}.{}",self.bus,self.device,self.function)
+    }
+}
+
+/// Memory mapped configuration space of segment 0, from the ACPI MCFG table.
+#[derive(Debug,Clone,Copy)]
+struct Ecam {
+    base:u64,
+    start_bus:u8,
+    end_bus:u8,
+}
+
+struct State {
+    ecam:Option<Ecam>,
+    /// Virtual addresses of the configuration spaces mapped so far; mapped on first use.
+    mapped:BTreeMap<Address,u64>,
+}
+
+lazy_static! {
+    static ref STATE:Mutex<State> = Mutex::new(State {ecam:None, mapped:BTreeMap::new()});
+}
+
+/// Uses ECAM if ACPI describes it, the legacy ports otherwise. Returns whether ECAM is used.
+pub(super) fn init() -> bool {
+    let ecam = Acpi::new().ok().and_then(|acpi|acpi.find_table(b"MCFG").ok()).and_then(|(table,length)|{
+        //entries of 16 bytes follow the header and 8 reserved bytes
+        (table+44..table+length).step_by(16).map(|entry|{
+            let read = |offset:u64|unsafe { core::ptr::read_unaligned(memory::phys_to_virt(PhysAddr::new(entry+offset)).as_ptr::<u64>()) };
+            let (base,rest) = (read(0),read(8));
+            (base,rest as u16,(rest >> 16) as u8,(rest >> 24) as u8)
+        }).find(|&(_,segment,_,_)|segment == 0).map(|(base,_,start_bus,end_bus)|Ecam {base, start_bus, end_bus})
+    });
+    interrupts::without_interrupts(||STATE.lock().ecam = ecam);
+    ecam.is_some()
+}
+
+/// The virtual address of the ECAM space of `address`, mapping it if needed.
+fn ecam_address(state:&mut State, address:Address) -> Option<u64> {
+    let ecam = state.ecam?;
+    if address.bus < ecam.start_bus || address.bus > ecam.end_bus {
+        return None;
+    }
+    if let Some(&virt) = state.mapped.get(&address) {
+        return Some(virt);
+    }
+    let offset = ((address.bus - ecam.start_bus) as u64) << 20 | (address.device as u64) << 15 | (address.function as u64) << 12;
+    let phys = PhysAddr::new(ecam.base + offset);
+    let virt = memory::with_page_table(memory::kernel_p4(),|mapper,frame_allocator|{
+        memory::map_mmio(phys,ECAM_FUNCTION_SIZE,mapper,frame_allocator)
+    }).ok()?.as_u64();
+    state.mapped.insert(address,virt);
+    Some(virt)
+}
+
+/// Accesses the register at `offset` through `ecam` with its mapped address, or
+/// through `legacy` with the data port once the address port selects it.
+fn access<R>(address:Address, offset:u16, ecam:impl FnOnce(*mut u32)->R, legacy:impl FnOnce(&mut Port<u32>)->R) -> R {
+    interrupts::without_interrupts(||{
+        let mut state = STATE.lock();//the two port accesses must not interleave with others
+        if let Some(virt) = ecam_address(&mut state,address) {
+            return ecam((virt + (offset & 0xffc) as u64) as *mut u32);
+        }
+        let selector = 0x8000_0000 | (address.bus as u32) << 16 | (address.device as u32) << 11
+            | (address.function as u32) << 8 | (offset & 0xfc) as u32;
+        unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(selector) };
+        legacy(&mut Port::new(CONFIG_DATA))
+    })
+}
+
+/// Reads the 32-bit register at `offset` (rounded down to a multiple of 4).
+pub fn read32(address:Address, offset:u16) -> u32 {
+    access(address,offset,|register|unsafe { register.read_volatile() },|data|unsafe { data.read() })
+}
+
+pub fn write32(address:Address, offset:u16, value:u32) {
+    access(address,offset,|register|unsafe { register.write_volatile(value) },|data|unsafe { data.write(value) })
+}
+
+pub fn read16(address:Address, offset:u16) -> u16 {
+    (read32(address,offset) >> ((offset & 2)*8)) as u16
+}
+
+pub fn read8(address:Address, offset:u16) -> u8 {
+    (read32(address,offset) >> ((offset & 3)*8)) as u8
+}
+
+/// Writes 16 bits with a read-modify-write of the register holding them.
+pub fn write16(address:Address, offset:u16, value:u16) {
+    let shift = (offset & 2)*8;
+    let old = read32(address,offset);
+    write32(address,offset,(old & !(0xffff << shift)) | (value as u32) << shift);
+}
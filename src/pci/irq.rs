@@ -0,0 +1,41 @@
+//! Legacy interrupt pins (INTx#), for devices and machines without MSI.
+//!
+//! The firmware routes the pins to a few PIC lines that devices share. The lines
+//! are level triggered: every handler on a line runs for each interrupt and must
+//! make its device deassert the pin (typically by reading an interrupt status
+//! register), or the interrupt comes right back after the EOI.
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::syscall::Errno;
+
+/// The lines `interrupts` has handlers for; QEMU's PIIX routes the pins to 10 and 11.
+pub const LINES:[u8;3] = [9,10,11];
+
+lazy_static! {
+    static ref HANDLERS:Mutex<Vec<(u8,fn())>> = Mutex::new(Vec::new());
+}
+
+/// Calls `handler` on every interrupt of line `irq` from now on and unmasks the
+/// line. Fails with `EINVAL` for lines other than `LINES`.
+pub fn add_handler(irq:u8, handler:fn()) -> Result<(),Errno> {
+    if !LINES.contains(&irq) {
+        return Err(Errno::EINVAL);
+    }
+    interrupts::without_interrupts(||HANDLERS.lock().push((irq,handler)));
+    crate::interrupts::unmask_irq(irq);
+    Ok(())
+}
+
+/// Called by the interrupt handler of line `irq`.
+pub fn dispatch(irq:u8) {
+    //handlers run under the lock: it is only taken with interrupts off, and
+    //copying them out would need the heap
+    for &(line,handler) in HANDLERS.lock().iter() {
+        if line == irq {
+            handler();
+        }
+    }
+}
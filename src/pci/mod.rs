@@ -0,0 +1,361 @@
+//! The PCI bus: enumeration, device resources and driver matching.
+//!
+//! `init` reaches configuration space through ECAM when ACPI has an MCFG table and
+//! through the legacy ports otherwise, then walks the buses from bus 0 through the
+//! bridges. Each function found becomes a `PciDevice` with its BARs sized. Drivers
+//! register with the ids or the class they handle; `probe` is called for each
+//! matching device no other driver has claimed yet, whether the driver comes
+//! before or after the scan.
+
+use alloc::{collections::{BTreeMap,BTreeSet},sync::Arc,vec,vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr,instructions::interrupts};
+use crate::memory;
+use crate::println;
+use crate::syscall::Errno;
+
+pub mod config;
+pub mod msi;
+pub mod irq;
+
+pub use config::Address;
+
+/// Configuration space registers of all header types.
+const REG_VENDOR_ID:u16 = 0x00;
+const REG_DEVICE_ID:u16 = 0x02;
+const REG_COMMAND:u16 = 0x04;
+const REG_STATUS:u16 = 0x06;
+const REG_REVISION:u16 = 0x08;
+const REG_HEADER_TYPE:u16 = 0x0e;
+const REG_BAR0:u16 = 0x10;
+const REG_CAPABILITIES:u16 = 0x34;
+const REG_INTERRUPT_LINE:u16 = 0x3c;
+const REG_INTERRUPT_PIN:u16 = 0x3d;
+/// Of PCI-to-PCI bridges (header type 1).
+const REG_SECONDARY_BUS:u16 = 0x19;
+
+pub const COMMAND_IO:u16 = 1 << 0;
+pub const COMMAND_MEMORY:u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER:u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE:u16 = 1 << 10;
+const STATUS_CAPABILITIES:u16 = 1 << 4;
+
+const HEADER_MULTIFUNCTION:u8 = 0x80;
+const CLASS_BRIDGE:u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE:u8 = 0x04;
+
+/// Capability ids.
+pub const CAP_MSI:u8 = 0x05;
+pub const CAP_VENDOR:u8 = 0x09;
+pub const CAP_MSIX:u8 = 0x11;
+
+/// A base address register: where the device decodes memory or I/O accesses.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Bar {
+    Memory {address:u64, size:u64, prefetchable:bool, is64:bool},
+    Io {port:u16, size:u16},
+}
+
+/// One function of a device on the bus.
+pub struct PciDevice {
+    pub address:Address,
+    pub vendor_id:u16,
+    pub device_id:u16,
+    pub class:u8,
+    pub subclass:u8,
+    pub prog_if:u8,
+    pub revision:u8,
+    /// Without the multifunction bit.
+    pub header_type:u8,
+    /// The legacy IRQ the firmware routed the interrupt pin to.
+    pub interrupt_line:u8,
+    /// 1 to 4 for INTA# to INTD#, 0 if the function has no legacy interrupt.
+    pub interrupt_pin:u8,
+    pub bars:[Option<Bar>;6],
+    /// Virtual addresses of the memory BARs mapped so far.
+    mapped:Mutex<[Option<u64>;6]>,
+}impl PciDevice {
+    /// Reads the function at `address`, or None if there is none.
+    fn probe(address:Address) -> Option<Self> {
+        let vendor_id = config::read16(address,REG_VENDOR_ID);
+        if vendor_id == 0xffff {
+            return None;
+        }
+        let class = config::read32(address,REG_REVISION);
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id:config::read16(address,REG_DEVICE_ID),
+            class:(class >> 24) as u8,
+            subclass:(class >> 16) as u8,
+            prog_if:(class >> 8) as u8,
+            revision:class as u8,
+            header_type:config::read8(address,REG_HEADER_TYPE) & !HEADER_MULTIFUNCTION,
+            interrupt_line:config::read8(address,REG_INTERRUPT_LINE),
+            interrupt_pin:config::read8(address,REG_INTERRUPT_PIN),
+            bars:[None;6],
+            mapped:Mutex::new([None;6]),
+        };
+        device.size_bars();
+        Some(device)
+    }
+
+    /// Finds the size of each BAR by writing all ones and reading back which bits
+    /// stick, with decoding off so the device doesn't answer at the probed address.
+    fn size_bars(&mut self) {
+        let count = match self.header_type {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO | COMMAND_MEMORY));
+        let mut index = 0;
+        while index < count {
+            let register = REG_BAR0 + 4*index as u16;
+            let original = self.read32(register);
+            self.write32(register,!0);
+            let mask = self.read32(register);
+            self.write32(register,original);
+            if original & 1 == 1 {
+                let size = (!(mask & !0x3) as u16).wrapping_add(1);
+                if mask & !0x3 != 0 && size != 0 {
+                    self.bars[index] = Some(Bar::Io {port:(original & !0x3) as u16, size});
+                }
+                index += 1;
+                continue;
+            }
+            let is64 = (original >> 1) & 0x3 == 0x2;
+            let (mut address,mut bits) = ((original & !0xf) as u64,(mask & !0xf) as u64);
+            if is64 && index + 1 < count {
+                let high = self.read32(register + 4);
+                self.write32(register + 4,!0);
+                let high_mask = self.read32(register + 4);
+                self.write32(register + 4,high);
+                address |= (high as u64) << 32;
+                bits |= (high_mask as u64) << 32;
+            } else {
+                bits |= 0xffff_ffff_0000_0000;//so the size stays below 4 GiB
+            }
+            if bits & 0xffff_ffff != 0 || (is64 && bits != 0) {
+                let size = (!bits).wrapping_add(1);
+                self.bars[index] = Some(Bar::Memory {address, size, prefetchable:original & 0x8 != 0, is64});
+            }
+            index += if is64 {2} else {1};
+        }
+        self.set_command(command);
+    }
+
+    pub fn read32(&self, offset:u16) -> u32 {
+        config::read32(self.address,offset)
+    }
+    pub fn write32(&self, offset:u16, value:u32) {
+        config::write32(self.address,offset,value)
+    }
+    pub fn read16(&self, offset:u16) -> u16 {
+        config::read16(self.address,offset)
+    }
+    pub fn write16(&self, offset:u16, value:u16) {
+        config::write16(self.address,offset,value)
+    }
+    pub fn read8(&self, offset:u16) -> u8 {
+        config::read8(self.address,offset)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read16(REG_COMMAND)
+    }
+    pub fn set_command(&self, command:u16) {
+        self.write16(REG_COMMAND,command)
+    }
+
+    /// Turns on decoding of the function's I/O and memory BARs.
+    pub fn enable_decoding(&self) {
+        let mut command = self.command();
+        for bar in self.bars.iter().flatten() {
+            command |= match bar {
+                Bar::Memory {..} => COMMAND_MEMORY,
+                Bar::Io {..} => COMMAND_IO,
+            };
+        }
+        self.set_command(command);
+    }
+
+    /// Lets the function do DMA (and send MSIs, which are memory writes).
+    pub fn enable_bus_master(&self) {
+        self.set_command(self.command() | COMMAND_BUS_MASTER);
+    }
+
+    /// The virtual address of memory BAR `index`, mapping it uncached on first use.
+    /// Fails with `EINVAL` if the BAR isn't a memory BAR and `ENOMEM` if mapping fails.
+    pub fn map_bar(&self, index:usize) -> Result<u64,Errno> {
+        let (address,size) = match self.bars.get(index) {
+            Some(&Some(Bar::Memory {address, size, ..})) => (address,size),
+            _ => return Err(Errno::EINVAL),
+        };
+        interrupts::without_interrupts(||{
+            let mut mapped = self.mapped.lock();
+            if let Some(virt) = mapped[index] {
+                return Ok(virt);
+            }
+            let virt = memory::with_page_table(memory::kernel_p4(),|mapper,frame_allocator|{
+                memory::map_mmio(PhysAddr::new(address),size,mapper,frame_allocator)
+            }).map_err(|_|Errno::ENOMEM)?.as_u64();
+            mapped[index] = Some(virt);
+            Ok(virt)
+        })
+    }
+
+    /// The offsets of the function's capabilities with their ids, in list order.
+    pub fn capabilities(&self) -> Vec<(u8,u16)> {
+        let mut found = Vec::new();
+        if self.read16(REG_STATUS) & STATUS_CAPABILITIES == 0 {
+            return found;
+        }
+        let mut offset = (self.read8(REG_CAPABILITIES) & !0x3) as u16;
+        while offset >= 0x40 && found.len() < 48 {//48 fit in the device specific space, so more means a loop
+            found.push((self.read8(offset),offset));
+            offset = (self.read8(offset + 1) & !0x3) as u16;
+        }
+        found
+    }
+
+    /// The offset of the first capability with id `id`.
+    pub fn find_capability(&self, id:u8) -> Option<u16> {
+        self.capabilities().into_iter().find(|&(cap,_)|cap == id).map(|(_,offset)|offset)
+    }
+}
+
+/// Which devices a driver handles.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Match {
+    Id {vendor:u16, device:u16},
+    /// Any programming interface if `prog_if` is None.
+    Class {class:u8, subclass:u8, prog_if:Option<u8>},
+}impl Match {
+    pub fn matches(&self, device:&PciDevice) -> bool {
+        match *self {
+            Match::Id {vendor, device:id} => device.vendor_id == vendor && device.device_id == id,
+            Match::Class {class, subclass, prog_if} =>
+                device.class == class && device.subclass == subclass && prog_if.map_or(true,|prog_if|device.prog_if == prog_if),
+        }
+    }
+}
+
+/// A driver for PCI devices. `probe` takes the device over; when it fails the
+/// device stays free for other drivers.
+pub struct Driver {
+    pub name:&'static str,
+    pub matches:&'static [Match],
+    pub probe:fn(&Arc<PciDevice>) -> Result<(),Errno>,
+}
+
+struct Registry {
+    devices:Vec<Arc<PciDevice>>,
+    drivers:Vec<&'static Driver>,
+    /// The name of the driver of each claimed device.
+    claimed:BTreeMap<Address,&'static str>,
+}
+
+lazy_static! {
+    static ref REGISTRY:Mutex<Registry> = Mutex::new(Registry {devices:Vec::new(), drivers:Vec::new(), claimed:BTreeMap::new()});
+}
+
+/// Offers the unclaimed devices `driver` matches to it. Devices are claimed before
+/// `probe` runs (outside the lock, since probing sleeps) so no other driver gets them
+/// meanwhile, and given back if it fails.
+fn bind(driver:&'static Driver) {
+    let candidates:Vec<Arc<PciDevice>> = interrupts::without_interrupts(||{
+        let mut registry = REGISTRY.lock();
+        let Registry {devices,claimed,..} = &mut *registry;
+        devices.iter().filter(|device|!claimed.contains_key(&device.address) && driver.matches.iter().any(|m|m.matches(device)))
+            .map(|device|{
+                claimed.insert(device.address,driver.name);
+                device.clone()
+            }).collect()
+    });
+    for device in candidates {
+        if let Err(err) = (driver.probe)(&device) {
+            println!("pci {}: {} failed:{:?}",device.address,driver.name,err);
+            interrupts::without_interrupts(||REGISTRY.lock().claimed.remove(&device.address));
+        }
+    }
+}
+
+/// Adds a driver and probes it against the devices found so far.
+pub fn register_driver(driver:&'static Driver) {
+    interrupts::without_interrupts(||REGISTRY.lock().drivers.push(driver));
+    bind(driver);
+}
+
+/// All functions found by `init`, in bus order.
+pub fn devices() -> Vec<Arc<PciDevice>> {
+    interrupts::without_interrupts(||REGISTRY.lock().devices.clone())
+}
+
+/// The first function with the given ids.
+pub fn find(vendor:u16, device:u16) -> Option<Arc<PciDevice>> {
+    devices().into_iter().find(|found|found.vendor_id == vendor && found.device_id == device)
+}
+
+/// The name of the driver that claimed the function at `address`.
+pub fn driver_of(address:Address) -> Option<&'static str> {
+    interrupts::without_interrupts(||REGISTRY.lock().claimed.get(&address).copied())
+}
+
+/// Adds the functions on `bus` to `found`, and those behind its bridges.
+fn scan_bus(bus:u8, found:&mut Vec<PciDevice>, visited:&mut BTreeSet<u8>) {
+    if !visited.insert(bus) {
+        return;
+    }
+    for device in 0..32 {
+        let first = match PciDevice::probe(Address::new(bus,device,0)) {
+            Some(first) => first,
+            None => continue,
+        };
+        let functions = if config::read8(first.address,REG_HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 {8} else {1};
+        let mut functions_found = vec![first];
+        functions_found.extend((1..functions).filter_map(|function|PciDevice::probe(Address::new(bus,device,function))));
+        for function in functions_found {
+            let bridge = function.class == CLASS_BRIDGE && function.subclass == SUBCLASS_PCI_BRIDGE && function.header_type == 1;
+            let secondary = function.read8(REG_SECONDARY_BUS);
+            found.push(function);
+            if bridge && secondary != 0 {
+                scan_bus(secondary,found,visited);
+            }
+        }
+    }
+}
+
+/// Scans the buses once and offers the devices to the drivers registered so far.
+/// Returns the number of functions found.
+pub fn init() -> usize {
+    let ecam = config::init();
+    let mut found = Vec::new();
+    let mut visited = BTreeSet::new();
+    scan_bus(0,&mut found,&mut visited);
+    //with several host bridges, function n of 00:00 is the one for bus n
+    if config::read8(Address::new(0,0,0),REG_HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 {
+        for function in 1..8 {
+            if config::read16(Address::new(0,0,function),REG_VENDOR_ID) != 0xffff {
+                scan_bus(function,&mut found,&mut visited);
+            }
+        }
+    }
+    println!("pci: {} functions found through {}",found.len(),if ecam {"ECAM"} else {"port I/O"});
+    for device in &found {
+        println!("  {} {:04x}:{:04x} class {:02x}:{:02x}.{:02x} irq {}",
+            device.address,device.vendor_id,device.device_id,device.class,device.subclass,device.prog_if,device.interrupt_line);
+    }
+    let count = found.len();
+    let drivers = interrupts::without_interrupts(||{
+        let mut registry = REGISTRY.lock();
+        registry.devices = found.into_iter().map(Arc::new).collect();
+        registry.drivers.clone()
+    });
+    for driver in drivers {
+        bind(driver);
+    }
+    count
+}
@@ -0,0 +1,143 @@
+//! Message signalled interrupts: the device raises an interrupt by writing a vector
+//! to the local APIC's address, so no IRQ line or PIC is involved.
+//!
+//! A small range of IDT vectors is set aside for them; `allocate_vector` hands one
+//! out along with the function its interrupt handler calls.
+
+use x86_64::instructions::interrupts;
+use spin::Mutex;
+use crate::apic;
+use crate::syscall::Errno;
+use super::{PciDevice,CAP_MSI,CAP_MSIX,COMMAND_INTX_DISABLE};
+
+/// The vectors `interrupts` routes to `dispatch`, after the IPIs.
+pub const VECTOR_BASE:u8 = 0x50;
+pub const VECTOR_COUNT:usize = 8;
+
+/// Where message writes go: the local APIC, with the destination id in bits 12 to 19.
+const MESSAGE_ADDRESS:u32 = 0xfee0_0000;
+
+/// MSI capability, as offsets from its start.
+const MSI_CONTROL:u16 = 2;
+const MSI_ADDRESS:u16 = 4;
+const MSI_CONTROL_ENABLE:u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE:u16 = 0x7 << 4;//vectors enabled, as a power of two
+const MSI_CONTROL_64BIT:u16 = 1 << 7;
+
+/// MSI-X capability.
+const MSIX_CONTROL:u16 = 2;
+const MSIX_TABLE:u16 = 4;
+const MSIX_CONTROL_MASK:u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE:u16 = 1 << 15;
+const MSIX_ENTRY_SIZE:u64 = 16;
+const MSIX_VECTOR_MASKED:u32 = 1;
+
+static HANDLERS:Mutex<[Option<fn()>;VECTOR_COUNT]> = Mutex::new([None;VECTOR_COUNT]);
+
+/// Reserves a vector whose interrupts call `handler`. Fails with `ENOSPC` when all
+/// are taken.
+pub fn allocate_vector(handler:fn()) -> Result<u8,Errno> {
+    interrupts::without_interrupts(||{
+        let mut handlers = HANDLERS.lock();
+        let index = handlers.iter().position(|slot|slot.is_none()).ok_or(Errno::ENOSPC)?;
+        handlers[index] = Some(handler);
+        Ok(VECTOR_BASE + index as u8)
+    })
+}
+
+/// Gives back a vector from `allocate_vector`; the device must not use it any more.
+pub fn free_vector(vector:u8) {
+    if let Some(slot) = (vector as usize).checked_sub(VECTOR_BASE as usize).filter(|&index|index < VECTOR_COUNT) {
+        interrupts::without_interrupts(||HANDLERS.lock()[slot] = None);
+    }
+}
+
+/// Called by the interrupt handler of vector `VECTOR_BASE + index`.
+pub fn dispatch(index:usize) {
+    let handler = HANDLERS.lock()[index];//interrupts are off in the handler
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+/// Messages are delivered to the calling CPU, so the local APIC must be up.
+fn message_address() -> Result<u32,Errno> {
+    if !apic::is_initialized() {
+        return Err(Errno::ENODEV);
+    }
+    Ok(MESSAGE_ADDRESS | apic::id() << 12)
+}
+
+/// Makes the device signal its interrupt with a message for `vector` instead of its
+/// interrupt pin. Fails with `ENODEV` if it has no MSI capability or there is no
+/// local APIC.
+pub fn enable_msi(device:&PciDevice, vector:u8) -> Result<(),Errno> {
+    let cap = device.find_capability(CAP_MSI).ok_or(Errno::ENODEV)?;
+    let address = message_address()?;
+    let control = device.read16(cap + MSI_CONTROL);
+    device.write32(cap + MSI_ADDRESS,address);
+    let data = if control & MSI_CONTROL_64BIT != 0 {
+        device.write32(cap + MSI_ADDRESS + 4,0);
+        cap + MSI_ADDRESS + 8
+    } else {
+        cap + MSI_ADDRESS + 4
+    };
+    device.write16(data,vector as u16);//edge triggered, fixed delivery
+    device.write16(cap + MSI_CONTROL,(control & !MSI_CONTROL_MULTIPLE) | MSI_CONTROL_ENABLE);
+    device.set_command(device.command() | COMMAND_INTX_DISABLE);
+    device.enable_bus_master();
+    Ok(())
+}
+
+/// The number of MSI-X table entries of the device, or None if it has no MSI-X.
+pub fn msix_entries(device:&PciDevice) -> Option<usize> {
+    let cap = device.find_capability(CAP_MSIX)?;
+    Some((device.read16(cap + MSIX_CONTROL) & 0x7ff) as usize + 1)
+}
+
+/// Enables MSI-X with table entry `i` sending `vectors[i]`; the other entries stay
+/// masked. Fails with `ENODEV` without MSI-X or a local APIC, and with `EINVAL` if
+/// the table has fewer entries than `vectors`.
+pub fn enable_msix(device:&PciDevice, vectors:&[u8]) -> Result<(),Errno> {
+    let cap = device.find_capability(CAP_MSIX).ok_or(Errno::ENODEV)?;
+    let address = message_address()?;
+    let control = device.read16(cap + MSIX_CONTROL);
+    let entries = (control & 0x7ff) as usize + 1;
+    if vectors.len() > entries {
+        return Err(Errno::EINVAL);
+    }
+    let table = device.read32(cap + MSIX_TABLE);
+    let base = device.map_bar((table & 0x7) as usize)? + (table & !0x7) as u64;
+    device.enable_decoding();
+    //masked as a whole while the table changes
+    device.write16(cap + MSIX_CONTROL,control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_MASK);
+    for entry in 0..entries {
+        let registers = (base + entry as u64*MSIX_ENTRY_SIZE) as *mut u32;
+        unsafe {
+            match vectors.get(entry) {
+                Some(&vector) => {
+                    registers.write_volatile(address);
+                    registers.add(1).write_volatile(0);
+                    registers.add(2).write_volatile(vector as u32);
+                    registers.add(3).write_volatile(0);
+                }
+                None => registers.add(3).write_volatile(MSIX_VECTOR_MASKED),
+            }
+        }
+    }
+    device.write16(cap + MSIX_CONTROL,(control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_MASK);
+    device.set_command(device.command() | COMMAND_INTX_DISABLE);
+    device.enable_bus_master();
+    Ok(())
+}
+
+/// Goes back to the interrupt pin.
+pub fn disable(device:&PciDevice) {
+    if let Some(cap) = device.find_capability(CAP_MSI) {
+        device.write16(cap + MSI_CONTROL,device.read16(cap + MSI_CONTROL) & !MSI_CONTROL_ENABLE);
+    }
+    if let Some(cap) = device.find_capability(CAP_MSIX) {
+        device.write16(cap + MSIX_CONTROL,device.read16(cap + MSIX_CONTROL) & !MSIX_CONTROL_ENABLE);
+    }
+    device.set_command(device.command() & !COMMAND_INTX_DISABLE);
+}
@@ -0,0 +1,68 @@
+//! Rebooting and powering off the machine.
+
+use x86_64::instructions::{interrupts,port::Port};
+use crate::acpi::{read_phys,Acpi};
+use crate::hlt_loop;
+
+/// Resets the machine through the keyboard controller, or by a triple fault if
+/// that does nothing.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    unsafe {
+        Port::<u8>::new(0x64).write(0xfe);
+        //an empty IDT turns the next interrupt into a triple fault
+        let empty = x86_64::structures::DescriptorTablePointer {limit:0, base:0};
+        x86_64::instructions::tables::lidt(&empty);
+    }
+    interrupts::int3();
+    hlt_loop();
+}
+
+/// SLP_TYPa of the \_S5 (soft off) package in the DSDT, which is AML like
+/// `_S5_ Package(){0x05, ...}`: a name, the package opcode 0x12, its length and
+/// element count, then the value, maybe behind a byte prefix 0x0a.
+fn s5_sleep_type(dsdt:u64, length:u64) -> Option<u16> {
+    let mut addr = dsdt + 36;
+    while addr + 8 < dsdt + length {
+        if read_phys::<[u8;4]>(addr) == *b"_S5_" && read_phys::<u8>(addr+4) == 0x12 {
+            let mut value = addr + 7;//after the opcode, a one byte length and the count
+            if read_phys::<u8>(value) == 0x0a {
+                value += 1;
+            }
+            return Some(read_phys::<u8>(value) as u16);
+        }
+        addr += 1;
+    }
+    None
+}
+
+/// Puts the machine in ACPI sleep state 5 through the PM1a control block.
+fn acpi_power_off() -> Option<()> {
+    let acpi = Acpi::new().ok()?;
+    let (fadt,fadt_length) = acpi.find_table(b"FACP").ok()?;
+    let mut dsdt = read_phys::<u32>(fadt+40) as u64;
+    if fadt_length >= 148 && read_phys::<u64>(fadt+140) != 0 {
+        dsdt = read_phys::<u64>(fadt+140);//X_DSDT
+    }
+    let dsdt_length = read_phys::<u32>(dsdt+4) as u64;
+    let sleep_type = s5_sleep_type(dsdt,dsdt_length)?;
+    let pm1a_control = read_phys::<u32>(fadt+64) as u16;
+    if pm1a_control == 0 {
+        return None;
+    }
+    unsafe { Port::<u16>::new(pm1a_control).write(sleep_type << 10 | 1 << 13) };//SLP_TYPa and SLP_EN
+    Some(())
+}
+
+/// Writes back the file systems and powers the machine off, through ACPI or the
+/// ports QEMU and Bochs shut down on; halts if none of them works.
+pub fn shutdown() -> ! {
+    let _ = crate::vfs::sync();
+    interrupts::disable();
+    let _ = acpi_power_off();
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000);//QEMU
+        Port::<u16>::new(0xb004).write(0x2000);//Bochs and older QEMU
+    }
+    hlt_loop();
+}
@@ -0,0 +1,98 @@
+use alloc::{sync::Arc,vec,vec::Vec};
+use crate::syscall::Errno;
+use crate::vfs::{Metadata,SeekFrom};
+use crate::{print,serial_print};
+
+/// Most descriptors a process may have open at the same time.
+pub const MAX_FILES:usize = 64;
+
+/// Anything a file descriptor can refer to.
+///
+/// Operations a kind of file doesn't support fail with `EBADF`, like writing to a
+/// descriptor opened for reading only, except that streams can't `seek` (`ESPIPE`).
+pub trait File:Send+Sync {
+    fn read(&self, _buf:&mut [u8]) -> Result<usize,Errno> {
+        Err(Errno::EBADF)
+    }
+    fn write(&self, _buf:&[u8]) -> Result<usize,Errno> {
+        Err(Errno::EBADF)
+    }
+    /// Moves the offset of the next `read` or `write` and returns it.
+    fn seek(&self, _pos:SeekFrom) -> Result<u64,Errno> {
+        Err(Errno::ESPIPE)
+    }
+    fn stat(&self) -> Result<Metadata,Errno> {
+        Err(Errno::EBADF)
+    }
+}
+
+/// The screen and the first serial port. Reading gives end of file for now.
+pub struct Console;
+impl File for Console {
+    fn read(&self, _buf:&mut [u8]) -> Result<usize,Errno> {
+        Ok(0)
+    }
+    fn write(&self, buf:&[u8]) -> Result<usize,Errno> {
+        let text = core::str::from_utf8(buf).map_err(|_|Errno::EINVAL)?;
+        print!("{}",text);
+        serial_print!("{}",text);
+        Ok(buf.len())
+    }
+}
+
+/// Per-process table mapping descriptor numbers to open files.
+#[derive(Clone)]
+pub struct FileTable {
+    files:Vec<Option<Arc<dyn File>>>,
+}impl FileTable {
+    pub fn new() -> Self {
+        FileTable {files:Vec::new()}
+    }
+
+    /// A table with stdin, stdout and stderr (0, 1, 2) connected to the console.
+    pub fn with_console() -> Self {
+        let console:Arc<dyn File> = Arc::new(Console);
+        FileTable {files:vec![Some(console.clone()),Some(console.clone()),Some(console)]}
+    }
+
+    /// Stores `file` under the lowest free descriptor and returns it.
+    pub fn insert(&mut self, file:Arc<dyn File>) -> Result<usize,Errno> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FILES {
+            return Err(Errno::EMFILE);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd:usize) -> Result<Arc<dyn File>,Errno> {
+        self.files.get(fd).cloned().flatten().ok_or(Errno::EBADF)
+    }
+
+    /// Makes `new` refer to the file of `old` and returns what `new` referred to before.
+    pub fn dup2(&mut self, old:usize, new:usize) -> Result<Option<Arc<dyn File>>,Errno> {
+        let file = self.get(old)?;
+        if new >= MAX_FILES {
+            return Err(Errno::EBADF);
+        }
+        if new >= self.files.len() {
+            self.files.resize(new + 1,None);
+        }
+        Ok(self.files[new].replace(file))
+    }
+
+    /// Removes `fd` from the table. The file is closed once the returned reference,
+    /// and every other one, is dropped.
+    pub fn close(&mut self, fd:usize) -> Result<Arc<dyn File>,Errno> {
+        self.files.get_mut(fd).and_then(Option::take).ok_or(Errno::EBADF)
+    }
+
+    /// Number of open descriptors.
+    pub fn len(&self) -> usize {
+        self.files.iter().filter(|file|file.is_some()).count()
+    }
+}
+
@@ -0,0 +1,319 @@
+//! User processes: an address space, open files and the threads running in them.
+//!
+//! Every process has a parent, the process that created it, or none if it was
+//! started by the kernel. When a process exits it becomes a zombie that keeps its
+//! exit code until the parent collects it with `wait`. Children of an exiting
+//! process are orphaned and reaped as soon as they exit themselves.
+
+use alloc::{collections::BTreeMap,string::String,sync::Arc,vec,vec::Vec};
+use core::sync::atomic::{AtomicU64,Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{VirtAddr,instructions::interrupts};
+use crate::elf::{self,ElfError};
+use crate::ipc::HandleTable;
+use crate::memory::address_space::AddressSpace;
+use crate::sync::WaitQueue;
+use crate::syscall::{Errno,SyscallFrame};
+use crate::task::{self,ThreadId};
+use crate::usermode;
+use crate::vfs;
+
+pub mod fd;
+pub mod signal;
+
+use fd::{File,FileTable};
+use signal::Signals;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct Pid(u64);
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID:AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1,Ordering::Relaxed))
+    }
+    pub fn from_u64(pid:u64) -> Self {
+        Pid(pid)
+    }
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ProcessState {
+    Running,
+    /// Exited with the given code, waiting for the parent to collect it.
+    Zombie(i32),
+}
+
+struct Process {
+    parent:Option<Pid>,
+    orphan:bool,//the parent exited, so nobody will wait for us
+    name:String,
+    state:ProcessState,
+    address_space:Option<Arc<AddressSpace>>,//dropped on exit
+    files:FileTable,
+    handles:HandleTable,
+    signals:Signals,
+    threads:Vec<ThreadId>,
+}
+
+struct ProcessTable {
+    processes:BTreeMap<Pid,Process>,
+    owners:BTreeMap<ThreadId,Pid>,//the process every user thread belongs to
+}impl ProcessTable {
+    fn current_pid(&self, thread:ThreadId) -> Option<Pid> {
+        self.owners.get(&thread).copied()
+    }
+    fn process_mut(&mut self, pid:Pid) -> &mut Process {
+        self.processes.get_mut(&pid).expect("unknown pid")
+    }
+}
+
+/// Pid of the process Ctrl-C is sent to, 0 if none.
+static FOREGROUND:AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref PROCESSES:Mutex<ProcessTable> = Mutex::new(ProcessTable {
+        processes:BTreeMap::new(),
+        owners:BTreeMap::new(),
+    });
+    /// Notified whenever a process becomes a zombie.
+    static ref EXITED:WaitQueue = WaitQueue::new();
+}
+
+/// Runs `f` on the process table. The table is also used from the page fault
+/// handler, so it is only ever locked with interrupts disabled.
+fn with_table<R, F:FnOnce(&mut ProcessTable)->R>(f:F) -> R {
+    interrupts::without_interrupts(||f(&mut PROCESSES.lock()))
+}
+
+/// Snapshot of a process for `ps`-style listings.
+#[derive(Debug,Clone)]
+pub struct ProcessInfo {
+    pub pid:Pid,
+    pub parent:Option<Pid>,
+    pub name:String,
+    pub state:ProcessState,
+    pub threads:usize,
+    pub open_files:usize,
+}
+
+/// Loads the ELF executable `image` and starts it as a child of the calling process.
+pub fn spawn(name:&str, image:&[u8], argv:&[&str], envp:&[&str]) -> Result<Pid,ElfError> {
+    let program = elf::load(image,argv,envp)?;
+    let caller = task::current_id();
+    let pid = Pid::new();
+    let (entry,stack) = (program.entry,program.stack_pointer);
+    with_table(|table|{
+        let parent = table.current_pid(caller);
+        //the thread is registered before it can run, since it may only start after we unlock
+        let thread = task::spawn_in(program.address_space.clone(),move ||unsafe { usermode::enter(entry,stack) });
+        table.owners.insert(thread,pid);
+        table.processes.insert(pid,Process {
+            parent,
+            orphan:false,
+            name:String::from(name),
+            state:ProcessState::Running,
+            address_space:Some(program.address_space),
+            files:FileTable::with_console(),
+            handles:HandleTable::new(),
+            signals:Signals::new(),
+            threads:vec![thread],
+        });
+    });
+    Ok(pid)
+}
+
+/// Duplicates the calling process. The child gets a copy-on-write copy of the
+/// address space and the open files, and continues with the registers in `frame`,
+/// except that its `fork` returns 0.
+pub fn fork(frame:&SyscallFrame) -> Result<Pid,Errno> {
+    let parent = current_pid().ok_or(Errno::EPERM)?;
+    let address_space = task::current_address_space().ok_or(Errno::EPERM)?;
+    let address_space = Arc::new(address_space.fork().map_err(|_|Errno::ENOMEM)?);
+    let mut child_frame = *frame;
+    child_frame.rax = 0;
+    let pid = Pid::new();
+    with_table(|table|{
+        let process = &table.processes[&parent];
+        let (name,files,handles,signals) = (process.name.clone(),process.files.clone(),process.handles.fork(),process.signals.fork());
+        let thread = task::spawn_in(address_space.clone(),move ||unsafe { usermode::resume(&child_frame) });
+        table.owners.insert(thread,pid);
+        table.processes.insert(pid,Process {
+            parent:Some(parent),
+            orphan:false,
+            name,
+            state:ProcessState::Running,
+            address_space:Some(address_space),
+            files,
+            handles,
+            signals,
+            threads:vec![thread],
+        });
+    });
+    Ok(pid)
+}
+
+/// Replaces the program of the calling process with the executable at `path`.
+///
+/// Open files are kept. On success the old address space is gone, and the caller
+/// has to continue in user mode at the returned entry point and stack pointer.
+pub fn exec(path:&str, argv:&[&str], envp:&[&str]) -> Result<(VirtAddr,VirtAddr),Errno> {
+    let pid = current_pid().ok_or(Errno::EPERM)?;
+    let image = vfs::read_file(path)?;
+    let program = elf::load(&image,argv,envp).map_err(|err|match err {
+        ElfError::OutOfMemory => Errno::ENOMEM,
+        _ => Errno::ENOEXEC,
+    })?;
+    let old = task::replace_address_space(program.address_space.clone());
+    with_table(|table|{
+        let process = table.process_mut(pid);
+        process.address_space = Some(program.address_space);
+        process.name = String::from(path.rsplit('/').next().unwrap_or(path));
+        process.signals.exec();
+    });
+    drop(old);
+    Ok((program.entry,program.stack_pointer))
+}
+
+/// Makes `pid` the process that receives SIGINT when Ctrl-C is pressed.
+pub fn set_foreground(pid:Option<Pid>) {
+    FOREGROUND.store(pid.map_or(0,Pid::as_u64),Ordering::Relaxed);
+}
+
+pub fn foreground() -> Option<Pid> {
+    match FOREGROUND.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+/// The process the calling thread belongs to, `None` for kernel threads.
+pub fn current_pid() -> Option<Pid> {
+    let thread = task::current_id();
+    with_table(|table|table.current_pid(thread))
+}
+
+/// The parent of the calling process.
+pub fn parent_pid() -> Option<Pid> {
+    let thread = task::current_id();
+    with_table(|table|{
+        let pid = table.current_pid(thread)?;
+        table.processes[&pid].parent
+    })
+}
+
+/// Terminates the calling process with exit `code`, or only the thread if it
+/// doesn't belong to a process.
+pub fn exit(code:i32) -> ! {
+    let thread = task::current_id();
+    let exited = with_table(|table|{
+        let pid = table.owners.remove(&thread)?;
+        let process = table.process_mut(pid);
+        process.threads.retain(|&id|id != thread);
+        process.state = ProcessState::Zombie(code);
+        //closing files and handles may wake other threads, so it happens after unlocking
+        let resources = (
+            core::mem::replace(&mut process.files,FileTable::new()),
+            core::mem::replace(&mut process.handles,HandleTable::new()),
+        );
+        process.address_space = None;//the thread keeps its own reference until it is gone
+        let (orphan,parent) = (process.orphan,process.parent);
+        if let Some(parent) = parent.and_then(|parent|table.processes.get_mut(&parent)) {
+            parent.signals.raise(signal::SIGCHLD);
+        }
+        //our children are orphans now; the ones that already exited are gone
+        table.processes.retain(|_,child|child.parent != Some(pid) || child.state == ProcessState::Running);
+        for child in table.processes.values_mut().filter(|child|child.parent == Some(pid)) {
+            child.parent = None;
+            child.orphan = true;
+        }
+        if orphan {
+            table.processes.remove(&pid);
+        }
+        Some(resources)
+    });
+    if let Some(resources) = exited {
+        drop(resources);
+        EXITED.notify_all();
+    }
+    task::exit();
+}
+
+/// Waits until a child of the caller exits and returns its pid and exit code.
+///
+/// `pid` selects a specific child, `None` accepts any. Kernel threads may wait for
+/// processes the kernel started. Fails with `ECHILD` if there is nothing to wait for
+/// and with `EINTR` if a signal arrives first.
+pub fn wait(pid:Option<Pid>) -> Result<(Pid,i32),Errno> {
+    let caller = with_table(|table|table.current_pid(task::current_id()));
+    let mut result = Err(Errno::ECHILD);
+    EXITED.wait_until(||{
+        with_table(|table|{
+            if let Some(caller) = caller {
+                if table.processes[&caller].signals.interrupts() {
+                    result = Err(Errno::EINTR);
+                    return true;
+                }
+            }
+            let mut children = table.processes.iter()
+                .filter(|(child,process)|process.parent == caller && !process.orphan && pid.map_or(true,|pid|pid == **child));
+            let mut any = false;
+            let zombie = children.find_map(|(&child,process)|{
+                any = true;
+                match process.state {
+                    ProcessState::Zombie(code) => Some((child,code)),
+                    ProcessState::Running => None,
+                }
+            });
+            match zombie {
+                Some((child,code)) => {
+                    table.processes.remove(&child);
+                    result = Ok((child,code));
+                    true
+                }
+                None => !any,//keep waiting only while there is a child that may still exit
+            }
+        })
+    });
+    result
+}
+
+/// Lists all processes, including zombies, ordered by pid.
+pub fn list() -> Vec<ProcessInfo> {
+    with_table(|table|{
+        table.processes.iter().map(|(&pid,process)|ProcessInfo {
+            pid,
+            parent:process.parent,
+            name:process.name.clone(),
+            state:process.state,
+            threads:process.threads.len(),
+            open_files:process.files.len(),
+        }).collect()
+    })
+}
+
+/// Runs `f` on the file table of the calling process.
+pub fn with_files<R, F:FnOnce(&mut FileTable)->R>(f:F) -> Result<R,Errno> {
+    let thread = task::current_id();
+    with_table(|table|{
+        let pid = table.current_pid(thread).ok_or(Errno::EBADF)?;
+        Ok(f(&mut table.process_mut(pid).files))
+    })
+}
+
+/// Runs `f` on the handle table of the calling process.
+pub fn with_handles<R, F:FnOnce(&mut HandleTable)->R>(f:F) -> Result<R,Errno> {
+    let thread = task::current_id();
+    with_table(|table|{
+        let pid = table.current_pid(thread).ok_or(Errno::EBADF)?;
+        Ok(f(&mut table.process_mut(pid).handles))
+    })
+}
+
+/// The open file behind descriptor `fd` of the calling process.
+pub fn file(fd:usize) -> Result<Arc<dyn File>,Errno> {
+    with_files(|files|files.get(fd))?
+}
@@ -0,0 +1,336 @@
+//! POSIX-like signals.
+//!
+//! Every process has a set of pending and a set of blocked signals and an action
+//! per signal. Signals are delivered when the process returns to user mode from a
+//! system call, a timer interrupt or an exception: a handler runs on the user stack
+//! with the interrupted registers saved in a `SignalFrame` below it, and returns
+//! through `sigreturn`. Exceptions in user code raise SIGSEGV, SIGFPE or SIGILL,
+//! which only terminate the process if there is no handler that can run.
+
+use crate::interrupts::TrapFrame;
+use crate::syscall::{uaccess,Errno,SyscallFrame};
+use crate::{gdt,task};
+use crate::usermode::{self,USER_START,USER_END};
+use super::{Pid,ProcessState,with_table};
+
+pub const SIGHUP:u32 = 1;
+pub const SIGINT:u32 = 2;
+pub const SIGQUIT:u32 = 3;
+pub const SIGILL:u32 = 4;
+pub const SIGTRAP:u32 = 5;
+pub const SIGABRT:u32 = 6;
+pub const SIGBUS:u32 = 7;
+pub const SIGFPE:u32 = 8;
+pub const SIGKILL:u32 = 9;
+pub const SIGUSR1:u32 = 10;
+pub const SIGSEGV:u32 = 11;
+pub const SIGUSR2:u32 = 12;
+pub const SIGPIPE:u32 = 13;
+pub const SIGALRM:u32 = 14;
+pub const SIGTERM:u32 = 15;
+pub const SIGCHLD:u32 = 17;
+pub const SIGCONT:u32 = 18;
+pub const SIGSTOP:u32 = 19;
+pub const SIGTSTP:u32 = 20;
+/// Signals are numbered 1 to `NSIG - 1`.
+pub const NSIG:u32 = 32;
+
+/// `sigaction` handler values with a special meaning.
+pub const SIG_DFL:u64 = 0;
+pub const SIG_IGN:u64 = 1;
+
+/// `sigprocmask` operations.
+pub const SIG_BLOCK:u64 = 0;
+pub const SIG_UNBLOCK:u64 = 1;
+pub const SIG_SETMASK:u64 = 2;
+
+/// Signals that can be neither caught, ignored nor blocked.
+const UNCATCHABLE:u32 = bit(SIGKILL) | bit(SIGSTOP);
+
+/// Leaf functions may use 128 bytes below rsp, which the signal frame must not overwrite.
+const RED_ZONE:u64 = 128;
+
+const fn bit(signal:u32) -> u32 {
+    1 << signal
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// Call `handler(signal)`, which returns to `restorer`; that has to call `sigreturn`.
+    Handler{handler:u64, restorer:u64},
+}
+
+/// Signal state of a process.
+#[derive(Debug,Clone)]
+pub(super) struct Signals {
+    pending:u32,
+    blocked:u32,
+    actions:[Action;NSIG as usize],
+}impl Signals {
+    pub(super) fn new() -> Self {
+        Signals {
+            pending:0,
+            blocked:0,
+            actions:[Action::Default;NSIG as usize],
+        }
+    }
+
+    /// The state of a forked child: the same actions and mask, nothing pending.
+    pub(super) fn fork(&self) -> Self {
+        Signals {pending:0, ..self.clone()}
+    }
+
+    /// After `exec` the handlers are gone, ignored signals stay ignored.
+    pub(super) fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let Action::Handler{..} = action {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    pub(super) fn raise(&mut self, signal:u32) {
+        self.pending |= bit(signal);
+    }
+
+    /// Pending signals that aren't blocked, lowest first.
+    fn deliverable(&self) -> impl Iterator<Item=u32> {
+        let set = self.pending & !(self.blocked & !UNCATCHABLE);
+        (1..NSIG).filter(move |&signal|set & bit(signal) != 0)
+    }
+
+    /// True if a signal is waiting that would interrupt a blocking system call.
+    pub(super) fn interrupts(&self) -> bool {
+        self.deliverable().any(|signal|self.effective_action(signal) != Action::Ignore)
+    }
+
+    /// The action with default actions that ignore the signal resolved to `Ignore`.
+    fn effective_action(&self, signal:u32) -> Action {
+        match self.actions[signal as usize] {
+            Action::Default if ignored_by_default(signal) => Action::Ignore,
+            action => action,
+        }
+    }
+}
+
+/// Signals whose default action is to do nothing. Without job control, stopping
+/// and continuing are ignored as well.
+fn ignored_by_default(signal:u32) -> bool {
+    match signal {
+        SIGCHLD|SIGCONT|SIGTSTP|SIGSTOP => true,
+        _ => signal >= 23,//SIGURG, SIGWINCH and the ones we don't name
+    }
+}
+
+/// Exit code of a process terminated by `signal`, as shells report it.
+pub fn termination_code(signal:u32) -> i32 {
+    128 + signal as i32
+}
+
+fn check_signal(signal:u32) -> Result<(),Errno> {
+    if signal == 0 || signal >= NSIG {Err(Errno::EINVAL)} else {Ok(())}
+}
+
+/// Sends `signal` to the process `pid`. Signal 0 only checks that the process exists.
+/// May be called from interrupt handlers.
+pub fn kill(pid:Pid, signal:u32) -> Result<(),Errno> {
+    if signal >= NSIG {
+        return Err(Errno::EINVAL);
+    }
+    let threads = with_table(|table|{
+        let process = table.processes.get_mut(&pid).ok_or(Errno::ESRCH)?;
+        if process.state != ProcessState::Running {
+            return Err(Errno::ESRCH);
+        }
+        if signal != 0 {
+            process.signals.raise(signal);
+        }
+        Ok(process.threads.clone())
+    })?;
+    for thread in threads {
+        task::wake(thread);//let a blocking system call notice the signal
+    }
+    Ok(())
+}
+
+/// True if the calling process has a signal pending that should interrupt a
+/// blocking system call with `EINTR`. Always false for kernel threads.
+pub fn interrupted() -> bool {
+    let thread = task::current_id();
+    with_table(|table|{
+        table.current_pid(thread).map_or(false,|pid|table.processes[&pid].signals.interrupts())
+    })
+}
+
+/// Changes the action for `signal` of the calling process and returns the old one.
+pub fn set_action(signal:u32, action:Action) -> Result<Action,Errno> {
+    check_signal(signal)?;
+    if bit(signal) & UNCATCHABLE != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let thread = task::current_id();
+    with_table(|table|{
+        let pid = table.current_pid(thread).ok_or(Errno::ESRCH)?;
+        let signals = &mut table.process_mut(pid).signals;
+        let old = core::mem::replace(&mut signals.actions[signal as usize],action);
+        if signals.effective_action(signal) == Action::Ignore {
+            signals.pending &= !bit(signal);//POSIX: setting SIG_IGN discards a pending signal
+        }
+        Ok(old)
+    })
+}
+
+/// Changes the blocked mask of the calling process as `sigprocmask(how, set)` and returns the old mask.
+pub fn set_blocked(how:u64, set:u32) -> Result<u32,Errno> {
+    let thread = task::current_id();
+    with_table(|table|{
+        let pid = table.current_pid(thread).ok_or(Errno::ESRCH)?;
+        let signals = &mut table.process_mut(pid).signals;
+        let old = signals.blocked;
+        signals.blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(Errno::EINVAL),
+        } & !UNCATCHABLE & !1;
+        Ok(old)
+    })
+}
+
+/// Raises `signal`, caused by the calling process itself, so that it can't be put
+/// off: if it is blocked or ignored, the default action applies instead, as the
+/// faulting instruction would only fault again.
+pub fn force(signal:u32) {
+    let thread = task::current_id();
+    with_table(|table|{
+        if let Some(pid) = table.current_pid(thread) {
+            let signals = &mut table.process_mut(pid).signals;
+            let handled = match signals.actions[signal as usize] {
+                Action::Handler{..} => signals.blocked & bit(signal) == 0,
+                _ => false,
+            };
+            if !handled {
+                signals.actions[signal as usize] = Action::Default;
+                signals.blocked &= !bit(signal);
+            }
+            signals.raise(signal);
+        }
+    });
+}
+
+/// Pushed on the user stack below the return address of a signal handler.
+#[repr(C)]
+#[derive(Debug,Clone,Copy)]
+struct SignalFrame {
+    saved:TrapFrame,//registers of the interrupted code
+    blocked:u32,//mask to restore on `sigreturn`
+    signal:u32,
+}
+
+/// Takes the next deliverable signal of the calling process, if any, with its
+/// action and the mask in effect before delivery.
+fn take_next() -> Option<(u32,Action,u32)> {
+    let thread = task::current_id();
+    with_table(|table|{
+        let pid = table.current_pid(thread)?;
+        let signals = &mut table.process_mut(pid).signals;
+        let signal = signals.deliverable().next()?;
+        signals.pending &= !bit(signal);
+        let action = signals.effective_action(signal);
+        let blocked = signals.blocked;
+        if let Action::Handler{..} = action {
+            signals.blocked |= bit(signal);//no nested delivery of the same signal
+        }
+        Some((signal,action,blocked))
+    })
+}
+
+/// The registers a system call returns to user mode with; `sysret` leaves rip
+/// and rflags in rcx and r11.
+fn from_syscall(frame:&SyscallFrame) -> TrapFrame {
+    let (code,data) = gdt::user_selectors();
+    TrapFrame {
+        r15:frame.r15, r14:frame.r14, r13:frame.r13, r12:frame.r12, r11:frame.rflags, r10:frame.r10,
+        r9:frame.r9, r8:frame.r8, rbp:frame.rbp, rdi:frame.rdi, rsi:frame.rsi, rdx:frame.rdx,
+        rcx:frame.rip, rbx:frame.rbx, rax:frame.rax, error_code:0,
+        rip:frame.rip, cs:code.0 as u64, rflags:frame.rflags, rsp:frame.rsp, ss:data.0 as u64,
+    }
+}
+
+fn to_syscall(frame:&TrapFrame) -> SyscallFrame {
+    SyscallFrame {
+        r9:frame.r9, r8:frame.r8, r10:frame.r10, rdx:frame.rdx, rsi:frame.rsi, rdi:frame.rdi,
+        rax:frame.rax, r15:frame.r15, r14:frame.r14, r13:frame.r13, r12:frame.r12, rbx:frame.rbx,
+        rbp:frame.rbp, rip:frame.rip, rflags:frame.rflags, rsp:frame.rsp,
+    }
+}
+
+/// Delivers pending signals before returning to user mode from a system call with `frame`.
+pub fn deliver(frame:&mut SyscallFrame) {
+    let mut trap = from_syscall(frame);
+    deliver_to(&mut trap);
+    *frame = to_syscall(&trap);
+}
+
+/// Delivers pending signals before an interrupt or exception returns with `frame`,
+/// if that goes back to user mode.
+pub fn deliver_on_interrupt(frame:&mut TrapFrame) {
+    if frame.from_user_mode() {
+        deliver_to(frame);
+    }
+}
+
+/// Terminates the process for signals with a terminating default action. For a
+/// handler, `frame` is changed to enter the handler; the next signal is only
+/// delivered when the handler returns or is interrupted.
+fn deliver_to(frame:&mut TrapFrame) {
+    while let Some((signal,action,blocked)) = take_next() {
+        match action {
+            Action::Ignore => continue,
+            Action::Default => super::exit(termination_code(signal)),
+            Action::Handler{handler,restorer} => {
+                if push_frame(frame,signal,handler,restorer,blocked).is_err() {
+                    super::exit(termination_code(SIGSEGV));//no usable stack for the handler
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn push_frame(frame:&mut TrapFrame, signal:u32, handler:u64, restorer:u64, blocked:u32) -> Result<(),Errno> {
+    let size = core::mem::size_of::<SignalFrame>() as u64;
+    let frame_addr = frame.rsp.checked_sub(RED_ZONE + size).ok_or(Errno::EFAULT)? & !0xf;
+    let return_addr = frame_addr - 8;//as after a `call`, rsp + 8 is 16-byte aligned
+    uaccess::write_user(frame_addr,SignalFrame {saved:*frame, blocked, signal})?;
+    uaccess::write_user(return_addr,restorer)?;
+    frame.rip = handler;
+    frame.rdi = signal as u64;
+    frame.rsp = return_addr;
+    frame.rflags &= !(1<<10);//the ABI wants the direction flag clear on function entry
+    Ok(())
+}
+
+/// Returns from a signal handler: restores the registers and mask saved in the
+/// signal frame, which the handler's `ret` left at the stack pointer, and goes
+/// back to the interrupted code, or the handler of the next pending signal.
+/// Only returns on failure.
+pub fn sigreturn(frame:&mut SyscallFrame) -> Result<u64,Errno> {
+    let saved:SignalFrame = uaccess::read_user(frame.rsp)?;
+    let mut trap = saved.saved;
+    //iretq to a non-canonical address would fault in ring 0
+    if trap.rip < USER_START || trap.rip >= USER_END || trap.rsp < USER_START || trap.rsp > USER_END {
+        super::exit(termination_code(SIGSEGV));
+    }
+    let thread = task::current_id();
+    with_table(|table|{
+        let pid = table.current_pid(thread).ok_or(Errno::ESRCH)?;
+        table.process_mut(pid).signals.blocked = saved.blocked & !UNCATCHABLE;
+        Ok(())
+    })?;
+    deliver_to(&mut trap);
+    //sysret would clobber rcx and r11, which an interrupt may have come between
+    unsafe { usermode::resume_trap(&trap) }
+}
@@ -1,22 +1,423 @@
-use uart_16550::SerialPort;
+//! The 16550 UARTs COM1 to COM4.
+//!
+//! COM1 works from the first `serial_print!` on, by polling. `init` switches every
+//! port that is present to interrupts: received bytes land in a ring buffer which
+//! `read` and friends take them from, and bytes written while the transmitter is
+//! busy wait in another one until the UART asks for more. COM1 and COM3 share
+//! IRQ 4, COM2 and COM4 IRQ 3.
+
+use core::fmt;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::sync::WaitQueue;
+use crate::syscall::Errno;
+
+/// The I/O ports of COM1 to COM4 and their IRQs.
+const BASES:[u16;4] = [0x3f8,0x2f8,0x3e8,0x2e8];
+const IRQS:[u8;4] = [4,3,4,3];
+/// Ports and IRQ numbers are indexed from 0: COM1 is port 0.
+pub const COM1:usize = 0;
+pub const COM2:usize = 1;
+pub const COM3:usize = 2;
+pub const COM4:usize = 3;
+
+/// Register offsets; with the DLAB bit set, 0 and 1 hold the baud rate divisor.
+const DATA:u16 = 0;
+const INTERRUPT_ENABLE:u16 = 1;
+const FIFO_CONTROL:u16 = 2;
+const LINE_CONTROL:u16 = 3;
+const MODEM_CONTROL:u16 = 4;
+const LINE_STATUS:u16 = 5;
+
+const IER_RECEIVED:u8 = 0x01;
+const IER_TRANSMIT_EMPTY:u8 = 0x02;
+const LCR_DLAB:u8 = 0x80;
+/// Enable and clear the FIFOs, interrupt at 14 received bytes.
+const FCR_ENABLE:u8 = 0xc7;
+/// DTR, RTS and OUT2, the last of which connects the interrupt line.
+const MCR_NORMAL:u8 = 0x0b;
+const MCR_LOOPBACK:u8 = 0x1e;
+const LSR_DATA_READY:u8 = 0x01;
+const LSR_TRANSMIT_EMPTY:u8 = 0x20;
+/// What a missing UART reads as.
+const LSR_MISSING:u8 = 0xff;
+
+/// The transmit FIFO's size: how many bytes go out per interrupt.
+const FIFO_SIZE:usize = 16;
+/// The clock the divisor divides.
+const MAX_BAUD:u32 = 115_200;
+const RING_SIZE:usize = 1024;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Always 1.
+    Mark,
+    /// Always 0.
+    Space,
+}
+
+/// Line settings; the default is 115200 baud, 8N1.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Config {
+    pub baud:u32,
+    /// 5 to 8.
+    pub data_bits:u8,
+    pub parity:Parity,
+    /// 1 or 2.
+    pub stop_bits:u8,
+}impl Config {
+    /// The divisor and line control value, or None for settings the UART lacks.
+    fn registers(&self) -> Option<(u16,u8)> {
+        if self.baud == 0 || MAX_BAUD%self.baud != 0 || !(5..=8).contains(&self.data_bits) || !(1..=2).contains(&self.stop_bits) {
+            return None;
+        }
+        let divisor = MAX_BAUD/self.baud;
+        if divisor > u16::MAX as u32 {
+            return None;//below 2 baud
+        }
+        let parity = match self.parity {
+            Parity::None => 0x00,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        };
+        Some((divisor as u16,(self.data_bits - 5) | (self.stop_bits - 1) << 2 | parity))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {baud:MAX_BAUD, data_bits:8, parity:Parity::None, stop_bits:1}
+    }
+}
+
+/// A byte queue that needs no heap, so COM1 works before there is one.
+struct Ring {
+    buf:[u8;RING_SIZE],
+    start:usize,
+    len:usize,
+}impl Ring {
+    const fn new() -> Self {
+        Ring {buf:[0;RING_SIZE], start:0, len:0}
+    }
+    fn push(&mut self, byte:u8) -> bool {
+        if self.len == RING_SIZE {
+            return false;
+        }
+        self.buf[(self.start + self.len)%RING_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.start];
+        self.start = (self.start + 1)%RING_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+pub struct SerialPort {
+    base:u16,
+    present:bool,
+    /// Set by `init`; until then everything is polled.
+    interrupts:bool,
+    config:Config,
+    rx:Ring,
+    tx:Ring,
+    /// Received bytes dropped because `rx` was full.
+    overruns:u64,
+}impl SerialPort {
+    const fn new(base:u16) -> Self {
+        SerialPort {base, present:false, interrupts:false, config:Config {baud:MAX_BAUD, data_bits:8, parity:Parity::None, stop_bits:1}, rx:Ring::new(), tx:Ring::new(), overruns:0}
+    }
+
+    fn read_reg(&self, reg:u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + reg).read() }
+    }
+    fn write_reg(&self, reg:u16, value:u8) {
+        unsafe { Port::<u8>::new(self.base + reg).write(value) }
+    }
+
+    /// Whether a UART answers at `base`: in loopback mode it must read back what is
+    /// written.
+    fn probe(&mut self) -> bool {
+        self.write_reg(INTERRUPT_ENABLE,0);
+        self.write_reg(MODEM_CONTROL,MCR_LOOPBACK);
+        self.write_reg(DATA,0xae);
+        self.present = self.read_reg(DATA) == 0xae;
+        self.write_reg(MODEM_CONTROL,MCR_NORMAL);
+        self.present
+    }
+
+    fn program(&mut self, config:Config) -> Result<(),Errno> {
+        let (divisor,line) = config.registers().ok_or(Errno::EINVAL)?;
+        self.write_reg(INTERRUPT_ENABLE,0);
+        self.write_reg(LINE_CONTROL,LCR_DLAB);
+        self.write_reg(DATA,divisor as u8);
+        self.write_reg(INTERRUPT_ENABLE,(divisor >> 8) as u8);
+        self.write_reg(LINE_CONTROL,line);
+        self.write_reg(FIFO_CONTROL,FCR_ENABLE);
+        self.write_reg(MODEM_CONTROL,MCR_NORMAL);
+        self.config = config;
+        self.enable_interrupts();
+        Ok(())
+    }
+
+    fn enable_interrupts(&self) {
+        if self.interrupts {
+            let transmit = if self.tx.len > 0 {IER_TRANSMIT_EMPTY} else {0};
+            self.write_reg(INTERRUPT_ENABLE,IER_RECEIVED | transmit);
+        }
+    }
+
+    fn transmit_empty(&self) -> bool {
+        self.read_reg(LINE_STATUS) & LSR_TRANSMIT_EMPTY != 0
+    }
+
+    /// Waits for the transmitter, then hands it `byte`.
+    fn send_polled(&self, byte:u8) {
+        while !self.transmit_empty() {
+            core::hint::spin_loop();
+        }
+        self.write_reg(DATA,byte);
+    }
+
+    pub fn write_byte(&mut self, byte:u8) {
+        if !self.interrupts {
+            return self.send_polled(byte);
+        }
+        if self.tx.len == 0 && self.transmit_empty() {
+            return self.write_reg(DATA,byte);
+        }
+        if self.tx.len == RING_SIZE {
+            //full: make room the slow way, the interrupt cannot come while we hold the lock
+            let oldest = self.tx.pop().unwrap();
+            self.send_polled(oldest);
+        }
+        self.tx.push(byte);
+        self.enable_interrupts();
+    }
+
+    /// Sends what waits in the transmit buffer right away.
+    fn flush(&mut self) {
+        while let Some(byte) = self.tx.pop() {
+            self.send_polled(byte);
+        }
+        self.enable_interrupts();
+    }
+
+    /// Moves received bytes into `rx` and waiting ones into the transmitter. Returns
+    /// whether something was received.
+    fn service(&mut self) -> bool {
+        let mut received = false;
+        loop {
+            let status = self.read_reg(LINE_STATUS);
+            if status == LSR_MISSING || status & LSR_DATA_READY == 0 {
+                break;
+            }
+            let byte = self.read_reg(DATA);
+            if !self.rx.push(byte) {
+                self.overruns += 1;
+            }
+            received = true;
+        }
+        if self.tx.len > 0 && self.transmit_empty() {
+            for _ in 0..FIFO_SIZE {
+                match self.tx.pop() {
+                    Some(byte) => self.write_reg(DATA,byte),
+                    None => break,
+                }
+            }
+        }
+        self.enable_interrupts();//stops the transmit interrupts once `tx` is empty
+        received
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s:&str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
 
 lazy_static! {
-    pub static ref SERIAL1:Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+    pub static ref PORTS:[Mutex<SerialPort>;4] = {
+        let ports = [
+            Mutex::new(SerialPort::new(BASES[0])),
+            Mutex::new(SerialPort::new(BASES[1])),
+            Mutex::new(SerialPort::new(BASES[2])),
+            Mutex::new(SerialPort::new(BASES[3])),
+        ];
+        {
+            let mut com1 = ports[COM1].lock();
+            com1.present = true;//what the early prints go to, even if the probe would fail
+            com1.program(Config::default()).unwrap();
+        }
+        ports
+    };
+    /// Threads waiting in `read`, per port.
+    static ref READERS:[WaitQueue;4] = [WaitQueue::new(),WaitQueue::new(),WaitQueue::new(),WaitQueue::new()];
+}
+
+fn with_port<R>(index:usize, f:impl FnOnce(&mut SerialPort)->R) -> Result<R,Errno> {
+    let port = PORTS.get(index).ok_or(Errno::ENODEV)?;
+    interrupts::without_interrupts(||{
+        let mut port = port.lock();
+        if !port.present {
+            return Err(Errno::ENODEV);
+        }
+        Ok(f(&mut port))
+    })
+}
+
+/// Probes COM2 to COM4 and switches every port found to interrupts. Returns which
+/// ports are present.
+pub fn init() -> [bool;4] {
+    let mut present = [false;4];
+    for (index,port) in PORTS.iter().enumerate() {
+        interrupts::without_interrupts(||{
+            let mut port = port.lock();
+            if index != COM1 && port.probe() {
+                port.program(Config::default()).unwrap();
+            }
+            if port.present {
+                port.interrupts = true;
+                port.enable_interrupts();
+            }
+            present[index] = port.present;
+        });
+        if present[index] {
+            crate::interrupts::unmask_irq(IRQS[index]);
+        }
+    }
+    present
+}
+
+/// Changes the line settings of port `index`. Fails with `EINVAL` for settings the
+/// UART cannot do and `ENODEV` if the port is missing.
+pub fn configure(index:usize, config:Config) -> Result<(),Errno> {
+    with_port(index,|port|{
+        port.flush();//the waiting bytes go out with the old settings
+        port.program(config)
+    })?
+}
+
+pub fn config(index:usize) -> Result<Config,Errno> {
+    with_port(index,|port|port.config)
+}
+
+/// Writes `data` to port `index`, as is.
+pub fn write(index:usize, data:&[u8]) -> Result<usize,Errno> {
+    with_port(index,|port|{
+        for &byte in data {
+            port.write_byte(byte);
+        }
+        data.len()
+    })
+}
+
+/// Takes what was received so far, up to `buf.len()` bytes, without waiting.
+pub fn try_read(index:usize, buf:&mut [u8]) -> Result<usize,Errno> {
+    with_port(index,|port|{
+        port.service();//for when the interrupt is late or missing
+        let mut len = 0;
+        while len < buf.len() {
+            match port.rx.pop() {
+                Some(byte) => buf[len] = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    })
+}
+
+/// Waits until something was received and reads it, up to `buf.len()` bytes.
+pub fn read(index:usize, buf:&mut [u8]) -> Result<usize,Errno> {
+    read_timeout(index,buf,None)
+}
+
+/// Like `read`, but gives up with `ETIMEDOUT` after `timeout` ticks, if there is one.
+pub fn read_timeout(index:usize, buf:&mut [u8], timeout:Option<u64>) -> Result<usize,Errno> {
+    if buf.is_empty() {
+        return with_port(index,|_|0);
+    }
+    let queue = READERS.get(index).ok_or(Errno::ENODEV)?;
+    let mut result = Ok(0);
+    let mut condition = ||{
+        result = try_read(index,buf);
+        result != Ok(0)
+    };
+    let done = match timeout {
+        Some(timeout) => queue.wait_until_timeout(&mut condition,timeout),
+        None => {
+            queue.wait_until(&mut condition);
+            true
+        }
     };
+    if !done {
+        return Err(Errno::ETIMEDOUT);
+    }
+    result
+}
+
+/// Switches port `index` to loopback mode, where what is written is received
+/// instead of sent, for testing the port.
+pub fn set_loopback(index:usize, enabled:bool) -> Result<(),Errno> {
+    with_port(index,|port|{
+        port.flush();
+        port.write_reg(MODEM_CONTROL,if enabled {MCR_LOOPBACK} else {MCR_NORMAL});
+    })
+}
+
+/// Bytes dropped on port `index` because nobody read them in time.
+pub fn overruns(index:usize) -> Result<u64,Errno> {
+    with_port(index,|port|port.overruns)
+}
+
+/// Sends everything still buffered on every port, e.g. before QEMU exits.
+pub fn flush() {
+    for port in PORTS.iter() {
+        interrupts::without_interrupts(||{
+            let mut port = port.lock();
+            if port.present {
+                port.flush();
+            }
+        });
+    }
+}
+
+/// Called by the handlers of IRQ 3 and 4 for the ports on `irq`.
+pub fn handle_interrupt(irq:u8) {
+    for index in (0..PORTS.len()).filter(|&index|IRQS[index] == irq) {
+        let received = {
+            let mut port = PORTS[index].lock();
+            port.present && port.interrupts && port.service()
+        };
+        if received {
+            READERS[index].notify_all();
+        }
+    }
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(||{
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+        PORTS[COM1].lock().write_fmt(args).expect("Printing to serial failed");
     });
 }
 
@@ -32,4 +433,4 @@ macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
     ($fmt:expr)=> ($crate::serial_print!(concat!($fmt,"\n")));
     ($fmt:expr,$($arg:tt)*)=>($crate::serial_print!(concat!($fmt,"\n"),$($arg)*));
-}
\ No newline at end of file
+}
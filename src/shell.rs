@@ -0,0 +1,163 @@
+//! The kernel shell, on any `console::Terminal`: the serial port for headless runs,
+//! the screen and keyboard otherwise, with the same commands on both. Besides the
+//! built-in ones, modules add commands of their own with `register`.
+
+use alloc::{collections::BTreeMap,string::String,sync::Arc,vec::Vec};
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::console::{LineEditor,Output,Terminal};
+use crate::syscall::Errno;
+use crate::vfs::{self,FileType};
+use crate::{allocator,memory,power,task,time};
+
+const PROMPT:&str = "bentos> ";
+
+/// A shell command. `run` gets the words after the command name.
+#[derive(Clone,Copy)]
+pub struct Command {
+    pub name:&'static str,
+    pub help:&'static str,
+    pub run:fn(out:&mut Output, args:&[&str]) -> Result<(),Errno>,
+}
+
+const BUILTINS:&[Command] = &[
+    Command {name:"help", help:"lists the commands", run:help},
+    Command {name:"echo", help:"prints its arguments", run:echo},
+    Command {name:"mem", help:"shows heap and frame usage", run:mem},
+    Command {name:"irq", help:"shows how many interrupts came in", run:irq},
+    Command {name:"uptime", help:"shows the time since boot", run:uptime},
+    Command {name:"ls", help:"lists a directory", run:ls},
+    Command {name:"cat", help:"prints files", run:cat},
+    Command {name:"reboot", help:"restarts the machine", run:reboot},
+    Command {name:"shutdown", help:"syncs the file systems and powers off", run:shutdown},
+];
+
+lazy_static! {
+    static ref COMMANDS:Mutex<BTreeMap<&'static str,Command>> = Mutex::new(
+        BUILTINS.iter().map(|command|(command.name,*command)).collect()
+    );
+}
+
+/// Adds a command for every shell; EEXIST if one by that name exists.
+pub fn register(command:Command) -> Result<(),Errno> {
+    interrupts::without_interrupts(||{
+        let mut commands = COMMANDS.lock();
+        if commands.contains_key(command.name) {
+            return Err(Errno::EEXIST);
+        }
+        commands.insert(command.name,command);
+        Ok(())
+    })
+}
+
+fn find(name:&str) -> Option<Command> {
+    interrupts::without_interrupts(||COMMANDS.lock().get(name).copied())
+}
+
+fn help(out:&mut Output, _args:&[&str]) -> Result<(),Errno> {
+    let commands:Vec<Command> = interrupts::without_interrupts(||COMMANDS.lock().values().copied().collect());
+    for command in commands {
+        let _ = writeln!(out,"{:<10}{}",command.name,command.help);
+    }
+    Ok(())
+}
+
+fn echo(out:&mut Output, args:&[&str]) -> Result<(),Errno> {
+    let _ = writeln!(out,"{}",args.join(" "));
+    Ok(())
+}
+
+fn mem(out:&mut Output, _args:&[&str]) -> Result<(),Errno> {
+    let (used,free) = allocator::heap_stats();
+    let _ = writeln!(out,"heap    {} KiB used, {} KiB free",used/1024,free/1024);
+    if let Some((total,used)) = memory::frame_stats() {
+        let _ = writeln!(out,"frames  {} of {} used ({} KiB free)",used,total,total.saturating_sub(used)*4);
+    }
+    Ok(())
+}
+
+fn irq(out:&mut Output, _args:&[&str]) -> Result<(),Errno> {
+    for (vector,name,count) in crate::interrupts::counts() {
+        let _ = writeln!(out,"{:#04x}  {:<14}{}",vector,name,count);
+    }
+    Ok(())
+}
+
+fn uptime(out:&mut Output, _args:&[&str]) -> Result<(),Errno> {
+    let seconds = time::ticks()/time::TICKS_PER_SECOND;
+    let _ = writeln!(out,"up {}:{:02}:{:02}",seconds/3600,seconds/60%60,seconds%60);
+    Ok(())
+}
+
+fn ls(out:&mut Output, args:&[&str]) -> Result<(),Errno> {
+    let path = args.first().copied().unwrap_or("/");
+    if vfs::stat(path)?.kind != FileType::Directory {
+        let _ = writeln!(out,"{}",path);
+        return Ok(());
+    }
+    for entry in vfs::readdir(path)? {
+        let suffix = match entry.kind {
+            FileType::Directory => "/",
+            FileType::Symlink => "@",
+            _ => "",
+        };
+        let _ = writeln!(out,"{}{}",entry.name,suffix);
+    }
+    Ok(())
+}
+
+fn cat(out:&mut Output, args:&[&str]) -> Result<(),Errno> {
+    if args.is_empty() {
+        return Err(Errno::EINVAL);
+    }
+    for path in args {
+        let data = vfs::read_file(path)?;
+        let _ = out.write_str(&String::from_utf8_lossy(&data));
+    }
+    Ok(())
+}
+
+fn reboot(_out:&mut Output, _args:&[&str]) -> Result<(),Errno> {
+    power::reboot();
+}
+
+fn shutdown(_out:&mut Output, _args:&[&str]) -> Result<(),Errno> {
+    power::shutdown();
+}
+
+/// Runs the command on `line`, printing its output and errors to `terminal`.
+pub fn execute(terminal:&dyn Terminal, line:&str) {
+    let words:Vec<&str> = line.split_whitespace().collect();
+    let (name,args) = match words.split_first() {
+        Some((name,args)) => (*name,args),
+        None => return,
+    };
+    let mut out = Output(terminal);
+    match find(name) {
+        Some(command) => {
+            if let Err(err) = (command.run)(&mut out,args) {
+                let _ = writeln!(out,"{}: {:?}",name,err);
+            }
+        }
+        None => {
+            let _ = writeln!(out,"{}: unknown command, try help",name);
+        }
+    }
+}
+
+/// Reads and runs commands on `terminal`, forever.
+pub fn run(terminal:&dyn Terminal) -> ! {
+    let mut editor = LineEditor::new();
+    loop {
+        if let Some(line) = editor.read_line(terminal,PROMPT) {
+            execute(terminal,&line);
+        }
+    }
+}
+
+/// Starts a shell on `terminal` in a thread of its own.
+pub fn spawn(terminal:Arc<dyn Terminal>) {
+    task::spawn(move ||run(&*terminal));
+}
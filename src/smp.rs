@@ -0,0 +1,148 @@
+use alloc::{boxed::Box,vec};
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use x86_64::{
+    PhysAddr,VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{mapper::MapToError,FrameAllocator,Mapper,Page,PageTableFlags,PhysFrame,Size4KiB},
+};
+use crate::{acpi::{Acpi,AcpiError},apic,gdt,interrupts,memory,task};
+use percpu::PerCpu;
+use spin::Mutex;
+
+pub mod percpu;
+pub mod ipi;
+pub mod tlb;
+mod trampoline;
+
+/// Upper bound on supported CPUs; CPU sets are stored as `u64` bitmasks.
+pub const MAX_CPUS:usize = 64;
+
+/// Kernel stack size of the initial (idle) thread of every application processor.
+const AP_STACK_SIZE:usize = 4096*4;
+
+static CPU_COUNT:AtomicUsize = AtomicUsize::new(1);
+static AP_STARTED:AtomicBool = AtomicBool::new(false);
+/// APIC ids of the running CPUs, indexed by CPU id.
+static APIC_IDS:Mutex<[u32;MAX_CPUS]> = Mutex::new([0;MAX_CPUS]);
+
+#[derive(Debug)]
+pub enum SmpError {
+    Acpi(AcpiError),
+    Map(MapToError<Size4KiB>),
+}
+impl From<AcpiError> for SmpError {
+    fn from(err:AcpiError) -> Self {
+        SmpError::Acpi(err)
+    }
+}
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(err:MapToError<Size4KiB>) -> Self {
+        SmpError::Map(err)
+    }
+}
+
+/// Number of CPUs that are up and scheduling threads.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Returns the local APIC id of the CPU with the given dense CPU id.
+pub fn apic_id_of(cpu:usize) -> Option<u32> {
+    if cpu < cpu_count() {
+        Some(x86_64::instructions::interrupts::without_interrupts(||APIC_IDS.lock()[cpu]))
+    } else {
+        None
+    }
+}
+
+/// Waits roughly `us` microseconds; every write to the POST port takes about 1us.
+fn io_delay(us:usize) {
+    use x86_64::instructions::port::Port;
+    let mut port:Port<u8> = Port::new(0x80);
+    for _ in 0..us {
+        unsafe { port.write(0) };
+    }
+}
+
+/// Brings up every enabled CPU listed in the ACPI MADT and returns the number of running CPUs.
+///
+/// Needs the heap. The application processors are started one after another with
+/// INIT-SIPI-SIPI, since they share the single trampoline copy in low memory.
+pub fn init(
+    mapper:&mut impl Mapper<Size4KiB>,
+    frame_allocator:&mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize,SmpError> {
+    let madt = Acpi::new()?.madt()?;
+    apic::init(PhysAddr::new(madt.local_apic_address),mapper,frame_allocator)?;
+    let bsp_apic_id = apic::id();
+    percpu::current().set_apic_id(bsp_apic_id);
+    APIC_IDS.lock()[0] = bsp_apic_id;
+
+    //the AP enables paging while running from the trampoline, so it has to be identity mapped
+    let trampoline_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(trampoline::TRAMPOLINE_ADDR));
+    let trampoline_page = Page::containing_address(VirtAddr::new(trampoline::TRAMPOLINE_ADDR));
+    match unsafe { mapper.map_to(trampoline_page,trampoline_frame,PageTableFlags::PRESENT|PageTableFlags::WRITABLE,frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::FrameAllocationFailed) => return Err(SmpError::Map(MapToError::FrameAllocationFailed)),
+        Err(_) => {}//the bootloader already identity maps low memory
+    }
+    let code = trampoline::code();
+    let base = memory::phys_to_virt(PhysAddr::new(trampoline::TRAMPOLINE_ADDR)).as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(),base,code.len()) };
+    let cr3 = Cr3::read().0.start_address().as_u64();
+
+    for cpu in madt.processors.iter().filter(|cpu|cpu.enabled && cpu.apic_id as u32 != bsp_apic_id) {
+        let cpu_id = cpu_count();
+        if cpu_id >= MAX_CPUS {
+            break;
+        }
+        let stack = Box::leak(vec![0u8;AP_STACK_SIZE].into_boxed_slice());
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+        let percpu:&'static PerCpu = Box::leak(Box::new(PerCpu::new(cpu_id)));
+        unsafe {
+            trampoline::set_parameters(base,cr3,stack_top,ap_main as u64,percpu as *const PerCpu as u64);
+        }
+        AP_STARTED.store(false,Ordering::SeqCst);
+        let apic_id = cpu.apic_id as u32;
+        apic::send_init(apic_id);
+        io_delay(10_000);
+        for _ in 0..2 {//the second SIPI is only needed if the first one got lost
+            apic::send_startup(apic_id,(trampoline::TRAMPOLINE_ADDR/4096) as u8);
+            io_delay(200);
+            if AP_STARTED.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+        //give the AP up to a second to reach ap_main
+        for _ in 0..1000 {
+            if AP_STARTED.load(Ordering::SeqCst) {
+                break;
+            }
+            io_delay(1000);
+        }
+        if AP_STARTED.load(Ordering::SeqCst) {
+            APIC_IDS.lock()[cpu_id] = apic_id;
+            CPU_COUNT.fetch_add(1,Ordering::AcqRel);
+        }
+    }
+    Ok(cpu_count())
+}
+
+/// 64-bit entry point of the application processors, called by the trampoline.
+extern "C" fn ap_main(cpu:&'static PerCpu) -> ! {
+    cpu.set_tss(gdt::init_ap());
+    interrupts::init_idt();
+    percpu::install(cpu);
+    crate::syscall::init();
+    apic::enable();
+    cpu.set_apic_id(apic::id());
+    task::scheduler::register_cpu(cpu.cpu_id());
+    AP_STARTED.store(true,Ordering::SeqCst);
+
+    apic::start_timer();
+    x86_64::instructions::interrupts::enable();
+    loop {//idle thread of this CPU
+        task::yield_now();
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,100 @@
+use alloc::{boxed::Box,collections::VecDeque,sync::Arc,vec::Vec};
+use core::sync::atomic::{AtomicUsize,Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::apic::{self,IpiDestination};
+use super::{percpu,MAX_CPUS};
+
+/// CPUs an inter-processor interrupt or a remote call is meant for.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Target {
+    Cpu(usize),
+    All,
+    AllButSelf,
+}
+
+/// Sends the interrupt `vector` to `target`.
+pub fn send(target:Target, vector:u8) {
+    let destination = match target {
+        Target::Cpu(cpu) if cpu == percpu::cpu_id() => IpiDestination::Myself,
+        Target::Cpu(cpu) => match super::apic_id_of(cpu) {
+            Some(apic_id) => IpiDestination::Apic(apic_id),
+            None => return,
+        },
+        Target::All => IpiDestination::All,
+        Target::AllButSelf => IpiDestination::AllButSelf,
+    };
+    apic::send_fixed_ipi(destination,vector);
+}
+
+/// A function queued for execution on other CPUs.
+struct Call {
+    function:Box<dyn Fn()+Send+Sync>,
+    pending:AtomicUsize,//CPUs that have not run `function` yet
+}
+
+lazy_static! {
+    static ref CALL_QUEUES:Vec<Mutex<VecDeque<Arc<Call>>>> = (0..MAX_CPUS).map(|_|Mutex::new(VecDeque::new())).collect();
+}
+
+/// Runs `function` on the `target` CPUs from their interrupt handler and waits until all of them are done.
+/// A `Target::Cpu` that is not up is skipped.
+///
+/// The function runs in interrupt context, so it must not block or allocate.
+/// The caller must have interrupts enabled: two CPUs waiting for each other with
+/// interrupts disabled would never see each other's call.
+pub fn call<F:Fn()+Send+Sync+'static>(target:Target, function:F) {
+    let call = call_async(target,function);
+    while call.pending.load(Ordering::Acquire) != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Like `call`, but returns as soon as the interrupts were sent.
+pub fn call_nowait<F:Fn()+Send+Sync+'static>(target:Target, function:F) {
+    call_async(target,function);
+}
+
+fn call_async<F:Fn()+Send+Sync+'static>(target:Target, function:F) -> Arc<Call> {
+    let me = percpu::cpu_id();
+    let cpus:Vec<usize> = match target {
+        Target::Cpu(cpu) => {
+            let mut cpus = Vec::new();
+            if cpu < super::cpu_count() {//a CPU that isn't up would never run it
+                cpus.push(cpu);
+            }
+            cpus
+        }
+        Target::All => (0..super::cpu_count()).collect(),
+        Target::AllButSelf => (0..super::cpu_count()).filter(|&cpu|cpu != me).collect(),
+    };
+    let remote:Vec<usize> = cpus.iter().copied().filter(|&cpu|cpu != me).collect();
+    let call = Arc::new(Call {
+        function:Box::new(function),
+        pending:AtomicUsize::new(remote.len()),
+    });
+    for &cpu in &remote {
+        interrupts::without_interrupts(||CALL_QUEUES[cpu].lock().push_back(call.clone()));
+        send(Target::Cpu(cpu),apic::CALL_FUNCTION_VECTOR);
+    }
+    if cpus.contains(&me) {
+        interrupts::without_interrupts(||(call.function)());
+    }
+    call
+}
+
+/// Runs the calls queued for this CPU. Called by the call-function interrupt handler.
+pub fn handle_call_function() {
+    let cpu = percpu::cpu_id();
+    loop {
+        let next = CALL_QUEUES[cpu].lock().pop_front();
+        match next {
+            Some(call) => {
+                (call.function)();
+                call.pending.fetch_sub(1,Ordering::Release);
+            }
+            None => break,
+        }
+    }
+}
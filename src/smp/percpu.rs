@@ -0,0 +1,108 @@
+use core::sync::atomic::{AtomicU32,AtomicU64,Ordering};
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+
+/// Data private to one CPU, reachable through the GS segment base.
+///
+/// The syscall entry code addresses the first three fields by offset, keep them in place.
+///
+/// While a CPU runs user code, the user's GS base is active and the kernel's is
+/// parked in the KernelGsBase MSR; every entry from user mode swaps them with
+/// `swapgs` (see `KernelGsGuard`), every return to user mode swaps them back.
+#[repr(C)]
+pub struct PerCpu {
+    self_ptr:AtomicU64,//must stay the first field: `gs:[0]` yields the address of this struct
+    kernel_stack:AtomicU64,//top of the running thread's kernel stack, loaded by the syscall entry
+    user_stack:AtomicU64,//scratch slot for the user stack pointer during syscall entry
+    cpu_id:usize,
+    apic_id:AtomicU32,
+    tss:AtomicU64,//*mut TaskStateSegment of this CPU
+}impl PerCpu {
+    pub const fn new(cpu_id:usize) -> Self {
+        PerCpu {
+            self_ptr:AtomicU64::new(0),
+            kernel_stack:AtomicU64::new(0),
+            user_stack:AtomicU64::new(0),
+            cpu_id,
+            apic_id:AtomicU32::new(0),
+            tss:AtomicU64::new(0),
+        }
+    }
+    /// Dense index of this CPU: 0 for the BSP, 1.. for the APs in startup order.
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+    pub fn set_apic_id(&self, apic_id:u32) {
+        self.apic_id.store(apic_id,Ordering::Relaxed);
+    }
+    pub fn set_tss(&self, tss:*mut TaskStateSegment) {
+        self.tss.store(tss as u64,Ordering::Relaxed);
+    }
+    /// Sets the stack the CPU switches to when user code is interrupted.
+    pub fn set_kernel_stack(&self, top:VirtAddr) {
+        self.kernel_stack.store(top.as_u64(),Ordering::Relaxed);
+        let tss = self.tss.load(Ordering::Relaxed) as *mut TaskStateSegment;
+        if !tss.is_null() {
+            unsafe { (*tss).privilege_stack_table[0] = top };
+        }
+    }
+    pub fn kernel_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.kernel_stack.load(Ordering::Relaxed))
+    }
+}
+
+static BSP:PerCpu = PerCpu::new(0);
+
+/// Points the GS base of the calling CPU at `cpu`.
+pub fn install(cpu:&'static PerCpu) {
+    let addr = cpu as *const PerCpu as u64;
+    cpu.self_ptr.store(addr,Ordering::Relaxed);
+    unsafe { GsBase::write(VirtAddr::new(addr)) };
+}
+
+pub fn init_bsp() {
+    BSP.set_tss(crate::gdt::bsp_tss());
+    install(&BSP);
+}
+
+/// Returns the per-CPU data of the calling CPU.
+pub fn current() -> &'static PerCpu {
+    let ptr:u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*(ptr as *const PerCpu)
+    }
+}
+
+pub fn cpu_id() -> usize {
+    current().cpu_id
+}
+
+/// Makes the kernel GS base active for the lifetime of the guard if the interrupted
+/// code ran in user mode (`code_segment` has RPL 3).
+///
+/// Every interrupt handler that can interrupt user code and touches per-CPU data
+/// has to create one before anything else.
+pub struct KernelGsGuard {
+    swapped:bool,
+}impl KernelGsGuard {
+    pub fn enter(code_segment:u64) -> Self {
+        let swapped = code_segment & 3 == 3;
+        if swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        KernelGsGuard {swapped}
+    }
+}
+
+impl Drop for KernelGsGuard {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! TLB shootdown.
+//!
+//! Changing or removing a mapping only flushes the TLB of the CPU doing it. Since
+//! all CPUs share the kernel page tables, every other CPU may still cache the old
+//! translation, so after each unmap or flag change the initiator sends the
+//! shootdown IPI and waits until every CPU flushed.
+//!
+//! Only one shootdown is in flight at a time. Acknowledgements are bits in
+//! `PENDING` rather than replies to the interrupt, so a CPU spinning with
+//! interrupts disabled (for example while waiting for its own shootdown) still
+//! answers requests from others and no two initiators can deadlock.
+
+use core::sync::atomic::{AtomicU64,Ordering};
+use spin::Mutex;
+use x86_64::{VirtAddr,instructions::tlb};
+use crate::apic;
+use super::{ipi,percpu};
+
+/// Ranges of at least this many pages are cheaper to handle with a full flush.
+const FLUSH_ALL_THRESHOLD:u64 = 32;
+
+static SHOOTDOWN_LOCK:Mutex<()> = Mutex::new(());
+static START:AtomicU64 = AtomicU64::new(0);
+static PAGES:AtomicU64 = AtomicU64::new(0);//0 means flush everything
+static PENDING:AtomicU64 = AtomicU64::new(0);//bit n set: CPU n still has to flush
+
+fn flush_local(start:u64, pages:u64) {
+    if pages == 0 || pages >= FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for page in 0..pages {
+            tlb::flush(VirtAddr::new(start + page*4096));
+        }
+    }
+}
+
+/// Flushes the current request on this CPU if it is still waiting for us.
+fn service_pending() {
+    let bit = 1u64 << percpu::cpu_id();
+    if PENDING.load(Ordering::Acquire) & bit != 0 {
+        flush_local(START.load(Ordering::Relaxed),PAGES.load(Ordering::Relaxed));
+        PENDING.fetch_and(!bit,Ordering::AcqRel);
+    }
+}
+
+/// Invalidates `pages` pages starting at `start` in the TLBs of all CPUs.
+/// With `pages == 0` the whole TLB (except global pages) is flushed.
+pub fn shootdown(start:VirtAddr, pages:u64) {
+    flush_local(start.as_u64(),pages);
+    let cpus = super::cpu_count();
+    if cpus <= 1 || !apic::is_initialized() {
+        return;
+    }
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        service_pending();
+    };
+    let me = percpu::cpu_id();
+    let all = if cpus >= 64 {u64::max_value()} else {(1u64 << cpus) - 1};
+    START.store(start.as_u64(),Ordering::Relaxed);
+    PAGES.store(pages,Ordering::Relaxed);
+    PENDING.store(all & !(1u64 << me),Ordering::Release);
+    ipi::send(ipi::Target::AllButSelf,apic::TLB_SHOOTDOWN_VECTOR);
+    while PENDING.load(Ordering::Acquire) != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Called by the shootdown interrupt handler.
+pub fn handle_shootdown() {
+    service_pending();
+}
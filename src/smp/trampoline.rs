@@ -0,0 +1,117 @@
+//! Real-mode startup code for the application processors.
+//!
+//! The code is copied to physical address `TRAMPOLINE_ADDR` and entered there by the
+//! STARTUP IPI in 16-bit real mode. It enables PAE and long mode with the BSP's page
+//! table (which identity-maps the trampoline page), far-jumps into 64-bit code and
+//! calls `entry(arg)` on `stack`. The BSP fills in the data slots before every SIPI.
+
+/// Physical address the trampoline is copied to; must be page aligned and below 1MiB.
+/// Keep in sync with `AP_BASE` in the assembly below.
+pub const TRAMPOLINE_ADDR:u64 = 0x8000;
+
+global_asm!(r#"
+.intel_syntax noprefix
+.set AP_BASE, 0x8000
+.code16
+.global bentos_ap_trampoline
+bentos_ap_trampoline:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [AP_BASE + (ap_gdt_ptr - bentos_ap_trampoline)]
+
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    mov eax, dword ptr [AP_BASE + (bentos_ap_cr3 - bentos_ap_trampoline)]
+    mov cr3, eax
+
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+
+    .byte 0x66, 0xea
+    .long AP_BASE + (ap_long_mode - bentos_ap_trampoline)
+    .word 0x08
+
+.code64
+ap_long_mode:
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, [rip + bentos_ap_stack]
+    mov rdi, [rip + bentos_ap_arg]
+    mov rax, [rip + bentos_ap_entry]
+    call rax
+1:
+    hlt
+    jmp 1b
+
+.align 16
+ap_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+ap_gdt_end:
+ap_gdt_ptr:
+    .word ap_gdt_end - ap_gdt - 1
+    .long AP_BASE + (ap_gdt - bentos_ap_trampoline)
+
+.align 8
+.global bentos_ap_cr3
+bentos_ap_cr3:
+    .quad 0
+.global bentos_ap_stack
+bentos_ap_stack:
+    .quad 0
+.global bentos_ap_entry
+bentos_ap_entry:
+    .quad 0
+.global bentos_ap_arg
+bentos_ap_arg:
+    .quad 0
+.global bentos_ap_trampoline_end
+bentos_ap_trampoline_end:
+.att_syntax
+"#);
+
+extern "C" {
+    static bentos_ap_trampoline:u8;
+    static bentos_ap_trampoline_end:u8;
+    static bentos_ap_cr3:u8;
+    static bentos_ap_stack:u8;
+    static bentos_ap_entry:u8;
+    static bentos_ap_arg:u8;
+}
+
+fn offset_of(symbol:&u8) -> usize {
+    symbol as *const u8 as usize - unsafe { &bentos_ap_trampoline as *const u8 as usize }
+}
+
+/// The trampoline code, as it has to be copied to `TRAMPOLINE_ADDR`.
+pub fn code() -> &'static [u8] {
+    unsafe {
+        let start = &bentos_ap_trampoline as *const u8;
+        core::slice::from_raw_parts(start,offset_of(&bentos_ap_trampoline_end))
+    }
+}
+
+/// Fills the data slots of a trampoline copy located at `base`.
+///
+/// This function is unsafe because `base` must point to a writable copy of `code()`.
+pub unsafe fn set_parameters(base:*mut u8, cr3:u64, stack:u64, entry:u64, arg:u64) {
+    (base.add(offset_of(&bentos_ap_cr3)) as *mut u64).write_volatile(cr3);
+    (base.add(offset_of(&bentos_ap_stack)) as *mut u64).write_volatile(stack);
+    (base.add(offset_of(&bentos_ap_entry)) as *mut u64).write_volatile(entry);
+    (base.add(offset_of(&bentos_ap_arg)) as *mut u64).write_volatile(arg);
+}
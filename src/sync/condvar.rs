@@ -0,0 +1,37 @@
+use super::{MutexGuard,WaitQueue};
+
+/// A condition variable to be used together with `sync::Mutex`.
+///
+/// As with any condition variable, `wait` may return spuriously; prefer `wait_while`.
+pub struct Condvar {
+    waiters:WaitQueue,
+}impl Condvar {
+    pub fn new() -> Self {
+        Condvar {
+            waiters:WaitQueue::new(),
+        }
+    }
+
+    /// Atomically releases the mutex and parks until notified, then re-acquires the mutex.
+    pub fn wait<'a,T>(&self, guard:MutexGuard<'a,T>) -> MutexGuard<'a,T> {
+        let mutex = guard.mutex();
+        self.waiters.wait_after(move||drop(guard));
+        mutex.lock()
+    }
+
+    /// Waits until `condition` returns false for the protected data.
+    pub fn wait_while<'a,T,F:FnMut(&mut T)->bool>(&self, mut guard:MutexGuard<'a,T>, mut condition:F) -> MutexGuard<'a,T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.notify_all();
+    }
+}
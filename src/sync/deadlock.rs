@@ -0,0 +1,85 @@
+//! Wait-for graph used to catch deadlocks in debug builds.
+//!
+//! Every sleeping lock with a single owner reports who holds it and who is about
+//! to block on it. Before a thread parks, the chain "lock -> owner -> lock the
+//! owner waits for -> ..." is followed; reaching the blocking thread again means
+//! nobody in the cycle can ever make progress, so we panic instead of hanging.
+//! In release builds all of this compiles to nothing.
+
+#[cfg(debug_assertions)]
+mod graph {
+    use alloc::collections::BTreeMap;
+    use lazy_static::lazy_static;
+    use spin::Mutex;
+    use x86_64::instructions::interrupts;
+    use crate::task::{self,ThreadId};
+
+    struct WaitForGraph {
+        owners:BTreeMap<usize,ThreadId>,//lock address -> owning thread
+        waiting:BTreeMap<ThreadId,usize>,//thread -> lock address it blocks on
+    }
+
+    lazy_static! {
+        static ref GRAPH:Mutex<WaitForGraph> = Mutex::new(WaitForGraph {
+            owners:BTreeMap::new(),
+            waiting:BTreeMap::new(),
+        });
+    }
+
+    pub fn acquired(lock:usize) {
+        let current = task::current_id();
+        interrupts::without_interrupts(||{
+            let mut graph = GRAPH.lock();
+            graph.waiting.remove(&current);
+            graph.owners.insert(lock,current);
+        });
+    }
+
+    pub fn released(lock:usize) {
+        interrupts::without_interrupts(||{
+            GRAPH.lock().owners.remove(&lock);
+        });
+    }
+
+    pub fn stopped_waiting() {
+        let current = task::current_id();
+        interrupts::without_interrupts(||{
+            GRAPH.lock().waiting.remove(&current);
+        });
+    }
+
+    pub fn will_block(lock:usize) {
+        let current = task::current_id();
+        interrupts::without_interrupts(||{
+            let mut graph = GRAPH.lock();
+            let mut next = lock;
+            while let Some(&owner) = graph.owners.get(&next) {
+                if owner == current {
+                    drop(graph);
+                    panic!("deadlock: thread {:?} blocks on lock {:#x} held (transitively) by itself",current,lock);
+                }
+                match graph.waiting.get(&owner) {
+                    Some(&lock) => next = lock,
+                    None => break,
+                }
+            }
+            graph.waiting.insert(current,lock);
+        });
+    }
+}
+
+#[cfg(debug_assertions)]
+pub use graph::{acquired,released,stopped_waiting,will_block};
+
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn acquired(_lock:usize) {}
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn released(_lock:usize) {}
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn stopped_waiting() {}
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn will_block(_lock:usize) {}
@@ -0,0 +1,19 @@
+//! Blocking synchronization primitives.
+//!
+//! Unlike `spin::Mutex`, a contended lock here parks the calling thread through
+//! `task::block_current` and lets other threads run. Waiters are woken through a
+//! `WaitQueue`, whose `notify_*` methods may be called from interrupt handlers.
+//! The sleeping primitives themselves must only be acquired from thread context.
+
+pub mod wait_queue;
+pub mod mutex;
+pub mod semaphore;
+pub mod condvar;
+pub mod rwlock;
+mod deadlock;
+
+pub use wait_queue::WaitQueue;
+pub use mutex::{Mutex,MutexGuard};
+pub use semaphore::Semaphore;
+pub use condvar::Condvar;
+pub use rwlock::{RwLock,RwLockReadGuard,RwLockWriteGuard};
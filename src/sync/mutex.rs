@@ -0,0 +1,86 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref,DerefMut};
+use core::sync::atomic::{AtomicBool,Ordering};
+use super::{deadlock,WaitQueue};
+
+/// A mutual exclusion lock that puts contending threads to sleep instead of spinning.
+pub struct Mutex<T> {
+    locked:AtomicBool,
+    waiters:WaitQueue,
+    data:UnsafeCell<T>,
+}
+unsafe impl<T:Send> Send for Mutex<T> {}
+unsafe impl<T:Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(data:T) -> Self {
+        Mutex {
+            locked:AtomicBool::new(false),
+            waiters:WaitQueue::new(),
+            data:UnsafeCell::new(data),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Acquires the lock, sleeping while another thread holds it.
+    pub fn lock(&self) -> MutexGuard<T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            deadlock::will_block(self.id());
+            self.waiters.wait_until(||!self.locked.load(Ordering::Acquire));
+        }
+    }
+
+    /// Acquires the lock only if it is free right now.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.locked.compare_exchange(false,true,Ordering::Acquire,Ordering::Relaxed).is_ok() {
+            deadlock::acquired(self.id());
+            Some(MutexGuard {mutex:self})
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+pub struct MutexGuard<'a,T> {
+    mutex:&'a Mutex<T>,
+}impl<'a,T> MutexGuard<'a,T> {
+    /// The mutex this guard belongs to; used by `Condvar` to re-acquire it.
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<'a,T> Deref for MutexGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a,T> DerefMut for MutexGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a,T> Drop for MutexGuard<'a,T> {
+    fn drop(&mut self) {
+        deadlock::released(self.mutex.id());
+        self.mutex.locked.store(false,Ordering::Release);
+        self.mutex.waiters.notify_one();
+    }
+}
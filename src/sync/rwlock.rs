@@ -0,0 +1,127 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref,DerefMut};
+use core::sync::atomic::{AtomicUsize,Ordering};
+use super::{deadlock,WaitQueue};
+
+const WRITE_LOCKED:usize = usize::MAX;
+
+/// A sleeping reader-writer lock. Waiting writers block new readers so they cannot starve.
+pub struct RwLock<T> {
+    state:AtomicUsize,//number of readers, or WRITE_LOCKED
+    writers_waiting:AtomicUsize,
+    waiters:WaitQueue,
+    data:UnsafeCell<T>,
+}
+unsafe impl<T:Send> Send for RwLock<T> {}
+unsafe impl<T:Send+Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(data:T) -> Self {
+        RwLock {
+            state:AtomicUsize::new(0),
+            writers_waiting:AtomicUsize::new(0),
+            waiters:WaitQueue::new(),
+            data:UnsafeCell::new(data),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state == WRITE_LOCKED || state == WRITE_LOCKED-1 || self.writers_waiting.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+        match self.state.compare_exchange(state,state+1,Ordering::Acquire,Ordering::Relaxed) {
+            Ok(_) => Some(RwLockReadGuard {lock:self}),
+            Err(_) => None,
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        if let Some(guard) = self.try_read() {
+            return guard;
+        }
+        loop {
+            deadlock::will_block(self.id());
+            self.waiters.wait_until(||{
+                self.state.load(Ordering::Acquire) != WRITE_LOCKED && self.writers_waiting.load(Ordering::Relaxed) == 0
+            });
+            if let Some(guard) = self.try_read() {
+                deadlock::stopped_waiting();
+                return guard;
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.state.compare_exchange(0,WRITE_LOCKED,Ordering::Acquire,Ordering::Relaxed).is_ok() {
+            deadlock::acquired(self.id());
+            Some(RwLockWriteGuard {lock:self})
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+        self.writers_waiting.fetch_add(1,Ordering::Relaxed);
+        let guard = loop {
+            deadlock::will_block(self.id());
+            self.waiters.wait_until(||self.state.load(Ordering::Acquire) == 0);
+            if let Some(guard) = self.try_write() {
+                break guard;
+            }
+        };
+        self.writers_waiting.fetch_sub(1,Ordering::Relaxed);
+        guard
+    }
+}
+
+pub struct RwLockReadGuard<'a,T> {
+    lock:&'a RwLock<T>,
+}
+
+impl<'a,T> Deref for RwLockReadGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a,T> Drop for RwLockReadGuard<'a,T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1,Ordering::Release) == 1 {
+            self.lock.waiters.notify_all();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a,T> {
+    lock:&'a RwLock<T>,
+}
+
+impl<'a,T> Deref for RwLockWriteGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a,T> DerefMut for RwLockWriteGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a,T> Drop for RwLockWriteGuard<'a,T> {
+    fn drop(&mut self) {
+        deadlock::released(self.lock.id());
+        self.lock.state.store(0,Ordering::Release);
+        self.lock.waiters.notify_all();
+    }
+}
@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicUsize,Ordering};
+use super::WaitQueue;
+
+/// A counting semaphore. `release` may be called from interrupt handlers.
+pub struct Semaphore {
+    count:AtomicUsize,
+    waiters:WaitQueue,
+}impl Semaphore {
+    pub fn new(count:usize) -> Self {
+        Semaphore {
+            count:AtomicUsize::new(count),
+            waiters:WaitQueue::new(),
+        }
+    }
+
+    /// Takes one unit, sleeping until one is available.
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters.wait_until(||self.count.load(Ordering::Acquire) > 0);
+        }
+    }
+
+    /// Takes one unit if one is available right now.
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::Relaxed);
+        while count > 0 {
+            match self.count.compare_exchange_weak(count,count-1,Ordering::Acquire,Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(actual) => count = actual,
+            }
+        }
+        false
+    }
+
+    /// Returns one unit and wakes a waiter.
+    pub fn release(&self) {
+        self.count.fetch_add(1,Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,116 @@
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::task::{self,ThreadId};
+use crate::time;
+
+/// A FIFO of threads parked until some condition changes.
+pub struct WaitQueue {
+    waiters:Mutex<VecDeque<ThreadId>>,
+}impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue {
+            waiters:Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Adds the current thread to the queue unless it is already in it.
+    fn enqueue_current(waiters:&mut VecDeque<ThreadId>) {
+        let current = task::current_id();
+        if !waiters.contains(&current) {
+            waiters.push_back(current);
+        }
+    }
+
+    /// Parks the current thread until it is notified. May return spuriously.
+    pub fn wait(&self) {
+        self.wait_after(||{});
+    }
+
+    /// Enqueues the current thread, runs `before_block` and then parks.
+    ///
+    /// A notification sent after the thread was enqueued is never lost, which is
+    /// what `Condvar` needs to release its mutex without a race.
+    pub fn wait_after<F:FnOnce()>(&self, before_block:F) {
+        interrupts::without_interrupts(||{
+            Self::enqueue_current(&mut self.waiters.lock());
+        });
+        before_block();
+        task::block_current();
+    }
+
+    /// Parks the current thread until `condition` returns true.
+    ///
+    /// The condition is evaluated while the queue is locked, so a notifier that
+    /// changes the state before calling `notify_*` cannot slip in between.
+    pub fn wait_until<F:FnMut()->bool>(&self, mut condition:F) {
+        loop {
+            let done = interrupts::without_interrupts(||{
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    true
+                } else {
+                    Self::enqueue_current(&mut waiters);
+                    false
+                }
+            });
+            if done {
+                return;
+            }
+            task::block_current();
+        }
+    }
+
+    /// Like `wait_until`, but gives up after `timeout` timer ticks. Returns whether
+    /// the condition became true.
+    pub fn wait_until_timeout<F:FnMut()->bool>(&self, mut condition:F, timeout:u64) -> bool {
+        let deadline = time::ticks() + timeout;
+        loop {
+            let done = interrupts::without_interrupts(||{
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    true
+                } else {
+                    Self::enqueue_current(&mut waiters);
+                    false
+                }
+            });
+            if done {
+                return true;
+            }
+            if time::ticks() >= deadline {
+                let current = task::current_id();
+                interrupts::without_interrupts(||self.waiters.lock().retain(|&id|id != current));
+                return false;
+            }
+            time::wake_at(deadline);
+            task::block_current();
+        }
+    }
+
+    /// Wakes the longest waiting thread. Returns false if nobody was waiting.
+    pub fn notify_one(&self) -> bool {
+        let next = interrupts::without_interrupts(||self.waiters.lock().pop_front());
+        match next {
+            Some(id) => {
+                task::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting thread and returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        let waiters = interrupts::without_interrupts(||core::mem::replace(&mut *self.waiters.lock(),VecDeque::new()));
+        let count = waiters.len();
+        for id in waiters {
+            task::wake(id);
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(||self.waiters.lock().is_empty())
+    }
+}
@@ -0,0 +1,159 @@
+use x86_64::registers::model_specific::{Efer,EferFlags,Msr};
+use x86_64::instructions::interrupts;
+use crate::gdt;
+
+mod entry;
+mod handlers;
+pub mod uaccess;
+
+pub use entry::SyscallFrame;
+
+const MSR_STAR:u32 = 0xc000_0081;
+const MSR_LSTAR:u32 = 0xc000_0082;
+const MSR_SFMASK:u32 = 0xc000_0084;
+
+/// RFLAGS bits cleared on `syscall`: TF, IF, DF and AC.
+const SFMASK:u64 = (1<<8) | (1<<9) | (1<<10) | (1<<18);
+
+/// System call numbers: the value of rax when executing `syscall`.
+pub mod nr {
+    pub const WRITE:u64 = 0;
+    pub const EXIT:u64 = 1;
+    pub const GETPID:u64 = 2;
+    pub const SLEEP:u64 = 3;
+    pub const MMAP:u64 = 4;
+    pub const YIELD:u64 = 5;
+    pub const WAIT:u64 = 6;
+    pub const GETPPID:u64 = 7;
+    pub const READ:u64 = 8;
+    pub const CLOSE:u64 = 9;
+    pub const FORK:u64 = 10;
+    pub const EXECVE:u64 = 11;
+    pub const KILL:u64 = 12;
+    pub const SIGACTION:u64 = 13;
+    pub const SIGPROCMASK:u64 = 14;
+    pub const SIGRETURN:u64 = 15;
+    pub const CHANNEL_CREATE:u64 = 16;
+    pub const CHANNEL_SEND:u64 = 17;
+    pub const CHANNEL_RECV:u64 = 18;
+    pub const CHANNEL_CALL:u64 = 19;
+    pub const CHANNEL_REPLY:u64 = 20;
+    pub const HANDLE_CLOSE:u64 = 21;
+    pub const HANDLE_DUP:u64 = 22;
+    pub const PIPE:u64 = 23;
+    pub const DUP2:u64 = 24;
+    pub const SHM_MAP:u64 = 25;
+    pub const SHM_UNLINK:u64 = 26;
+    pub const MUNMAP:u64 = 27;
+}
+
+/// Error numbers returned (negated) in rax; the values follow Linux.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EPIPE = 32,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+    EMSGSIZE = 90,
+    EADDRINUSE = 98,
+    ENETUNREACH = 101,
+    ECONNRESET = 104,
+    ENOTCONN = 107,
+    ETIMEDOUT = 110,
+    ECONNREFUSED = 111,
+    EHOSTUNREACH = 113,
+}
+
+pub type SyscallResult = Result<u64,Errno>;
+type Handler = fn(&mut SyscallFrame) -> SyscallResult;
+
+/// Handlers indexed by system call number.
+const TABLE:&[Handler] = &[
+    handlers::sys_write,
+    handlers::sys_exit,
+    handlers::sys_getpid,
+    handlers::sys_sleep,
+    handlers::sys_mmap,
+    handlers::sys_yield,
+    handlers::sys_wait,
+    handlers::sys_getppid,
+    handlers::sys_read,
+    handlers::sys_close,
+    handlers::sys_fork,
+    handlers::sys_execve,
+    handlers::sys_kill,
+    handlers::sys_sigaction,
+    handlers::sys_sigprocmask,
+    handlers::sys_sigreturn,
+    handlers::sys_channel_create,
+    handlers::sys_channel_send,
+    handlers::sys_channel_recv,
+    handlers::sys_channel_call,
+    handlers::sys_channel_reply,
+    handlers::sys_handle_close,
+    handlers::sys_handle_dup,
+    handlers::sys_pipe,
+    handlers::sys_dup2,
+    handlers::sys_shm_map,
+    handlers::sys_shm_unlink,
+    handlers::sys_munmap,
+];
+
+/// Enables `syscall`/`sysret` on the calling CPU.
+pub fn init() {
+    let (kernel_code,kernel_data) = gdt::kernel_selectors();
+    let (user_code,user_data) = gdt::user_selectors();
+    //sysret loads SS from STAR[63:48]+8 and CS from STAR[63:48]+16
+    let sysret_base = (user_data.0 & !3) - 8;
+    assert_eq!(user_code.0 & !3,sysret_base + 16,"GDT layout doesn't fit sysret");
+    assert_eq!(kernel_data.0,kernel_code.0 + 8,"GDT layout doesn't fit syscall");
+    unsafe {
+        Msr::new(MSR_STAR).write((sysret_base as u64) << 48 | (kernel_code.0 as u64) << 32);
+        Msr::new(MSR_LSTAR).write(entry::bentos_syscall_entry as u64);
+        Msr::new(MSR_SFMASK).write(SFMASK);
+        Efer::update(|flags|flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Called by the entry code with interrupts enabled, on the thread's kernel stack.
+#[no_mangle]
+extern "C" fn bentos_syscall_dispatch(frame:&mut SyscallFrame) {
+    let result = match TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => Err(Errno::ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+    crate::process::signal::deliver(frame);
+    //never let user code return with interrupts off or with IOPL raised
+    frame.rflags = (frame.rflags & !(0b11<<12)) | 0x202;
+    interrupts::disable();
+}
@@ -0,0 +1,92 @@
+/// Registers of the calling user thread, saved on its kernel stack by `bentos_syscall_entry`.
+///
+/// The field order mirrors the pushes in the entry code (last pushed comes first).
+/// Handlers may modify the frame; it is restored on return to user mode.
+#[repr(C)]
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SyscallFrame {
+    pub r9:u64,
+    pub r8:u64,
+    pub r10:u64,
+    pub rdx:u64,
+    pub rsi:u64,
+    pub rdi:u64,
+    pub rax:u64,//system call number on entry, return value on exit
+    pub r15:u64,
+    pub r14:u64,
+    pub r13:u64,
+    pub r12:u64,
+    pub rbx:u64,
+    pub rbp:u64,
+    pub rip:u64,//saved in rcx by `syscall`
+    pub rflags:u64,//saved in r11 by `syscall`
+    pub rsp:u64,
+}impl SyscallFrame {
+    /// The `n`th argument, following the rdi, rsi, rdx, r10, r8, r9 convention.
+    pub fn arg(&self, n:usize) -> u64 {
+        match n {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            5 => self.r9,
+            _ => panic!("system calls take at most 6 arguments"),
+        }
+    }
+}
+
+// `syscall` leaves the user stack in place and interrupts disabled (see SFMASK),
+// so the first thing to do is to switch to the kernel GS base and the thread's
+// kernel stack, both found in the per-CPU data (offsets 8 and 16 of `PerCpu`).
+global_asm!(r#"
+.intel_syntax noprefix
+.global bentos_syscall_entry
+bentos_syscall_entry:
+    swapgs
+    mov gs:[16], rsp
+    mov rsp, gs:[8]
+    push qword ptr gs:[16]
+    push r11
+    push rcx
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    mov rdi, rsp
+    sti
+    call bentos_syscall_dispatch
+    cli
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    pop rcx
+    pop r11
+    pop rsp
+    swapgs
+    sysretq
+.att_syntax
+"#);
+
+extern "C" {
+    pub fn bentos_syscall_entry();
+}
@@ -0,0 +1,391 @@
+use alloc::{string::String,sync::Arc,vec,vec::Vec};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use crate::{print,process,serial_print,task,time,usermode};
+use crate::process::signal::{self,Action};
+use crate::ipc::{self,Message,Object,PageTransfer,Rights};
+use crate::memory::shared;
+use crate::process::fd::File;
+use super::{uaccess,Errno,SyscallFrame,SyscallResult};
+
+/// Largest buffer `read` and `write` copy at once.
+const MAX_IO:usize = 4096;
+/// Longest path and most arguments (or environment strings) `execve` accepts.
+const MAX_PATH:usize = 4096;
+const MAX_ARGS:usize = 64;
+
+pub const PROT_WRITE:u64 = 2;
+pub const PROT_EXEC:u64 = 4;
+
+/// write(fd, buf, len)
+///
+/// Threads outside of a process (kernel code running in user mode) may only
+/// write to fd 1 and 2, which go to the screen and the serial port.
+pub fn sys_write(frame:&mut SyscallFrame) -> SyscallResult {
+    let (fd,buf,len) = (frame.arg(0),frame.arg(1),frame.arg(2) as usize);
+    let mut data = vec![0u8;len.min(MAX_IO)];
+    uaccess::copy_from_user(buf,&mut data)?;
+    if process::current_pid().is_none() {
+        if fd != 1 && fd != 2 {
+            return Err(Errno::EBADF);
+        }
+        let text = core::str::from_utf8(&data).map_err(|_|Errno::EINVAL)?;
+        print!("{}",text);
+        serial_print!("{}",text);
+        return Ok(data.len() as u64);
+    }
+    match process::file(fd as usize)?.write(&data) {
+        Ok(written) => Ok(written as u64),
+        Err(Errno::EPIPE) => {
+            if let Some(pid) = process::current_pid() {
+                let _ = signal::kill(pid,signal::SIGPIPE);
+            }
+            Err(Errno::EPIPE)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// read(fd, buf, len)
+pub fn sys_read(frame:&mut SyscallFrame) -> SyscallResult {
+    let (fd,buf,len) = (frame.arg(0),frame.arg(1),frame.arg(2) as usize);
+    let file = process::file(fd as usize)?;
+    uaccess::check_user_range(buf,len.min(MAX_IO),true)?;
+    let mut data = vec![0u8;len.min(MAX_IO)];
+    let read = file.read(&mut data)?;
+    uaccess::copy_to_user(buf,&data[..read])?;
+    Ok(read as u64)
+}
+
+/// close(fd)
+pub fn sys_close(frame:&mut SyscallFrame) -> SyscallResult {
+    let file = process::with_files(|files|files.close(frame.arg(0) as usize))??;
+    drop(file);//closing a pipe wakes threads, so not while the process table is locked
+    Ok(0)
+}
+
+/// dup2(old, new): returns `new`.
+pub fn sys_dup2(frame:&mut SyscallFrame) -> SyscallResult {
+    let (old,new) = (frame.arg(0) as usize,frame.arg(1) as usize);
+    let replaced = process::with_files(|files|files.dup2(old,new))??;
+    drop(replaced);
+    Ok(new as u64)
+}
+
+/// pipe(fds): stores the read and the write descriptor as two 32-bit values at `fds`.
+pub fn sys_pipe(frame:&mut SyscallFrame) -> SyscallResult {
+    let fds = frame.arg(0);
+    uaccess::check_user_range(fds,8,true)?;
+    let (reader,writer) = ipc::pipe();
+    let (reader,writer):(Arc<dyn File>,Arc<dyn File>) = (Arc::new(reader),Arc::new(writer));
+    let pair = process::with_files(|files|{
+        let read_fd = files.insert(reader)?;
+        match files.insert(writer) {
+            Ok(write_fd) => Ok([read_fd as u32,write_fd as u32]),
+            Err(err) => {
+                let _ = files.close(read_fd);//nobody else knows this pipe, so dropping it here wakes nobody
+                Err(err)
+            }
+        }
+    })??;
+    uaccess::write_user(fds,pair)?;
+    Ok(0)
+}
+
+/// exit(code)
+pub fn sys_exit(frame:&mut SyscallFrame) -> SyscallResult {
+    process::exit(frame.arg(0) as i32);
+}
+
+/// getpid(): the process id, or the thread id for threads outside of a process.
+pub fn sys_getpid(_frame:&mut SyscallFrame) -> SyscallResult {
+    match process::current_pid() {
+        Some(pid) => Ok(pid.as_u64()),
+        None => Ok(task::current_id().as_u64()),
+    }
+}
+
+/// getppid(): 0 if the process was started by the kernel.
+pub fn sys_getppid(_frame:&mut SyscallFrame) -> SyscallResult {
+    Ok(process::parent_pid().map_or(0,|pid|pid.as_u64()))
+}
+
+/// wait(pid, status): waits for the child `pid` (any child if -1) to exit and
+/// stores its exit code at `status` unless that is null. Returns the child's pid.
+pub fn sys_wait(frame:&mut SyscallFrame) -> SyscallResult {
+    let (pid,status) = (frame.arg(0) as i64,frame.arg(1));
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(process::Pid::from_u64(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+    if status != 0 {
+        uaccess::check_user_range(status,4,true)?;
+    }
+    let (child,code) = process::wait(pid)?;
+    if status != 0 {
+        uaccess::write_user(status,code)?;
+    }
+    Ok(child.as_u64())
+}
+
+/// sleep(milliseconds)
+pub fn sys_sleep(frame:&mut SyscallFrame) -> SyscallResult {
+    time::sleep_ms(frame.arg(0));
+    Ok(0)
+}
+
+/// mmap(hint, len, prot): maps zeroed anonymous memory; the hint is ignored.
+pub fn sys_mmap(frame:&mut SyscallFrame) -> SyscallResult {
+    let (len,prot) = (frame.arg(1),frame.arg(2));
+    if len == 0 || len > usermode::USER_END - usermode::USER_START {
+        return Err(Errno::EINVAL);
+    }
+    let mut flags = PageTableFlags::PRESENT;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let pages = (len + 4095)/4096;
+    let addr = match task::current_address_space() {
+        Some(address_space) => address_space.map_anonymous(pages,flags),
+        None => usermode::map_anonymous(pages,flags),//kernel function running in user mode
+    }.map_err(|_|Errno::ENOMEM)?;
+    Ok(addr.as_u64())
+}
+
+/// yield()
+pub fn sys_yield(_frame:&mut SyscallFrame) -> SyscallResult {
+    task::yield_now();
+    Ok(0)
+}
+
+/// fork(): returns the child's pid in the parent and 0 in the child.
+pub fn sys_fork(frame:&mut SyscallFrame) -> SyscallResult {
+    Ok(process::fork(frame)?.as_u64())
+}
+
+/// execve(path, argv, envp): `argv` and `envp` are null terminated arrays of
+/// string pointers; `envp` may be null. Only returns on error.
+pub fn sys_execve(frame:&mut SyscallFrame) -> SyscallResult {
+    let (entry,stack) = exec_from_user(frame.arg(0),frame.arg(1),frame.arg(2))?;
+    unsafe { usermode::enter(entry,stack) };
+}
+
+/// Copies the `execve` arguments into the kernel and replaces the program. Kept
+/// separate so that the copies are freed before `sys_execve` leaves for user mode.
+fn exec_from_user(path:u64, argv:u64, envp:u64) -> Result<(VirtAddr,VirtAddr),Errno> {
+    let path = uaccess::cstring_from_user(path,MAX_PATH)?;
+    let argv = strings_from_user(argv)?;
+    let envp = strings_from_user(envp)?;
+    let argv:Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp:Vec<&str> = envp.iter().map(String::as_str).collect();
+    process::exec(&path,&argv,&envp)
+}
+
+/// Reads a null terminated array of string pointers; a null array is empty.
+fn strings_from_user(mut array:u64) -> Result<Vec<String>,Errno> {
+    let mut strings = Vec::new();
+    if array == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let pointer = uaccess::read_user::<u64>(array)?;
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_ARGS {
+            return Err(Errno::E2BIG);
+        }
+        strings.push(uaccess::cstring_from_user(pointer,MAX_PATH)?);
+        array += 8;
+    }
+}
+
+/// kill(pid, signal)
+pub fn sys_kill(frame:&mut SyscallFrame) -> SyscallResult {
+    let (pid,sig) = (frame.arg(0),frame.arg(1));
+    if sig >= signal::NSIG as u64 {
+        return Err(Errno::EINVAL);
+    }
+    signal::kill(process::Pid::from_u64(pid),sig as u32)?;
+    Ok(0)
+}
+
+/// sigaction(signal, handler, restorer): `handler` is SIG_DFL, SIG_IGN or the
+/// address of `fn(signal)`, which returns to `restorer`, which has to call
+/// `sigreturn`. Returns the previous handler.
+pub fn sys_sigaction(frame:&mut SyscallFrame) -> SyscallResult {
+    let (sig,handler,restorer) = (frame.arg(0),frame.arg(1),frame.arg(2));
+    if sig >= signal::NSIG as u64 {
+        return Err(Errno::EINVAL);
+    }
+    let action = match handler {
+        signal::SIG_DFL => Action::Default,
+        signal::SIG_IGN => Action::Ignore,
+        _ => {
+            let user = usermode::USER_START..usermode::USER_END;
+            if !user.contains(&handler) || !user.contains(&restorer) {
+                return Err(Errno::EFAULT);
+            }
+            Action::Handler{handler,restorer}
+        }
+    };
+    Ok(match signal::set_action(sig as u32,action)? {
+        Action::Default => signal::SIG_DFL,
+        Action::Ignore => signal::SIG_IGN,
+        Action::Handler{handler,..} => handler,
+    })
+}
+
+/// sigprocmask(how, set): returns the previous mask. Bit n stands for signal n.
+pub fn sys_sigprocmask(frame:&mut SyscallFrame) -> SyscallResult {
+    Ok(signal::set_blocked(frame.arg(0),frame.arg(1) as u32)? as u64)
+}
+
+/// sigreturn(): only to be called by a signal handler's restorer.
+pub fn sys_sigreturn(frame:&mut SyscallFrame) -> SyscallResult {
+    signal::sigreturn(frame)
+}
+
+/// channel_create(capacity): returns a handle with send and receive rights.
+pub fn sys_channel_create(frame:&mut SyscallFrame) -> SyscallResult {
+    let endpoint = ipc::channel(frame.arg(0) as usize)?;
+    let handle = process::with_handles(|handles|handles.insert(Object::Channel(Arc::new(endpoint))))??;
+    Ok(handle as u64)
+}
+
+/// Copies an inline message of `len` bytes from user memory.
+fn message_from_user(buf:u64, len:u64) -> Result<Vec<u8>,Errno> {
+    if len as usize > ipc::MAX_MESSAGE {
+        return Err(Errno::EINVAL);
+    }
+    let mut data = vec![0u8;len as usize];
+    uaccess::copy_from_user(buf,&mut data)?;
+    Ok(data)
+}
+
+/// channel_send(handle, buf, len, pages_addr, pages): `pages` pages starting at
+/// `pages_addr` are moved to the receiver and unmapped here; pass 0 to send none.
+pub fn sys_channel_send(frame:&mut SyscallFrame) -> SyscallResult {
+    let endpoint = process::with_handles(|handles|handles.endpoint(frame.arg(0) as usize))??;
+    let data = message_from_user(frame.arg(1),frame.arg(2))?;
+    let message = match frame.arg(4) {
+        0 => Message::new(data),
+        pages => {
+            let address_space = task::current_address_space().ok_or(Errno::EPERM)?;
+            Message::with_pages(data,PageTransfer::take(&address_space,VirtAddr::new(frame.arg(3)),pages)?)
+        }
+    };
+    endpoint.send(message)?;
+    Ok(0)
+}
+
+/// Written by `channel_recv` to describe the received message.
+#[repr(C)]
+#[derive(Clone,Copy)]
+struct RecvInfo {
+    len:u64,//full length, even if the buffer was too short
+    pages_addr:u64,//where the transferred pages were mapped
+    pages:u64,
+    reply:u64,//handle to answer a call, u64::MAX if the message needs no answer
+}
+
+/// channel_recv(handle, buf, len, info): sleeps for a message, copies up to `len`
+/// bytes of it to `buf` and describes it in the `RecvInfo` at `info`.
+pub fn sys_channel_recv(frame:&mut SyscallFrame) -> SyscallResult {
+    let endpoint = process::with_handles(|handles|handles.endpoint(frame.arg(0) as usize))??;
+    let (buf,len,info) = (frame.arg(1),frame.arg(2) as usize,frame.arg(3));
+    uaccess::check_user_range(info,core::mem::size_of::<RecvInfo>(),true)?;
+    let mut message = endpoint.recv()?;
+    let copied = message.data.len().min(len);
+    uaccess::copy_to_user(buf,&message.data[..copied])?;
+    let (pages_addr,pages) = match message.pages.take() {
+        Some(transfer) => {
+            let pages = transfer.pages();
+            let address_space = task::current_address_space().ok_or(Errno::EPERM)?;
+            (transfer.map_into(&address_space)?.as_u64(),pages)
+        }
+        None => (0,0),
+    };
+    let reply = match message.take_reply() {
+        Some(reply) => process::with_handles(|handles|handles.insert(Object::Reply(reply)))?? as u64,
+        None => u64::MAX,
+    };
+    uaccess::write_user(info,RecvInfo {len:message.data.len() as u64, pages_addr, pages, reply})?;
+    Ok(copied as u64)
+}
+
+/// channel_call(handle, buf, len, reply_buf, reply_len): sends a message, sleeps
+/// until it is answered and copies up to `reply_len` bytes of the answer to
+/// `reply_buf`. Returns the length of the answer.
+pub fn sys_channel_call(frame:&mut SyscallFrame) -> SyscallResult {
+    let endpoint = process::with_handles(|handles|handles.endpoint(frame.arg(0) as usize))??;
+    let data = message_from_user(frame.arg(1),frame.arg(2))?;
+    let (reply_buf,reply_len) = (frame.arg(3),frame.arg(4) as usize);
+    let answer = endpoint.call(Message::new(data))?;
+    uaccess::copy_to_user(reply_buf,&answer.data[..answer.data.len().min(reply_len)])?;
+    Ok(answer.data.len() as u64)
+}
+
+/// channel_reply(reply_handle, buf, len): answers a call and closes the reply handle.
+pub fn sys_channel_reply(frame:&mut SyscallFrame) -> SyscallResult {
+    let data = message_from_user(frame.arg(1),frame.arg(2))?;
+    let handle = frame.arg(0) as usize;
+    let reply = process::with_handles(|handles|handles.take_reply(handle))??;
+    reply.reply(Message::new(data));
+    Ok(0)
+}
+
+/// handle_close(handle)
+pub fn sys_handle_close(frame:&mut SyscallFrame) -> SyscallResult {
+    let object = process::with_handles(|handles|handles.remove(frame.arg(0) as usize))??;
+    drop(object);//may wake threads, so not while the process table is locked
+    Ok(0)
+}
+
+/// handle_dup(handle, rights): a new handle for the same channel with (at most) `rights`.
+pub fn sys_handle_dup(frame:&mut SyscallFrame) -> SyscallResult {
+    let rights = Rights::from_bits(frame.arg(1) as u32).ok_or(Errno::EINVAL)?;
+    let handle = process::with_handles(|handles|{
+        let endpoint = handles.endpoint(frame.arg(0) as usize)?.duplicate(rights)?;
+        handles.insert(Object::Channel(Arc::new(endpoint)))
+    })??;
+    Ok(handle as u64)
+}
+
+/// Longest shared memory object name.
+const MAX_SHM_NAME:usize = 255;
+pub const SHM_CREATE:u64 = 1;
+pub const SHM_READONLY:u64 = 2;
+
+/// shm_map(name, len, flags): maps the shared memory object `name`, creating it
+/// with `len` bytes if `flags` has SHM_CREATE. Returns the address of the mapping.
+pub fn sys_shm_map(frame:&mut SyscallFrame) -> SyscallResult {
+    let name = uaccess::cstring_from_user(frame.arg(0),MAX_SHM_NAME)?;
+    let (len,flags) = (frame.arg(1),frame.arg(2));
+    let create = if flags & SHM_CREATE != 0 {Some((len + 4095)/4096)} else {None};
+    let object = shared::open(&name,create)?;
+    let address_space = task::current_address_space().ok_or(Errno::EPERM)?;
+    Ok(object.map_into(&address_space,flags & SHM_READONLY == 0)?.as_u64())
+}
+
+/// shm_unlink(name)
+pub fn sys_shm_unlink(frame:&mut SyscallFrame) -> SyscallResult {
+    let name = uaccess::cstring_from_user(frame.arg(0),MAX_SHM_NAME)?;
+    shared::unlink(&name)?;
+    Ok(0)
+}
+
+/// munmap(addr, len)
+pub fn sys_munmap(frame:&mut SyscallFrame) -> SyscallResult {
+    let (addr,len) = (frame.arg(0),frame.arg(1));
+    if addr % 4096 != 0 || addr < usermode::USER_START || addr.saturating_add(len) > usermode::USER_END {
+        return Err(Errno::EINVAL);
+    }
+    let address_space = task::current_address_space().ok_or(Errno::EPERM)?;
+    address_space.unmap(VirtAddr::new(addr),(len + 4095)/4096);
+    Ok(0)
+}
@@ -0,0 +1,100 @@
+use alloc::{string::String,vec,vec::Vec};
+use x86_64::{VirtAddr,registers::control::Cr3,structures::paging::PageTableFlags};
+use crate::memory::{self,address_space::COPY_ON_WRITE};
+use crate::task;
+use crate::usermode::{USER_START,USER_END};
+use super::Errno;
+
+/// Checks that `[addr, addr+len)` lies in the user part of the caller's address space
+/// and that every page of it is mapped user accessible (and writable if `write`).
+pub fn check_user_range(addr:u64, len:usize, write:bool) -> Result<(),Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len as u64).ok_or(Errno::EFAULT)?;
+    if addr < USER_START || end > USER_END {
+        return Err(Errno::EFAULT);
+    }
+    let p4 = Cr3::read().0;
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut page = addr & !0xfff;
+    while page < end {
+        match memory::page_flags(p4,VirtAddr::new(page)) {
+            Some(flags) if flags.contains(required) => {}
+            //the kernel writes with supervisor rights, so copy-on-write pages must be copied here
+            Some(flags) if write && flags.contains(COPY_ON_WRITE) => {
+                let resolved = task::current_address_space().map_or(false,|space|space.resolve_copy_on_write(VirtAddr::new(page)));
+                if !resolved {
+                    return Err(Errno::EFAULT);
+                }
+            }
+            _ => return Err(Errno::EFAULT),
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// Copies `buf.len()` bytes from user address `addr` into `buf`.
+pub fn copy_from_user(addr:u64, buf:&mut [u8]) -> Result<(),Errno> {
+    check_user_range(addr,buf.len(),false)?;
+    unsafe { core::ptr::copy_nonoverlapping(addr as *const u8,buf.as_mut_ptr(),buf.len()) };
+    Ok(())
+}
+
+/// Copies `data` to user address `addr`.
+pub fn copy_to_user(addr:u64, data:&[u8]) -> Result<(),Errno> {
+    check_user_range(addr,data.len(),true)?;
+    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(),addr as *mut u8,data.len()) };
+    Ok(())
+}
+
+/// Reads a plain value from user memory.
+pub fn read_user<T:Copy>(addr:u64) -> Result<T,Errno> {
+    check_user_range(addr,core::mem::size_of::<T>(),false)?;
+    Ok(unsafe { core::ptr::read_unaligned(addr as *const T) })
+}
+
+/// Writes a plain value to user memory.
+pub fn write_user<T:Copy>(addr:u64, value:T) -> Result<(),Errno> {
+    check_user_range(addr,core::mem::size_of::<T>(),true)?;
+    unsafe { core::ptr::write_unaligned(addr as *mut T,value) };
+    Ok(())
+}
+
+/// Copies a UTF-8 string of `len` bytes from user memory.
+pub fn string_from_user(addr:u64, len:usize) -> Result<String,Errno> {
+    let mut buf = vec![0u8;len];
+    copy_from_user(addr,&mut buf)?;
+    String::from_utf8(buf).map_err(|_|Errno::EINVAL)
+}
+
+/// Copies a zero terminated UTF-8 string of at most `max` bytes from user memory.
+pub fn cstring_from_user(addr:u64, max:usize) -> Result<String,Errno> {
+    let mut bytes = Vec::new();
+    let mut current = addr;
+    loop {
+        //check at most up to the end of the page, the string may end before the next one
+        let chunk = (4096 - current % 4096) as usize;
+        check_user_range(current,chunk,false)?;
+        let data = unsafe { core::slice::from_raw_parts(current as *const u8,chunk) };
+        match data.iter().position(|&byte|byte == 0) {
+            Some(len) => {
+                bytes.extend_from_slice(&data[..len]);
+                break;
+            }
+            None => bytes.extend_from_slice(data),
+        }
+        if bytes.len() > max {
+            return Err(Errno::E2BIG);
+        }
+        current += chunk as u64;
+    }
+    if bytes.len() > max {
+        return Err(Errno::E2BIG);
+    }
+    String::from_utf8(bytes).map_err(|_|Errno::EINVAL)
+}
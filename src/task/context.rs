@@ -0,0 +1,45 @@
+/// Saves the callee-saved registers and RFLAGS of the running thread on its own stack,
+/// stores the resulting stack pointer into `*old_rsp` and resumes the thread whose
+/// stack pointer is `new_rsp`.
+///
+/// The frame layout (from low to high address) is r15,r14,r13,r12,rbx,rbp,rflags,rip,
+/// which is also what `Thread::new` prepares on a fresh stack.
+global_asm!(r#"
+.intel_syntax noprefix
+.global bentos_switch_context
+bentos_switch_context:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+.att_syntax
+"#);
+
+extern "C" {
+    fn bentos_switch_context(old_rsp:*mut u64, new_rsp:u64);
+}
+
+/// Number of u64 slots `bentos_switch_context` pops off a stack before returning.
+pub const SWITCH_FRAME_SLOTS:usize = 8;
+
+/// Switch from the current thread to another one.
+///
+/// This function is unsafe because the caller must guarantee that interrupts are
+/// disabled, that `old_rsp` stays valid until the old thread is resumed and that
+/// `new_rsp` points to a frame built by a previous switch or by `Thread::new`.
+pub unsafe fn switch(old_rsp:*mut u64, new_rsp:u64) {
+    bentos_switch_context(old_rsp, new_rsp);
+}
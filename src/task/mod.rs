@@ -0,0 +1,108 @@
+use alloc::{boxed::Box,sync::Arc,vec};
+use core::sync::atomic::{AtomicU64,Ordering};
+use x86_64::{VirtAddr,instructions::interrupts};
+use crate::memory::address_space::AddressSpace;
+
+pub mod context;
+pub mod scheduler;
+
+pub use scheduler::{spawn,spawn_in,current_id,current_address_space,replace_address_space,is_alive,yield_now,block_current,wake,exit,tick};
+
+/// Size of the kernel stack given to every spawned thread.
+pub const THREAD_STACK_SIZE:usize = 4096*4;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct ThreadId(u64);
+impl ThreadId {
+    /// Id of the thread that booted the kernel and runs `kernel_main`.
+    pub const BOOT:ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID:AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1,Ordering::Relaxed))
+    }
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Blocked,
+    Exited,
+}
+
+/// A kernel thread: its own stack plus the stack pointer saved while it is switched out.
+pub struct Thread {
+    id:ThreadId,
+    state:ThreadState,
+    rsp:u64,//valid only while the thread is not running
+    stack:Option<Box<[u8]>>,//None for the boot threads, which run on the stack their CPU started with
+    entry:Option<Box<dyn FnOnce()+Send>>,//taken by thread_start
+    address_space:Option<Arc<AddressSpace>>,//None: kernel thread on the kernel's page table
+    wakeup_pending:bool,//set when `wake` hits a thread that has not blocked yet
+    cpu:usize,//the CPU whose run queue the thread belongs to
+}impl Thread {
+    /// Describes the context a CPU is already running when it joins the scheduler.
+    fn boot(cpu:usize) -> Self {
+        Thread {
+            id:if cpu == 0 {ThreadId::BOOT} else {ThreadId::new()},
+            state:ThreadState::Running,
+            rsp:0,
+            stack:None,
+            entry:None,
+            address_space:None,
+            wakeup_pending:false,
+            cpu,
+        }
+    }
+
+    /// Allocates a stack and builds an initial switch frame that "returns" into `thread_start`.
+    fn new(entry:Box<dyn FnOnce()+Send>, address_space:Option<Arc<AddressSpace>>) -> Self {
+        let mut stack = vec![0u8;THREAD_STACK_SIZE].into_boxed_slice();
+        let top = (stack.as_mut_ptr() as u64 + THREAD_STACK_SIZE as u64) & !0xf;
+        //leave one padding slot so that rsp is 16-byte aligned + 8 on entry, like after a `call`
+        let frame = (top - 8 - (context::SWITCH_FRAME_SLOTS as u64)*8) as *mut u64;
+        unsafe {
+            for slot in 0..6 {//r15,r14,r13,r12,rbx,rbp
+                frame.add(slot).write(0);
+            }
+            frame.add(6).write(0x2);//rflags: reserved bit only, interrupts off until thread_start
+            frame.add(7).write(thread_start as u64);
+        }
+        Thread {
+            id:ThreadId::new(),
+            state:ThreadState::Ready,
+            rsp:frame as u64,
+            stack:Some(stack),
+            entry:Some(entry),
+            address_space,
+            wakeup_pending:false,
+            cpu:0,//chosen by `spawn`
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+    /// Top of the thread's own kernel stack; `None` for boot threads.
+    pub fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(|stack|VirtAddr::new((stack.as_ptr() as u64 + stack.len() as u64) & !0xf))
+    }
+}
+
+/// First code every spawned thread runs, entered through `bentos_switch_context`'s `ret`.
+extern "C" fn thread_start() -> ! {
+    let entry = scheduler::take_current_entry().expect("spawned thread without entry");
+    interrupts::enable();
+    entry();
+    exit();
+}
@@ -0,0 +1,301 @@
+use alloc::{boxed::Box,collections::{BTreeMap,VecDeque},sync::Arc,vec::Vec};
+use core::sync::atomic::{AtomicBool,Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::{context,Thread,ThreadId,ThreadState};
+use crate::smp::percpu;
+use crate::memory::{self,address_space::AddressSpace};
+use x86_64::registers::control::{Cr3,Cr3Flags};
+
+/// Number of timer ticks a thread may run before it is preempted.
+const TIME_SLICE:usize = 5;
+
+/// Set by the first `spawn`; until then there is nothing to schedule and the timer
+/// interrupt must not touch the (heap allocated) scheduler.
+static STARTED:AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref SCHEDULER:Mutex<Scheduler> = Mutex::new(Scheduler::new());
+}
+
+/// Scheduling state of one CPU.
+///
+/// A thread stays on the CPU it was spawned on, so only the owning CPU ever pops
+/// from its run queue or switches away from its threads.
+struct CpuQueue {
+    current:ThreadId,
+    run_queue:VecDeque<ThreadId>,
+    slice_left:usize,
+}impl CpuQueue {
+    fn new(current:ThreadId) -> Self {
+        CpuQueue {
+            current,
+            run_queue:VecDeque::new(),
+            slice_left:TIME_SLICE,
+        }
+    }
+}
+
+/// Round-robin scheduler with one run queue per CPU.
+///
+/// Every access happens with interrupts disabled, since the timer interrupt
+/// preempts threads through `tick`.
+struct Scheduler {
+    threads:BTreeMap<ThreadId,Box<Thread>>,//boxed so that pointers to `rsp` stay valid
+    cpus:Vec<CpuQueue>,//indexed by `percpu::cpu_id()`
+    exited:Vec<ThreadId>,//waiting to be freed by another thread of the same CPU
+}impl Scheduler {
+    fn new() -> Self {
+        let mut threads = BTreeMap::new();
+        threads.insert(ThreadId::BOOT,Box::new(Thread::boot(0)));
+        let mut cpus = Vec::new();
+        cpus.push(CpuQueue::new(ThreadId::BOOT));
+        Scheduler {
+            threads,
+            cpus,
+            exited:Vec::new(),
+        }
+    }
+    fn thread_mut(&mut self, id:ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("unknown thread id")
+    }
+    /// Frees the stacks of threads that exited on `cpu` and no longer run on it.
+    fn reap(&mut self, cpu:usize) {
+        let current = self.cpus[cpu].current;
+        let threads = &mut self.threads;
+        self.exited.retain(|id|{
+            if *id == current || threads[id].cpu != cpu {
+                true
+            } else {
+                threads.remove(id);
+                false
+            }
+        });
+    }
+    /// The CPU with the shortest run queue.
+    fn least_loaded_cpu(&self) -> usize {
+        (0..self.cpus.len()).min_by_key(|&cpu|self.cpus[cpu].run_queue.len()).unwrap_or(0)
+    }
+}
+
+/// Picks the next thread to run on this CPU and switches to it.
+///
+/// If nothing is runnable and the current thread cannot continue, the CPU halts
+/// until an interrupt handler wakes a thread.
+/// Must be called with interrupts disabled.
+fn schedule() {
+    let cpu = percpu::cpu_id();
+    loop {
+        let mut sched = SCHEDULER.lock();
+        sched.reap(cpu);
+        let current = sched.cpus[cpu].current;
+        let current_runnable = sched.thread_mut(current).state == ThreadState::Running;
+        match sched.cpus[cpu].run_queue.pop_front() {
+            Some(next) if next == current => {//woken while we were idling on its stack
+                sched.thread_mut(current).state = ThreadState::Running;
+                sched.cpus[cpu].slice_left = TIME_SLICE;
+                return;
+            }
+            Some(next) => {
+                if current_runnable {
+                    sched.thread_mut(current).state = ThreadState::Ready;
+                    sched.cpus[cpu].run_queue.push_back(current);
+                }
+                sched.thread_mut(next).state = ThreadState::Running;
+                sched.cpus[cpu].current = next;
+                sched.cpus[cpu].slice_left = TIME_SLICE;
+                if let Some(top) = sched.thread_mut(next).kernel_stack_top() {
+                    percpu::current().set_kernel_stack(top);//where user mode of `next` traps to
+                }
+                //kernel threads go back to the kernel's table, so that an address space
+                //that is about to be freed is never left loaded
+                let p4 = match &sched.thread_mut(next).address_space {
+                    Some(address_space) => address_space.p4(),
+                    None => memory::kernel_p4(),
+                };
+                if Cr3::read().0 != p4 {
+                    unsafe { Cr3::write(p4,Cr3Flags::empty()) };
+                }
+                let old_rsp = &mut sched.thread_mut(current).rsp as *mut u64;
+                let new_rsp = sched.thread_mut(next).rsp;
+                drop(sched);
+                unsafe { context::switch(old_rsp,new_rsp) };
+                return;
+            }
+            None => {
+                if current_runnable {
+                    return;
+                }
+                drop(sched);
+                interrupts::enable_interrupts_and_hlt();
+                interrupts::disable();
+            }
+        }
+    }
+}
+
+/// Registers an application processor whose current context becomes its first thread.
+/// CPUs have to be registered in the order of their ids.
+pub fn register_cpu(cpu:usize) {
+    interrupts::without_interrupts(||{
+        let mut sched = SCHEDULER.lock();
+        assert_eq!(sched.cpus.len(),cpu,"CPUs must be registered in order");
+        let thread = Box::new(Thread::boot(cpu));
+        let id = thread.id;
+        sched.threads.insert(id,thread);
+        sched.cpus.push(CpuQueue::new(id));
+    });
+    STARTED.store(true,Ordering::Release);
+}
+
+/// Creates a new kernel thread running `entry` and puts it on the least loaded CPU.
+pub fn spawn<F:FnOnce()+Send+'static>(entry:F) -> ThreadId {
+    add_thread(Box::new(Thread::new(Box::new(entry),None)))
+}
+
+/// Creates a thread that runs `entry` with `address_space` loaded, e.g. to enter user mode.
+pub fn spawn_in<F:FnOnce()+Send+'static>(address_space:Arc<AddressSpace>, entry:F) -> ThreadId {
+    add_thread(Box::new(Thread::new(Box::new(entry),Some(address_space))))
+}
+
+fn add_thread(mut thread:Box<Thread>) -> ThreadId {
+    let id = thread.id;
+    interrupts::without_interrupts(||{
+        let mut sched = SCHEDULER.lock();
+        let cpu = sched.least_loaded_cpu();
+        thread.cpu = cpu;
+        sched.threads.insert(id,thread);
+        sched.cpus[cpu].run_queue.push_back(id);
+    });
+    STARTED.store(true,Ordering::Release);
+    id
+}
+
+/// Returns the id of the thread that is currently running on this CPU.
+pub fn current_id() -> ThreadId {
+    if !STARTED.load(Ordering::Acquire) {
+        return ThreadId::BOOT;
+    }
+    interrupts::without_interrupts(||SCHEDULER.lock().cpus[percpu::cpu_id()].current)
+}
+
+/// Returns false once the thread exited (or never existed).
+pub fn is_alive(id:ThreadId) -> bool {
+    interrupts::without_interrupts(||{
+        SCHEDULER.lock().threads.get(&id).map_or(false,|thread|thread.state != ThreadState::Exited)
+    })
+}
+
+pub(super) fn take_current_entry() -> Option<Box<dyn FnOnce()+Send>> {
+    interrupts::without_interrupts(||{
+        let mut sched = SCHEDULER.lock();
+        let current = sched.cpus[percpu::cpu_id()].current;
+        sched.thread_mut(current).entry.take()
+    })
+}
+
+/// The address space of the current thread, `None` for kernel threads.
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    if !STARTED.load(Ordering::Acquire) {
+        return None;
+    }
+    interrupts::without_interrupts(||{
+        let mut sched = SCHEDULER.lock();
+        let current = sched.cpus[percpu::cpu_id()].current;
+        sched.thread_mut(current).address_space.clone()
+    })
+}
+
+/// Gives the current thread a new address space and loads it, returning the old one.
+pub fn replace_address_space(address_space:Arc<AddressSpace>) -> Option<Arc<AddressSpace>> {
+    interrupts::without_interrupts(||{
+        let mut sched = SCHEDULER.lock();
+        let current = sched.cpus[percpu::cpu_id()].current;
+        unsafe { address_space.activate() };
+        core::mem::replace(&mut sched.thread_mut(current).address_space,Some(address_space))
+    })
+}
+
+/// Gives up the CPU to the next ready thread, if there is one.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Parks the current thread until somebody calls `wake` on it.
+///
+/// Returns immediately if a wakeup arrived since the thread last blocked, so a
+/// thread that publishes itself somewhere and then blocks never misses a `wake`.
+/// Callers must be prepared for spurious returns and re-check their condition.
+pub fn block_current() {
+    interrupts::without_interrupts(||{
+        {
+            let mut sched = SCHEDULER.lock();
+            let current = sched.cpus[percpu::cpu_id()].current;
+            let thread = sched.thread_mut(current);
+            if thread.wakeup_pending {
+                thread.wakeup_pending = false;
+                return;
+            }
+            thread.state = ThreadState::Blocked;
+        }
+        schedule();
+    });
+}
+
+/// Makes a blocked thread runnable again on its CPU. Safe to call from interrupt handlers.
+pub fn wake(id:ThreadId) {
+    interrupts::without_interrupts(||{
+        let mut guard = SCHEDULER.lock();
+        let sched = &mut *guard;
+        let thread = match sched.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => return,
+        };
+        match thread.state {
+            ThreadState::Blocked => {
+                thread.state = ThreadState::Ready;
+                sched.cpus[thread.cpu].run_queue.push_back(id);
+            }
+            ThreadState::Running|ThreadState::Ready => thread.wakeup_pending = true,
+            ThreadState::Exited => {}
+        }
+    });
+}
+
+/// Terminates the current thread. The boot thread must never exit.
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut sched = SCHEDULER.lock();
+        let current = sched.cpus[percpu::cpu_id()].current;
+        assert_ne!(current,ThreadId::BOOT,"the boot thread cannot exit");
+        sched.thread_mut(current).state = ThreadState::Exited;
+        sched.exited.push(current);
+    }
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Called by the timer interrupt handlers after the end-of-interrupt was sent.
+pub fn tick() {
+    if !STARTED.load(Ordering::Acquire) {
+        return;
+    }
+    let cpu = percpu::cpu_id();
+    let preempt = {
+        let mut sched = SCHEDULER.lock();
+        let current = sched.cpus[cpu].current;
+        if sched.thread_mut(current).state != ThreadState::Running {
+            false//idling inside `schedule`, which will pick up any woken thread itself
+        } else if sched.cpus[cpu].slice_left > 1 {
+            sched.cpus[cpu].slice_left -= 1;
+            false
+        } else {
+            true
+        }
+    };
+    if preempt {
+        schedule();
+    }
+}
@@ -0,0 +1,68 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64,Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts,port::Port};
+use crate::task::{self,ThreadId};
+
+/// Frequency the PIT is programmed to; one timer interrupt per tick.
+pub const TICKS_PER_SECOND:u64 = 100;
+const PIT_FREQUENCY:u64 = 1_193_182;
+
+static TICKS:AtomicU64 = AtomicU64::new(0);
+/// Threads sleeping in `sleep_ticks`, with the tick they want to wake up at.
+static SLEEPERS:Mutex<Vec<(u64,ThreadId)>> = Mutex::new(Vec::new());
+
+/// Programs channel 0 of the PIT to fire `TICKS_PER_SECOND` times per second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY/TICKS_PER_SECOND) as u16;
+    let mut command:Port<u8> = Port::new(0x43);
+    let mut channel0:Port<u8> = Port::new(0x40);
+    unsafe {
+        command.write(0x36);//channel 0, lobyte/hibyte, rate generator
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks()*1000/TICKS_PER_SECOND
+}
+
+/// Called by the timer interrupt handler on every tick; wakes the sleepers that are due.
+pub fn tick() {
+    let now = TICKS.fetch_add(1,Ordering::Relaxed) + 1;
+    let mut sleepers = SLEEPERS.lock();
+    let mut i = 0;
+    while i < sleepers.len() {
+        if sleepers[i].0 <= now {
+            let (_,id) = sleepers.swap_remove(i);
+            task::wake(id);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Blocks the current thread for at least `ticks` timer ticks.
+pub fn sleep_ticks(ticks:u64) {
+    let deadline = self::ticks() + ticks;
+    while self::ticks() < deadline {
+        wake_at(deadline);
+        task::block_current();
+    }
+}
+
+/// Has the current thread woken up at tick `deadline`, for waits with a timeout.
+/// If it was woken up earlier for another reason, the wakeup comes spuriously.
+pub fn wake_at(deadline:u64) {
+    interrupts::without_interrupts(||SLEEPERS.lock().push((deadline,task::current_id())));
+}
+
+pub fn sleep_ms(ms:u64) {
+    sleep_ticks((ms*TICKS_PER_SECOND + 999)/1000);
+}
@@ -0,0 +1,163 @@
+use core::sync::atomic::{AtomicU64,Ordering};
+use x86_64::{
+    VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{mapper::MapToError,FrameAllocator,Mapper,Page,PageTableFlags,Size4KiB},
+};
+use crate::{gdt,memory,println,task};
+use crate::interrupts::TrapFrame;
+use crate::syscall::SyscallFrame;
+
+/// Lowest address of the user part of an address space.
+pub const USER_START:u64 = 0x_5000_0000_0000;
+/// End (exclusive) of the user part; user stacks are placed right below it.
+pub const USER_END:u64 = 0x_7000_0000_0000;
+/// Default size of a user stack in pages.
+pub const USER_STACK_PAGES:u64 = 16;
+/// Anonymous memory handed out by `mmap` starts here.
+pub const MMAP_START:u64 = 0x_6000_0000_0000;
+
+static NEXT_CODE:AtomicU64 = AtomicU64::new(USER_START);
+static NEXT_STACK_TOP:AtomicU64 = AtomicU64::new(USER_END);
+static NEXT_MMAP:AtomicU64 = AtomicU64::new(MMAP_START);
+
+/// Makes the kernel function at `function` executable from user mode and returns its user address.
+///
+/// The pages containing the function are mapped a second time, user accessible and
+/// read-only, into the user part of the current address space. The function must
+/// be self-contained: it may only call code on the same pages and must talk to the
+/// kernel through system calls.
+pub fn map_function(function:usize) -> Result<VirtAddr,MapToError<Size4KiB>> {
+    let p4 = Cr3::read().0;
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(function as u64));
+    let pages = 2;//the function may cross a page boundary
+    let alias = NEXT_CODE.fetch_add(pages*4096,Ordering::Relaxed);
+    for i in 0..pages {
+        let frame = memory::with_page_table(p4,|mapper,_|mapper.translate_page(first + i))
+            .map_err(|_|MapToError::FrameAllocationFailed)?;
+        let page = Page::containing_address(VirtAddr::new(alias + i*4096));
+        memory::map_user_page(p4,page,frame,PageTableFlags::PRESENT)?;
+    }
+    Ok(VirtAddr::new(alias) + (function as u64 - first.start_address().as_u64()))
+}
+
+/// Maps `pages` zeroed frames at `start` in the current address space.
+fn map_zeroed(start:u64, pages:u64, flags:PageTableFlags) -> Result<(),MapToError<Size4KiB>> {
+    let p4 = Cr3::read().0;
+    for i in 0..pages {
+        let frame = memory::GlobalFrameAllocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),0,4096);
+        }
+        memory::map_user_page(p4,Page::containing_address(VirtAddr::new(start + i*4096)),frame,flags)?;
+    }
+    Ok(())
+}
+
+/// Allocates and maps a user stack of `pages` pages in the current address space
+/// and returns its (16-byte aligned) top.
+pub fn map_stack(pages:u64) -> Result<VirtAddr,MapToError<Size4KiB>> {
+    let top = NEXT_STACK_TOP.fetch_sub((pages+1)*4096,Ordering::Relaxed);//one unmapped guard page
+    map_zeroed(top - pages*4096,pages,PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+    Ok(VirtAddr::new(top))
+}
+
+/// Maps `pages` pages of zeroed memory with `flags` and returns their start address.
+pub fn map_anonymous(pages:u64, flags:PageTableFlags) -> Result<VirtAddr,MapToError<Size4KiB>> {
+    let start = NEXT_MMAP.fetch_add(pages*4096,Ordering::Relaxed);
+    if start + pages*4096 > NEXT_STACK_TOP.load(Ordering::Relaxed) {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    map_zeroed(start,pages,flags)?;
+    Ok(VirtAddr::new(start))
+}
+
+/// Switches the current thread to ring 3, continuing at `entry` with stack pointer `stack`.
+///
+/// This function is unsafe because `entry` and `stack` must be user accessible
+/// mappings and the current thread must have its own kernel stack, which the CPU
+/// switches back to (through the TSS) on every interrupt and exception.
+pub unsafe fn enter(entry:VirtAddr, stack:VirtAddr) -> ! {
+    let (code,data) = gdt::user_selectors();
+    asm!(
+        "cli",//no interrupt may see the user GS base while we are still in ring 0
+        "swapgs",
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "iretq",
+        ss = in(reg) data.0 as u64,
+        rsp = in(reg) stack.as_u64(),
+        rflags = in(reg) 0x202u64,//interrupts enabled
+        cs = in(reg) code.0 as u64,
+        rip = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
+
+/// Returns to user mode with all registers taken from `frame`, e.g. in a forked child.
+///
+/// This function is unsafe for the same reasons as `enter`.
+pub unsafe fn resume(frame:&SyscallFrame) -> ! {
+    let (code,data) = gdt::user_selectors();
+    let rflags = (frame.rflags & !(0b11<<12)) | 0x202;//same sanitizing as on syscall return
+    asm!(
+        "cli",
+        "swapgs",
+        "push {ss}",
+        "push qword ptr [rax + 15*8]",
+        "push {rflags}",
+        "push {cs}",
+        "push qword ptr [rax + 13*8]",
+        "mov r9, [rax + 0*8]",
+        "mov r8, [rax + 1*8]",
+        "mov r10, [rax + 2*8]",
+        "mov rdx, [rax + 3*8]",
+        "mov rsi, [rax + 4*8]",
+        "mov rdi, [rax + 5*8]",
+        "mov r15, [rax + 7*8]",
+        "mov r14, [rax + 8*8]",
+        "mov r13, [rax + 9*8]",
+        "mov r12, [rax + 10*8]",
+        "mov rbx, [rax + 11*8]",
+        "mov rbp, [rax + 12*8]",
+        "mov rax, [rax + 6*8]",
+        "iretq",
+        in("rax") frame as *const SyscallFrame,
+        ss = in(reg) data.0 as u64,
+        rflags = in(reg) rflags,
+        cs = in(reg) code.0 as u64,
+        options(noreturn)
+    );
+}
+
+/// Returns to user mode with all registers taken from `frame`, rcx and r11
+/// included, through the exit path of the interrupt entry stubs.
+///
+/// This function is unsafe for the same reasons as `enter`, and has to be called
+/// with the kernel GS base active, as in a system call.
+pub unsafe fn resume_trap(frame:&TrapFrame) -> ! {
+    let (code,data) = gdt::user_selectors();
+    let mut frame = *frame;
+    frame.cs = code.0 as u64;
+    frame.ss = data.0 as u64;
+    frame.rflags = (frame.rflags & !(0b11<<12)) | 0x202;//same sanitizing as on syscall return
+    asm!(
+        "cli",
+        "swapgs",
+        "mov rsp, {}",
+        "jmp bentos_trap_return",
+        in(reg) &frame as *const TrapFrame,
+        options(noreturn)
+    );
+}
+
+/// Handles an exception that user code caused: raises `signal`, which the process
+/// dies of unless it has a handler for it, and enters that handler.
+pub fn fault(exception:&str, signal:u32, frame:&mut TrapFrame) {
+    println!("thread {:?}: {} at {:#x}",task::current_id(),exception,frame.rip);
+    crate::process::signal::force(signal);
+    crate::process::signal::deliver_on_interrupt(frame);
+}
@@ -0,0 +1,156 @@
+use alloc::{collections::BTreeMap,string::String,sync::Arc};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::syscall::Errno;
+use super::inode::{FileType,Inode,MAX_NAME};
+
+/// A name in the directory tree, bound to the inode it refers to.
+///
+/// Looked up names stay cached in their parent until they are unlinked or the file
+/// system they belong to is unmounted, so resolving a path only asks the file
+/// system about names it hasn't seen yet.
+pub struct Dentry {
+    name:String,
+    inode:Arc<dyn Inode>,
+    parent:Option<Arc<Dentry>>,//None for the root of a file system
+    covers:Option<Arc<Dentry>>,//for the root of a mounted file system, its mount point
+    pub(super) mount:u64,//id of the mount the dentry belongs to
+    children:Mutex<BTreeMap<String,Arc<Dentry>>>,
+    mounted:Mutex<Option<Arc<Dentry>>>,//root of the file system mounted here
+}impl Dentry {
+    /// The root dentry of the file system of `inode`, mounted over `covers`.
+    pub(super) fn root(inode:Arc<dyn Inode>, covers:Option<Arc<Dentry>>, mount:u64) -> Arc<Self> {
+        Arc::new(Dentry {
+            name:String::from("/"),
+            inode,
+            parent:None,
+            covers,
+            mount,
+            children:Mutex::new(BTreeMap::new()),
+            mounted:Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.inode.metadata().kind == FileType::Directory
+    }
+
+    /// The entry `name` of this directory, from the cache or the file system.
+    pub fn child(self:&Arc<Self>, name:&str) -> Result<Arc<Dentry>,Errno> {
+        check_name(name)?;
+        if let Some(child) = interrupts::without_interrupts(||self.children.lock().get(name).cloned()) {
+            return Ok(child);
+        }
+        let inode = self.inode.lookup(name)?;//may sleep, so not under the lock
+        Ok(self.cache(name,inode))
+    }
+
+    /// Creates the entry `name` in this directory.
+    pub fn create(self:&Arc<Self>, name:&str, kind:FileType) -> Result<Arc<Dentry>,Errno> {
+        check_name(name)?;
+        let inode = self.inode.create(name,kind)?;
+        Ok(self.cache(name,inode))
+    }
+
+    /// Adds `name` to this directory as another name of `inode`.
+    pub fn link(&self, name:&str, inode:&Arc<dyn Inode>) -> Result<(),Errno> {
+        check_name(name)?;
+        self.inode.link(name,inode)
+    }
+
+    /// Removes the entry `name` from this directory and from the cache.
+    pub fn unlink(&self, name:&str) -> Result<(),Errno> {
+        check_name(name)?;
+        let cached = interrupts::without_interrupts(||self.children.lock().get(name).cloned());
+        if cached.map_or(false,|child|child.mount_root().is_some()) {
+            return Err(Errno::EBUSY);
+        }
+        self.inode.unlink(name)?;
+        //dropping the inode may sleep (freeing its data), so not under the lock
+        let removed = interrupts::without_interrupts(||self.children.lock().remove(name));
+        drop(removed);
+        Ok(())
+    }
+
+    /// Adds `name` to the cache unless another thread was faster, and returns the cached entry.
+    fn cache(self:&Arc<Self>, name:&str, inode:Arc<dyn Inode>) -> Arc<Dentry> {
+        let child = Arc::new(Dentry {
+            name:String::from(name),
+            inode,
+            parent:Some(self.clone()),
+            covers:None,
+            mount:self.mount,
+            children:Mutex::new(BTreeMap::new()),
+            mounted:Mutex::new(None),
+        });
+        interrupts::without_interrupts(||{
+            let mut children = self.children.lock();
+            match children.get(name) {
+                Some(cached) => cached.clone(),//`child` is dropped after unlocking
+                None => {
+                    children.insert(String::from(name),child.clone());
+                    child.clone()
+                }
+            }
+        })
+    }
+
+    /// The directory ".." refers to. At the root of a mounted file system that is the
+    /// parent of the mount point; the root of the whole tree is its own parent.
+    pub fn parent_dir(self:&Arc<Self>) -> Arc<Dentry> {
+        match (&self.parent,&self.covers) {
+            (Some(parent),_) => parent.clone(),
+            (None,Some(mount_point)) => mount_point.parent_dir(),
+            (None,None) => self.clone(),
+        }
+    }
+
+    /// The root of the file system mounted on this dentry, if any.
+    pub(super) fn mount_root(&self) -> Option<Arc<Dentry>> {
+        interrupts::without_interrupts(||self.mounted.lock().clone())
+    }
+
+    pub(super) fn set_mount_root(&self, root:Option<Arc<Dentry>>) {
+        interrupts::without_interrupts(||*self.mounted.lock() = root);
+    }
+
+    /// The mount point this dentry, the root of a mounted file system, covers.
+    pub(super) fn covers(&self) -> Option<&Arc<Dentry>> {
+        self.covers.as_ref()
+    }
+
+    /// Follows mounts stacked on this dentry to the topmost root.
+    pub(super) fn follow_mounts(self:&Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self.clone();
+        while let Some(root) = dentry.mount_root() {
+            dentry = root;
+        }
+        dentry
+    }
+
+    /// Drops the cached entries below this dentry, e.g. after unmounting its file system.
+    pub(super) fn forget_children(&self) {
+        let children = interrupts::without_interrupts(||core::mem::replace(&mut *self.children.lock(),BTreeMap::new()));
+        for child in children.values() {
+            child.forget_children();
+        }
+    }
+}
+
+fn check_name(name:&str) -> Result<(),Errno> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(Errno::EINVAL);
+    }
+    if name.len() > MAX_NAME {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use alloc::{sync::Arc,vec::Vec};
+use crate::process::fd::File;
+use crate::sync::Mutex;
+use crate::syscall::Errno;
+use super::inode::{DirEntry,FileType,Inode,Metadata};
+
+/// How a file is opened; the values follow Linux `open` flags.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct OpenFlags(u32);
+impl OpenFlags {
+    pub const READ:OpenFlags = OpenFlags(0);
+    pub const WRITE:OpenFlags = OpenFlags(1);
+    pub const READ_WRITE:OpenFlags = OpenFlags(2);
+    /// Create the file if it doesn't exist.
+    pub const CREATE:OpenFlags = OpenFlags(0o100);
+    /// With `CREATE`, fail if the file exists.
+    pub const EXCLUSIVE:OpenFlags = OpenFlags(0o200);
+    pub const TRUNCATE:OpenFlags = OpenFlags(0o1000);
+    /// Every write goes to the end of the file.
+    pub const APPEND:OpenFlags = OpenFlags(0o2000);
+    /// Fail unless the path is a directory.
+    pub const DIRECTORY:OpenFlags = OpenFlags(0o200000);
+
+    const ACCESS_MODE:u32 = 3;
+    const ALL:u32 = 3 | 0o100 | 0o200 | 0o1000 | 0o2000 | 0o200000;
+
+    pub fn from_bits(bits:u32) -> Option<OpenFlags> {
+        if bits & !Self::ALL == 0 && bits & Self::ACCESS_MODE != 3 {Some(OpenFlags(bits))} else {None}
+    }
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+    pub fn contains(self, other:OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn readable(self) -> bool {
+        self.0 & Self::ACCESS_MODE != Self::WRITE.0
+    }
+    pub fn writable(self) -> bool {
+        self.0 & Self::ACCESS_MODE != Self::READ.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+    fn bitor(self, other:OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// Where `seek` measures the new offset from.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file: an inode, the access mode and the current offset.
+///
+/// Descriptors duplicated with `dup2` or inherited on `fork` share one `OpenFile`,
+/// and with it the offset.
+pub struct OpenFile {
+    inode:Arc<dyn Inode>,
+    flags:OpenFlags,
+    offset:Mutex<u64>,//held across the I/O so that concurrent reads don't get the same bytes
+}impl OpenFile {
+    pub(super) fn new(inode:Arc<dyn Inode>, flags:OpenFlags) -> Self {
+        OpenFile {inode, flags, offset:Mutex::new(0)}
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// The entries of an open directory.
+    pub fn readdir(&self) -> Result<Vec<DirEntry>,Errno> {
+        self.inode.readdir()
+    }
+
+    pub fn truncate(&self, size:u64) -> Result<(),Errno> {
+        if !self.flags.writable() {
+            return Err(Errno::EBADF);
+        }
+        self.inode.truncate(size)
+    }
+}
+
+impl File for OpenFile {
+    fn read(&self, buf:&mut [u8]) -> Result<usize,Errno> {
+        if !self.flags.readable() {
+            return Err(Errno::EBADF);
+        }
+        let mut offset = self.offset.lock();
+        let len = self.inode.read_at(*offset,buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    fn write(&self, buf:&[u8]) -> Result<usize,Errno> {
+        if !self.flags.writable() {
+            return Err(Errno::EBADF);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.inode.metadata().size;
+        }
+        let len = self.inode.write_at(*offset,buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&self, pos:SeekFrom) -> Result<u64,Errno> {
+        let metadata = self.inode.metadata();
+        if metadata.kind == FileType::CharDevice {
+            return Err(Errno::ESPIPE);
+        }
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(new) => Some(new),
+            SeekFrom::Current(delta) => add_signed(*offset,delta),
+            SeekFrom::End(delta) => add_signed(metadata.size,delta),
+        }.ok_or(Errno::EINVAL)?;
+        *offset = new;
+        Ok(new)
+    }
+
+    fn stat(&self) -> Result<Metadata,Errno> {
+        Ok(self.inode.metadata())
+    }
+}
+
+fn add_signed(base:u64, delta:i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.wrapping_neg() as u64)//also right for i64::MIN
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
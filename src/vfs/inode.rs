@@ -0,0 +1,90 @@
+use alloc::{string::String,sync::Arc,vec::Vec};
+use crate::syscall::Errno;
+
+/// Longest name of a directory entry, in bytes.
+pub const MAX_NAME:usize = 255;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Symlink,
+}
+
+/// What `stat` reports about a file.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Metadata {
+    /// Unique within the file system.
+    pub ino:u64,
+    pub kind:FileType,
+    pub size:u64,
+    /// Number of directory entries referring to the file.
+    pub links:u32,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DirEntry {
+    pub name:String,
+    pub ino:u64,
+    pub kind:FileType,
+}
+
+/// A mounted (or mountable) file system.
+pub trait FileSystem:Send+Sync {
+    /// Short type name, e.g. "ramfs", for listings of the mount table.
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+    /// Writes cached changes back to the storage.
+    fn sync(&self) -> Result<(),Errno> {
+        Ok(())
+    }
+}
+
+/// A file or directory of a file system.
+///
+/// Directory operations fail with `ENOTDIR` and data operations with `EISDIR` by
+/// default, so each kind of inode only implements what applies to it. Names passed
+/// in are single path components, never ".", ".." or empty, and at most `MAX_NAME`
+/// bytes long.
+pub trait Inode:Send+Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Reads from `offset` into `buf` and returns the number of bytes read, 0 at the end.
+    fn read_at(&self, _offset:u64, _buf:&mut [u8]) -> Result<usize,Errno> {
+        Err(Errno::EISDIR)
+    }
+    /// Writes `buf` at `offset`, growing the file if needed.
+    fn write_at(&self, _offset:u64, _buf:&[u8]) -> Result<usize,Errno> {
+        Err(Errno::EISDIR)
+    }
+    /// Shrinks or grows (with zeros) the file to `size` bytes.
+    fn truncate(&self, _size:u64) -> Result<(),Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    fn lookup(&self, _name:&str) -> Result<Arc<dyn Inode>,Errno> {
+        Err(Errno::ENOTDIR)
+    }
+    /// Creates an empty file or directory called `name`. Fails with `EEXIST` if the name is taken.
+    fn create(&self, _name:&str, _kind:FileType) -> Result<Arc<dyn Inode>,Errno> {
+        Err(Errno::ENOTDIR)
+    }
+    /// Adds `name` as another name of `target`, an inode of the same file system.
+    fn link(&self, _name:&str, _target:&Arc<dyn Inode>) -> Result<(),Errno> {
+        Err(Errno::ENOTDIR)
+    }
+    /// Removes the entry `name`; directories must be empty (`ENOTEMPTY`).
+    fn unlink(&self, _name:&str) -> Result<(),Errno> {
+        Err(Errno::ENOTDIR)
+    }
+    fn readdir(&self) -> Result<Vec<DirEntry>,Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// The target of a symbolic link; `EINVAL` for anything else.
+    fn readlink(&self) -> Result<String,Errno> {
+        Err(Errno::EINVAL)
+    }
+}
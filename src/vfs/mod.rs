@@ -0,0 +1,317 @@
+//! The virtual file system: one directory tree made of mounted file systems.
+//!
+//! File systems implement `FileSystem` and `Inode`; the VFS resolves paths through
+//! a cache of `Dentry`s, steps into file systems mounted on directories and hands
+//! out `OpenFile`s, which carry the offset and are what file descriptors refer to.
+//! Paths are resolved from the root; there is no working directory yet, so
+//! relative paths start at the root as well. "." and ".." are handled here, file
+//! systems never see them; so are symbolic links, which are followed everywhere
+//! except in the last component of paths given to `readlink` and to the calls that
+//! create or remove names.
+
+use alloc::{string::String,sync::Arc,vec,vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::syscall::Errno;
+
+pub mod inode;
+pub mod dentry;
+pub mod file;
+
+pub use inode::{DirEntry,FileSystem,FileType,Inode,Metadata,MAX_NAME};
+pub use dentry::Dentry;
+pub use file::{OpenFile,OpenFlags,SeekFrom};
+pub use crate::process::fd::File;
+
+/// Longest path accepted, in bytes.
+pub const MAX_PATH:usize = 4096;
+/// Most symbolic links followed while resolving one path, after which it fails with `ELOOP`.
+pub const MAX_SYMLINKS:usize = 40;
+
+struct Mount {
+    id:u64,
+    path:String,
+    fs:Arc<dyn FileSystem>,
+    root:Arc<Dentry>,
+}
+
+struct MountTable {
+    mounts:Vec<Mount>,//in mount order, the root file system first
+    next_id:u64,
+}
+
+lazy_static! {
+    static ref MOUNTS:Mutex<MountTable> = Mutex::new(MountTable {mounts:Vec::new(), next_id:1});
+}
+
+fn with_mounts<R, F:FnOnce(&mut MountTable)->R>(f:F) -> R {
+    interrupts::without_interrupts(||f(&mut MOUNTS.lock()))
+}
+
+/// An entry of the mount table, for listings.
+#[derive(Debug,Clone)]
+pub struct MountInfo {
+    pub path:String,
+    pub fs:&'static str,
+}
+
+/// Mounts `fs` on the directory `path`. The first file system has to be mounted
+/// on "/" and becomes the root; mounting on "/" later hides the old root.
+pub fn mount(path:&str, fs:Arc<dyn FileSystem>) -> Result<(),Errno> {
+    let root_inode = fs.root();
+    if root_inode.metadata().kind != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    let path = normalize(path)?;
+    let mount_point = match root() {
+        Ok(_) => Some(lookup(&path)?),
+        Err(_) if path == "/" => None,
+        Err(err) => return Err(err),
+    };
+    if let Some(mount_point) = &mount_point {
+        if !mount_point.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+    }
+    with_mounts(|table|{
+        if mount_point.is_none() && !table.mounts.is_empty() {
+            return Err(Errno::EBUSY);//raced with another root mount
+        }
+        if mount_point.as_ref().map_or(false,|dentry|dentry.mount_root().is_some()) {
+            return Err(Errno::EBUSY);//`lookup` stepped over existing mounts, so this one appeared just now
+        }
+        let id = table.next_id;
+        table.next_id += 1;
+        let root = Dentry::root(root_inode,mount_point.clone(),id);
+        if let Some(mount_point) = &mount_point {
+            mount_point.set_mount_root(Some(root.clone()));
+        }
+        table.mounts.push(Mount {id, path, fs, root});
+        Ok(())
+    })
+}
+
+/// Unmounts the file system mounted at `path`. Fails with `EBUSY` for the root and
+/// while other file systems are mounted inside it. Files that are still open keep
+/// working on the detached file system.
+pub fn umount(path:&str) -> Result<(),Errno> {
+    let root = lookup(path)?;
+    let mount = with_mounts(|table|{
+        let index = table.mounts.iter().position(|mount|Arc::ptr_eq(&mount.root,&root)).ok_or(Errno::EINVAL)?;
+        let id = table.mounts[index].id;
+        let mount_point = root.covers().ok_or(Errno::EBUSY)?;//the root file system
+        if table.mounts.iter().any(|mount|mount.root.covers().map_or(false,|covered|covered.mount == id)) {
+            return Err(Errno::EBUSY);
+        }
+        mount_point.set_mount_root(None);
+        Ok(table.mounts.remove(index))
+    })?;
+    let result = mount.fs.sync();
+    mount.root.forget_children();
+    result
+}
+
+/// The mount table, the root file system first.
+pub fn mounts() -> Vec<MountInfo> {
+    with_mounts(|table|table.mounts.iter().map(|mount|MountInfo {path:mount.path.clone(), fs:mount.fs.name()}).collect())
+}
+
+/// Writes back the cached changes of all file systems.
+pub fn sync() -> Result<(),Errno> {
+    let file_systems:Vec<Arc<dyn FileSystem>> = with_mounts(|table|table.mounts.iter().map(|mount|mount.fs.clone()).collect());
+    file_systems.iter().map(|fs|fs.sync()).fold(Ok(()),|result,next|result.and(next))
+}
+
+fn root() -> Result<Arc<Dentry>,Errno> {
+    let root = with_mounts(|table|table.mounts.first().map(|mount|mount.root.clone())).ok_or(Errno::ENOENT)?;
+    Ok(root.follow_mounts())
+}
+
+/// The components of `path`, without empty ones and ".".
+fn components(path:&str) -> Result<impl DoubleEndedIterator<Item=&str>,Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    if path.len() > MAX_PATH {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    Ok(path.split('/').filter(|name|!name.is_empty() && *name != "."))
+}
+
+/// `path` as an absolute path without "." and "..", for the mount table.
+fn normalize(path:&str) -> Result<String,Errno> {
+    let mut names:Vec<&str> = Vec::new();
+    for name in components(path)? {
+        if name == ".." {
+            names.pop();
+        } else {
+            names.push(name);
+        }
+    }
+    let mut normalized = String::new();
+    for name in names {
+        normalized.push('/');
+        normalized.push_str(name);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Resolves `names` starting at `dentry`, following symbolic links on the way and,
+/// if `follow_last` is set, at the end.
+fn walk<'a>(mut dentry:Arc<Dentry>, names:impl Iterator<Item=&'a str>, follow_last:bool) -> Result<Arc<Dentry>,Errno> {
+    let mut pending:Vec<String> = names.map(String::from).collect();
+    pending.reverse();//taken from the end
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if !dentry.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+        let next = if name == ".." {
+            dentry.parent_dir()
+        } else {
+            dentry.child(&name)?
+        }.follow_mounts();
+        if next.inode().metadata().kind != FileType::Symlink || (pending.is_empty() && !follow_last) {
+            dentry = next;
+            continue;
+        }
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(Errno::ELOOP);
+        }
+        let target = next.inode().readlink()?;
+        if target.starts_with('/') {
+            dentry = root()?;
+        }//relative targets start in the directory holding the link
+        pending.extend(components(&target)?.rev().map(String::from));
+    }
+    Ok(dentry)
+}
+
+/// The dentry `path` refers to.
+pub fn lookup(path:&str) -> Result<Arc<Dentry>,Errno> {
+    walk(root()?,components(path)?,true)
+}
+
+/// The directory that contains the last component of `path`, and that component.
+/// Fails with `EINVAL` if the path ends in "." or "..", or names the root.
+fn lookup_parent(path:&str) -> Result<(Arc<Dentry>,&str),Errno> {
+    let names:Vec<&str> = components(path)?.collect();
+    let (&name,parents) = names.split_last().ok_or(Errno::EINVAL)?;
+    if name == ".." {
+        return Err(Errno::EINVAL);
+    }
+    let parent = walk(root()?,parents.iter().copied(),true)?;
+    if !parent.is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok((parent,name))
+}
+
+/// Opens the file at `path`.
+pub fn open(path:&str, flags:OpenFlags) -> Result<Arc<OpenFile>,Errno> {
+    let dentry = if flags.contains(OpenFlags::CREATE) {
+        let (parent,name) = lookup_parent(path)?;
+        match parent.child(name) {
+            Ok(_) if flags.contains(OpenFlags::EXCLUSIVE) => return Err(Errno::EEXIST),
+            Ok(dentry) if dentry.inode().metadata().kind == FileType::Symlink => lookup(path)?,
+            Ok(dentry) => dentry.follow_mounts(),
+            Err(Errno::ENOENT) => parent.create(name,FileType::Regular)?,
+            Err(err) => return Err(err),
+        }
+    } else {
+        lookup(path)?
+    };
+    let kind = dentry.inode().metadata().kind;
+    if kind == FileType::Directory && flags.writable() {
+        return Err(Errno::EISDIR);
+    }
+    if kind != FileType::Directory && flags.contains(OpenFlags::DIRECTORY) {
+        return Err(Errno::ENOTDIR);
+    }
+    if kind == FileType::Regular && flags.writable() && flags.contains(OpenFlags::TRUNCATE) {
+        dentry.inode().truncate(0)?;
+    }
+    Ok(Arc::new(OpenFile::new(dentry.inode().clone(),flags)))
+}
+
+/// Information about the file at `path`.
+pub fn stat(path:&str) -> Result<Metadata,Errno> {
+    Ok(lookup(path)?.inode().metadata())
+}
+
+/// The target of the symbolic link at `path`.
+pub fn readlink(path:&str) -> Result<String,Errno> {
+    walk(root()?,components(path)?,false)?.inode().readlink()
+}
+
+/// The entries of the directory at `path`, without "." and "..".
+pub fn readdir(path:&str) -> Result<Vec<DirEntry>,Errno> {
+    lookup(path)?.inode().readdir()
+}
+
+/// Reads the whole file at `path`.
+pub fn read_file(path:&str) -> Result<Vec<u8>,Errno> {
+    let file = open(path,OpenFlags::READ)?;
+    let size = file.stat()?.size as usize;
+    let mut data = vec![0;size];
+    let mut filled = 0;
+    while filled < size {
+        match file.read(&mut data[filled..])? {
+            0 => break,//shrunk meanwhile
+            len => filled += len,
+        }
+    }
+    data.truncate(filled);
+    Ok(data)
+}
+
+/// Replaces the contents of the file at `path` with `data`, creating the file if needed.
+pub fn write_file(path:&str, data:&[u8]) -> Result<(),Errno> {
+    let file = open(path,OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+    let mut written = 0;
+    while written < data.len() {
+        written += file.write(&data[written..])?;
+    }
+    Ok(())
+}
+
+pub fn mkdir(path:&str) -> Result<(),Errno> {
+    let (parent,name) = lookup_parent(path)?;
+    parent.create(name,FileType::Directory).map(|_|())
+}
+
+/// Removes the name `path` of a file that is not a directory.
+pub fn unlink(path:&str) -> Result<(),Errno> {
+    let (parent,name) = lookup_parent(path)?;
+    if parent.child(name)?.is_dir() {
+        return Err(Errno::EISDIR);
+    }
+    parent.unlink(name)
+}
+
+/// Removes the empty directory `path`.
+pub fn rmdir(path:&str) -> Result<(),Errno> {
+    let (parent,name) = lookup_parent(path)?;
+    if !parent.child(name)?.is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    parent.unlink(name)
+}
+
+/// Makes `new` another name of the file at `old`, which must not be a directory.
+pub fn link(old:&str, new:&str) -> Result<(),Errno> {
+    let target = lookup(old)?;
+    if target.is_dir() {
+        return Err(Errno::EPERM);
+    }
+    let (parent,name) = lookup_parent(new)?;
+    if parent.mount != target.mount {
+        return Err(Errno::EXDEV);
+    }
+    parent.link(name,target.inode())
+}
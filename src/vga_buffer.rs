@@ -5,13 +5,15 @@ use core::fmt;
 use volatile::Volatile;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::instructions::port::Port;
 
 #[cfg(test)]
 use crate::{serial_print,serial_println};
 
 lazy_static! {
     pub static ref WRITER:Mutex<Writer> = Mutex::new(Writer {
-        column_position:0,
+        row:BUFFER_HEIGHT-1,
+        column:0,
         color_code:ColorCode::new(Color::LightCyan,Color::Blue),
         buffer:unsafe { &mut *(0xb8000 as *mut Buffer)},
     });
@@ -55,8 +57,13 @@ struct ScreenChar {
     color_code:ColorCode,
 }
 
-const BUFFER_HEIGHT:usize = 25;
-const BUFFER_WIDTH:usize = 80;
+pub const BUFFER_HEIGHT:usize = 25;
+pub const BUFFER_WIDTH:usize = 80;
+/// Tab stops are every this many columns.
+const TAB_WIDTH:usize = 8;
+/// CRT controller index and data ports, for the hardware cursor.
+const CRTC_INDEX:u16 = 0x3d4;
+const CRTC_DATA:u16 = 0x3d5;
 
 #[repr(transparent)]
 struct Buffer {
@@ -88,33 +95,58 @@ macro_rules! println {
 
 //Write to screen
 pub struct Writer {
-    column_position:usize,//cursor location in last row
+    row:usize,//cursor location
+    column:usize,
     color_code:ColorCode,
     buffer: &'static mut Buffer,
 }
 impl Writer {
-    pub fn write_byte(&mut self, byte:u8) {//byte is ASCii code
+    /// Writes a CP437 byte at the cursor, or handles `\n`, `\r`, `\t` and backspace (0x08).
+    pub fn write_byte(&mut self, byte:u8) {
+        self.put(byte);
+        self.update_cursor();
+    }
+    fn put(&mut self, byte:u8) {
         match byte {
             b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
+            b'\r' => self.column = 0,
+            b'\t' => {
+                let stop = (self.column/TAB_WIDTH + 1)*TAB_WIDTH;
+                while self.column < stop.min(BUFFER_WIDTH) {
+                    self.put_glyph(b' ');
+                }
+            }
+            0x08 => {//backspace erases the character before the cursor
+                if self.column == 0 && self.row > 0 {
+                    self.row -= 1;
+                    self.column = BUFFER_WIDTH;
+                }
+                if self.column > 0 {
+                    self.column -= 1;
+                    self.blank(self.row,self.column);
                 }
-                
-                let row = BUFFER_HEIGHT -1;//25 -1 =24
-                let col = self.column_position;
-
-                let color_code = self.color_code;
-                //cursor location:
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character:byte,
-                    color_code,
-                });
-                self.column_position = self.column_position + 1;
             }
+            byte => self.put_glyph(byte),
         }
     }
+    fn put_glyph(&mut self, byte:u8) {
+        if self.column >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        let color_code = self.color_code;
+        self.buffer.chars[self.row][self.column].write(ScreenChar {
+            ascii_character:byte,
+            color_code,
+        });
+        self.column = self.column + 1;
+    }
+    /// Moves to the start of the next row, scrolling everything up at the bottom.
     fn new_line(&mut self) {
+        self.column = 0;
+        if self.row < BUFFER_HEIGHT-1 {
+            self.row += 1;
+            return;
+        }
         for row in 1..BUFFER_HEIGHT {  //MOVE every single character 1 up
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -122,27 +154,124 @@ impl Writer {
             }
         }
         self.clear_row(BUFFER_HEIGHT-1); //clear bottom line.
-        self.column_position = 0;
     }
-    fn clear_row(&mut self, row:usize) {
+    fn blank(&mut self, row:usize, col:usize) {
         let blank = ScreenChar {
             ascii_character:b' ',
             color_code:self.color_code,
         };
+        self.buffer.chars[row][col].write(blank);
+    }
+    fn clear_row(&mut self, row:usize) {
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.blank(row,col);
+        }
+    }
+
+    /// Moves the hardware cursor to where the next character goes.
+    fn update_cursor(&mut self) {
+        let position = (self.row*BUFFER_WIDTH + self.column.min(BUFFER_WIDTH-1)) as u16;
+        let mut index = Port::<u8>::new(CRTC_INDEX);
+        let mut data = Port::<u8>::new(CRTC_DATA);
+        unsafe {
+            index.write(0x0f);//cursor location low
+            data.write(position as u8);
+            index.write(0x0e);//cursor location high
+            data.write((position >> 8) as u8);
+        }
+    }
+
+    /// The row and column the next character goes to.
+    pub fn position(&self) -> (usize,usize) {
+        (self.row,self.column)
+    }
+
+    /// Moves the cursor, clamped to the screen.
+    pub fn set_position(&mut self, row:usize, column:usize) {
+        self.row = row.min(BUFFER_HEIGHT-1);
+        self.column = column.min(BUFFER_WIDTH-1);
+        self.update_cursor();
+    }
+
+    /// Colors for what is written from now on.
+    pub fn set_color(&mut self, foreground:Color, background:Color) {
+        self.color_code = ColorCode::new(foreground,background);
+    }
+
+    /// Blanks the screen in the current colors and puts the cursor at the top left.
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_position(0,0);
+    }
+
+    /// Moves the cursor `n` characters back, to the end of the row above at the start
+    /// of a row, stopping at the top left.
+    pub fn cursor_left(&mut self, n:usize) {
+        let position = (self.row*BUFFER_WIDTH + self.column).saturating_sub(n);
+        self.row = position/BUFFER_WIDTH;
+        self.column = position%BUFFER_WIDTH;
+        self.update_cursor();
+    }
+
+    /// Blanks the screen from the cursor on.
+    pub fn clear_to_end(&mut self) {
+        for col in self.column..BUFFER_WIDTH {
+            self.blank(self.row,col);
+        }
+        for row in self.row+1..BUFFER_HEIGHT {
+            self.clear_row(row);
         }
     }
 
     pub fn write_string(&mut self, s:&str) {
-        for byte in s.bytes() {
-            match byte {
-                //printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),//'|' means OR
-                //illegal
-                _=>self.write_byte(0xfe),
+        for character in s.chars() {
+            match character {
+                '\n' | '\r' | '\t' | '\u{8}' => self.put(character as u8),
+                character => self.put_glyph(cp437(character)),
             }
         }
+        self.update_cursor();
+    }
+}
+
+/// The code page 437 byte showing `character`, or a small square (0xfe) for the
+/// ones it lacks, control characters included.
+fn cp437(character:char) -> u8 {
+    if (' '..='~').contains(&character) {
+        return character as u8;
+    }
+    const HIGH:[char;128] = [
+        'Ç','ü','é','â','ä','à','å','ç','ê','ë','è','ï','î','ì','Ä','Å',
+        'É','æ','Æ','ô','ö','ò','û','ù','ÿ','Ö','Ü','¢','£','¥','₧','ƒ',
+        'á','í','ó','ú','ñ','Ñ','ª','º','¿','⌐','¬','½','¼','¡','«','»',
+        '░','▒','▓','│','┤','╡','╢','╖','╕','╣','║','╗','╝','╜','╛','┐',
+        '└','┴','┬','├','─','┼','╞','╟','╚','╔','╩','╦','╠','═','╬','╧',
+        '╨','╤','╥','╙','╘','╒','╓','╫','╪','┘','┌','█','▄','▌','▐','▀',
+        'α','ß','Γ','π','Σ','σ','µ','τ','Φ','Θ','Ω','δ','∞','φ','ε','∩',
+        '≡','±','≥','≤','⌠','⌡','÷','≈','°','∙','·','√','ⁿ','²','■','\u{a0}',
+    ];
+    const LOW:[char;31] = [
+        '☺','☻','♥','♦','♣','♠','•','◘','○','◙','♂','♀','♪','♫','☼',
+        '►','◄','↕','‼','¶','§','▬','↨','↑','↓','→','←','∟','↔','▲','▼',
+    ];
+    if let Some(index) = HIGH.iter().position(|&c|c == character) {
+        return 0x80 + index as u8;
+    }
+    if let Some(index) = LOW.iter().position(|&c|c == character) {
+        return 1 + index as u8;
+    }
+    match character {
+        '⌂' => 0x7f,
+        'β' => 0xe1,//looks the same as ß
+        'μ' => 0xe6,//the Greek letter rather than the micro sign
+        'Ø' | '∅' => 0xed,
+        '…' => b'.',
+        '‘' | '’' => b'\'',
+        '“' | '”' => b'"',
+        '–' | '—' => b'-',
+        _ => 0xfe,
     }
 }
 
@@ -181,4 +310,63 @@ fn test_println_output() {
     });
 
     serial_println!("[ok]");
-}
\ No newline at end of file
+}
+#[cfg(test)]
+fn cursor_register() -> u16 {
+    let mut index = Port::<u8>::new(CRTC_INDEX);
+    let mut data = Port::<u8>::new(CRTC_DATA);
+    unsafe {
+        index.write(0x0e);
+        let high = data.read() as u16;
+        index.write(0x0f);
+        high << 8 | data.read() as u16
+    }
+}
+
+#[test_case]
+fn test_positioning_and_control_characters() {
+    use x86_64::instructions::interrupts;
+
+    serial_print!("test_positioning_and_control_characters... ");
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        writer.clear_screen();
+        assert_eq!(writer.position(),(0,0));
+        assert_eq!(cursor_register(),0);
+        writer.write_string("ab\tc\rX\nxyz\u{8}");
+        assert_eq!(writer.buffer.chars[0][0].read().ascii_character,b'X');
+        assert_eq!(writer.buffer.chars[0][1].read().ascii_character,b'b');
+        assert_eq!(writer.buffer.chars[0][TAB_WIDTH].read().ascii_character,b'c');
+        assert_eq!(writer.buffer.chars[1][2].read().ascii_character,b' ');
+        assert_eq!(writer.position(),(1,2));
+        assert_eq!(cursor_register(),(BUFFER_WIDTH + 2) as u16);
+        writer.cursor_left(3);
+        assert_eq!(writer.position(),(0,BUFFER_WIDTH-1));
+        writer.set_position(100,100);
+        assert_eq!(writer.position(),(BUFFER_HEIGHT-1,BUFFER_WIDTH-1));
+        writer.set_position(BUFFER_HEIGHT-1,0);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_colors_and_cp437() {
+    use x86_64::instructions::interrupts;
+
+    serial_print!("test_colors_and_cp437... ");
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code;
+        writer.set_position(BUFFER_HEIGHT-1,0);
+        writer.set_color(Color::Yellow,Color::Red);
+        writer.write_string("é─█€");
+        writer.color_code = previous;
+        let row = &writer.buffer.chars[BUFFER_HEIGHT-1];
+        let bytes:[u8;4] = [row[0].read().ascii_character,row[1].read().ascii_character,
+            row[2].read().ascii_character,row[3].read().ascii_character];
+        assert_eq!(bytes,[0x82,0xc4,0xdb,0xfe]);
+        assert_eq!(row[0].read().color_code,ColorCode::new(Color::Yellow,Color::Red));
+        writer.write_string("\n");
+    });
+    serial_println!("[ok]");
+}
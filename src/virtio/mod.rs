@@ -0,0 +1,309 @@
+//! Virtio 1.0 devices on the modern PCI transport.
+//!
+//! The device's vendor capabilities point into its BARs at four register blocks:
+//! the common configuration (features, status, queue setup), the notification
+//! area, the interrupt status and the device specific configuration. A driver
+//! negotiates features, sets up its virtqueues and then exchanges buffers with the
+//! device through them. Completions are signalled through MSI-X when the local
+//! APIC is up and through the legacy interrupt pin otherwise.
+
+use alloc::{sync::Arc,vec::Vec};
+use x86_64::structures::paging::{FrameAllocator,PhysFrame};
+use crate::memory::{self,GlobalFrameAllocator};
+use crate::pci::{self,msi,PciDevice,CAP_VENDOR};
+use crate::syscall::Errno;
+
+pub mod queue;
+
+pub use queue::{Buffer,Virtqueue};
+
+pub const VENDOR_ID:u16 = 0x1af4;
+/// PCI device ids of transitional devices are `0x1000 + type - 1`, those of modern
+/// ones `MODERN_DEVICE_ID_BASE + type`.
+pub const MODERN_DEVICE_ID_BASE:u16 = 0x1040;
+
+/// Kinds of configuration structures in vendor capabilities.
+const CFG_COMMON:u8 = 1;
+const CFG_NOTIFY:u8 = 2;
+const CFG_ISR:u8 = 3;
+const CFG_DEVICE:u8 = 4;
+
+/// Common configuration registers.
+const DEVICE_FEATURE_SELECT:u64 = 0;
+const DEVICE_FEATURE:u64 = 4;
+const DRIVER_FEATURE_SELECT:u64 = 8;
+const DRIVER_FEATURE:u64 = 12;
+const MSIX_CONFIG:u64 = 16;
+const DEVICE_STATUS:u64 = 20;
+const CONFIG_GENERATION:u64 = 21;
+const QUEUE_SELECT:u64 = 22;
+const QUEUE_SIZE:u64 = 24;
+const QUEUE_MSIX_VECTOR:u64 = 26;
+const QUEUE_ENABLE:u64 = 28;
+const QUEUE_NOTIFY_OFF:u64 = 30;
+const QUEUE_DESC:u64 = 32;
+const QUEUE_DRIVER:u64 = 40;
+const QUEUE_DEVICE:u64 = 48;
+
+const STATUS_ACKNOWLEDGE:u8 = 1;
+const STATUS_DRIVER:u8 = 2;
+const STATUS_DRIVER_OK:u8 = 4;
+const STATUS_FEATURES_OK:u8 = 8;
+const STATUS_FAILED:u8 = 128;
+
+/// Set in `msix_config` and `queue_msix_vector` for "no MSI-X vector".
+const NO_VECTOR:u16 = 0xffff;
+
+/// Feature bits common to all device types.
+pub const F_VERSION_1:u64 = 1 << 32;
+
+fn read<T>(address:u64) -> T {
+    unsafe { core::ptr::read_volatile(address as *const T) }
+}
+fn write<T>(address:u64, value:T) {
+    unsafe { core::ptr::write_volatile(address as *mut T,value) }
+}
+/// 64-bit registers are written as two halves, which every device accepts.
+fn write64(address:u64, value:u64) {
+    write(address,value as u32);
+    write(address + 4,(value >> 32) as u32);
+}
+
+/// A virtio device found on the PCI bus, with its register blocks mapped.
+pub struct VirtioPci {
+    pub device:Arc<PciDevice>,
+    common:u64,
+    notify:u64,
+    notify_multiplier:u32,
+    isr:u64,
+    config:u64,
+    /// Whether interrupts come through MSI-X (table entry 0) rather than the pin.
+    msix:bool,
+}impl VirtioPci {
+    /// Finds the register blocks of `device`. Fails with `ENODEV` if it isn't a
+    /// modern virtio device.
+    pub fn new(device:&Arc<PciDevice>) -> Result<Self,Errno> {
+        let mut blocks = [None;5];
+        let mut notify_multiplier = 0;
+        for (_,cap) in device.capabilities().into_iter().filter(|&(id,_)|id == CAP_VENDOR) {
+            let kind = device.read8(cap + 3);
+            if kind < CFG_COMMON || kind > CFG_DEVICE || blocks[kind as usize].is_some() {
+                continue;//the first structure of a kind is the preferred one
+            }
+            let (bar,offset) = (device.read8(cap + 4) as usize,device.read32(cap + 8));
+            let base = match device.map_bar(bar) {
+                Ok(base) => base,
+                Err(_) => continue,//an I/O BAR, which a driver may skip
+            };
+            blocks[kind as usize] = Some(base + offset as u64);
+            if kind == CFG_NOTIFY {
+                notify_multiplier = device.read32(cap + 16);
+            }
+        }
+        match (blocks[CFG_COMMON as usize],blocks[CFG_NOTIFY as usize],blocks[CFG_ISR as usize]) {
+            (Some(common),Some(notify),Some(isr)) => {
+                device.enable_decoding();
+                device.enable_bus_master();
+                let config = blocks[CFG_DEVICE as usize].unwrap_or(0);
+                Ok(VirtioPci {device:device.clone(), common, notify, notify_multiplier, isr, config, msix:false})
+            }
+            _ => Err(Errno::ENODEV),
+        }
+    }
+
+    fn status(&self) -> u8 {
+        read(self.common + DEVICE_STATUS)
+    }
+    fn set_status(&self, status:u8) {
+        write(self.common + DEVICE_STATUS,status);
+    }
+
+    /// Resets the device and negotiates the features in `wanted` it offers, plus
+    /// `F_VERSION_1`, which it must offer. Returns the accepted features.
+    pub fn negotiate(&self, wanted:u64) -> Result<u64,Errno> {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();//the reset is done when the status reads 0
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let mut offered = 0u64;
+        for select in 0..2u32 {
+            write(self.common + DEVICE_FEATURE_SELECT,select);
+            offered |= (read::<u32>(self.common + DEVICE_FEATURE) as u64) << (32*select);
+        }
+        if offered & F_VERSION_1 == 0 {
+            self.fail();
+            return Err(Errno::ENODEV);//a legacy-only device
+        }
+        let accepted = offered & (wanted | F_VERSION_1);
+        for select in 0..2u32 {
+            write(self.common + DRIVER_FEATURE_SELECT,select);
+            write(self.common + DRIVER_FEATURE,(accepted >> (32*select)) as u32);
+        }
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err(Errno::ENODEV);
+        }
+        Ok(accepted)
+    }
+
+    /// Routes the device's interrupts to `handler`: through MSI-X when possible,
+    /// else through the interrupt pin. Must come between `negotiate` and `queue`.
+    /// Fails if neither works; the driver can still poll.
+    pub fn set_interrupt_handler(&mut self, handler:fn()) -> Result<(),Errno> {
+        if let Ok(vector) = msi::allocate_vector(handler) {
+            if msi::enable_msix(&self.device,&[vector]).is_ok() {
+                write(self.common + MSIX_CONFIG,0u16);
+                if read::<u16>(self.common + MSIX_CONFIG) != NO_VECTOR {
+                    self.msix = true;
+                    return Ok(());
+                }
+                msi::disable(&self.device);
+            }
+            msi::free_vector(vector);
+        }
+        match self.device.interrupt_pin {
+            0 => Err(Errno::ENODEV),
+            _ => pci::irq::add_handler(self.device.interrupt_line,handler),
+        }
+    }
+
+    /// Whether an interrupt was the device's, for the handler of a shared pin; reading
+    /// the status deasserts the pin. Interrupts through MSI-X are always the device's.
+    pub fn interrupted(&self) -> bool {
+        self.msix || read::<u8>(self.isr) != 0
+    }
+
+    /// Sets up queue `index` with at most `queue::MAX_SIZE` entries.
+    pub fn queue(&self, index:u16) -> Result<Virtqueue,Errno> {
+        write(self.common + QUEUE_SELECT,index);
+        let max = read::<u16>(self.common + QUEUE_SIZE);
+        if max == 0 {
+            return Err(Errno::ENODEV);//no such queue
+        }
+        let mut size = max.min(queue::MAX_SIZE);
+        while !size.is_power_of_two() {
+            size &= size - 1;
+        }
+        let notify_off = read::<u16>(self.common + QUEUE_NOTIFY_OFF) as u64;
+        let queue = Virtqueue::new(index,size,self.notify + notify_off*self.notify_multiplier as u64)?;
+        write(self.common + QUEUE_SIZE,size);
+        write64(self.common + QUEUE_DESC,queue.desc_phys());
+        write64(self.common + QUEUE_DRIVER,queue.avail_phys());
+        write64(self.common + QUEUE_DEVICE,queue.used_phys());
+        if self.msix {
+            write(self.common + QUEUE_MSIX_VECTOR,0u16);
+        }
+        write(self.common + QUEUE_ENABLE,1u16);
+        Ok(queue)
+    }
+
+    /// Tells the device the driver is ready; the queues are live from here on.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Stops the device; it no longer touches its queues afterwards.
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Reads the device specific configuration with `f`, which gets its address,
+    /// again if the device changed it meanwhile.
+    pub fn read_config<T, F:Fn(u64)->T>(&self, f:F) -> T {
+        loop {
+            let generation = read::<u8>(self.common + CONFIG_GENERATION);
+            let value = f(self.config);
+            if read::<u8>(self.common + CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+
+    pub fn config_u16(&self, offset:u64) -> u16 {
+        self.read_config(|config|read::<u16>(config + offset))
+    }
+
+    pub fn config_u32(&self, offset:u64) -> u32 {
+        self.read_config(|config|read::<u32>(config + offset))
+    }
+
+    /// 64-bit fields are read as two halves, so they need the generation check.
+    pub fn config_u64(&self, offset:u64) -> u64 {
+        self.read_config(|config|read::<u32>(config + offset) as u64 | (read::<u32>(config + offset + 4) as u64) << 32)
+    }
+
+    pub fn config_u8(&self, offset:u64) -> u8 {
+        self.read_config(|config|read::<u8>(config + offset))
+    }
+}
+
+/// Physically backed memory for buffers the device reads or writes, one frame
+/// per descriptor since the frames need not be contiguous.
+pub struct Dma {
+    frames:Vec<PhysFrame>,
+}impl Dma {
+    /// `pages` zeroed frames.
+    pub fn new(pages:usize) -> Result<Self,Errno> {
+        let mut dma = Dma {frames:Vec::with_capacity(pages)};
+        for _ in 0..pages {
+            let frame = GlobalFrameAllocator.allocate_frame().ok_or(Errno::ENOMEM)?;//dropping `dma` frees the others
+            unsafe { core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),0,4096) };
+            dma.frames.push(frame);
+        }
+        Ok(dma)
+    }
+
+    pub fn pages(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The physical address of page `page`, for a descriptor.
+    pub fn phys(&self, page:usize) -> u64 {
+        self.frames[page].start_address().as_u64()
+    }
+
+    fn ptr(&self, page:usize) -> *mut u8 {
+        memory::phys_to_virt(self.frames[page].start_address()).as_mut_ptr()
+    }
+
+    /// Copies bytes starting at `offset` into `buf`.
+    pub fn read(&self, offset:usize, buf:&mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done;
+            let chunk = (4096 - at%4096).min(buf.len() - done);
+            unsafe { core::ptr::copy_nonoverlapping(self.ptr(at/4096).add(at%4096),buf[done..].as_mut_ptr(),chunk) };
+            done += chunk;
+        }
+    }
+
+    /// Copies `data` to the bytes starting at `offset`.
+    pub fn write(&self, offset:usize, data:&[u8]) {
+        let mut done = 0;
+        while done < data.len() {
+            let at = offset + done;
+            let chunk = (4096 - at%4096).min(data.len() - done);
+            unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(),self.ptr(at/4096).add(at%4096),chunk) };
+            done += chunk;
+        }
+    }
+}
+
+impl Drop for Dma {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Split virtqueues: a descriptor table and an available ring the driver fills,
+//! and a used ring the device fills.
+
+use alloc::{collections::VecDeque,vec::Vec};
+use core::sync::atomic::{fence,Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameAllocator,PhysFrame};
+use crate::memory::{self,GlobalFrameAllocator};
+use crate::syscall::Errno;
+
+/// Largest queue used, so the descriptors and the available ring fit one frame
+/// and the used ring another.
+pub const MAX_SIZE:u16 = 128;
+
+const DESC_SIZE:u64 = 16;
+const DESC_F_NEXT:u16 = 1;
+const DESC_F_WRITE:u16 = 2;
+
+/// A buffer in physical memory, one descriptor of a chain.
+#[derive(Debug,Clone,Copy)]
+pub struct Buffer {
+    pub addr:u64,
+    pub len:u32,
+    /// The device writes it rather than reads it.
+    pub writable:bool,
+}
+
+struct State {
+    free:Vec<u16>,
+    /// Where the next chain goes in the available ring (free running).
+    avail_idx:u16,
+    /// How far the used ring has been read.
+    last_used:u16,
+    /// Heads of the chains the device is done with, in the order it finished them,
+    /// with the number of bytes it wrote.
+    done:VecDeque<(u16,u32)>,
+}
+
+pub struct Virtqueue {
+    pub index:u16,
+    size:u16,
+    rings:PhysFrame,
+    used:PhysFrame,
+    /// Where to write `index` to tell the device about new buffers.
+    notify:u64,
+    state:Mutex<State>,
+}impl Virtqueue {
+    /// `size` must be a power of two of at most `MAX_SIZE`.
+    pub(super) fn new(index:u16, size:u16, notify:u64) -> Result<Self,Errno> {
+        let rings = GlobalFrameAllocator.allocate_frame().ok_or(Errno::ENOMEM)?;
+        let used = match GlobalFrameAllocator.allocate_frame() {
+            Some(used) => used,
+            None => {
+                unsafe { GlobalFrameAllocator.deallocate_frame(rings) };
+                return Err(Errno::ENOMEM);
+            }
+        };
+        for frame in &[rings,used] {
+            unsafe { core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),0,4096) };
+        }
+        Ok(Virtqueue {
+            index,
+            size,
+            rings,
+            used,
+            notify,
+            state:Mutex::new(State {free:(0..size).rev().collect(), avail_idx:0, last_used:0, done:VecDeque::new()}),
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(super) fn desc_phys(&self) -> u64 {
+        self.rings.start_address().as_u64()
+    }
+    pub(super) fn avail_phys(&self) -> u64 {
+        self.desc_phys() + DESC_SIZE*self.size as u64
+    }
+    pub(super) fn used_phys(&self) -> u64 {
+        self.used.start_address().as_u64()
+    }
+
+    fn ptr<T>(phys:u64) -> *mut T {
+        memory::phys_to_virt(x86_64::PhysAddr::new(phys)).as_mut_ptr()
+    }
+
+    /// Hands a chain of buffers to the device and returns its head, or None if there
+    /// are not enough free descriptors right now.
+    pub fn submit(&self, buffers:&[Buffer]) -> Option<u16> {
+        interrupts::without_interrupts(||{
+            let mut state = self.state.lock();
+            if buffers.is_empty() || state.free.len() < buffers.len() {
+                return None;
+            }
+            let at = state.free.len() - buffers.len();
+            let chain:Vec<u16> = state.free.drain(at..).rev().collect();
+            for (i,buffer) in buffers.iter().enumerate() {
+                let mut flags = if buffer.writable {DESC_F_WRITE} else {0};
+                if i + 1 < chain.len() {
+                    flags |= DESC_F_NEXT;
+                }
+                let next = chain.get(i + 1).copied().unwrap_or(0);
+                let desc = Self::ptr::<u8>(self.desc_phys() + DESC_SIZE*chain[i] as u64);
+                unsafe {
+                    (desc as *mut u64).write_volatile(buffer.addr);
+                    (desc.add(8) as *mut u32).write_volatile(buffer.len);
+                    (desc.add(12) as *mut u16).write_volatile(flags);
+                    (desc.add(14) as *mut u16).write_volatile(next);
+                }
+            }
+            let avail = Self::ptr::<u16>(self.avail_phys());
+            let slot = state.avail_idx%self.size;
+            unsafe { avail.add(2 + slot as usize).write_volatile(chain[0]) };
+            state.avail_idx = state.avail_idx.wrapping_add(1);
+            fence(Ordering::SeqCst);//the device must see the chain before the index
+            unsafe { avail.add(1).write_volatile(state.avail_idx) };
+            fence(Ordering::SeqCst);
+            unsafe { (self.notify as *mut u16).write_volatile(self.index) };
+            Some(chain[0])
+        })
+    }
+
+    /// Moves what the device put in the used ring to `done` and frees the chains.
+    fn collect(&self, state:&mut State) {
+        let used = Self::ptr::<u16>(self.used_phys());
+        loop {
+            let idx = unsafe { used.add(1).read_volatile() };
+            if idx == state.last_used {
+                break;
+            }
+            fence(Ordering::SeqCst);
+            let element = unsafe { (used.add(2) as *mut u32).add(2*(state.last_used%self.size) as usize) };
+            let (head,len) = unsafe { (element.read_volatile() as u16,element.add(1).read_volatile()) };
+            state.last_used = state.last_used.wrapping_add(1);
+            let mut desc = head;
+            loop {
+                state.free.push(desc);
+                let entry = Self::ptr::<u8>(self.desc_phys() + DESC_SIZE*desc as u64);
+                let (flags,next) = unsafe { ((entry.add(12) as *mut u16).read_volatile(),(entry.add(14) as *mut u16).read_volatile()) };
+                if flags & DESC_F_NEXT == 0 {
+                    break;
+                }
+                desc = next;
+            }
+            state.done.push_back((head,len));
+        }
+    }
+
+    /// If the chain starting at `head` is done, the number of bytes the device wrote to it.
+    pub fn take(&self, head:u16) -> Option<u32> {
+        interrupts::without_interrupts(||{
+            let mut state = self.state.lock();
+            self.collect(&mut state);
+            let position = state.done.iter().position(|&(done,_)|done == head)?;
+            state.done.remove(position).map(|(_,len)|len)
+        })
+    }
+
+    /// The oldest chain the device is done with, as its head and the bytes written.
+    pub fn pop(&self) -> Option<(u16,u32)> {
+        interrupts::without_interrupts(||{
+            let mut state = self.state.lock();
+            self.collect(&mut state);
+            state.done.pop_front()
+        })
+    }
+}
+
+impl Drop for Virtqueue {
+    /// The device must have been reset, so it no longer uses the rings.
+    fn drop(&mut self) {
+        unsafe {
+            GlobalFrameAllocator.deallocate_frame(self.rings);
+            GlobalFrameAllocator.deallocate_frame(self.used);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::block::{self,ata};
+use bentos::syscall::Errno;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// QEMU attaches the boot image as the first IDE disk, so there normally is an
+/// "hda": its last two sectors are written, read back and restored. Without disks
+/// this only checks that probing returns.
+#[test_case]
+fn probe_and_round_trip(){
+    serial_print!("probe_and_round_trip... ");
+    let disks = ata::init();
+    for name in &disks {
+        assert!(block::device(name).is_some());
+    }
+    if let Some(disk) = block::device("hda") {
+        let last = disk.block_count() - 1;
+        let mut saved = vec![0u8;1024];
+        disk.read_blocks(last - 1,&mut saved).unwrap();
+        let pattern:alloc::vec::Vec<u8> = (0..1024u32).map(|i|(i*13) as u8).collect();
+        disk.write_blocks(last - 1,&pattern).unwrap();
+        disk.flush().unwrap();
+        let mut back = vec![0u8;1024];
+        disk.read_blocks(last - 1,&mut back).unwrap();
+        assert_eq!(back,pattern);
+        assert_eq!(disk.read_blocks(last,&mut back),Err(Errno::EINVAL));
+        disk.write_blocks(last - 1,&saved).unwrap();
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,201 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{string::String,sync::Arc,vec,vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::block::{self,partition::crc32,BlockCache,BlockDevice,CacheStats,PartitionKind,RamDisk};
+use bentos::fs::fat;
+use bentos::syscall::Errno;
+use bentos::vfs;
+use common::{put32,put64};
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    bentos::fs::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+fn block_of(device:&dyn BlockDevice, block:u64) -> Vec<u8> {
+    let mut data = vec![0u8;device.block_size()];
+    device.read_blocks(block,&mut data).unwrap();
+    data
+}
+
+/// A partition table entry of an MBR or EBR.
+fn mbr_entry(sector:&mut [u8], slot:usize, kind:u8, start:u32, blocks:u32) {
+    let entry = &mut sector[446 + 16*slot..462 + 16*slot];
+    entry[4] = kind;
+    put32(entry,8,start);
+    put32(entry,12,blocks);
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+}
+
+#[test_case]
+fn cache_writes_back(){
+    serial_print!("cache_writes_back... ");
+    let disk:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(64).unwrap());
+    let cache = BlockCache::new(disk.clone(),4);
+    cache.write_blocks(3,&[7u8;512]).unwrap();
+    assert_eq!(block_of(&*disk,3),[0u8;512].to_vec());//not written through
+    assert_eq!(block_of(&cache,3),[7u8;512].to_vec());
+    assert_eq!(block_of(&cache,4),[0u8;512].to_vec());
+    assert_eq!(cache.stats(),CacheStats {hits:1, misses:1, writebacks:0});
+    cache.sync().unwrap();
+    assert_eq!(block_of(&*disk,3),[7u8;512].to_vec());
+    assert_eq!(cache.stats().writebacks,1);
+    cache.sync().unwrap();
+    assert_eq!(cache.stats().writebacks,1);//clean now
+    assert_eq!(cache.read_blocks(64,&mut [0u8;512]),Err(Errno::EINVAL));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cache_evicts_least_recently_used(){
+    serial_print!("cache_evicts_least_recently_used... ");
+    let disk:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(64).unwrap());
+    let cache = BlockCache::new(disk.clone(),2);
+    cache.write_blocks(0,&[1u8;512]).unwrap();
+    cache.write_blocks(1,&[2u8;512]).unwrap();
+    block_of(&cache,0);//now block 1 is the least recently used
+    cache.write_blocks(2,&[3u8;512]).unwrap();
+    assert_eq!(block_of(&*disk,1),[2u8;512].to_vec());
+    assert_eq!(block_of(&*disk,0),[0u8;512].to_vec());
+    drop(cache);//syncs
+    assert_eq!(block_of(&*disk,0),[1u8;512].to_vec());
+    assert_eq!(block_of(&*disk,2),[3u8;512].to_vec());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mbr_with_logical_partitions(){
+    serial_print!("mbr_with_logical_partitions... ");
+    let disk:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(256).unwrap());
+    let mut sector = [0u8;512];
+    mbr_entry(&mut sector,0,0x0c,8,40);
+    mbr_entry(&mut sector,1,0x05,64,128);
+    mbr_entry(&mut sector,2,0x83,250,100);//past the end of the disk
+    disk.write_blocks(0,&sector).unwrap();
+    let mut ebr = [0u8;512];
+    mbr_entry(&mut ebr,0,0x83,2,20);
+    mbr_entry(&mut ebr,1,0x05,36,40);//the next record, relative to the extended partition
+    disk.write_blocks(64,&ebr).unwrap();
+    let mut ebr = [0u8;512];
+    mbr_entry(&mut ebr,0,0x82,2,10);
+    disk.write_blocks(100,&ebr).unwrap();
+
+    let partitions = block::partitions(&disk).unwrap();
+    let found:Vec<(u32,u64,u64)> = partitions.iter().map(|partition|(partition.number,partition.start,partition.block_count())).collect();
+    assert_eq!(found,vec![(1,8,40),(5,66,20),(6,102,10)]);
+    assert_eq!(partitions[2].kind,PartitionKind::Mbr(0x82));
+    partitions[1].write_blocks(19,&[9u8;512]).unwrap();
+    assert_eq!(block_of(&*disk,85),[9u8;512].to_vec());
+    assert_eq!(partitions[1].write_blocks(20,&[9u8;512]),Err(Errno::EINVAL));
+    let blank:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(8).unwrap());
+    assert!(block::partitions(&blank).unwrap().is_empty());
+    serial_println!("[ok]");
+}
+
+/// Writes a GPT header at `at` whose entries are at `entries_at`.
+fn gpt_header(disk:&dyn BlockDevice, at:u64, backup:u64, entries_at:u64, entries:&[u8]) {
+    let mut header = [0u8;512];
+    header[0..8].copy_from_slice(b"EFI PART");
+    put32(&mut header,8,0x0001_0000);//revision 1.0
+    put32(&mut header,12,92);
+    put64(&mut header,24,at);
+    put64(&mut header,32,backup);
+    put64(&mut header,40,3);//first usable block
+    put64(&mut header,48,125);//last usable block
+    put64(&mut header,72,entries_at);
+    put32(&mut header,80,4);
+    put32(&mut header,84,128);
+    put32(&mut header,88,crc32(entries));
+    let checksum = crc32(&header[..92]);
+    put32(&mut header,16,checksum);
+    disk.write_blocks(at,&header).unwrap();
+    disk.write_blocks(entries_at,entries).unwrap();
+}
+
+#[test_case]
+fn gpt_partitions(){
+    serial_print!("gpt_partitions... ");
+    let disk:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(128).unwrap());
+    let mut protective = [0u8;512];
+    mbr_entry(&mut protective,0,0xee,1,127);
+    disk.write_blocks(0,&protective).unwrap();
+    let mut entries = [0u8;512];//4 entries of 128 bytes
+    for (i,&(first,last,name)) in [(3u64,50u64,"boot"),(51,125,"data")].iter().enumerate() {
+        let entry = &mut entries[128*i..128*(i+1)];
+        entry[0..16].copy_from_slice(&[0xa0 + i as u8;16]);//type
+        entry[16..32].copy_from_slice(&[0x10 + i as u8;16]);//unique id
+        put64(entry,32,first);
+        put64(entry,40,last);
+        for (j,unit) in name.encode_utf16().enumerate() {
+            entry[56+2*j..58+2*j].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    gpt_header(&*disk,1,127,2,&entries);
+    gpt_header(&*disk,127,1,126,&entries);
+
+    let check = |disk:&Arc<dyn BlockDevice>|{
+        let partitions = block::partitions(disk).unwrap();
+        let found:Vec<(u32,u64,u64)> = partitions.iter().map(|partition|(partition.number,partition.start,partition.block_count())).collect();
+        assert_eq!(found,vec![(1,3,48),(2,51,75)]);
+        assert_eq!(partitions[1].kind,PartitionKind::Gpt {type_guid:[0xa1;16], name:String::from("data")});
+    };
+    check(&disk);
+    disk.write_blocks(1,&[0u8;512]).unwrap();//the backup header takes over
+    check(&disk);
+    disk.write_blocks(127,&[0u8;512]).unwrap();
+    assert_eq!(block::partitions(&disk).err(),Some(Errno::EIO));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fat_on_a_cached_partition(){
+    serial_print!("fat_on_a_cached_partition... ");
+    let disk:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(4096).unwrap());
+    let mut sector = [0u8;512];
+    mbr_entry(&mut sector,0,0x0c,2048,2048);
+    disk.write_blocks(0,&sector).unwrap();
+    let partition:Arc<dyn BlockDevice> = Arc::new(block::partitions(&disk).unwrap().remove(0));
+    let cache = Arc::new(BlockCache::new(partition.clone(),16));
+    fat::format(&*cache,"cached").unwrap();
+    vfs::mkdir("/part").unwrap();
+    fat::mount("/part",cache.clone()).unwrap();
+    vfs::write_file("/part/file.txt",b"through the cache").unwrap();
+    vfs::umount("/part").unwrap();//syncs the file system and with it the cache
+    assert!(cache.stats().hits > 0);
+    fat::mount("/part",partition).unwrap();
+    assert_eq!(vfs::read_file("/part/file.txt").unwrap(),b"through the cache");
+    vfs::umount("/part").unwrap();
+    serial_println!("[ok]");
+}
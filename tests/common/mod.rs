@@ -0,0 +1,57 @@
+//! Helpers shared by the integration tests, which include this with `mod common;`.
+//! Each test uses only some of them.
+#![allow(dead_code)]
+
+use alloc::{string::String,vec::Vec};
+use bentos::{usermode,vfs};
+
+/// Builds a static executable with one read+execute segment holding the headers and `code`.
+pub fn minimal_elf(code:&[u8]) -> Vec<u8> {
+    elf_with_flags(code,5)
+}
+
+/// Like `minimal_elf`, with the segment flags `flags` (1 execute, 2 write, 4 read).
+pub fn elf_with_flags(code:&[u8], flags:u32) -> Vec<u8> {
+    let base = usermode::USER_START;
+    let code_offset = 64 + 56u64;
+    let mut image = Vec::new();
+    image.extend_from_slice(&[0x7f,b'E',b'L',b'F',2,1,1,0]);
+    image.extend_from_slice(&[0;8]);
+    image.extend_from_slice(&2u16.to_le_bytes());//ET_EXEC
+    image.extend_from_slice(&0x3eu16.to_le_bytes());//x86_64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(base + code_offset).to_le_bytes());//entry
+    image.extend_from_slice(&64u64.to_le_bytes());//program headers
+    image.extend_from_slice(&0u64.to_le_bytes());//no section headers
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&64u16.to_le_bytes());
+    image.extend_from_slice(&56u16.to_le_bytes());
+    image.extend_from_slice(&1u16.to_le_bytes());
+    image.extend_from_slice(&[0;6]);
+    let size = code_offset + code.len() as u64;
+    image.extend_from_slice(&1u32.to_le_bytes());//PT_LOAD
+    image.extend_from_slice(&flags.to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&4096u64.to_le_bytes());
+    image.extend_from_slice(code);
+    image
+}
+
+/// The names in the directory at `path`, sorted.
+pub fn names(path:&str) -> Vec<String> {
+    let mut names:Vec<String> = vfs::readdir(path).unwrap().into_iter().map(|entry|entry.name).collect();
+    names.sort();
+    names
+}
+
+/// Stores `value` little-endian at `offset`, as on-disk structures are laid out.
+pub fn put32(data:&mut [u8], offset:usize, value:u32) {
+    data[offset..offset+4].copy_from_slice(&value.to_le_bytes());
+}
+pub fn put64(data:&mut [u8], offset:usize, value:u64) {
+    data[offset..offset+8].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque,string::String,vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use bentos::{serial_print,serial_println};
+use bentos::console::{Key,LineEditor,Output,Terminal};
+use bentos::shell::{self,Command};
+use bentos::syscall::Errno;
+use core::fmt::Write;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// A terminal that types the keys it was given and records what is written to it.
+struct Script {
+    keys:Mutex<VecDeque<Key>>,
+    output:Mutex<String>,
+}impl Script {
+    fn new(keys:&[Key]) -> Self {
+        Script {keys:Mutex::new(keys.iter().copied().collect()), output:Mutex::new(String::new())}
+    }
+    fn typed(text:&str) -> Vec<Key> {
+        text.chars().map(Key::Char).collect()
+    }
+}
+
+impl Terminal for Script {
+    fn read_key(&self) -> Key {
+        self.keys.lock().pop_front().expect("the script ran out of keys")
+    }
+    fn write_str(&self, text:&str) {
+        self.output.lock().push_str(text);
+    }
+    fn cursor_left(&self, _n:usize) {}
+    fn clear_to_end(&self) {}
+}
+
+#[test_case]
+fn edits_the_line(){
+    serial_print!("edits_the_line... ");
+    let mut keys = Script::typed("hllo!");
+    keys.extend_from_slice(&[Key::Backspace,Key::Home,Key::Right,Key::Char('e'),Key::End,Key::Left,Key::Delete,Key::Enter]);
+    let script = Script::new(&keys);
+    let mut editor = LineEditor::new();
+    assert_eq!(editor.read_line(&script,"> ").as_deref(),Some("hell"));
+    assert!(script.output.lock().starts_with("> "));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn recalls_history(){
+    serial_print!("recalls_history... ");
+    let mut keys = Script::typed("one");
+    keys.push(Key::Enter);
+    keys.extend(Script::typed("two"));
+    keys.push(Key::Enter);
+    keys.extend(Script::typed("tw"));
+    keys.extend_from_slice(&[Key::Up,Key::Up,Key::Up,Key::Down,Key::Char('!'),Key::Enter]);
+    keys.extend(Script::typed("x"));
+    keys.extend_from_slice(&[Key::Up,Key::Down,Key::Enter]);
+    let script = Script::new(&keys);
+    let mut editor = LineEditor::new();
+    assert_eq!(editor.read_line(&script,"").as_deref(),Some("one"));
+    assert_eq!(editor.read_line(&script,"").as_deref(),Some("two"));
+    assert_eq!(editor.read_line(&script,"").as_deref(),Some("two!"));
+    assert_eq!(editor.read_line(&script,"").as_deref(),Some("x"));//Down past the newest entry brings back the draft
+    assert_eq!(editor.history().collect::<Vec<_>>(),["one","two","two!","x"]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn interrupt_and_end_of_file(){
+    serial_print!("interrupt_and_end_of_file... ");
+    let mut keys = Script::typed("abc");
+    keys.extend_from_slice(&[Key::Interrupt,Key::Char('d'),Key::EndOfFile,Key::Backspace,Key::EndOfFile]);
+    let script = Script::new(&keys);
+    let mut editor = LineEditor::new();
+    assert_eq!(editor.read_line(&script,"").as_deref(),Some(""));
+    assert_eq!(editor.read_line(&script,""),None);//Ctrl-D only counts on an empty line
+    assert_eq!(editor.history().count(),0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn runs_commands(){
+    serial_print!("runs_commands... ");
+    let script = Script::new(&[]);
+    shell::execute(&script,"  echo hello   world ");
+    assert_eq!(*script.output.lock(),"hello world\n");
+    script.output.lock().clear();
+    shell::execute(&script,"frobnicate");
+    assert_eq!(*script.output.lock(),"frobnicate: unknown command, try help\n");
+    script.output.lock().clear();
+    shell::execute(&script,"help");
+    assert!(script.output.lock().contains("echo"));
+    serial_println!("[ok]");
+}
+
+fn greet(out:&mut Output, args:&[&str]) -> Result<(),Errno> {
+    match args.first() {
+        Some(name) => {
+            let _ = writeln!(out,"hello, {}",name);
+            Ok(())
+        }
+        None => Err(Errno::EINVAL),
+    }
+}
+
+#[test_case]
+fn registers_commands(){
+    serial_print!("registers_commands... ");
+    let greet = Command {name:"greet", help:"greets someone", run:greet};
+    shell::register(greet).unwrap();
+    assert_eq!(shell::register(greet),Err(Errno::EEXIST));
+    assert_eq!(shell::register(Command {name:"help", ..greet}),Err(Errno::EEXIST));
+    let script = Script::new(&[]);
+    shell::execute(&script,"greet world");
+    assert_eq!(*script.output.lock(),"hello, world\n");
+    script.output.lock().clear();
+    shell::execute(&script,"greet");
+    assert_eq!(*script.output.lock(),"greet: EINVAL\n");
+    script.output.lock().clear();
+    shell::execute(&script,"help");
+    assert!(script.output.lock().contains("greets someone"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn built_in_commands(){
+    serial_print!("built_in_commands... ");
+    let script = Script::new(&[]);
+    shell::execute(&script,"uptime");
+    assert!(script.output.lock().starts_with("up 0:00:"));
+    script.output.lock().clear();
+    shell::execute(&script,"mem");
+    assert!(script.output.lock().starts_with("heap "));
+    script.output.lock().clear();
+    while bentos::time::ticks() == 0 {
+        x86_64::instructions::hlt();
+    }
+    shell::execute(&script,"irq");
+    assert!(script.output.lock().contains("timer"));
+    script.output.lock().clear();
+    shell::execute(&script,"cat");
+    assert_eq!(*script.output.lock(),"cat: EINVAL\n");
+    serial_println!("[ok]");
+}
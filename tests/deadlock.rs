@@ -0,0 +1,58 @@
+//! Two threads that each hold a lock and block on the other's: in debug builds the
+//! wait-for graph catches the cycle and panics instead of hanging forever.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{exit_qemu,QemuExitCode,serial_print,serial_println,task};
+use bentos::sync::{Mutex,Semaphore};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref FIRST:Mutex<()> = Mutex::new(());
+    static ref SECOND:Mutex<()> = Mutex::new(());
+    static ref HOLDS_SECOND:Semaphore = Semaphore::new(0);
+}
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("deadlock_is_detected... ");
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    if cfg!(not(debug_assertions)) {
+        serial_println!("[skipped, release build]");//the detection compiles to nothing there
+        exit_qemu(QemuExitCode::Success);
+        bentos::hlt_loop();
+    }
+    let _first = FIRST.lock();
+    task::spawn(||{
+        let _second = SECOND.lock();
+        HOLDS_SECOND.release();
+        let _first = FIRST.lock();
+    });
+    HOLDS_SECOND.acquire();
+    //whichever of the two threads blocks last closes the cycle and panics
+    let _second = SECOND.lock();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info:&PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{elf::{self,ElfError},serial_print,serial_println,task};
+use common::minimal_elf;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// mov eax, 1 (exit); xor edi, edi; syscall
+const EXIT_CODE:[u8;9] = [0xb8,0x01,0x00,0x00,0x00,0x31,0xff,0x0f,0x05];
+
+#[test_case]
+fn elf_program_runs_and_exits(){
+    serial_print!("elf_program_runs_and_exits... ");
+    let id = elf::spawn(&minimal_elf(&EXIT_CODE),&["init"],&["PATH=/"]).expect("loading failed");
+    for _ in 0..100_000 {
+        if !task::is_alive(id) {
+            serial_println!("[ok]");
+            return;
+        }
+        task::yield_now();
+    }
+    panic!("program still running");
+}
+
+#[test_case]
+fn malformed_images_are_rejected(){
+    serial_print!("malformed_images_are_rejected... ");
+    let good = minimal_elf(&EXIT_CODE);
+    assert_eq!(elf::load(&good[..32],&[],&[]).err(),Some(ElfError::TooShort));
+    let mut bad = good.clone();
+    bad[0] = 0;
+    assert_eq!(elf::load(&bad,&[],&[]).err(),Some(ElfError::BadMagic));
+    let mut bad = good.clone();
+    bad[4] = 1;
+    assert_eq!(elf::load(&bad,&[],&[]).err(),Some(ElfError::Not64Bit));
+    let mut bad = good.clone();
+    bad[18] = 0x28;//ARM
+    assert_eq!(elf::load(&bad,&[],&[]).err(),Some(ElfError::UnsupportedMachine(0x28)));
+    let mut bad = good.clone();
+    bad[64+24..64+32].copy_from_slice(&0x1000u64.to_le_bytes());//segment below the user range
+    assert_eq!(elf::load(&bad,&[],&[]).err(),Some(ElfError::SegmentOutOfRange));
+    let mut bad = good.clone();
+    bad[64+32..64+40].copy_from_slice(&0x10_0000u64.to_le_bytes());//file size past the end
+    assert_eq!(elf::load(&bad,&[],&[]).err(),Some(ElfError::BadProgramHeader));
+    serial_println!("[ok]");
+}
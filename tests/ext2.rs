@@ -0,0 +1,294 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{format,string::String,sync::Arc,vec,vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::block::{self,ata,BlockDevice,RamDisk};
+use bentos::fs::{ext2,Ext2Fs};
+use bentos::syscall::Errno;
+use bentos::vfs::{self,File,FileType,OpenFlags,SeekFrom};
+use common::{names,put32};
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    bentos::fs::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+const BLOCK:usize = 1024;
+const BLOCKS:u32 = 256;
+const INODES:u32 = 32;
+const INODE_TABLE:u32 = 5;
+const FIRST_DATA:u32 = 9;
+
+const S_IFREG:u16 = 0x8000;
+const S_IFDIR:u16 = 0x4000;
+const S_IFLNK:u16 = 0xa000;
+
+/// Writes a small ext2 image (1 KiB blocks, one group) straight to a ramdisk: the
+/// superblock in block 1, the descriptor in 2, bitmaps in 3 and 4, which nothing
+/// reads, the inode table in 5 to 8 and data from 9 on.
+struct Image {
+    disk:Arc<RamDisk>,
+    next:u32,
+}impl Image {
+    fn new(incompat:u32) -> Self {
+        let disk = Arc::new(RamDisk::new(BLOCKS as u64*2).unwrap());
+        let mut superblock = [0u8;1024];
+        put32(&mut superblock,0,INODES);
+        put32(&mut superblock,4,BLOCKS);
+        put32(&mut superblock,20,1);//first data block
+        put32(&mut superblock,32,8192);//blocks per group
+        put32(&mut superblock,36,8192);//fragments per group
+        put32(&mut superblock,40,INODES);
+        superblock[56..58].copy_from_slice(&0xef53u16.to_le_bytes());
+        superblock[58..60].copy_from_slice(&1u16.to_le_bytes());//clean
+        put32(&mut superblock,76,1);//dynamic revision
+        put32(&mut superblock,84,11);//first non-reserved inode
+        superblock[88..90].copy_from_slice(&128u16.to_le_bytes());
+        put32(&mut superblock,96,incompat);
+        block::write_bytes(&*disk,1024,&superblock).unwrap();
+        let mut descriptor = [0u8;32];
+        put32(&mut descriptor,0,3);
+        put32(&mut descriptor,4,4);
+        put32(&mut descriptor,8,INODE_TABLE);
+        block::write_bytes(&*disk,2*BLOCK as u64,&descriptor).unwrap();
+        Image {disk, next:FIRST_DATA}
+    }
+
+    fn write(&self, block:u32, data:&[u8]) {
+        block::write_bytes(&*self.disk,block as u64*BLOCK as u64,data).unwrap();
+    }
+
+    /// Stores `data` in new blocks and returns their numbers.
+    fn data(&mut self, data:&[u8]) -> Vec<u32> {
+        data.chunks(BLOCK).map(|chunk|{
+            let block = self.next;
+            self.next += 1;
+            self.write(block,chunk);
+            block
+        }).collect()
+    }
+
+    /// Writes inode `ino`; `blocks` beyond the twelfth go into a single indirect block.
+    fn inode(&mut self, ino:u32, mode:u16, size:u64, links:u16, blocks:&[u32]) {
+        let mut raw = [0u8;128];
+        raw[0..2].copy_from_slice(&(mode | 0o644).to_le_bytes());
+        put32(&mut raw,4,size as u32);
+        raw[26..28].copy_from_slice(&links.to_le_bytes());
+        let mut used = blocks.iter().filter(|&&block|block != 0).count() as u32;
+        for (i,&block) in blocks.iter().take(12).enumerate() {
+            put32(&mut raw,40 + 4*i,block);
+        }
+        if blocks.len() > 12 {
+            let mut pointers = vec![0u8;BLOCK];
+            for (i,&block) in blocks[12..].iter().enumerate() {
+                put32(&mut pointers,4*i,block);
+            }
+            let indirect = self.data(&pointers)[0];
+            put32(&mut raw,40 + 4*12,indirect);
+            used += 1;
+        }
+        put32(&mut raw,28,used*(BLOCK/512) as u32);
+        put32(&mut raw,108,(size >> 32) as u32);
+        block::write_bytes(&*self.disk,INODE_TABLE as u64*BLOCK as u64 + (ino as u64 - 1)*128,&raw).unwrap();
+    }
+
+    fn file(&mut self, ino:u32, data:&[u8]) {
+        let blocks = self.data(data);
+        self.inode(ino,S_IFREG,data.len() as u64,1,&blocks);
+    }
+
+    /// A directory of one block holding "." and ".." and `entries` (name, inode, type).
+    fn dir(&mut self, ino:u32, parent:u32, entries:&[(&str,u32,u8)]) {
+        let mut data = vec![0u8;BLOCK];
+        let mut offset = 0;
+        let all:Vec<(&str,u32,u8)> = [(".",ino,2),("..",parent,2)].iter().chain(entries).copied().collect();
+        for (i,&(name,entry_ino,kind)) in all.iter().enumerate() {
+            let len = if i + 1 == all.len() {BLOCK - offset} else {(8 + name.len() + 3)/4*4};
+            put32(&mut data,offset,entry_ino);
+            data[offset+4..offset+6].copy_from_slice(&(len as u16).to_le_bytes());
+            data[offset+6] = name.len() as u8;
+            data[offset+7] = kind;
+            data[offset+8..offset+8+name.len()].copy_from_slice(name.as_bytes());
+            offset += len;
+        }
+        let blocks = self.data(&data);
+        let subdirs = entries.iter().filter(|entry|entry.2 == 2).count() as u16;
+        self.inode(ino,S_IFDIR,BLOCK as u64,2 + subdirs,&blocks);
+    }
+
+    fn symlink(&mut self, ino:u32, target:&str) {
+        if target.len() < 60 {
+            //kept in the block pointers
+            self.inode(ino,S_IFLNK,target.len() as u64,1,&[]);
+            let position = INODE_TABLE as u64*BLOCK as u64 + (ino as u64 - 1)*128 + 40;
+            block::write_bytes(&*self.disk,position,target.as_bytes()).unwrap();
+        } else {
+            let blocks = self.data(target.as_bytes());
+            self.inode(ino,S_IFLNK,target.len() as u64,1,&blocks);
+        }
+    }
+
+    fn device(&self) -> Arc<dyn BlockDevice> {
+        self.disk.clone()
+    }
+}
+
+/// 15 blocks of numbered data with a hole at block 3, so that it needs an indirect block.
+fn big_file() -> Vec<u8> {
+    let mut data:Vec<u8> = (0..15*BLOCK as u32 - 100).map(|i|(i%251) as u8).collect();
+    data[3*BLOCK..4*BLOCK].iter_mut().for_each(|byte|*byte = 0);
+    data
+}
+
+/// An image with a root directory, files, a subdirectory and symbolic links, mounted at `path`.
+fn sample(path:&str) -> Arc<dyn BlockDevice> {
+    let mut image = Image::new(0x0002);//file types in directory entries
+    image.file(12,b"hello from ext2\n");
+    let big = big_file();
+    let mut blocks = image.data(&big);
+    blocks[3] = 0;
+    image.inode(13,S_IFREG,big.len() as u64,1,&blocks);
+    image.file(15,b"inner\n");
+    image.dir(14,2,&[("inner",15,1)]);
+    image.symlink(16,"sub/inner");
+    let long_target = {
+        let mut target = String::from("/");
+        target.push_str(&path[1..]);
+        for _ in 0..30 {
+            target.push_str("/.");
+        }
+        target.push_str("/hello.txt");
+        target
+    };
+    image.symlink(17,&long_target);
+    image.symlink(18,"loop");
+    image.dir(2,2,&[("hello.txt",12,1),("big",13,1),("sub",14,2),("link",16,7),("abs",17,7),("loop",18,7)]);
+    let device = image.device();
+    vfs::mkdir(path).unwrap();
+    ext2::mount(path,device.clone()).unwrap();
+    device
+}
+
+#[test_case]
+fn reads_files_and_directories(){
+    serial_print!("reads_files_and_directories... ");
+    sample("/ext2a");
+    assert_eq!(names("/ext2a"),vec!["abs","big","hello.txt","link","loop","sub"]);
+    assert_eq!(vfs::read_file("/ext2a/hello.txt").unwrap(),b"hello from ext2\n");
+    assert_eq!(vfs::read_file("/ext2a/big").unwrap(),big_file());
+    assert_eq!(vfs::read_file("/ext2a/sub/inner").unwrap(),b"inner\n");
+    let sub = vfs::stat("/ext2a/sub").unwrap();
+    assert_eq!((sub.kind,sub.ino,sub.links),(FileType::Directory,14,2));
+    assert_eq!(vfs::read_file("/ext2a/missing"),Err(Errno::ENOENT));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn is_read_only(){
+    serial_print!("is_read_only... ");
+    sample("/ext2b");
+    assert_eq!(vfs::write_file("/ext2b/hello.txt",b"changed"),Err(Errno::EROFS));
+    assert_eq!(vfs::write_file("/ext2b/new",b""),Err(Errno::EROFS));
+    assert_eq!(vfs::mkdir("/ext2b/dir"),Err(Errno::EROFS));
+    assert_eq!(vfs::unlink("/ext2b/hello.txt"),Err(Errno::EROFS));
+    assert_eq!(vfs::read_file("/ext2b/hello.txt").unwrap(),b"hello from ext2\n");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn follows_symlinks(){
+    serial_print!("follows_symlinks... ");
+    sample("/ext2c");
+    assert_eq!(vfs::readlink("/ext2c/link").unwrap(),"sub/inner");
+    assert_eq!(vfs::read_file("/ext2c/link").unwrap(),b"inner\n");
+    assert!(vfs::readlink("/ext2c/abs").unwrap().len() >= 60);//stored in a data block
+    assert_eq!(vfs::read_file("/ext2c/abs").unwrap(),b"hello from ext2\n");
+    assert_eq!(vfs::stat("/ext2c/loop").err(),Some(Errno::ELOOP));
+    assert_eq!(vfs::readlink("/ext2c/hello.txt"),Err(Errno::EINVAL));
+    let kinds:Vec<FileType> = vfs::readdir("/ext2c").unwrap().into_iter().filter(|entry|entry.name == "link").map(|entry|entry.kind).collect();
+    assert_eq!(kinds,vec![FileType::Symlink]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rejects_unsupported_features(){
+    serial_print!("rejects_unsupported_features... ");
+    let mut image = Image::new(0x0002 | 0x0040);//extents
+    image.dir(2,2,&[]);
+    assert_eq!(Ext2Fs::new(image.device()).err(),Some(Errno::EINVAL));
+    let blank:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(64).unwrap());
+    assert_eq!(Ext2Fs::new(blank).err(),Some(Errno::EINVAL));
+    serial_println!("[ok]");
+}
+
+/// A directory claiming to be larger than the volume fails instead of trying to
+/// read all of it into memory.
+#[test_case]
+fn rejects_oversized_directories(){
+    serial_print!("rejects_oversized_directories... ");
+    let mut image = Image::new(0x0002);
+    image.inode(14,S_IFDIR,0xffff_0000,2,&[]);
+    image.dir(2,2,&[("huge",14,2)]);
+    vfs::mkdir("/ext2d").unwrap();
+    ext2::mount("/ext2d",image.device()).unwrap();
+    assert_eq!(vfs::readdir("/ext2d/huge"),Err(Errno::EIO));
+    serial_println!("[ok]");
+}
+
+/// The volume build.rs makes with mke2fs, which QEMU attaches as "hdc": 4 KiB
+/// blocks, four groups and the default features.
+#[test_case]
+fn mke2fs_volume(){
+    serial_print!("mke2fs_volume... ");
+    ata::init();
+    let disk = block::device("hdc").expect("target/ext2-test.img isn't attached");
+    vfs::mkdir("/ext2e").unwrap();
+    ext2::mount("/ext2e",disk).unwrap();
+    assert_eq!(names("/ext2e"),vec!["big","docs","hello.txt","lost+found","many"]);
+    assert_eq!(vfs::read_file("/ext2e/hello.txt").unwrap(),b"hello from mke2fs\n");
+    assert_eq!(vfs::read_file("/ext2e/docs/notes.md").unwrap(),b"nested\n");
+    assert_eq!(vfs::readdir("/ext2e/many").unwrap().len(),1500);//six blocks of entries
+    assert_eq!(vfs::read_file("/ext2e/many/f1499").unwrap(),b"1499");//an inode in the third group
+    //the big file reaches through single and double indirect blocks into the second group
+    assert_eq!(vfs::stat("/ext2e/big").unwrap().size,5120*4096);
+    let big = vfs::open("/ext2e/big",OpenFlags::READ).unwrap();
+    for &number in &[0u64,11,12,1035,1036,4107,5119] {
+        let label = format!("block {}",number);
+        let mut buf = vec![0u8;label.len()];
+        big.seek(SeekFrom::Start(number*4096)).unwrap();
+        assert_eq!(big.read(&mut buf),Ok(label.len()));
+        assert_eq!(buf,label.as_bytes());
+    }
+    assert_eq!(vfs::write_file("/ext2e/hello.txt",b""),Err(Errno::EROFS));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,184 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{format,sync::Arc,vec,vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::block::{self,ata,BlockDevice,RamDisk};
+use bentos::fs::{fat,FatFs};
+use bentos::syscall::Errno;
+use bentos::vfs::{self,File,FileType,OpenFlags,SeekFrom};
+use common::names;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    bentos::fs::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// A freshly formatted 1 MiB volume mounted at `path`.
+fn volume(path:&str) -> (Arc<dyn BlockDevice>,Arc<FatFs>) {
+    let disk:Arc<dyn BlockDevice> = Arc::new(RamDisk::new(2048).unwrap());
+    fat::format(&*disk,"test").unwrap();
+    vfs::mkdir(path).unwrap();
+    let fs = fat::mount(path,disk.clone()).unwrap();
+    (disk,fs)
+}
+
+fn unmount(path:&str) {
+    vfs::umount(path).unwrap();
+    vfs::rmdir(path).unwrap();
+}
+
+#[test_case]
+fn short_and_long_names(){
+    serial_print!("short_and_long_names... ");
+    let _volume = volume("/fat1");
+    vfs::write_file("/fat1/README.TXT",b"a").unwrap();
+    vfs::write_file("/fat1/notes.md",b"b").unwrap();
+    vfs::write_file("/fat1/A rather long file name.text",b"c").unwrap();
+    vfs::write_file("/fat1/A rather long file name.text2",b"d").unwrap();
+    assert_eq!(names("/fat1"),vec!["A rather long file name.text","A rather long file name.text2","README.TXT","notes.md"]);
+    assert_eq!(vfs::read_file("/fat1/readme.txt").unwrap(),b"a");//names are case-insensitive
+    assert_eq!(vfs::read_file("/fat1/A rather long file name.text2").unwrap(),b"d");
+    assert_eq!(vfs::write_file("/fat1/what?",b""),Err(Errno::EINVAL));
+    unmount("/fat1");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn multi_cluster_files(){
+    serial_print!("multi_cluster_files... ");
+    let (_,fs) = volume("/fat2");
+    let free = fs.free_space();
+    let data:Vec<u8> = (0..20000u32).map(|i|(i*7) as u8).collect();
+    vfs::write_file("/fat2/big",&data).unwrap();
+    assert_eq!(vfs::read_file("/fat2/big").unwrap(),data);
+    assert_eq!(vfs::stat("/fat2/big").unwrap().size,20000);
+    assert!(fs.free_space() <= free - 20000);
+    vfs::unlink("/fat2/big").unwrap();
+    assert_eq!(fs.free_space(),free);
+    unmount("/fat2");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn seeks_within_chains(){
+    serial_print!("seeks_within_chains... ");
+    let _volume = volume("/fat5");
+    let data:Vec<u8> = (0..20000u32).map(|i|(i*3) as u8).collect();
+    vfs::write_file("/fat5/big",&data).unwrap();
+    let file = vfs::open("/fat5/big",OpenFlags::READ_WRITE).unwrap();
+    let mut buf = [0u8;100];
+    for &at in &[15000u64,1000,19950,0] {//forwards and back again
+        file.seek(SeekFrom::Start(at)).unwrap();
+        let len = file.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len],&data[at as usize..at as usize + len]);
+    }
+    file.truncate(3000).unwrap();//frees the clusters read last
+    file.seek(SeekFrom::Start(10000)).unwrap();
+    assert_eq!(file.write(b"end"),Ok(3));
+    let read = vfs::read_file("/fat5/big").unwrap();
+    assert_eq!(read.len(),10003);
+    assert_eq!(&read[..3000],&data[..3000]);
+    assert!(read[3000..10000].iter().all(|&byte|byte == 0));
+    assert_eq!(&read[10000..],b"end");
+    drop(file);
+    unmount("/fat5");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn directories(){
+    serial_print!("directories... ");
+    let _volume = volume("/fat3");
+    vfs::mkdir("/fat3/sub").unwrap();
+    for i in 0..40 {
+        vfs::write_file(&format!("/fat3/sub/file number {}",i),&[i as u8]).unwrap();//more than one cluster of entries
+    }
+    assert_eq!(vfs::stat("/fat3/sub").unwrap().kind,FileType::Directory);
+    assert_eq!(vfs::readdir("/fat3/sub").unwrap().len(),40);
+    assert_eq!(vfs::read_file("/fat3/sub/file number 39").unwrap(),[39]);
+    assert_eq!(vfs::rmdir("/fat3/sub"),Err(Errno::ENOTEMPTY));
+    for i in 0..40 {
+        vfs::unlink(&format!("/fat3/sub/file number {}",i)).unwrap();
+    }
+    vfs::rmdir("/fat3/sub").unwrap();
+    assert!(names("/fat3").is_empty());
+    unmount("/fat3");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn survives_remount(){
+    serial_print!("survives_remount... ");
+    let (disk,fs) = volume("/fat4");
+    vfs::mkdir("/fat4/etc").unwrap();
+    vfs::write_file("/fat4/etc/Configuration File",b"key=value\n").unwrap();
+    vfs::sync().unwrap();
+    let free = fs.free_space();
+    vfs::umount("/fat4").unwrap();
+    drop(fs);
+    let fs = fat::mount("/fat4",disk).unwrap();
+    assert_eq!(fs.free_space(),free);
+    assert_eq!(vfs::read_file("/fat4/etc/Configuration File").unwrap(),b"key=value\n");
+    assert_eq!(vfs::link("/fat4/etc/Configuration File","/fat4/copy"),Err(Errno::EPERM));
+    unmount("/fat4");
+    serial_println!("[ok]");
+}
+
+/// The volume build.rs makes with mkfs.fat and mcopy, which QEMU attaches as "hdb".
+#[test_case]
+fn mkfs_volume(){
+    serial_print!("mkfs_volume... ");
+    ata::init();
+    let disk = block::device("hdb").expect("target/fat32-test.img isn't attached");
+    vfs::mkdir("/fat6").unwrap();
+    fat::mount("/fat6",disk).unwrap();
+    assert_eq!(names("/fat6"),vec!["A long file name.txt","big.bin","docs","many"]);
+    assert_eq!(vfs::read_file("/fat6/a LONG file name.TXT").unwrap(),b"long names work\n");
+    assert_eq!(vfs::read_file("/fat6/docs/Read Me First.md").unwrap(),b"nested\n");
+    assert_eq!(vfs::read_file("/fat6/docs/sub/deep.txt").unwrap(),b"deeper\n");
+    assert_eq!(vfs::readdir("/fat6/many").unwrap().len(),40);
+    assert_eq!(vfs::read_file("/fat6/many/file number 39").unwrap(),[39]);
+    let big:Vec<u8> = (0..100_000u32).map(|i|(i*7) as u8).collect();
+    assert_eq!(vfs::read_file("/fat6/big.bin").unwrap(),big);
+    //written and removed again, so that the image stays as it was made
+    vfs::write_file("/fat6/docs/sub/Written by the kernel.txt",b"hello mtools\n").unwrap();
+    assert_eq!(names("/fat6/docs/sub"),vec!["Written by the kernel.txt","deep.txt"]);
+    vfs::sync().unwrap();
+    vfs::umount("/fat6").unwrap();
+    fat::mount("/fat6",block::device("hdb").unwrap()).unwrap();
+    assert_eq!(vfs::read_file("/fat6/docs/sub/Written by the kernel.txt").unwrap(),b"hello mtools\n");
+    vfs::unlink("/fat6/docs/sub/Written by the kernel.txt").unwrap();
+    vfs::sync().unwrap();
+    unmount("/fat6");
+    serial_println!("[ok]");
+}
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println,vfs};
+use bentos::fs::initrd::{self,Archive,EntryKind,InitrdError};
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    bentos::fs::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+#[test_case]
+fn initrd_is_unpacked_at_boot(){
+    serial_print!("initrd_is_unpacked_at_boot... ");
+    assert_eq!(vfs::read_file("/etc/motd").unwrap(),include_bytes!("../initrd/etc/motd"));
+    assert_eq!(vfs::stat("/etc").unwrap().kind,vfs::FileType::Directory);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn archive_lists_members(){
+    serial_print!("archive_lists_members... ");
+    let entries = Archive::new(initrd::IMAGE).collect::<Result<Vec<_>,_>>().unwrap();
+    let motd = entries.iter().find(|entry|entry.path() == "etc/motd").unwrap();
+    assert_eq!(motd.kind,EntryKind::File);
+    assert!(entries.iter().any(|entry|entry.path() == "etc" && entry.kind == EntryKind::Directory));
+    vfs::mkdir("/copy").unwrap();
+    assert_eq!(initrd::unpack(initrd::IMAGE,"/copy"),Ok(entries.len()));
+    assert_eq!(vfs::read_file("/copy/etc/motd").unwrap(),motd.data);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn damaged_archives_are_rejected(){
+    serial_print!("damaged_archives_are_rejected... ");
+    let mut corrupt = initrd::IMAGE.to_vec();
+    corrupt[0] ^= 1;//part of the first name, covered by the checksum
+    assert_eq!(Archive::new(&corrupt).next().unwrap().err(),Some(InitrdError::BadChecksum));
+    assert_eq!(Archive::new(&initrd::IMAGE[..100]).next().unwrap().err(),Some(InitrdError::Truncated));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,178 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc,vec,vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println,task};
+use bentos::ipc::{self,Message,PageTransfer,Rights};
+use bentos::memory::{self,address_space::AddressSpace};
+use bentos::syscall::Errno;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+#[test_case]
+fn messages_arrive_in_order(){
+    serial_print!("messages_arrive_in_order... ");
+    let endpoint = Arc::new(ipc::channel(2).unwrap());
+    let sender = endpoint.clone();
+    task::spawn(move ||{
+        for i in 0..10u8 {
+            sender.send(Message::new(vec![i])).unwrap();//blocks whenever two are queued
+        }
+    });
+    let received:Vec<u8> = (0..10).map(|_|endpoint.recv().unwrap().data[0]).collect();
+    assert_eq!(received,(0..10).collect::<Vec<u8>>());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn call_waits_for_reply(){
+    serial_print!("call_waits_for_reply... ");
+    let server = ipc::channel(4).unwrap();
+    let client = server.duplicate(Rights::SEND).unwrap();
+    task::spawn(move ||{
+        let mut request = server.recv().unwrap();
+        let answer = request.data.iter().map(|byte|byte * 2).collect();
+        request.take_reply().unwrap().reply(Message::new(answer));
+    });
+    let answer = client.call(Message::new(vec![1,2,3])).unwrap();
+    assert_eq!(answer.data,vec![2,4,6]);
+    assert_eq!(client.recv().err(),Some(Errno::EPERM));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn closed_peer_is_reported(){
+    serial_print!("closed_peer_is_reported... ");
+    let receiver = ipc::channel(4).unwrap();
+    let sender = receiver.duplicate(Rights::SEND).unwrap();
+    let receiver = {
+        let only_receive = receiver.duplicate(Rights::RECV).unwrap();
+        drop(receiver);
+        only_receive
+    };
+    sender.send(Message::new(vec![1])).unwrap();
+    drop(sender);
+    assert_eq!(receiver.recv().unwrap().data,vec![1]);//queued messages are still delivered
+    assert_eq!(receiver.recv().err(),Some(Errno::EPIPE));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn pages_move_between_address_spaces(){
+    serial_print!("pages_move_between_address_spaces... ");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let source = AddressSpace::new().unwrap();
+    let target = AddressSpace::new().unwrap();
+    let addr = source.map_anonymous(2,flags).unwrap();
+    source.write_bytes(addr + 4096u64,b"hello").unwrap();
+    let transfer = PageTransfer::take(&source,addr,2).unwrap();
+    assert!(source.translate(addr).is_none());
+    let moved = transfer.map_into(&target).unwrap();
+    let phys = target.translate(moved + 4096u64).unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(memory::phys_to_virt(phys).as_ptr::<u8>(),5) };
+    assert_eq!(bytes,b"hello");
+    serial_println!("[ok]");
+}
+
+/// Mapping fails on the second page, which is taken already: the first page is
+/// unmapped again, the range given back and both frames freed.
+#[test_case]
+fn failed_transfer_cleans_up(){
+    serial_print!("failed_transfer_cleans_up... ");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let source = AddressSpace::new().unwrap();
+    let target = AddressSpace::new().unwrap();
+    let next = target.reserve(0).unwrap();//where the transfer will be put
+    target.map_zeroed(next + 4096u64,1,flags).unwrap();
+    let addr = source.map_anonymous(2,flags).unwrap();
+    let transfer = PageTransfer::take(&source,addr,2).unwrap();
+    let (_,used) = memory::frame_stats().unwrap();
+    assert_eq!(transfer.map_into(&target),Err(Errno::ENOMEM));
+    assert_eq!(memory::frame_stats().unwrap().1,used - 2);
+    assert!(target.translate(next).is_none());
+    assert!(target.translate(next + 4096u64).is_some());//not ours to unmap
+    assert_eq!(target.reserve(0),Some(next));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn pipe_delivers_bytes_then_eof(){
+    serial_print!("pipe_delivers_bytes_then_eof... ");
+    use bentos::process::fd::File;
+    let (reader,writer) = ipc::pipe();
+    task::spawn(move ||{
+        let data:Vec<u8> = (0..10_000u32).map(|i|i as u8).collect();
+        assert_eq!(writer.write(&data),Ok(data.len()));//more than fits, so this blocks
+    });
+    let mut received = Vec::new();
+    let mut buf = [0u8;1000];
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,//the writer thread exited and dropped its end
+            len => received.extend_from_slice(&buf[..len]),
+        }
+    }
+    assert_eq!(received.len(),10_000);
+    assert!(received.iter().enumerate().all(|(i,&byte)|byte == i as u8));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn pipe_without_reader_fails(){
+    serial_print!("pipe_without_reader_fails... ");
+    use bentos::process::fd::File;
+    let (reader,writer) = ipc::pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"lost"),Err(Errno::EPIPE));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn shared_memory_is_shared(){
+    serial_print!("shared_memory_is_shared... ");
+    use bentos::memory::shared;
+    let first = AddressSpace::new().unwrap();
+    let second = AddressSpace::new().unwrap();
+    let object = shared::open("test",Some(2)).unwrap();
+    let a = object.map_into(&first,true).unwrap();
+    let b = shared::open("test",None).unwrap().map_into(&second,false).unwrap();
+    shared::unlink("test").unwrap();
+    drop(object);//the mappings keep the frames alive
+    assert!(shared::open("test",None).is_err());
+    first.write_bytes(a + 4096u64 + 8u64,b"shared").unwrap();
+    assert_eq!(first.translate(a + 4096u64),second.translate(b + 4096u64));
+    let phys = second.translate(b + 4096u64 + 8u64).unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(memory::phys_to_virt(phys).as_ptr::<u8>(),6) };
+    assert_eq!(bytes,b"shared");
+    serial_println!("[ok]");
+}
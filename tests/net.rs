@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::net::{self,Ipv4Addr,TcpListener,TcpStream,UdpSocket};
+use bentos::sync::Semaphore;
+use bentos::syscall::Errno;
+use bentos::{task,time};
+use lazy_static::lazy_static;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    net::init();
+    net::virtio::init();
+    bentos::pci::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref SERVER_DONE:Semaphore = Semaphore::new(0);
+}
+
+#[test_case]
+fn pings_loopback(){
+    serial_print!("pings_loopback... ");
+    net::ping(Ipv4Addr::LOCALHOST,time::TICKS_PER_SECOND).unwrap();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn udp_over_loopback(){
+    serial_print!("udp_over_loopback... ");
+    let mut server = UdpSocket::bind(7000).unwrap();
+    assert_eq!(UdpSocket::bind(7000).err(),Some(Errno::EADDRINUSE));
+    let client = UdpSocket::bind(0).unwrap();
+    client.send_to(b"hello",Ipv4Addr::LOCALHOST,7000).unwrap();
+    let mut buf = [0u8;16];
+    let (len,from,port) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len],b"hello");
+    assert_eq!((from,port),(Ipv4Addr::LOCALHOST,client.local_port()));
+    server.set_timeout(Some(10));
+    assert_eq!(server.recv_from(&mut buf).err(),Some(Errno::ETIMEDOUT));
+    serial_println!("[ok]");
+}
+
+/// An echo server on port 7 that serves one connection, then signals.
+fn echo_server(listener:TcpListener) {
+    let stream = listener.accept().unwrap();
+    let mut buf = [0u8;1000];
+    loop {
+        match stream.read(&mut buf).unwrap() {
+            0 => break,
+            len => stream.write_all(&buf[..len]).unwrap(),
+        }
+    }
+    drop(stream);
+    SERVER_DONE.release();
+}
+
+#[test_case]
+fn tcp_echo_over_loopback(){
+    serial_print!("tcp_echo_over_loopback... ");
+    let listener = TcpListener::bind(7).unwrap();
+    task::spawn(move ||echo_server(listener));
+    let stream = TcpStream::connect(Ipv4Addr::LOCALHOST,7).unwrap();
+    assert_eq!(stream.peer(),(Ipv4Addr::LOCALHOST,7));
+    //more than both buffers together, so the flow has to be controlled
+    let data:Vec<u8> = (0..40_000u32).map(|i|(i*7 + i/251) as u8).collect();
+    let mut echoed = Vec::new();
+    let mut buf = [0u8;1500];
+    let mut written = 0;
+    for chunk in data.chunks(5000) {
+        stream.write_all(chunk).unwrap();
+        written += chunk.len();
+        while echoed.len() < written {
+            let len = stream.read(&mut buf).unwrap();
+            assert_ne!(len,0);
+            echoed.extend_from_slice(&buf[..len]);
+        }
+    }
+    stream.shutdown();
+    assert_eq!(stream.read(&mut buf),Ok(0));
+    assert!(echoed == data);
+    SERVER_DONE.acquire();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tcp_refused(){
+    serial_print!("tcp_refused... ");
+    assert_eq!(TcpStream::connect(Ipv4Addr::LOCALHOST,9).err(),Some(Errno::ECONNREFUSED));
+    serial_println!("[ok]");
+}
+
+/// The test runner attaches a virtio-net card with QEMU's user-mode network, whose
+/// DHCP server hands out 10.0.2.15 and whose gateway at 10.0.2.2 answers pings.
+#[test_case]
+fn dhcp_and_ping_gateway(){
+    serial_print!("dhcp_and_ping_gateway... ");
+    let config = net::dhcp::configure("eth0").unwrap();
+    assert_eq!(config.address,Ipv4Addr::new(10,0,2,15));
+    assert_eq!(config.prefix,24);
+    assert_eq!(config.gateway,Some(Ipv4Addr::new(10,0,2,2)));
+    net::ping(Ipv4Addr::new(10,0,2,2),2*time::TICKS_PER_SECOND).unwrap();
+    serial_println!("[ok]");
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use bentos::{serial_print,serial_println};
+use bentos::pci::{self,Address,Bar,Driver,Match,PciDevice};
+use bentos::syscall::Errno;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    pci::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// QEMU's default machine (i440FX with a PIIX3) has the host bridge at 00:00.0 and
+/// the IDE controller at 00:01.1.
+#[test_case]
+fn finds_the_chipset(){
+    serial_print!("finds_the_chipset... ");
+    let host = pci::find(0x8086,0x1237).expect("no i440FX host bridge");
+    assert_eq!(host.address,Address::new(0,0,0));
+    assert_eq!((host.class,host.subclass),(0x06,0x00));
+    let ide = pci::find(0x8086,0x7010).expect("no PIIX3 IDE controller");
+    assert_eq!(ide.address,Address::new(0,1,1));
+    assert_eq!((ide.class,ide.subclass),(0x01,0x01));
+    assert_eq!(alloc::format!("{}",ide.address),"00:01.1");
+    assert!(pci::devices().windows(2).all(|pair|pair[0].address < pair[1].address));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sizes_bars(){
+    serial_print!("sizes_bars... ");
+    let ide = pci::find(0x8086,0x7010).unwrap();
+    match ide.bars[4] {
+        Some(Bar::Io {size, ..}) => assert_eq!(size,16),//bus master registers
+        bar => panic!("unexpected BAR4 {:?}",bar),
+    }
+    assert_eq!(ide.map_bar(4),Err(Errno::EINVAL));
+    let vga = pci::find(0x1234,0x1111).expect("no standard VGA");
+    match vga.bars[0] {
+        Some(Bar::Memory {size, prefetchable, ..}) => {
+            assert_eq!(size,16 << 20);
+            assert!(prefetchable);
+        }
+        bar => panic!("unexpected BAR0 {:?}",bar),
+    }
+    assert!(vga.map_bar(0).is_ok());
+    assert_eq!(vga.map_bar(0),vga.map_bar(0));//mapped once
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn enables_bus_mastering(){
+    serial_print!("enables_bus_mastering... ");
+    let ide = pci::find(0x8086,0x7010).unwrap();
+    let command = ide.command();
+    ide.enable_bus_master();
+    assert_ne!(ide.command() & pci::COMMAND_BUS_MASTER,0);
+    ide.set_command(command);
+    serial_println!("[ok]");
+}
+
+static PROBED:AtomicUsize = AtomicUsize::new(0);
+
+fn refuse(_device:&Arc<PciDevice>) -> Result<(),Errno> {
+    Err(Errno::ENODEV)
+}
+fn accept(_device:&Arc<PciDevice>) -> Result<(),Errno> {
+    PROBED.fetch_add(1,Ordering::SeqCst);
+    Ok(())
+}
+
+static REFUSING:Driver = Driver {name:"refusing", matches:&[Match::Class {class:0x01, subclass:0x01, prog_if:None}], probe:refuse};
+static IDE:Driver = Driver {name:"ide", matches:&[Match::Class {class:0x01, subclass:0x01, prog_if:None}], probe:accept};
+static SECOND:Driver = Driver {name:"second", matches:&[Match::Id {vendor:0x8086, device:0x7010}], probe:accept};
+
+#[test_case]
+fn matches_drivers(){
+    serial_print!("matches_drivers... ");
+    let ide = pci::find(0x8086,0x7010).unwrap();
+    pci::register_driver(&REFUSING);
+    assert_eq!(pci::driver_of(ide.address),None);//a failed probe leaves it free
+    pci::register_driver(&IDE);
+    assert_eq!(PROBED.load(Ordering::SeqCst),1);
+    assert_eq!(pci::driver_of(ide.address),Some("ide"));
+    pci::register_driver(&SECOND);
+    assert_eq!(PROBED.load(Ordering::SeqCst),1);//already claimed
+    serial_println!("[ok]");
+}
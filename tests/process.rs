@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{process::{self,signal,ProcessState},serial_print,serial_println,syscall::Errno,vfs};
+use common::minimal_elf;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    bentos::fs::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// mov edi, 42; mov eax, 1 (exit); syscall
+const EXIT_42:[u8;12] = [0xbf,0x2a,0x00,0x00,0x00,0xb8,0x01,0x00,0x00,0x00,0x0f,0x05];
+/// fork; the child exits with 7, the parent waits for it and exits with the child's code + 1
+const FORK_AND_WAIT:[u8;54] = [
+    0xb8,0x0a,0x00,0x00,0x00, 0x0f,0x05,//fork
+    0x48,0x85,0xc0, 0x75,0x0c,//test rax, rax; jnz parent
+    0xbf,0x07,0x00,0x00,0x00, 0xb8,0x01,0x00,0x00,0x00, 0x0f,0x05,//exit(7)
+    0x48,0x89,0xc7, 0x48,0x83,0xec,0x10, 0x48,0x89,0xe6,//parent: rdi = pid, rsi = status on the stack
+    0xb8,0x06,0x00,0x00,0x00, 0x0f,0x05,//wait
+    0x8b,0x3c,0x24, 0x83,0xc7,0x01,//edi = status + 1
+    0xb8,0x01,0x00,0x00,0x00, 0x0f,0x05,//exit
+];
+/// execve("/bin/exit42", NULL, NULL), exit(1) if that fails
+const EXEC_EXIT42:[u8;42] = [
+    0x48,0x8d,0x3d,0x17,0x00,0x00,0x00,//lea rdi, [rip + path]
+    0x31,0xf6, 0x31,0xd2,
+    0xb8,0x0b,0x00,0x00,0x00, 0x0f,0x05,
+    0xbf,0x01,0x00,0x00,0x00, 0xb8,0x01,0x00,0x00,0x00, 0x0f,0x05,
+    b'/',b'b',b'i',b'n',b'/',b'e',b'x',b'i',b't',b'4',b'2',0,
+];
+/// hlt, which is privileged
+const FAULT:[u8;1] = [0xf4];
+
+#[test_case]
+fn wait_returns_exit_code(){
+    serial_print!("wait_returns_exit_code... ");
+    let pid = process::spawn("exit42",&minimal_elf(&EXIT_42),&["exit42"],&[]).expect("spawn failed");
+    assert!(process::list().iter().any(|info|info.pid == pid && info.name == "exit42"));
+    assert_eq!(process::wait(Some(pid)),Ok((pid,42)));
+    //the zombie is gone once it was collected
+    assert!(process::list().iter().all(|info|info.pid != pid));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn faulting_process_is_killed(){
+    serial_print!("faulting_process_is_killed... ");
+    let pid = process::spawn("fault",&minimal_elf(&FAULT),&[],&[]).expect("spawn failed");
+    assert_eq!(process::wait(None),Ok((pid,signal::termination_code(signal::SIGSEGV))));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn forked_child_is_waited_for(){
+    serial_print!("forked_child_is_waited_for... ");
+    let pid = process::spawn("fork",&minimal_elf(&FORK_AND_WAIT),&[],&[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,8)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn exec_replaces_program(){
+    serial_print!("exec_replaces_program... ");
+    vfs::mkdir("/bin").unwrap();
+    vfs::write_file("/bin/exit42",&minimal_elf(&EXIT_42)).unwrap();
+    let pid = process::spawn("exec",&minimal_elf(&EXEC_EXIT42),&[],&[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,42)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn wait_without_children_fails(){
+    serial_print!("wait_without_children_fails... ");
+    assert_eq!(process::wait(None),Err(Errno::ECHILD));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn zombies_are_listed(){
+    serial_print!("zombies_are_listed... ");
+    let pid = process::spawn("zombie",&minimal_elf(&EXIT_42),&[],&[]).expect("spawn failed");
+    let mut state = ProcessState::Running;
+    for _ in 0..100_000 {
+        state = process::list().iter().find(|info|info.pid == pid).unwrap().state;
+        if state != ProcessState::Running {
+            break;
+        }
+        bentos::task::yield_now();
+    }
+    assert_eq!(state,ProcessState::Zombie(42));
+    assert_eq!(process::wait(Some(pid)),Ok((pid,42)));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::serial::{self,Config,Parity,COM1,COM2};
+use bentos::syscall::Errno;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// The test runner gives QEMU one serial port, so COM2 is missing.
+#[test_case]
+fn finds_the_ports(){
+    serial_print!("finds_the_ports... ");
+    assert_eq!(serial::config(COM1),Ok(Config::default()));
+    assert_eq!(serial::config(COM2),Err(Errno::ENODEV));
+    assert_eq!(serial::write(7,b"x"),Err(Errno::ENODEV));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rejects_bad_settings(){
+    serial_print!("rejects_bad_settings... ");
+    let bad = Config {baud:7, ..Config::default()};
+    assert_eq!(serial::configure(COM1,bad),Err(Errno::EINVAL));
+    let bad = Config {baud:1, ..Config::default()};//the divisor doesn't fit in 16 bits
+    assert_eq!(serial::configure(COM1,bad),Err(Errno::EINVAL));
+    let bad = Config {data_bits:9, ..Config::default()};
+    assert_eq!(serial::configure(COM1,bad),Err(Errno::EINVAL));
+    let even = Config {baud:38400, parity:Parity::Even, ..Config::default()};
+    serial::configure(COM1,even).unwrap();
+    assert_eq!(serial::config(COM1),Ok(even));
+    serial::configure(COM1,Config::default()).unwrap();
+    serial_println!("[ok]");
+}
+
+/// In loopback mode the UART receives what it sends, which goes through the receive
+/// interrupt and the ring buffer.
+#[test_case]
+fn receives_in_loopback(){
+    serial_print!("receives_in_loopback... ");
+    let mut buf = [0u8;16];
+    assert_eq!(serial::read_timeout(COM1,&mut buf,Some(5)),Err(Errno::ETIMEDOUT));
+    serial::set_loopback(COM1,true).unwrap();
+    serial::write(COM1,b"help\n").unwrap();
+    let mut received = [0u8;5];
+    let mut len = 0;
+    while len < received.len() {
+        len += serial::read_timeout(COM1,&mut received[len..],Some(100)).unwrap();
+    }
+    assert_eq!(serial::try_read(COM1,&mut buf),Ok(0));
+    serial::set_loopback(COM1,false).unwrap();
+    assert_eq!(&received,b"help\n");
+    serial_println!("[ok]");
+}
@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{process::{self,signal},serial_print,serial_println};
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// Installs a SIGUSR1 handler that stores the signal number in `flag`, sends
+/// SIGUSR1 to itself and exits with the value of `flag`.
+const HANDLE_USR1:[u8;78] = [
+    0xb8,0x0d,0x00,0x00,0x00, 0xbf,0x0a,0x00,0x00,0x00,//sigaction(SIGUSR1,
+    0x48,0x8d,0x35,0x2d,0x00,0x00,0x00, 0x48,0x8d,0x15,0x2e,0x00,0x00,0x00,//handler, restorer)
+    0x0f,0x05,
+    0xb8,0x02,0x00,0x00,0x00, 0x0f,0x05,//getpid
+    0x48,0x89,0xc7, 0xbe,0x0a,0x00,0x00,0x00, 0xb8,0x0c,0x00,0x00,0x00, 0x0f,0x05,//kill(pid, SIGUSR1)
+    0x0f,0xb6,0x3d,0x16,0x00,0x00,0x00,//movzx edi, byte [rip + flag]
+    0xb8,0x01,0x00,0x00,0x00, 0x0f,0x05,//exit
+    0x40,0x88,0x3d,0x08,0x00,0x00,0x00, 0xc3,//handler: mov [rip + flag], dil; ret
+    0xb8,0x0f,0x00,0x00,0x00, 0x0f,0x05,//restorer: sigreturn
+    0x00,//flag
+];
+/// Ignores SIGTERM, sends it to itself and exits with 0.
+const IGNORE_TERM:[u8;50] = [
+    0xb8,0x0d,0x00,0x00,0x00, 0xbf,0x0f,0x00,0x00,0x00, 0xbe,0x01,0x00,0x00,0x00, 0x31,0xd2, 0x0f,0x05,
+    0xb8,0x02,0x00,0x00,0x00, 0x0f,0x05,
+    0x48,0x89,0xc7, 0xbe,0x0f,0x00,0x00,0x00, 0xb8,0x0c,0x00,0x00,0x00, 0x0f,0x05,
+    0x31,0xff, 0xb8,0x01,0x00,0x00,0x00, 0x0f,0x05,
+];
+/// jmp $
+const SPIN:[u8;2] = [0xeb,0xfe];
+/// Installs a SIGSEGV handler that exits with 42, then reads address 0.
+const HANDLE_SEGV:[u8;54] = [
+    0xb8,0x0d,0x00,0x00,0x00, 0xbf,0x0b,0x00,0x00,0x00,//sigaction(SIGSEGV,
+    0x48,0x8d,0x35,0x12,0x00,0x00,0x00, 0x48,0x8d,0x15,0x17,0x00,0x00,0x00,//handler, restorer)
+    0x0f,0x05,
+    0x8b,0x04,0x25,0x00,0x00,0x00,0x00,//mov eax, [0]
+    0xeb,0xfe,
+    0xbf,0x2a,0x00,0x00,0x00, 0xb8,0x01,0x00,0x00,0x00, 0x0f,0x05,//handler: exit(42)
+    0xb8,0x0f,0x00,0x00,0x00, 0x0f,0x05,//restorer: sigreturn
+];
+/// mov eax, [0]
+const FAULT:[u8;7] = [0x8b,0x04,0x25,0x00,0x00,0x00,0x00];
+/// Installs a SIGUSR1 handler that stores the signal number in `flag`, then spins
+/// without system calls until the flag is set, with 5 in rcx, and exits with rcx.
+const SPIN_UNTIL_USR1:[u8;65] = [
+    0xb8,0x0d,0x00,0x00,0x00, 0xbf,0x0a,0x00,0x00,0x00,//sigaction(SIGUSR1,
+    0x48,0x8d,0x35,0x20,0x00,0x00,0x00, 0x48,0x8d,0x15,0x21,0x00,0x00,0x00,//handler, restorer)
+    0x0f,0x05,
+    0xb9,0x05,0x00,0x00,0x00,//mov ecx, 5
+    0x80,0x3d,0x1a,0x00,0x00,0x00,0x00, 0x74,0xf7,//wait: cmp byte [rip + flag], 0; je wait
+    0x89,0xcf, 0xb8,0x01,0x00,0x00,0x00, 0x0f,0x05,//exit(ecx)
+    0x40,0x88,0x3d,0x08,0x00,0x00,0x00, 0xc3,//handler: mov [rip + flag], dil; ret
+    0xb8,0x0f,0x00,0x00,0x00, 0x0f,0x05,//restorer: sigreturn
+    0x00,//flag
+];
+
+/// Builds a static executable with one read+write+execute segment holding the headers and `code`.
+fn minimal_elf(code:&[u8]) -> Vec<u8> {
+    common::elf_with_flags(code,7)
+}
+
+#[test_case]
+fn handler_runs_and_returns(){
+    serial_print!("handler_runs_and_returns... ");
+    let pid = process::spawn("usr1",&minimal_elf(&HANDLE_USR1),&[],&[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,signal::SIGUSR1 as i32)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn ignored_signal_is_discarded(){
+    serial_print!("ignored_signal_is_discarded... ");
+    let pid = process::spawn("ignore",&minimal_elf(&IGNORE_TERM),&[],&[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,0)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn default_action_terminates_running_process(){
+    serial_print!("default_action_terminates_running_process... ");
+    let pid = process::spawn("spin",&minimal_elf(&SPIN),&[],&[]).expect("spawn failed");
+    signal::kill(pid,signal::SIGTERM).expect("kill failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,signal::termination_code(signal::SIGTERM))));
+    assert!(signal::kill(pid,signal::SIGTERM).is_err());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fault_runs_handler(){
+    serial_print!("fault_runs_handler... ");
+    let pid = process::spawn("segv",&minimal_elf(&HANDLE_SEGV),&[],&[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,42)));
+    let pid = process::spawn("fault",&minimal_elf(&FAULT),&[],&[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,signal::termination_code(signal::SIGSEGV))));
+    serial_println!("[ok]");
+}
+
+/// A process that makes no system calls gets its handler run from the timer
+/// interrupt, and goes on where it was interrupted with all registers intact.
+#[test_case]
+fn handler_interrupts_running_process(){
+    serial_print!("handler_interrupts_running_process... ");
+    let pid = process::spawn("spin_usr1",&minimal_elf(&SPIN_UNTIL_USR1),&[],&[]).expect("spawn failed");
+    bentos::time::sleep_ms(100);//for the handler to be installed
+    signal::kill(pid,signal::SIGUSR1).expect("kill failed");
+    assert_eq!(process::wait(Some(pid)),Ok((pid,5)));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use bentos::{serial_print,serial_println,task,smp};
+use bentos::sync::Semaphore;
+use lazy_static::lazy_static;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    smp::init(&mut mapper, &mut frame_allocator).expect("SMP initialization failed");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref DONE:Semaphore = Semaphore::new(0);
+}
+
+//QEMU is started with `-smp 4` for the tests
+#[test_case]
+fn all_cpus_online(){
+    serial_print!("all_cpus_online... ");
+    assert_eq!(smp::cpu_count(),4);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn threads_run_on_every_cpu(){
+    serial_print!("threads_run_on_every_cpu... ");
+    static SEEN:AtomicUsize = AtomicUsize::new(0);
+    fn worker() {
+        SEEN.fetch_or(1 << smp::percpu::cpu_id(),Ordering::SeqCst);
+        DONE.release();
+    }
+    for _ in 0..8 {
+        task::spawn(worker);
+    }
+    for _ in 0..8 {
+        DONE.acquire();
+    }
+    assert_eq!(SEEN.load(Ordering::SeqCst),0b1111);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn remote_call_runs_on_other_cpus(){
+    serial_print!("remote_call_runs_on_other_cpus... ");
+    static CALLED:AtomicUsize = AtomicUsize::new(0);
+    smp::ipi::call(smp::ipi::Target::AllButSelf,||{
+        CALLED.fetch_or(1 << smp::percpu::cpu_id(),Ordering::SeqCst);
+    });
+    assert_eq!(CALLED.load(Ordering::SeqCst),0b1110);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tlb_shootdown_is_acknowledged(){
+    serial_print!("tlb_shootdown_is_acknowledged... ");
+    //returns only once every other CPU flushed
+    smp::tlb::shootdown(x86_64::VirtAddr::new(bentos::allocator::HEAP_START as u64),1);
+    smp::tlb::shootdown(x86_64::VirtAddr::new(0),0);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,132 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use bentos::{serial_print,serial_println,task};
+use bentos::sync::{Mutex,RwLock,Semaphore,Condvar};
+use lazy_static::lazy_static;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref DONE:Semaphore = Semaphore::new(0);
+    static ref COUNTER:Mutex<usize> = Mutex::new(0);
+    static ref READY:Mutex<bool> = Mutex::new(false);
+    static ref READY_CHANGED:Condvar = Condvar::new();
+    static ref TABLE:RwLock<usize> = RwLock::new(0);
+}
+
+#[test_case]
+fn semaphore_wakes_waiter(){
+    serial_print!("semaphore_wakes_waiter... ");
+    task::spawn(||DONE.release());
+    DONE.acquire();//parks the boot thread until the spawned thread ran
+    assert_eq!(DONE.count(),0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mutex_is_exclusive(){
+    serial_print!("mutex_is_exclusive... ");
+    static RUNNING:AtomicUsize = AtomicUsize::new(0);
+    fn worker() {
+        for _ in 0..1000 {
+            let mut counter = COUNTER.lock();
+            assert_eq!(RUNNING.fetch_add(1,Ordering::SeqCst),0);
+            *counter += 1;
+            task::yield_now();//give the other thread a chance to run into the lock
+            RUNNING.fetch_sub(1,Ordering::SeqCst);
+        }
+        DONE.release();
+    }
+    task::spawn(worker);
+    task::spawn(worker);
+    DONE.acquire();
+    DONE.acquire();
+    assert_eq!(*COUNTER.lock(),2000);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn condvar_signals_change(){
+    serial_print!("condvar_signals_change... ");
+    task::spawn(||{
+        *READY.lock() = true;
+        READY_CHANGED.notify_all();
+    });
+    let ready = READY_CHANGED.wait_while(READY.lock(),|ready|!*ready);
+    assert!(*ready);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rwlock_shares_readers(){
+    serial_print!("rwlock_shares_readers... ");
+    static READING:AtomicUsize = AtomicUsize::new(0);
+    fn reader() {
+        let table = TABLE.read();
+        READING.fetch_add(1,Ordering::SeqCst);
+        while READING.load(Ordering::SeqCst) < 3 {
+            task::yield_now();//only gets past this if all readers hold the lock at once
+        }
+        assert_eq!(*table,0);
+        drop(table);
+        DONE.release();
+    }
+    for _ in 0..3 {
+        task::spawn(reader);
+    }
+    for _ in 0..3 {
+        DONE.acquire();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rwlock_writer_excludes_readers(){
+    serial_print!("rwlock_writer_excludes_readers... ");
+    static READ:AtomicUsize = AtomicUsize::new(0);
+    let mut table = TABLE.write();
+    task::spawn(||{
+        READ.store(*TABLE.read(),Ordering::SeqCst);
+        DONE.release();
+    });
+    for _ in 0..10 {
+        task::yield_now();
+    }
+    assert!(TABLE.try_read().is_none());
+    assert_eq!(READ.load(Ordering::SeqCst),0);
+    *table = 42;
+    drop(table);
+    DONE.acquire();
+    assert_eq!(READ.load(Ordering::SeqCst),42);//the reader only got in after the write
+    serial_println!("[ok]");
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![feature(asm)]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println,task,usermode};
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// Waits until the thread is gone, failing after a few seconds.
+fn wait_for_exit(id:task::ThreadId) {
+    for _ in 0..100_000 {
+        if !task::is_alive(id) {
+            return;
+        }
+        task::yield_now();
+    }
+    panic!("user thread still alive");
+}
+
+#[inline(never)]
+extern "C" fn touch_kernel_memory() -> ! {
+    unsafe { core::ptr::read_volatile(bentos::allocator::HEAP_START as *const u8) };
+    loop {}
+}
+
+#[inline(never)]
+extern "C" fn execute_privileged() -> ! {
+    x86_64::instructions::hlt();
+    loop {}
+}
+
+fn run_in_user_mode(function:usize) {
+    let entry = usermode::map_function(function).expect("mapping user code failed");
+    let stack = usermode::map_stack(usermode::USER_STACK_PAGES).expect("mapping user stack failed");
+    unsafe { usermode::enter(entry,stack) };
+}
+
+#[test_case]
+fn user_page_fault_kills_thread(){
+    serial_print!("user_page_fault_kills_thread... ");
+    let id = task::spawn(||run_in_user_mode(touch_kernel_memory as usize));
+    wait_for_exit(id);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_privileged_instruction_kills_thread(){
+    serial_print!("user_privileged_instruction_kills_thread... ");
+    let id = task::spawn(||run_in_user_mode(execute_privileged as usize));
+    wait_for_exit(id);
+    serial_println!("[ok]");
+}
+
+#[inline(never)]
+extern "C" fn call_kernel() -> ! {
+    //only code on this function's pages is mapped for user mode, so the system
+    //calls are issued inline and the message is built from immediates on the stack
+    let message = 0x0a21_6f6c_6c65_68u64.to_le_bytes();//"hello!\n"
+    unsafe {
+        asm!("syscall", inout("rax") 0u64 => _, in("rdi") 1u64, in("rsi") message.as_ptr(), in("rdx") 7u64,
+            lateout("rcx") _, lateout("r11") _);
+        asm!("syscall", inout("rax") 0u64 => _, in("rdi") 1u64, in("rsi") 0xdead_beefu64, in("rdx") 7u64,
+            lateout("rcx") _, lateout("r11") _);//bad pointer, must fail with EFAULT
+        asm!("syscall", inout("rax") 5u64 => _, lateout("rcx") _, lateout("r11") _);
+        asm!("syscall", in("rax") 1u64, in("rdi") 0u64, options(noreturn));
+    }
+}
+
+#[test_case]
+fn user_thread_exits_through_syscall(){
+    serial_print!("user_thread_exits_through_syscall... ");
+    let id = task::spawn(||run_in_user_mode(call_kernel as usize));
+    wait_for_exit(id);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn kernel_pointers_are_rejected(){
+    serial_print!("kernel_pointers_are_rejected... ");
+    use bentos::syscall::{uaccess,Errno};
+    let kernel_addr = bentos::allocator::HEAP_START as u64;
+    assert_eq!(uaccess::check_user_range(kernel_addr,8,false),Err(Errno::EFAULT));
+    assert_eq!(uaccess::check_user_range(usermode::USER_END - 4,8,false),Err(Errno::EFAULT));
+    serial_println!("[ok]");
+}
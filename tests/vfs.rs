@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{string::String,vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::fs::RamFs;
+use bentos::syscall::Errno;
+use bentos::vfs::{self,File,FileType,OpenFlags,SeekFrom};
+use common::names;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    bentos::fs::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+#[test_case]
+fn write_seek_read(){
+    serial_print!("write_seek_read... ");
+    let file = vfs::open("/notes",OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(file.write(b"hello world"),Ok(11));
+    assert_eq!(file.seek(SeekFrom::Start(6)),Ok(6));
+    let mut buf = [0u8;16];
+    assert_eq!(file.read(&mut buf),Ok(5));
+    assert_eq!(&buf[..5],b"world");
+    assert_eq!(file.read(&mut buf),Ok(0));
+    assert_eq!(file.seek(SeekFrom::Current(-20)),Err(Errno::EINVAL));
+    assert_eq!(vfs::stat("/notes").unwrap().size,11);
+    assert_eq!(vfs::open("/notes",OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE).err(),Some(Errno::EEXIST));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn holes_and_truncate(){
+    serial_print!("holes_and_truncate... ");
+    let file = vfs::open("/sparse",OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+    file.seek(SeekFrom::Start(3*4096 + 10)).unwrap();
+    file.write(b"tail").unwrap();
+    file.truncate(4096 + 2).unwrap();
+    file.truncate(3*4096).unwrap();
+    let data = vfs::read_file("/sparse").unwrap();
+    assert_eq!(data.len(),3*4096);
+    assert!(data.iter().all(|&byte|byte == 0));
+    vfs::write_file("/sparse",b"short").unwrap();
+    assert_eq!(vfs::read_file("/sparse").unwrap(),b"short");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn directories_and_dot_dot(){
+    serial_print!("directories_and_dot_dot... ");
+    vfs::mkdir("/a").unwrap();
+    vfs::mkdir("/a/b").unwrap();
+    vfs::write_file("/a/b/c",b"c").unwrap();
+    assert_eq!(vfs::read_file("/a/./b/../b//c").unwrap(),b"c");
+    assert_eq!(vfs::read_file("/../../a/b/c").unwrap(),b"c");
+    assert_eq!(vfs::stat("/a").unwrap().links,3);
+    assert_eq!(names("/a/b"),vec![String::from("c")]);
+    assert_eq!(vfs::mkdir("/a/b/c/d"),Err(Errno::ENOTDIR));
+    assert_eq!(vfs::rmdir("/a/b"),Err(Errno::ENOTEMPTY));
+    assert_eq!(vfs::unlink("/a/b"),Err(Errno::EISDIR));
+    assert_eq!(vfs::open("/a",OpenFlags::WRITE).err(),Some(Errno::EISDIR));
+    vfs::unlink("/a/b/c").unwrap();
+    vfs::rmdir("/a/b").unwrap();
+    assert_eq!(vfs::stat("/a/b").err(),Some(Errno::ENOENT));
+    assert_eq!(vfs::stat("/a").unwrap().links,2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn hard_links_share_data(){
+    serial_print!("hard_links_share_data... ");
+    vfs::write_file("/first",b"shared").unwrap();
+    vfs::link("/first","/second").unwrap();
+    assert_eq!(vfs::stat("/second").unwrap().links,2);
+    let open = vfs::open("/second",OpenFlags::READ).unwrap();
+    vfs::unlink("/first").unwrap();
+    vfs::unlink("/second").unwrap();
+    let mut buf = [0u8;6];
+    assert_eq!(open.read(&mut buf),Ok(6));//unlinked files live on while open
+    assert_eq!(&buf,b"shared");
+    assert_eq!(open.stat().unwrap().links,0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mounts_and_limits(){
+    serial_print!("mounts_and_limits... ");
+    vfs::mkdir("/tmp").unwrap();
+    vfs::mount("/tmp",RamFs::with_limit(2*4096)).unwrap();
+    assert_eq!(vfs::mounts().len(),2);
+    vfs::write_file("/tmp/x",&[1;4096]).unwrap();
+    assert_eq!(vfs::write_file("/tmp/y",&[1;2*4096]),Err(Errno::ENOSPC));
+    vfs::write_file("/notes2",b"root").unwrap();
+    assert_eq!(vfs::link("/notes2","/tmp/notes2"),Err(Errno::EXDEV));
+    assert_eq!(vfs::rmdir("/tmp"),Err(Errno::EBUSY));
+    assert_eq!(vfs::stat("/tmp/..").unwrap().kind,FileType::Directory);
+    assert_eq!(vfs::lookup("/tmp/..").unwrap().inode().metadata().ino,vfs::stat("/").unwrap().ino);
+    vfs::umount("/tmp").unwrap();
+    assert_eq!(vfs::stat("/tmp/x").err(),Some(Errno::ENOENT));
+    assert_eq!(vfs::umount("/"),Err(Errno::EBUSY));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,244 @@
+//! The VFS layer on its own, over a minimal in-memory file system defined here:
+//! path resolution, mounts, open files and the directory calls.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap,string::String,sync::Arc,vec,vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64,Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use bentos::{serial_print,serial_println};
+use bentos::syscall::Errno;
+use bentos::vfs::{self,DirEntry,File,FileSystem,FileType,Inode,Metadata,OpenFlags,SeekFrom};
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    vfs::mount("/",TestFs::new()).unwrap();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+static NEXT_INO:AtomicU64 = AtomicU64::new(1);
+
+/// A file or directory of `TestFs`; directories hold their entries, files their bytes.
+struct Node {
+    ino:u64,
+    kind:FileType,
+    data:Mutex<Vec<u8>>,
+    entries:Mutex<BTreeMap<String,Arc<Node>>>,
+}impl Node {
+    fn new(kind:FileType) -> Arc<Self> {
+        Arc::new(Node {
+            ino:NEXT_INO.fetch_add(1,Ordering::Relaxed),
+            kind,
+            data:Mutex::new(Vec::new()),
+            entries:Mutex::new(BTreeMap::new()),
+        })
+    }
+    fn directory(&self) -> Result<(),Errno> {
+        if self.kind == FileType::Directory {Ok(())} else {Err(Errno::ENOTDIR)}
+    }
+    fn file(&self) -> Result<(),Errno> {
+        if self.kind == FileType::Regular {Ok(())} else {Err(Errno::EISDIR)}
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        let size = interrupts::without_interrupts(||self.data.lock().len()) as u64;
+        Metadata {ino:self.ino, kind:self.kind, size, links:1}
+    }
+
+    fn read_at(&self, offset:u64, buf:&mut [u8]) -> Result<usize,Errno> {
+        self.file()?;
+        interrupts::without_interrupts(||{
+            let data = self.data.lock();
+            let start = (offset as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start+len]);
+            Ok(len)
+        })
+    }
+    fn write_at(&self, offset:u64, buf:&[u8]) -> Result<usize,Errno> {
+        self.file()?;
+        interrupts::without_interrupts(||{
+            let mut data = self.data.lock();
+            let end = offset as usize + buf.len();
+            if data.len() < end {
+                data.resize(end,0);
+            }
+            data[offset as usize..end].copy_from_slice(buf);
+            Ok(buf.len())
+        })
+    }
+    fn truncate(&self, size:u64) -> Result<(),Errno> {
+        self.file()?;
+        interrupts::without_interrupts(||self.data.lock().resize(size as usize,0));
+        Ok(())
+    }
+
+    fn lookup(&self, name:&str) -> Result<Arc<dyn Inode>,Errno> {
+        self.directory()?;
+        assert!(name != "." && name != "..","the VFS resolves {:?} itself",name);
+        let node = interrupts::without_interrupts(||self.entries.lock().get(name).cloned());
+        node.map(|node|node as Arc<dyn Inode>).ok_or(Errno::ENOENT)
+    }
+    fn create(&self, name:&str, kind:FileType) -> Result<Arc<dyn Inode>,Errno> {
+        self.directory()?;
+        interrupts::without_interrupts(||{
+            let mut entries = self.entries.lock();
+            if entries.contains_key(name) {
+                return Err(Errno::EEXIST);
+            }
+            let node = Node::new(kind);
+            entries.insert(String::from(name),node.clone());
+            Ok(node as Arc<dyn Inode>)
+        })
+    }
+    fn unlink(&self, name:&str) -> Result<(),Errno> {
+        self.directory()?;
+        interrupts::without_interrupts(||{
+            let mut entries = self.entries.lock();
+            let node = entries.get(name).ok_or(Errno::ENOENT)?;
+            if !node.entries.lock().is_empty() {
+                return Err(Errno::ENOTEMPTY);
+            }
+            entries.remove(name);
+            Ok(())
+        })
+    }
+    fn readdir(&self) -> Result<Vec<DirEntry>,Errno> {
+        self.directory()?;
+        Ok(interrupts::without_interrupts(||self.entries.lock().iter().map(|(name,node)|DirEntry {name:name.clone(), ino:node.ino, kind:node.kind}).collect()))
+    }
+}
+
+struct TestFs {
+    root:Arc<Node>,
+}impl TestFs {
+    fn new() -> Arc<Self> {
+        Arc::new(TestFs {root:Node::new(FileType::Directory)})
+    }
+}
+
+impl FileSystem for TestFs {
+    fn name(&self) -> &'static str {
+        "testfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+fn create(path:&str, data:&[u8]) {
+    let file = vfs::open(path,OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(file.write(data),Ok(data.len()));
+}
+
+fn names(path:&str) -> Vec<String> {
+    vfs::readdir(path).unwrap().into_iter().map(|entry|entry.name).collect()
+}
+
+#[test_case]
+fn resolves_paths(){
+    serial_print!("resolves_paths... ");
+    vfs::mkdir("/a").unwrap();
+    vfs::mkdir("/a/b").unwrap();
+    create("/a/b/f",b"x");
+    assert_eq!(vfs::read_file("//a/./b/../b///f"),Ok(vec![b'x']));
+    assert_eq!(vfs::stat("/..").unwrap().ino,vfs::stat("/").unwrap().ino);//the root is its own parent
+    assert_eq!(vfs::stat("/a/missing").err(),Some(Errno::ENOENT));
+    assert_eq!(vfs::stat("/a/b/f/g").err(),Some(Errno::ENOTDIR));
+    assert_eq!(vfs::stat("").err(),Some(Errno::ENOENT));
+    let long = "x".repeat(vfs::MAX_NAME + 1);
+    assert_eq!(vfs::stat(&long).err(),Some(Errno::ENAMETOOLONG));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn open_files_keep_offsets(){
+    serial_print!("open_files_keep_offsets... ");
+    let file = vfs::open("/notes",OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(file.write(b"hello world"),Ok(11));
+    assert_eq!(file.seek(SeekFrom::Start(6)),Ok(6));
+    let mut buf = [0u8;16];
+    assert_eq!(file.read(&mut buf),Ok(5));
+    assert_eq!(&buf[..5],b"world");
+    let other = vfs::open("/notes",OpenFlags::READ).unwrap();//an offset of its own
+    assert_eq!(other.read(&mut buf[..5]),Ok(5));
+    assert_eq!(&buf[..5],b"hello");
+    assert_eq!(other.write(b"!"),Err(Errno::EBADF));
+    let append = vfs::open("/notes",OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+    append.write(b"!").unwrap();
+    assert_eq!(vfs::read_file("/notes").unwrap(),b"hello world!");
+    vfs::open("/notes",OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(vfs::stat("/notes").unwrap().size,0);
+    assert_eq!(vfs::open("/notes",OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE).err(),Some(Errno::EEXIST));
+    assert_eq!(vfs::open("/",OpenFlags::WRITE).err(),Some(Errno::EISDIR));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn creates_and_removes_names(){
+    serial_print!("creates_and_removes_names... ");
+    vfs::mkdir("/dir").unwrap();
+    create("/dir/file",b"data");
+    assert_eq!(vfs::mkdir("/dir"),Err(Errno::EEXIST));
+    assert_eq!(vfs::stat("/dir").unwrap().kind,FileType::Directory);
+    assert_eq!(names("/dir"),["file"]);
+    assert_eq!(vfs::rmdir("/dir"),Err(Errno::ENOTEMPTY));
+    assert_eq!(vfs::rmdir("/dir/file"),Err(Errno::ENOTDIR));
+    assert_eq!(vfs::unlink("/dir"),Err(Errno::EISDIR));
+    assert_eq!(vfs::unlink("/dir/.."),Err(Errno::EINVAL));
+    vfs::unlink("/dir/file").unwrap();
+    assert_eq!(vfs::stat("/dir/file").err(),Some(Errno::ENOENT));//the cached dentry went too
+    vfs::rmdir("/dir").unwrap();
+    assert!(!names("/").contains(&String::from("dir")));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mounts_and_unmounts(){
+    serial_print!("mounts_and_unmounts... ");
+    vfs::mkdir("/mnt").unwrap();
+    create("/mnt/hidden",b"");
+    vfs::mount("/mnt",TestFs::new()).unwrap();
+    assert!(names("/mnt").is_empty());
+    create("/mnt/inner",b"inside");
+    assert_eq!(vfs::stat("/mnt/..").unwrap().ino,vfs::stat("/").unwrap().ino);//".." leaves the mount
+    assert!(vfs::mounts().iter().any(|mount|mount.path == "/mnt" && mount.fs == "testfs"));
+    let open = vfs::open("/mnt/inner",OpenFlags::READ).unwrap();
+    assert_eq!(vfs::umount("/"),Err(Errno::EBUSY));
+    vfs::umount("/mnt").unwrap();
+    assert_eq!(names("/mnt"),["hidden"]);
+    let mut buf = [0u8;6];
+    assert_eq!(open.read(&mut buf),Ok(6));//still works on the detached file system
+    assert_eq!(&buf,b"inside");
+    serial_println!("[ok]");
+}
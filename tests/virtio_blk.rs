@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(bentos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{vec,vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use bentos::{serial_print,serial_println};
+use bentos::block;
+use bentos::syscall::Errno;
+
+entry_point!(main);
+
+fn main(boot_info:&'static BootInfo) -> !{
+    use bentos::allocator;
+    use bentos::memory::{self,BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    bentos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    block::virtio::init();
+    bentos::pci::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info:&PanicInfo)->! {
+    bentos::test_panic_handler(info)
+}
+
+/// The test runner attaches `target/virtio-test.img` (made by build.rs, 8192
+/// sectors each starting with "sector <n>") with `-drive if=virtio`.
+#[test_case]
+fn finds_the_disk(){
+    serial_print!("finds_the_disk... ");
+    let disk = block::device("vda").expect("no virtio disk");
+    assert_eq!(disk.block_size(),512);
+    assert_eq!(disk.block_count(),8192);
+    let mut sector = vec![0u8;1024];
+    disk.read_blocks(5,&mut sector).unwrap();
+    assert!(sector.starts_with(b"sector 5\0"));
+    assert!(sector[512..].starts_with(b"sector 6\0"));
+    assert_eq!(disk.read_blocks(8192,&mut sector[..512]),Err(Errno::EINVAL));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn round_trip(){
+    serial_print!("round_trip... ");
+    let disk = block::device("vda").unwrap();
+    //more than one request's worth of pages, not ending on a page
+    let pattern:Vec<u8> = (0..330*512u32).map(|i|(i*7 + i/512) as u8).collect();
+    disk.write_blocks(4000,&pattern).unwrap();
+    disk.flush().unwrap();
+    let mut back = vec![0u8;pattern.len()];
+    disk.read_blocks(4000,&mut back).unwrap();
+    assert!(back == pattern);
+    let mut neighbour = vec![0u8;512];
+    disk.read_blocks(4330,&mut neighbour).unwrap();
+    assert!(neighbour.starts_with(b"sector 4330\0"));
+    serial_println!("[ok]");
+}